@@ -31,13 +31,15 @@ struct OpenPR<'a> {
     access_token: String,
     title: &'a str,
     head: &'a str,
-    packages: &'a str,
+    base: &'a str,
     id: u64,
     key: EncodingKey,
     desc: &'a str,
     pkg_affected: &'a [String],
+    build_order: &'a [&'a str],
     tags: Option<&'a [String]>,
     archs: &'a [&'a str],
+    checked_archs: &'a [&'a str],
 }
 
 #[derive(Debug)]
@@ -49,6 +51,10 @@ pub struct OpenPRRequest<'a> {
     pub tags: Option<Vec<String>>,
     /// If None, automatically deduced via `get_archs()`
     pub archs: Option<Vec<&'a str>>,
+    /// Branch to merge into, usually stable
+    pub base: String,
+    /// Archs already built successfully, checked in the PR body
+    pub checked_archs: Vec<&'a str>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -84,12 +90,18 @@ pub async fn open_pr(
         title,
         tags,
         archs,
+        base,
+        checked_archs,
     } = openpr_request;
 
     let _lock = ABBS_REPO_LOCK.lock().await;
 
     update_abbs(&git_ref, &abbs_path, false).await?;
 
+    if !remote_branch_exists(&abbs_path, &base).await? {
+        return Err(anyhow!("Base branch {base} does not exist").into());
+    }
+
     let abbs_path_clone = abbs_path.clone();
     let commits = task::spawn_blocking(move || get_commits(&abbs_path_clone))
         .instrument(info_span!("get_commits"))
@@ -124,17 +136,30 @@ pub async fn open_pr(
     .instrument(info_span!("find_version_by_packages_list"))
     .await?;
 
+    let entries = packages.split(',').collect::<Vec<_>>();
+    let names = entries
+        .iter()
+        .map(|entry| parse_qualified_package(entry).name.to_string())
+        .collect::<Vec<_>>();
+    let abbs_path_clone = abbs_path.clone();
+    let deps = task::spawn_blocking(move || find_deps_by_packages(&names, &abbs_path_clone))
+        .instrument(info_span!("find_deps_by_packages"))
+        .await?;
+    let build_order = sort_build_order(&entries, &deps);
+
     let pr = open_pr_inner(OpenPR {
         access_token: access_token.to_string(),
         title: &title,
         head: &git_ref,
-        packages: &packages,
+        base: &base,
         id: app_id,
         key: key.clone(),
         desc: &commits,
         pkg_affected: &pkg_affected,
+        build_order: &build_order,
         tags: tags.as_deref(),
         archs: &archs,
+        checked_archs: &checked_archs,
     })
     .await?;
 
@@ -200,6 +225,90 @@ pub fn find_version_by_packages(pkgs: &[String], p: &Path) -> Vec<(String, Strin
     res
 }
 
+/// Names a package provides and the dependencies it requires
+#[derive(Debug, Default, Clone)]
+pub struct PackageDeps {
+    /// PKGNAME of each defines, e.g. split packages
+    pub provides: Vec<String>,
+    /// PKGDEP and BUILDDEP without version constraints
+    pub deps: Vec<String>,
+}
+
+/// `packages` should have no groups nor modifiers
+/// return map of package name to its dependencies
+#[tracing::instrument(skip(p))]
+pub fn find_deps_by_packages(pkgs: &[String], p: &Path) -> BTreeMap<String, PackageDeps> {
+    let mut res: BTreeMap<String, PackageDeps> = BTreeMap::new();
+
+    for_each_abbs(p, |pkg, path| {
+        if !pkgs.contains(&pkg.to_string()) {
+            return;
+        }
+
+        let entry = res.entry(pkg.to_string()).or_default();
+        for i in locate_defines(path) {
+            let Ok(defines) = std::fs::read_to_string(i) else {
+                continue;
+            };
+            let defines = read_ab_with_apml(&defines);
+
+            if let Some(pkgname) = defines.get("PKGNAME") {
+                entry.provides.push(pkgname.clone());
+            }
+            for var in ["PKGDEP", "BUILDDEP"] {
+                for dep in defines.get(var).map(|s| s.as_str()).unwrap_or("").split_whitespace() {
+                    // strip version constraints, e.g. glibc>=2.38
+                    let name = dep.split(['<', '>', '=']).next().unwrap_or(dep);
+                    if !name.is_empty() {
+                        entry.deps.push(name.to_string());
+                    }
+                }
+            }
+        }
+    });
+
+    res
+}
+
+/// Order packages so that dependencies are built first
+///
+/// `packages` may have groups and modifiers, which are looked up in `deps` by
+/// name. Packages keep their relative order unless a dependency requires
+/// otherwise, cycles are broken in the original order.
+pub fn sort_build_order<'a>(packages: &[&'a str], deps: &BTreeMap<String, PackageDeps>) -> Vec<&'a str> {
+    let names = packages
+        .iter()
+        .map(|pkg| parse_qualified_package(pkg).name)
+        .collect::<Vec<_>>();
+
+    // whether package i depends on package j
+    let depends_on = |i: usize, j: usize| {
+        let (Some(pkg), Some(dep)) = (deps.get(names[i]), deps.get(names[j])) else {
+            return false;
+        };
+        names[i] != names[j]
+            && pkg
+                .deps
+                .iter()
+                .any(|d| d == names[j] || dep.provides.contains(d))
+    };
+
+    let mut res = vec![];
+    let mut done = vec![false; packages.len()];
+    while res.len() < packages.len() {
+        let next = (0..packages.len())
+            .filter(|i| !done[*i])
+            .find(|i| (0..packages.len()).all(|j| done[j] || !depends_on(*i, j)))
+            // cycle
+            .or_else(|| (0..packages.len()).find(|i| !done[*i]))
+            .unwrap();
+        done[next] = true;
+        res.push(packages[next]);
+    }
+
+    res
+}
+
 /// `packages` should have no groups nor modifiers
 #[tracing::instrument(skip(p))]
 fn find_version_by_packages_list(pkgs: &[String], p: &Path) -> Vec<String> {
@@ -303,13 +412,111 @@ fn get_commits(path: &Path) -> anyhow::Result<Vec<Commit>> {
     Ok(res)
 }
 
+/// What a git ref given by the user points to
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum GitRefKind {
+    Branch,
+    Tag,
+    Commit,
+}
+
+impl GitRefKind {
+    /// Name recorded in pipelines
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GitRefKind::Branch => "branch",
+            GitRefKind::Tag => "tag",
+            GitRefKind::Commit => "commit",
+        }
+    }
+}
+
+/// Whether the git ref is a full commit sha
+pub fn is_commit_sha(git_ref: &str) -> bool {
+    git_ref.len() == 40 && git_ref.chars().all(|ch| ch.is_ascii_hexdigit())
+}
+
+/// Detect whether a git ref is a branch, tag or commit and resolve it to a commit sha,
+/// branches win over tags of the same name
+///
+/// Must be called after the ref has been fetched into the repo
+pub async fn resolve_ref(repo: &Path, git_ref: &str) -> anyhow::Result<(GitRefKind, String)> {
+    // a raw sha needs no branch lookup
+    let candidates = if is_commit_sha(git_ref) {
+        vec![(GitRefKind::Commit, git_ref.to_string())]
+    } else {
+        vec![
+            (GitRefKind::Branch, format!("refs/remotes/origin/{git_ref}")),
+            (GitRefKind::Branch, format!("refs/heads/{git_ref}")),
+            (GitRefKind::Tag, format!("refs/tags/{git_ref}")),
+        ]
+    };
+
+    for (kind, name) in candidates {
+        let output = process::Command::new("git")
+            .args(["rev-parse", "--verify", "--quiet"])
+            .arg(format!("{name}^{{commit}}"))
+            .current_dir(repo)
+            .output()
+            .await?;
+
+        if output.status.success() {
+            let sha = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            return Ok((kind, sha));
+        }
+    }
+
+    bail!("Unknown git ref: {git_ref}")
+}
+
+/// Whether the branch exists on the origin of the repo
+pub async fn remote_branch_exists(repo: &Path, branch: &str) -> anyhow::Result<bool> {
+    let output = process::Command::new("git")
+        .args(["ls-remote", "--exit-code", "--heads", "origin"])
+        .arg(format!("refs/heads/{branch}"))
+        .current_dir(repo)
+        .output()
+        .await?;
+
+    // exit code 2 means no matching refs
+    match output.status.code() {
+        Some(0) => Ok(true),
+        Some(2) => Ok(false),
+        _ => {
+            print_stdout_and_stderr(&output);
+            bail!("Failed to list branches of origin")
+        }
+    }
+}
+
+/// Paths changed between two commits of the repo
+pub async fn changed_paths(repo: &Path, from: &str, to: &str) -> anyhow::Result<Vec<String>> {
+    let output = process::Command::new("git")
+        .args(["diff", "--name-only", from, to])
+        .current_dir(repo)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        print_stdout_and_stderr(&output);
+        bail!("Failed to diff {from} and {to}");
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line.to_string())
+        .collect())
+}
+
 /// Update ABBS tree commit logs
+///
+/// Returns what the git ref resolved to
 #[tracing::instrument(skip(abbs_path))]
 pub async fn update_abbs<P: AsRef<Path>>(
     git_ref: &str,
     abbs_path: P,
     skip_git_fetch: bool,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<(GitRefKind, String)> {
     info!("Running git checkout -b stable ...");
 
     let abbs_path = abbs_path.as_ref();
@@ -342,16 +549,45 @@ pub async fn update_abbs<P: AsRef<Path>>(
     } else {
         info!("Running git fetch origin {git_ref} ...");
 
-        let output = process::Command::new("git")
-            .arg("fetch")
-            .arg("origin")
-            .arg(git_ref)
-            .current_dir(abbs_path)
-            .output()
-            .instrument(info_span!("git_fetch_origin"))
-            .await?;
+        let mut output = None;
+        if !is_commit_sha(git_ref) {
+            // fetch the branch by its full name, `git fetch origin name` picks
+            // a tag of the same name over it
+            let branch = process::Command::new("git")
+                .args(["fetch", "--tags", "origin"])
+                .arg(format!("+refs/heads/{git_ref}:refs/remotes/origin/{git_ref}"))
+                .current_dir(abbs_path)
+                .output()
+                .instrument(info_span!("git_fetch_origin"))
+                .await?;
 
-        print_stdout_and_stderr(&output);
+            print_stdout_and_stderr(&branch);
+            output = Some(branch).filter(|output| output.status.success());
+        }
+
+        // tags and commits
+        let output = match output {
+            Some(output) => output,
+            None => {
+                let mut command = process::Command::new("git");
+                command.arg("fetch");
+                if !is_commit_sha(git_ref) {
+                    // so that tags can be told apart from branches
+                    command.arg("--tags");
+                }
+
+                let output = command
+                    .arg("origin")
+                    .arg(git_ref)
+                    .current_dir(abbs_path)
+                    .output()
+                    .instrument(info_span!("git_fetch_origin"))
+                    .await?;
+
+                print_stdout_and_stderr(&output);
+                output
+            }
+        };
 
         if !output.status.success() {
             bail!("Failed to fetch origin git-ref: {git_ref}");
@@ -371,6 +607,31 @@ pub async fn update_abbs<P: AsRef<Path>>(
 
     print_stdout_and_stderr(&output);
 
+    let (kind, sha) = resolve_ref(abbs_path, git_ref)
+        .await
+        .context("Failed to resolve git ref")?;
+    info!("Resolved {git_ref} as {kind:?} {sha}");
+
+    if kind != GitRefKind::Branch {
+        info!("Running git checkout --detach {sha} ...");
+
+        let output = process::Command::new("git")
+            .args(["checkout", "--detach"])
+            .arg(&sha)
+            .current_dir(abbs_path)
+            .output()
+            .instrument(info_span!("git_checkout_detach"))
+            .await?;
+
+        print_stdout_and_stderr(&output);
+
+        if !output.status.success() {
+            bail!("Failed to checkout {git_ref}");
+        }
+
+        return Ok((kind, sha));
+    }
+
     info!("Running git checkout -b {git_ref} ...");
 
     let output = process::Command::new("git")
@@ -400,10 +661,11 @@ pub async fn update_abbs<P: AsRef<Path>>(
         bail!("Failed to checkout {git_ref}");
     }
 
-    info!("Running git reset FETCH_HEAD --hard ...");
+    info!("Running git reset {sha} --hard ...");
 
+    // FETCH_HEAD is stale when fetching is skipped
     let output = process::Command::new("git")
-        .args(["reset", "FETCH_HEAD", "--hard"])
+        .args(["reset", &sha, "--hard"])
         .current_dir(abbs_path)
         .output()
         .instrument(info_span!("git_reset_head"))
@@ -415,7 +677,14 @@ pub async fn update_abbs<P: AsRef<Path>>(
         bail!("Failed to checkout {git_ref}");
     }
 
-    Ok(())
+    // the branch now points to the fetched commit
+    let output = process::Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .current_dir(abbs_path)
+        .output()
+        .await?;
+
+    Ok((kind, String::from_utf8_lossy(&output.stdout).trim().to_string()))
 }
 
 pub fn print_stdout_and_stderr(output: &Output) {
@@ -469,13 +738,15 @@ async fn open_pr_inner(pr: OpenPR<'_>) -> Result<PullRequest, octocrab::Error> {
         access_token,
         title,
         head,
-        packages,
+        base,
         id,
         key,
         desc,
         pkg_affected,
+        build_order,
         tags,
         archs,
+        checked_archs,
     } = pr;
 
     let crab = octocrab::Octocrab::builder()
@@ -484,13 +755,7 @@ async fn open_pr_inner(pr: OpenPR<'_>) -> Result<PullRequest, octocrab::Error> {
         .build()?;
 
     // pr body
-    let body = format!(
-        PR!(),
-        desc,
-        pkg_affected.join("\n"),
-        format!("#buildit {}", packages.replace(',', " ")),
-        format_archs(archs)
-    );
+    let body = pr_body(desc, pkg_affected, build_order, archs, checked_archs);
 
     // pr tags
     let tags = if let Some(tags) = tags {
@@ -507,7 +772,7 @@ async fn open_pr_inner(pr: OpenPR<'_>) -> Result<PullRequest, octocrab::Error> {
         // Optional Parameters
         .state(params::State::Open)
         .head(format!("AOSC-Dev:{}", head))
-        .base("stable")
+        .base(base)
         // Send the request
         .send()
         .await?;
@@ -538,7 +803,7 @@ async fn open_pr_inner(pr: OpenPR<'_>) -> Result<PullRequest, octocrab::Error> {
     // create a new pr
     let pr = crab
         .pulls("AOSC-Dev", "aosc-os-abbs")
-        .create(title, head, "stable")
+        .create(title, head, base)
         .draft(true)
         .maintainer_can_modify(true)
         .body(&body)
@@ -624,7 +889,23 @@ fn auto_add_label(title: &str) -> Vec<String> {
     res
 }
 
-fn format_archs(archs: &[&str]) -> String {
+fn pr_body(
+    desc: &str,
+    pkg_affected: &[String],
+    build_order: &[&str],
+    archs: &[&str],
+    checked_archs: &[&str],
+) -> String {
+    format!(
+        PR!(),
+        desc,
+        pkg_affected.join("\n"),
+        format!("#buildit {}", build_order.join(" ")),
+        format_archs(archs, checked_archs)
+    )
+}
+
+fn format_archs(archs: &[&str], checked_archs: &[&str]) -> String {
     let mut s = "".to_string();
 
     let mut map = HashMap::new();
@@ -650,7 +931,8 @@ fn format_archs(archs: &[&str]) -> String {
 
     for i in ["amd64", "arm64", "loongarch64", "noarch"] {
         if archs.contains(&i) {
-            s.push_str(&format!("- [ ] {}\n", map[i]));
+            let check = if checked_archs.contains(&i) { "x" } else { " " };
+            s.push_str(&format!("- [{check}] {}\n", map[i]));
         }
     }
 
@@ -664,7 +946,8 @@ fn format_archs(archs: &[&str]) -> String {
 
     for i in ["loongson3", "ppc64el", "riscv64"] {
         if archs.contains(&i) {
-            s.push_str(&format!("- [ ] {}\n", map[i]));
+            let check = if checked_archs.contains(&i) { "x" } else { " " };
+            s.push_str(&format!("- [{check}] {}\n", map[i]));
         }
     }
 
@@ -678,6 +961,107 @@ pub fn strip_modifiers(pkg: &str) -> &str {
     }
 }
 
+/// Package name as requested, e.g. `app-shells/fish`, `llvm:+stage2` or `gcc:runtime`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QualifiedPackage<'a> {
+    pub section: Option<&'a str>,
+    pub name: &'a str,
+    /// subpackage of a split package
+    pub variant: Option<&'a str>,
+    /// acbs modifiers, e.g. `+stage2`
+    pub modifiers: Option<&'a str>,
+}
+
+pub fn parse_qualified_package(pkg: &str) -> QualifiedPackage<'_> {
+    let (path, suffix) = match pkg.split_once(':') {
+        Some((path, suffix)) => (path, Some(suffix)),
+        None => (pkg, None),
+    };
+    let (variant, modifiers) = match suffix {
+        Some(suffix) if suffix.starts_with('+') => (None, Some(suffix)),
+        Some(suffix) => (Some(suffix), None),
+        None => (None, None),
+    };
+    let (section, name) = match path.rsplit_once('/') {
+        Some((section, name)) => (Some(section), name),
+        None => (None, path),
+    };
+
+    QualifiedPackage {
+        section,
+        name,
+        variant,
+        modifiers,
+    }
+}
+
+/// List subpackages of a split package, e.g. `01-runtime` becomes `runtime`
+pub fn list_variants(path: &Path) -> Vec<String> {
+    if path.join("autobuild").exists() {
+        return vec![];
+    }
+
+    let mut res = vec![];
+    for defines in locate_defines(path) {
+        let Some(dir) = defines
+            .parent()
+            .and_then(|dir| dir.file_name())
+            .and_then(|name| name.to_str())
+        else {
+            continue;
+        };
+
+        let variant = match dir.split_once('-') {
+            Some((prefix, variant)) if prefix.chars().all(|ch| ch.is_ascii_digit()) => variant,
+            _ => dir,
+        };
+        res.push(variant.to_string());
+    }
+    res.sort();
+    res
+}
+
+/// Check that section and subpackage of `pkg` match the abbs tree at `p`
+pub fn check_qualified_package(pkg: &QualifiedPackage, p: &Path) -> anyhow::Result<()> {
+    let mut found = None;
+    for_each_abbs(p, |name, path| {
+        if name != pkg.name {
+            return;
+        }
+
+        let section = path
+            .parent()
+            .and_then(|dir| dir.file_name())
+            .and_then(|dir| dir.to_str());
+        if pkg.section.is_none() || pkg.section == section {
+            found = Some(path.to_path_buf());
+        }
+    });
+
+    let path = match (found, pkg.section) {
+        (Some(path), _) => path,
+        (None, Some(section)) => bail!("{} is not in section {}", pkg.name, section),
+        (None, None) => bail!("{} does not exist", pkg.name),
+    };
+
+    if let Some(variant) = pkg.variant {
+        let variants = list_variants(&path);
+        if variants.is_empty() {
+            bail!("{} has no subpackages", pkg.name);
+        }
+        if !variants.iter().any(|v| v == variant) {
+            bail!(
+                "Unknown subpackage {} of {}, available: {}",
+                variant,
+                pkg.name,
+                variants.join(", ")
+            );
+        }
+    }
+
+    Ok(())
+}
+
 // find autobuild/defines files under `path`
 pub fn locate_defines(path: &Path) -> Vec<PathBuf> {
     if path.join("autobuild").exists() {
@@ -766,18 +1150,23 @@ pub fn get_archs<'a>(p: &'a Path, packages: &'a [String]) -> Vec<&'static str> {
     }
 }
 
-pub fn read_ab_with_apml(file: &str) -> HashMap<String, String> {
-    let mut context = HashMap::new();
-
+/// Parse an autobuild file with apml, returning the syntax errors if any
+pub fn parse_ab(file: &str, context: &mut HashMap<String, String>) -> anyhow::Result<()> {
     // Try to set some ab3 flags to reduce the chance of returning errors
     for i in ["ARCH", "PKGDIR", "SRCDIR"] {
         context.insert(i.to_string(), "".to_string());
     }
 
-    match abbs_meta_apml::parse(file, &mut context).map_err(|e| {
+    abbs_meta_apml::parse(file, context).map_err(|e| {
         let e: Vec<String> = e.iter().map(|e| e.to_string()).collect();
         anyhow!(e.join("; "))
-    }) {
+    })
+}
+
+pub fn read_ab_with_apml(file: &str) -> HashMap<String, String> {
+    let mut context = HashMap::new();
+
+    match parse_ab(file, &mut context) {
         Ok(()) => (),
         Err(e) => {
             error!("{e}, buildit will use fallback method to parse file");
@@ -870,8 +1259,6 @@ pub fn fail_arch_regex(expr: &str) -> anyhow::Result<Regex> {
 pub fn resolve_packages(pkgs: &[String], p: &Path) -> anyhow::Result<Vec<String>> {
     let mut req_pkgs = vec![];
     for i in pkgs {
-        // strip modifiers: e.g. llvm:+stage2 becomes llvm
-        let i = strip_modifiers(i);
         if i.starts_with("groups/") {
             let f = fs::File::open(p.join(i))?;
             let lines = BufReader::new(f).lines();
@@ -882,12 +1269,100 @@ pub fn resolve_packages(pkgs: &[String], p: &Path) -> anyhow::Result<Vec<String>
                 req_pkgs.push(pkg.to_string());
             }
         } else {
-            req_pkgs.push(i.to_string());
+            // strip section, modifiers and subpackage: e.g. llvm:+stage2 becomes llvm
+            req_pkgs.push(parse_qualified_package(i).name.to_string());
         }
     }
     Ok(req_pkgs)
 }
 
+/// List names of all packages in the abbs tree, sorted
+pub fn list_packages(p: &Path) -> Vec<String> {
+    let mut res = vec![];
+    for_each_abbs(p, |pkg, _path| {
+        res.push(pkg.to_string());
+    });
+    res.sort();
+    res.dedup();
+    res
+}
+
+/// Names of all packages, including split packages named by PKGNAME in their defines
+pub fn list_package_names(p: &Path) -> Vec<String> {
+    let mut res = list_packages(p);
+    for_each_abbs(p, |_pkg, path| {
+        for i in WalkDir::new(path).min_depth(2).max_depth(2).into_iter().flatten() {
+            if i.file_name() != "defines" {
+                continue;
+            }
+
+            let Ok(content) = fs::read_to_string(i.path()) else {
+                continue;
+            };
+
+            if let Some(name) = content
+                .lines()
+                .find_map(|line| line.trim().strip_prefix("PKGNAME="))
+            {
+                res.push(name.trim_matches(['"', '\'']).to_string());
+            }
+        }
+    });
+    res.sort();
+    res.dedup();
+    res
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    let mut cur = vec![0; b.len() + 1];
+
+    for (i, ca) in a.chars().enumerate() {
+        cur[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+
+    prev[b.len()]
+}
+
+/// Find packages missing from `index` (see `list_packages`),
+/// along with up to three closest names for each of them
+///
+/// `pkgs` should have no groups nor modifiers
+pub fn find_unknown_packages(pkgs: &[String], index: &[String]) -> Vec<(String, Vec<String>)> {
+    let mut res = vec![];
+    for pkg in pkgs {
+        let pkg = pkg.trim();
+        if index.binary_search_by(|name| name.as_str().cmp(pkg)).is_ok() {
+            continue;
+        }
+
+        // allow roughly one typo every three characters, a swap counts as two
+        let max_distance = (pkg.len() / 3).max(2);
+        let mut suggestions = index
+            .iter()
+            .map(|name| (levenshtein(pkg, name), name))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .collect::<Vec<_>>();
+        suggestions.sort();
+
+        res.push((
+            pkg.to_string(),
+            suggestions
+                .into_iter()
+                .take(3)
+                .map(|(_, name)| name.clone())
+                .collect(),
+        ));
+    }
+    res
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 pub struct EnvironmentRequirement {
     pub min_core: Option<i32>,
@@ -1003,3 +1478,201 @@ fn test_auto_add_label() {
         ]
     );
 }
+
+#[test]
+fn test_find_unknown_packages() {
+    let index = ["fd", "fish", "nginx", "nginx-mainline", "zsh"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+
+    // exact match
+    assert!(find_unknown_packages(&["nginx".to_string()], &index).is_empty());
+
+    // near miss
+    assert_eq!(
+        find_unknown_packages(&["ngnix".to_string()], &index),
+        vec![("ngnix".to_string(), vec!["nginx".to_string()])]
+    );
+    assert_eq!(
+        find_unknown_packages(&["nginx-mainlin".to_string()], &index),
+        vec![("nginx-mainlin".to_string(), vec!["nginx-mainline".to_string()])]
+    );
+
+    // no suggestion
+    assert_eq!(
+        find_unknown_packages(&["libreoffice".to_string()], &index),
+        vec![("libreoffice".to_string(), vec![])]
+    );
+}
+
+#[test]
+fn test_parse_qualified_package() {
+    assert_eq!(
+        parse_qualified_package("fish"),
+        QualifiedPackage {
+            section: None,
+            name: "fish",
+            variant: None,
+            modifiers: None,
+        }
+    );
+    assert_eq!(
+        parse_qualified_package("app-shells/fish"),
+        QualifiedPackage {
+            section: Some("app-shells"),
+            name: "fish",
+            variant: None,
+            modifiers: None,
+        }
+    );
+    assert_eq!(
+        parse_qualified_package("llvm:+stage2"),
+        QualifiedPackage {
+            section: None,
+            name: "llvm",
+            variant: None,
+            modifiers: Some("+stage2"),
+        }
+    );
+    assert_eq!(
+        parse_qualified_package("core-devel/gcc:runtime"),
+        QualifiedPackage {
+            section: Some("core-devel"),
+            name: "gcc",
+            variant: Some("runtime"),
+            modifiers: None,
+        }
+    );
+}
+
+#[test]
+fn test_check_qualified_package() {
+    let p = std::env::temp_dir().join(format!("buildit-abbs-{}", std::process::id()));
+    fs::create_dir_all(p.join("app-shells/fish/autobuild")).unwrap();
+    fs::write(p.join("app-shells/fish/autobuild/defines"), "").unwrap();
+    fs::create_dir_all(p.join("core-devel/gcc/01-runtime")).unwrap();
+    fs::write(p.join("core-devel/gcc/01-runtime/defines"), "").unwrap();
+    fs::create_dir_all(p.join("core-devel/gcc/02-gcc")).unwrap();
+    fs::write(p.join("core-devel/gcc/02-gcc/defines"), "").unwrap();
+
+    let check = |pkg| check_qualified_package(&parse_qualified_package(pkg), &p);
+    assert!(check("fish").is_ok());
+    assert!(check("app-shells/fish").is_ok());
+    assert!(check("core-devel/fish").is_err());
+    assert!(check("gcc:runtime").is_ok());
+    assert!(check("gcc:+stage2").is_ok());
+    assert_eq!(
+        check("gcc:libs").unwrap_err().to_string(),
+        "Unknown subpackage libs of gcc, available: gcc, runtime"
+    );
+    assert_eq!(
+        check("fish:docs").unwrap_err().to_string(),
+        "fish has no subpackages"
+    );
+
+    fs::remove_dir_all(&p).unwrap();
+}
+
+#[tokio::test]
+async fn test_resolve_ref() {
+    let p = std::env::temp_dir().join(format!("buildit-ref-{}", std::process::id()));
+    fs::create_dir_all(&p).unwrap();
+
+    let git = |args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .args(["-c", "user.name=buildit", "-c", "user.email=buildit@aosc.io"])
+            .args(args)
+            .current_dir(&p)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    };
+    git(&["init", "-q"]);
+    git(&["commit", "-q", "--allow-empty", "-m", "first"]);
+    let first = git(&["rev-parse", "HEAD"]);
+    git(&["tag", "-a", "v1.0", "-m", "release"]);
+    git(&["checkout", "-q", "-b", "fd-9.0.0"]);
+    git(&["commit", "-q", "--allow-empty", "-m", "second"]);
+    let second = git(&["rev-parse", "HEAD"]);
+    git(&["tag", "fd-9.0.0", &first]);
+
+    // the branch wins over the tag of the same name
+    assert_eq!(
+        resolve_ref(&p, "fd-9.0.0").await.unwrap(),
+        (GitRefKind::Branch, second.clone())
+    );
+    assert_eq!(
+        resolve_ref(&p, "v1.0").await.unwrap(),
+        (GitRefKind::Tag, first.clone())
+    );
+    assert_eq!(
+        resolve_ref(&p, &first).await.unwrap(),
+        (GitRefKind::Commit, first.clone())
+    );
+    assert!(resolve_ref(&p, "nonexistent").await.is_err());
+    assert!(is_commit_sha(&second));
+    assert!(!is_commit_sha("fd-9.0.0"));
+
+    fs::remove_dir_all(&p).unwrap();
+}
+
+#[test]
+fn test_sort_build_order() {
+    let deps = BTreeMap::from([
+        (
+            "llvm".to_string(),
+            PackageDeps {
+                provides: vec!["llvm".to_string(), "llvm-runtime".to_string()],
+                deps: vec!["zlib".to_string(), "python-3".to_string()],
+            },
+        ),
+        (
+            "rustc".to_string(),
+            PackageDeps {
+                provides: vec!["rustc".to_string()],
+                deps: vec!["llvm-runtime".to_string(), "curl".to_string()],
+            },
+        ),
+        (
+            "cargo-c".to_string(),
+            PackageDeps {
+                provides: vec!["cargo-c".to_string()],
+                deps: vec!["rustc".to_string()],
+            },
+        ),
+        (
+            "fd".to_string(),
+            PackageDeps {
+                provides: vec!["fd".to_string()],
+                deps: vec![],
+            },
+        ),
+    ]);
+
+    // dependencies first, others keep their order
+    let order = sort_build_order(&["cargo-c", "fd", "rustc", "llvm:+stage2"], &deps);
+    assert_eq!(order, vec!["fd", "llvm:+stage2", "rustc", "cargo-c"]);
+
+    // already sorted
+    assert_eq!(
+        sort_build_order(&["llvm", "rustc"], &deps),
+        vec!["llvm", "rustc"]
+    );
+
+    // cycles are broken in the original order
+    let mut cyclic = deps.clone();
+    cyclic.get_mut("llvm").unwrap().deps.push("rustc".to_string());
+    assert_eq!(
+        sort_build_order(&["groups/bootstrap", "rustc", "llvm"], &cyclic),
+        vec!["groups/bootstrap", "rustc", "llvm"]
+    );
+
+    let affected = vec!["- cargo-c: 0.9.32".to_string(), "- rustc: 1.79.0".to_string()];
+    let body = pr_body("Update Rust", &affected, &order, &["amd64", "riscv64"], &["amd64"]);
+    assert!(body.contains("Package(s) Affected\n-------------------\n\n- cargo-c: 0.9.32\n- rustc: 1.79.0\n"));
+    assert!(body.contains("Build Order\n-----------\n\n```\n#buildit fd llvm:+stage2 rustc cargo-c\n```"));
+    assert!(body.contains("- [x] AMD64 `amd64`\n"));
+    assert!(body.contains("- [ ] RISC-V 64-bit `riscv64`\n"));
+}
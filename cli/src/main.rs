@@ -29,6 +29,9 @@ pub enum BiCommand {
         packages: Vec<String>,
         #[arg(long)]
         tags: Option<Vec<String>>,
+        /// Branch to merge into
+        #[arg(long, default_value = "stable")]
+        base: String,
     },
     /// Login to Github
     Login,
@@ -59,6 +62,7 @@ async fn main() -> eyre::Result<()> {
             git_ref,
             packages,
             tags,
+            base,
         } => {
             let login = dirs_next::data_dir()
                 .ok_or_else(|| eyre!("no data dir found!"))?
@@ -95,6 +99,8 @@ async fn main() -> eyre::Result<()> {
                     title,
                     tags,
                     archs: None,
+                    base,
+                    checked_archs: vec![],
                 },
             )
             .await
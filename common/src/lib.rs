@@ -1,4 +1,5 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{borrow::Cow, collections::BTreeMap};
 
 #[derive(Serialize, Deserialize)]
 pub struct WorkerPollRequest {
@@ -8,6 +9,13 @@ pub struct WorkerPollRequest {
     pub memory_bytes: i64,
     pub logical_cores: i32,
     pub disk_free_space_bytes: i64,
+    /// Capabilities of the worker, only jobs requiring none or one of them are taken
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// Arch patterns of the jobs taken when the server uses a shared queue,
+    /// e.g. `loong*`, only jobs of `arch` when empty
+    #[serde(default)]
+    pub arch_patterns: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -16,19 +24,76 @@ pub struct WorkerPollResponse {
     pub git_branch: String,
     pub git_sha: String,
     pub packages: String,
+    /// Mirror or caching proxy to fetch sources through: workers prepend it
+    /// to the upstream URL, e.g. `https://proxy.example.org/` turns
+    /// `https://github.com/foo/bar.git` into
+    /// `https://proxy.example.org/https://github.com/foo/bar.git`.
+    /// Upstream URLs are used as-is when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_mirror: Option<String>,
+    /// Git URL of the abbs tree to build from, AOSC-Dev/aosc-os-abbs when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_url: Option<String>,
+    /// Arch of the job, other than the arch of the worker when taken from a
+    /// shared queue. Empty from servers predating shared queues
+    #[serde(default)]
+    pub arch: String,
+    /// Do not push the built packages, the job builds a tag or commit rather
+    /// than a branch named after a topic
+    #[serde(default)]
+    pub skip_pushpkg: bool,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct WorkerHeartbeatRequest {
+impl WorkerPollResponse {
+    /// Rewrite an upstream source URL according to `source_mirror`
+    pub fn source_url(&self, url: &str) -> String {
+        match &self.source_mirror {
+            Some(mirror) => format!("{mirror}{url}"),
+            None => url.to_string(),
+        }
+    }
+}
+
+/// Sent once when a worker starts, records what the worker is capable of
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorkerRegisterRequest {
     pub hostname: String,
     pub arch: String,
+    pub worker_secret: String,
     pub git_commit: String,
     pub memory_bytes: i64,
     pub logical_cores: i32,
     pub disk_free_space_bytes: i64,
-    pub worker_secret: String,
     pub performance: Option<i64>,
+    /// Version of the worker binary
+    pub version: Option<String>,
+    /// Capabilities of the worker, e.g. bigmem
+    pub labels: Vec<String>,
+}
+
+/// Liveness ping of a worker
+///
+/// Registered workers leave the capability fields unset, workers predating
+/// registration send all of them on every heartbeat.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorkerHeartbeatRequest {
+    pub hostname: String,
+    pub arch: String,
+    pub worker_secret: String,
+    pub disk_free_space_bytes: i64,
     pub internet_connectivity: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_bytes: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logical_cores: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub performance: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,6 +102,48 @@ pub enum JobResult {
     Error(String),
 }
 
+/// Build output as it was written, which is not always valid UTF-8
+///
+/// Sent as an array of bytes, the text sent by workers before schema
+/// version 3 is accepted too.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct LogBytes(pub Vec<u8>);
+
+impl LogBytes {
+    /// Text for display, invalid UTF-8 is replaced by U+FFFD
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+}
+
+impl From<Vec<u8>> for LogBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<&str> for LogBytes {
+    fn from(text: &str) -> Self {
+        Self(text.as_bytes().to_vec())
+    }
+}
+
+impl<'de> Deserialize<'de> for LogBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Text(String),
+            Bytes(Vec<u8>),
+        }
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Text(text) => Self(text.into_bytes()),
+            Repr::Bytes(bytes) => Self(bytes),
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JobOk {
     /// Is the build successful?
@@ -53,6 +160,46 @@ pub struct JobOk {
     pub elapsed_secs: i64,
     /// If pushpkg succeeded
     pub pushpkg_success: bool,
+    /// Last lines of the build log
+    #[serde(default)]
+    pub log_tail: Option<LogBytes>,
+    /// Toolchain versions of the build environment, e.g. gcc and kernel
+    #[serde(default)]
+    pub environment: Option<BTreeMap<String, String>>,
+    /// Peak memory used by the build
+    #[serde(default)]
+    pub peak_memory_bytes: Option<i64>,
+    /// Disk space taken by the build
+    #[serde(default)]
+    pub disk_bytes: Option<i64>,
+    /// Seconds spent on each package, in build order
+    #[serde(default)]
+    pub package_timings: Option<Vec<(String, i64)>>,
+}
+
+/// Package being built by a running job, parsed from the build output
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorkerJobProgressRequest {
+    pub hostname: String,
+    pub arch: String,
+    pub worker_secret: String,
+    pub job_id: i32,
+    pub current_package: String,
+    /// Starts from 1
+    pub index: u32,
+    pub total: u32,
+}
+
+/// Version of the job result format sent by workers,
+/// bump it when a change cannot be handled by serde defaults
+pub const JOB_RESULT_SCHEMA_VERSION: u32 = 3;
+
+/// Oldest job result format still accepted by the server
+pub const MIN_JOB_RESULT_SCHEMA_VERSION: u32 = 1;
+
+// workers without versioning send the v1 format
+fn default_schema_version() -> u32 {
+    1
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -62,4 +209,32 @@ pub struct WorkerJobUpdateRequest {
     pub job_id: i32,
     pub result: JobResult,
     pub worker_secret: String,
+    #[serde(default = "default_schema_version")]
+    pub schema_version: u32,
+}
+
+/// Header carrying the worker secret when a worker opens its websocket,
+/// only authenticated workers are sent [`WorkerControl`] requests
+pub const WORKER_SECRET_HEADER: &str = "x-buildit-worker-secret";
+
+/// Request from the server to a worker, sent as JSON text over the websocket
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum WorkerControl {
+    /// Ask for the latest log lines of the running job
+    RequestLiveLog { request_id: u64, job_id: i32 },
+    /// Send a heartbeat now instead of at the next interval, no reply
+    PingNow,
+    /// Stop building the job, which was cancelled on the server, no reply
+    CancelJob { job_id: i32 },
+}
+
+/// Reply of a worker to a [`WorkerControl`] request, sent as JSON binary
+/// messages to tell them apart from log lines, which are text
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum WorkerControlReply {
+    LiveLog {
+        request_id: u64,
+        job_id: i32,
+        log: String,
+    },
 }
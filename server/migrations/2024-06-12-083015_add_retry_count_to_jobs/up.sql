@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE jobs ADD COLUMN retry_count INT NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+CREATE INDEX jobs_finish_time_idx ON jobs (finish_time);
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+CREATE TABLE chat_settings (
+  telegram_chat_id BIGINT PRIMARY KEY,
+  notify_mode TEXT NOT NULL DEFAULT 'all'
+);
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE jobs ADD COLUMN environment TEXT;
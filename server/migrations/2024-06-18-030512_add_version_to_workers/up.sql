@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE workers ADD COLUMN version TEXT;
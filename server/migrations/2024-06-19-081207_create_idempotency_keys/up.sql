@@ -0,0 +1,6 @@
+-- Your SQL goes here
+CREATE TABLE idempotency_keys (
+  key TEXT PRIMARY KEY,
+  pipeline_id INT4 NOT NULL REFERENCES pipelines(id) ON DELETE CASCADE,
+  creation_time TIMESTAMPTZ NOT NULL
+);
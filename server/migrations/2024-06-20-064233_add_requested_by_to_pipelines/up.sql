@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE pipelines ADD COLUMN requested_by TEXT;
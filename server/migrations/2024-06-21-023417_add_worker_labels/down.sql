@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE jobs DROP COLUMN require_label;
+ALTER TABLE workers DROP COLUMN labels;
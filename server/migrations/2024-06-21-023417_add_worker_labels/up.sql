@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE jobs ADD COLUMN require_label TEXT;
+ALTER TABLE workers ADD COLUMN labels TEXT;
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE pipelines ADD COLUMN build_plan_hash TEXT;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE jobs DROP COLUMN disk_bytes;
+ALTER TABLE jobs DROP COLUMN peak_memory_bytes;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE jobs ADD COLUMN peak_memory_bytes BIGINT;
+ALTER TABLE jobs ADD COLUMN disk_bytes BIGINT;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE opened_prs (
+  id SERIAL PRIMARY KEY,
+  pr_number INT8 NOT NULL,
+  title TEXT NOT NULL,
+  git_ref TEXT NOT NULL,
+  telegram_chat_id INT8 NOT NULL,
+  creation_time TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX opened_prs_telegram_chat_id_idx ON opened_prs (telegram_chat_id);
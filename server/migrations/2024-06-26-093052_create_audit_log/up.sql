@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE audit_log (
+  id SERIAL PRIMARY KEY,
+  time TIMESTAMPTZ NOT NULL,
+  actor TEXT NOT NULL,
+  action TEXT NOT NULL,
+  details TEXT NOT NULL
+);
+CREATE INDEX audit_log_time_idx ON audit_log (time);
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE pending_prs (
+  pipeline_id INT4 PRIMARY KEY REFERENCES pipelines(id),
+  title TEXT NOT NULL,
+  git_ref TEXT NOT NULL,
+  packages TEXT NOT NULL,
+  telegram_chat_id INT8 NOT NULL,
+  creation_time TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE pipelines ADD COLUMN repo TEXT NOT NULL DEFAULT 'AOSC-Dev/aosc-os-abbs';
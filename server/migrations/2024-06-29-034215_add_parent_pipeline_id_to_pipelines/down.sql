@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE pipelines DROP COLUMN parent_pipeline_id;
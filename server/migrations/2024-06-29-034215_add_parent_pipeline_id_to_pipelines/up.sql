@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE pipelines ADD COLUMN parent_pipeline_id INTEGER REFERENCES pipelines(id) ON DELETE SET NULL;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE pending_prs DROP CONSTRAINT pending_prs_pipeline_id_fkey;
+ALTER TABLE pending_prs ADD CONSTRAINT pending_prs_pipeline_id_fkey FOREIGN KEY (pipeline_id) REFERENCES pipelines(id);
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE pipelines ADD COLUMN git_ref_kind TEXT NOT NULL DEFAULT 'branch';
@@ -1,12 +1,28 @@
 use crate::{
-    github::{get_crab_github_installation, get_packages_from_pr},
-    models::{Job, NewJob, NewPipeline, Pipeline, User, Worker},
+    audit::{build_audit, cancel_audit, github_actor, record_audit},
+    canary::{canary_of, initial_status, HELD},
+    drain::{check_draining, is_draining},
+    formatter::SUCCESS,
+    github::{complete_check_runs, get_crab_github_installation, get_packages_from_pr},
+    lint::{is_defines, is_spec, lint_files, LintContext, LintFile, LintReport},
+    mirror::{mirror_state, read_packages_index, MirrorStatus},
+    models::{
+        ChatSetting, IdempotencyKey, Job, NewJob, NewOpenedPr, NewPipeline, OpenedPr, Pipeline,
+        User, Worker,
+    },
+    package_index::{refresh_package_index, PackageIndex},
+    repo::{owner_repo, repo_by_full_name, RepoConfig},
+    routes::{cancel_on_worker, job_finished, WSStateMap},
     DbPool, ALL_ARCH, ARGS,
 };
 use anyhow::Context;
 use anyhow::{anyhow, bail};
 use buildit_utils::{
-    github::{get_archs, get_environment_requirement, resolve_packages, update_abbs},
+    github::{
+        changed_paths, check_qualified_package, find_unknown_packages, find_version_by_packages,
+        get_archs, get_environment_requirement, list_package_names, parse_qualified_package,
+        resolve_packages, update_abbs,
+    },
     ABBS_REPO_LOCK,
 };
 use diesel::r2d2::PoolTransactionManager;
@@ -15,11 +31,24 @@ use diesel::{
     SelectableHelper,
 };
 use diesel::{
-    dsl::count, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, RunQueryDsl,
+    dsl::count, pg::Pg, BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension,
+    PgConnection, QueryDsl, RunQueryDsl, TextExpressionMethods,
 };
+use octocrab::{
+    models::{pulls::PullRequest, repos::DiffEntryStatus, IssueState},
+    params::checks::CheckRunConclusion,
+};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
-use tracing::warn;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use teloxide::Bot;
+use tracing::{info, warn};
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum JobSource {
@@ -31,12 +60,23 @@ pub enum JobSource {
     Manual,
 }
 
+/// Name of the check run of an arch
+pub fn check_run_name(arch: &str) -> String {
+    format!("buildit {}", arch)
+}
+
 // create github check run for the specified git commit
 #[tracing::instrument(skip(crab))]
-async fn create_check_run(crab: octocrab::Octocrab, arch: String, git_sha: String) -> Option<u64> {
+async fn create_check_run(
+    crab: octocrab::Octocrab,
+    repo: String,
+    arch: String,
+    git_sha: String,
+) -> Option<u64> {
+    let (owner, repo) = owner_repo(&repo);
     match crab
-        .checks("AOSC-Dev", "aosc-os-abbs")
-        .create_check_run(format!("buildit {}", arch), git_sha)
+        .checks(owner, repo)
+        .create_check_run(check_run_name(&arch), git_sha)
         .status(octocrab::params::checks::CheckRunStatus::Queued)
         .send()
         .await
@@ -51,36 +91,359 @@ async fn create_check_run(crab: octocrab::Octocrab, arch: String, git_sha: Strin
     return None;
 }
 
+/// Sort archs in the order of `ALL_ARCH`, unknown ones last, and remove duplicates
+pub fn sort_archs(archs: &mut Vec<&str>) {
+    archs.sort_by_key(|arch| {
+        (
+            ALL_ARCH
+                .iter()
+                .position(|a| a == arch)
+                .unwrap_or(ALL_ARCH.len()),
+            *arch,
+        )
+    });
+    archs.dedup();
+}
+
+/// Expand `mainline`, check that every arch is supported, and sort them in
+/// the order of `ALL_ARCH` without duplicates
+pub fn normalize_archs(archs: &str) -> anyhow::Result<Vec<&str>> {
+    let mut archs: Vec<&str> = archs
+        .split(',')
+        .map(str::trim)
+        .filter(|arch| !arch.is_empty())
+        .collect();
+    if archs.contains(&"mainline") {
+        archs.extend(ALL_ARCH.iter());
+        archs.retain(|arch| *arch != "mainline");
+    }
+    for arch in &archs {
+        if !ALL_ARCH.contains(arch) && arch != &"noarch" {
+            return Err(anyhow!("Architecture {arch} is not supported"));
+        }
+    }
+    sort_archs(&mut archs);
+    if archs.contains(&"noarch") && archs.len() > 1 {
+        return Err(anyhow!("Architecture noarch must not be mixed with others"));
+    }
+    Ok(archs)
+}
+
+/// Worker labels required by packages, e.g. `chromium=bigmem,llvm*=bigmem`
+#[derive(Debug, Clone)]
+pub struct PackageLabels(Vec<(Regex, String)>);
+
+/// Parse `pattern=label` pairs separated by commas, `*` in patterns matches anything
+pub fn parse_package_labels(s: &str) -> anyhow::Result<PackageLabels> {
+    let mut res = vec![];
+    for pair in s
+        .split(',')
+        .map(|pair| pair.trim())
+        .filter(|pair| !pair.is_empty())
+    {
+        let (pattern, label) = pair
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected pattern=label, got {pair}"))?;
+        if label.trim().is_empty() {
+            bail!("Missing label for {pattern}");
+        }
+        res.push((glob_regex(pattern.trim())?, label.trim().to_string()));
+    }
+    Ok(PackageLabels(res))
+}
+
+/// Regex of a pattern where `*` matches anything
+fn glob_regex(pattern: &str) -> anyhow::Result<Regex> {
+    let regex = pattern
+        .split('*')
+        .map(regex::escape)
+        .collect::<Vec<_>>()
+        .join(".*");
+    Ok(Regex::new(&format!("^{regex}$"))?)
+}
+
+impl PackageLabels {
+    /// Label a worker must carry to build the packages, from the first matching pattern
+    pub fn required_label(&self, packages: &[&str]) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(pattern, _)| packages.iter().any(|pkg| pattern.is_match(pkg)))
+            .map(|(_, label)| label.as_str())
+    }
+}
+
+/// Archs never built for some git refs
+#[derive(Debug, Clone)]
+pub struct ExcludedArchs(Vec<(Regex, Vec<String>)>);
+
+/// Parse `pattern=archs` pairs separated by semicolons, archs are separated
+/// by commas and `*` in patterns matches anything
+pub fn parse_excluded_archs(s: &str) -> anyhow::Result<ExcludedArchs> {
+    let mut res = vec![];
+    for pair in s
+        .split(';')
+        .map(|pair| pair.trim())
+        .filter(|pair| !pair.is_empty())
+    {
+        let (pattern, archs) = pair
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected pattern=archs, got {pair}"))?;
+        let archs = archs
+            .split(',')
+            .map(str::trim)
+            .filter(|arch| !arch.is_empty())
+            .map(|arch| {
+                if ALL_ARCH.contains(&arch) || arch == "noarch" {
+                    Ok(arch.to_string())
+                } else {
+                    Err(anyhow!("Architecture {arch} is not supported"))
+                }
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if archs.is_empty() {
+            bail!("Missing archs for {pattern}");
+        }
+        res.push((glob_regex(pattern.trim())?, archs));
+    }
+    Ok(ExcludedArchs(res))
+}
+
+impl ExcludedArchs {
+    /// Whether the arch must not be built for the git ref, by any matching pattern
+    pub fn is_excluded(&self, git_ref: &str, arch: &str) -> bool {
+        self.0
+            .iter()
+            .any(|(pattern, archs)| pattern.is_match(git_ref) && archs.iter().any(|a| a == arch))
+    }
+}
+
+/// Drop archs excluded for the git ref by policy, after `mainline` is expanded
+///
+/// Returns the accepted archs and a note on the dropped ones if any
+pub fn apply_excluded_archs<'a>(
+    archs: Vec<&'a str>,
+    git_ref: &str,
+    excluded: &ExcludedArchs,
+) -> anyhow::Result<(Vec<&'a str>, Option<String>)> {
+    let (dropped, accepted): (Vec<_>, Vec<_>) = archs
+        .into_iter()
+        .partition(|arch| excluded.is_excluded(git_ref, arch));
+
+    let note = format!(
+        "{} excluded for branch {git_ref} by policy",
+        dropped.join(", ")
+    );
+    if accepted.is_empty() {
+        bail!("{note}, nothing to build");
+    }
+    if dropped.is_empty() {
+        return Ok((accepted, None));
+    }
+    info!("{note}");
+    Ok((accepted, Some(note)))
+}
+
+/// Drop or refuse archs whose queue has reached the depth cap
+///
+/// Returns the accepted archs and why the others were skipped
+pub fn apply_queue_cap<'a>(
+    archs: Vec<&'a str>,
+    depths: &BTreeMap<String, i64>,
+    cap: i64,
+    partial: bool,
+) -> anyhow::Result<(Vec<&'a str>, Vec<String>)> {
+    let mut accepted = vec![];
+    let mut full = vec![];
+    for arch in archs {
+        let depth = depths.get(arch).copied().unwrap_or(0);
+        if depth >= cap {
+            full.push(format!("queue for {arch} is full ({depth} jobs)"));
+        } else {
+            accepted.push(arch);
+        }
+    }
+
+    if full.is_empty() || (partial && !accepted.is_empty()) {
+        if !full.is_empty() {
+            warn!("Skipping full queues: {}", full.join(", "));
+        }
+        Ok((accepted, full))
+    } else {
+        bail!("{}, try later", full.join(", "))
+    }
+}
+
+/// Number of queued jobs of each arch
+fn queue_depths(conn: &mut PgConnection) -> anyhow::Result<BTreeMap<String, i64>> {
+    Ok(crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::status.eq("created"))
+        .group_by(crate::schema::jobs::dsl::arch)
+        .select((
+            crate::schema::jobs::dsl::arch,
+            count(crate::schema::jobs::dsl::id),
+        ))
+        .load::<(String, i64)>(conn)?
+        .into_iter()
+        .collect())
+}
+
+/// Archs with a worker that sent a heartbeat recently
+fn online_archs(conn: &mut PgConnection) -> anyhow::Result<Vec<String>> {
+    let deadline =
+        chrono::Utc::now() - chrono::Duration::try_seconds(crate::HEARTBEAT_TIMEOUT).unwrap();
+    Ok(crate::schema::workers::dsl::workers
+        .filter(crate::schema::workers::dsl::last_heartbeat_time.gt(deadline))
+        .select(crate::schema::workers::dsl::arch)
+        .distinct()
+        .load::<String>(conn)?)
+}
+
+/// Archs that no online worker can build, noarch is built on amd64
+pub fn offline_archs<'a>(archs: &[&'a str], online: &[String]) -> Vec<&'a str> {
+    archs
+        .iter()
+        .filter(|arch| {
+            let arch = if **arch == "noarch" { "amd64" } else { arch };
+            !online.iter().any(|online| online == arch)
+        })
+        .copied()
+        .collect()
+}
+
+/// Refuse archs without online workers if asked to, their jobs would wait
+/// until a worker connects
+pub fn check_offline_archs(offline: &[&str], refuse: bool) -> anyhow::Result<()> {
+    if offline.is_empty() {
+        return Ok(());
+    }
+    if refuse {
+        bail!(
+            "No workers currently online for {}, try later",
+            offline.join(", ")
+        );
+    }
+    warn!(
+        "Queueing jobs without online workers: {}",
+        offline.join(", ")
+    );
+    Ok(())
+}
+
+/// Archs of the pipeline without online workers
+pub async fn pipeline_offline_archs(
+    pool: DbPool,
+    pipeline: &Pipeline,
+) -> anyhow::Result<Vec<String>> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+    let archs = pipeline.archs.split(',').collect::<Vec<_>>();
+    Ok(offline_archs(&archs, &online_archs(&mut conn)?)
+        .into_iter()
+        .map(str::to_string)
+        .collect())
+}
+
+/// Refuse to create pipelines without packages, e.g. a PR without a `#buildit` line
+pub fn check_packages(packages: &str) -> anyhow::Result<()> {
+    if packages.split(',').all(|pkg| pkg.trim().is_empty()) {
+        bail!("No packages to build; specify packages or add a #buildit line");
+    }
+    Ok(())
+}
+
+/// Drop repeated packages, keeping the first occurrence of each, and
+/// return the packages that were removed
+pub fn dedup_packages(packages: &str) -> (String, Vec<&str>) {
+    let mut seen = vec![];
+    let mut duplicates = vec![];
+    for pkg in packages.split(',') {
+        if seen.contains(&pkg) {
+            if !duplicates.contains(&pkg) {
+                duplicates.push(pkg);
+            }
+        } else {
+            seen.push(pkg);
+        }
+    }
+    (seen.join(","), duplicates)
+}
+
+/// Number of queued and running jobs
+#[tracing::instrument(skip(pool))]
+pub async fn active_job_counts(pool: DbPool) -> anyhow::Result<(i64, i64)> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+    let queued = queue_depths(&mut conn)?.values().sum();
+    let running = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::status.eq("running"))
+        .count()
+        .get_result::<i64>(&mut conn)?;
+    Ok((queued, running))
+}
+
+#[allow(clippy::too_many_arguments)]
 #[tracing::instrument(skip(pool))]
 pub async fn pipeline_new(
     pool: DbPool,
+    repo: &RepoConfig,
     git_branch: &str,
     git_sha: Option<&str>,
     github_pr: Option<u64>,
     packages: &str,
     archs: &str,
     source: JobSource,
+    requested_by: Option<&str>,
     skip_git_fetch: bool,
-) -> anyhow::Result<Pipeline> {
+    priority: i32,
+    canary: bool,
+    parent_pipeline_id: Option<i32>,
+    idempotency_key: Option<&str>,
+) -> anyhow::Result<(Pipeline, PipelineNotes)> {
+    check_draining(is_draining())?;
+    check_packages(packages)?;
+
+    // building a package twice only wastes time
+    let mut notes = PipelineNotes::default();
+    let (deduped, duplicates) = dedup_packages(packages);
+    if !duplicates.is_empty() {
+        info!("Removed duplicate package(s): {}", duplicates.join(", "));
+    }
+    notes.duplicates = duplicates.into_iter().map(str::to_string).collect();
+    let packages = deduped.as_str();
+
     // sanitize archs arg
-    let mut archs: Vec<&str> = archs.split(',').collect();
-    archs.sort();
-    archs.dedup();
-    if archs.contains(&"noarch") && archs.len() > 1 {
-        return Err(anyhow!("Architecture noarch must not be mixed with others"));
+    let mut archs = normalize_archs(archs)?;
+    if let Some(excluded) = &ARGS.excluded_archs {
+        (archs, notes.excluded_archs) = apply_excluded_archs(archs, git_branch, excluded)?;
     }
-    if archs.contains(&"mainline") {
-        // archs
-        archs.extend(ALL_ARCH.iter());
-        archs.retain(|arch| *arch != "mainline");
+
+    // refuse to grow full queues
+    if let Some(cap) = ARGS.max_queue_depth {
+        let mut conn = pool
+            .get()
+            .context("Failed to get db connection from pool")?;
+        let depths = queue_depths(&mut conn)?;
+        (archs, notes.full_queues) =
+            apply_queue_cap(archs, &depths, cap, ARGS.partial_enqueue == Some(true))?;
     }
-    for arch in &archs {
-        if !ALL_ARCH.contains(arch) && arch != &"noarch" {
-            return Err(anyhow!("Architecture {arch} is not supported"));
-        }
+
+    // jobs of archs without workers only wait
+    {
+        let mut conn = pool
+            .get()
+            .context("Failed to get db connection from pool")?;
+        let offline = offline_archs(&archs, &online_archs(&mut conn)?);
+        check_offline_archs(&offline, ARGS.refuse_offline_archs == Some(true))?;
     }
-    archs.sort();
-    archs.dedup();
+
+    // hold back other archs until the canary arch succeeds
+    let canary = if canary {
+        canary_of(&archs, &ARGS.canary_arch)?
+    } else {
+        None
+    };
 
     // sanitize packages arg
     if !packages.chars().all(|ch| {
@@ -104,11 +467,12 @@ pub async fn pipeline_new(
     }
 
     let lock = ABBS_REPO_LOCK.lock().await;
-    update_abbs(git_branch, &ARGS.abbs_path, skip_git_fetch)
+    let (ref_kind, resolved_sha) = update_abbs(git_branch, &repo.abbs_path, skip_git_fetch)
         .await
         .context("Failed to update ABBS tree")?;
+    let index = refresh_package_index(&repo.abbs_path);
 
-    // resolve branch name to commit hash if not specified
+    // use the commit the ref resolved to if not specified
     let git_sha = match git_sha {
         Some(git_sha) => {
             if !git_sha.chars().all(|ch| ch.is_ascii_alphanumeric()) {
@@ -117,30 +481,88 @@ pub async fn pipeline_new(
             git_sha.to_string()
         }
         None => {
-            let output = tokio::process::Command::new("git")
-                .arg("rev-parse")
-                .arg("HEAD")
-                .current_dir(&ARGS.abbs_path)
-                .output()
-                .await
-                .context("Failed to resolve branch to git commit")?;
-            String::from_utf8_lossy(&output.stdout).trim().to_string()
+            info!("Resolved {git_branch} as {ref_kind:?} {resolved_sha}");
+            resolved_sha
+        }
+    };
+
+    // check requested packages against the abbs tree
+    let unknown = find_unknown_packages(
+        &packages
+            .split(',')
+            .filter(|pkg| !pkg.starts_with("groups/"))
+            .map(|pkg| parse_qualified_package(pkg).name.to_string())
+            .collect::<Vec<String>>(),
+        index.packages(),
+    );
+    let packages = if unknown.is_empty() {
+        packages.to_string()
+    } else {
+        let description = unknown
+            .iter()
+            .map(|(pkg, suggestions)| {
+                if suggestions.is_empty() {
+                    pkg.clone()
+                } else {
+                    format!("{pkg} (did you mean: {}?)", suggestions.join(", "))
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        if ARGS.drop_unknown_packages != Some(true) {
+            return Err(anyhow!("Unknown package(s): {description}"));
         }
+
+        let known = packages
+            .split(',')
+            .filter(|pkg| {
+                !unknown
+                    .iter()
+                    .any(|(name, _)| name == parse_qualified_package(pkg).name.trim())
+            })
+            .collect::<Vec<_>>();
+        if known.is_empty() {
+            return Err(anyhow!("Unknown package(s): {description}"));
+        }
+        warn!("Dropping unknown package(s): {description}");
+        notes.dropped_packages = unknown.into_iter().map(|(pkg, _)| pkg).collect();
+        known.join(",")
     };
 
+    // check section and subpackage of qualified names
+    for pkg in packages.split(',') {
+        let qualified = parse_qualified_package(pkg);
+        if pkg.starts_with("groups/")
+            || (qualified.section.is_none() && qualified.variant.is_none())
+        {
+            continue;
+        }
+        check_qualified_package(&qualified, &repo.abbs_path)?;
+    }
+
     // find environment requirements
     let resolved_pkgs = resolve_packages(
         &packages
             .split(",")
             .map(|s| s.to_string())
             .collect::<Vec<String>>(),
-        &ARGS.abbs_path,
+        &repo.abbs_path,
     )
     .context("Resolve packages")?;
 
-    let env_req = get_environment_requirement(&ARGS.abbs_path, &resolved_pkgs);
+    let env_req = get_environment_requirement(&repo.abbs_path, &resolved_pkgs);
     drop(lock);
 
+    let plan_hash = build_plan_hash(&resolved_pkgs, &git_sha, &archs);
+
+    // route packages needing special hardware to capable workers
+    let require_label = ARGS.package_labels.as_ref().and_then(|labels| {
+        labels
+            .required_label(&resolved_pkgs.iter().map(|s| s.as_str()).collect::<Vec<_>>())
+            .map(|label| label.to_string())
+    });
+
     // create a new pipeline
     let mut conn = pool
         .get()
@@ -169,16 +591,48 @@ pub async fn pipeline_new(
         github_pr: github_pr.map(|pr| pr as i64),
         telegram_user: telegram_user,
         creator_user_id: creator_user_id,
+        requested_by: requested_by.map(|s| s.to_string()),
+        build_plan_hash: Some(plan_hash),
+        repo: repo.full_name(),
+        parent_pipeline_id,
+        telegram_message_id: None,
+        git_ref_kind: ref_kind.as_str().to_string(),
     };
-    let pipeline = diesel::insert_into(pipelines::table)
-        .values(&new_pipeline)
-        .returning(Pipeline::as_returning())
-        .get_result(&mut conn)
-        .context("Failed to create pipeline")?;
+    // a retry of the request finds the pipeline by the key once it exists
+    let pipeline = conn.transaction::<Pipeline, anyhow::Error, _>(|conn| {
+        let pipeline = diesel::insert_into(pipelines::table)
+            .values(&new_pipeline)
+            .returning(Pipeline::as_returning())
+            .get_result(conn)
+            .context("Failed to create pipeline")?;
+        if let Some(key) = idempotency_key {
+            idempotency_key_set(
+                conn,
+                key,
+                pipeline.id,
+                pipeline.creation_time,
+                ARGS.idempotency_ttl,
+            )
+            .context("Failed to record idempotency key")?;
+        }
+        Ok(pipeline)
+    })?;
+    let (actor, details) = build_audit(&pipeline);
+    if let Err(err) = record_audit(&mut conn, &actor, "build", &details) {
+        // the pipeline exists already, keep creating its jobs
+        warn!(
+            "Failed to record build of pipeline #{}: {err:?}",
+            pipeline.id
+        );
+    }
 
     // authenticate with github app
     let crab = match get_crab_github_installation().await {
-        Ok(Some(crab)) => Some(crab),
+        Ok(Some(crab)) if ARGS.github_report.check_run() => Some(crab),
+        Ok(Some(_)) => {
+            // reported in comments instead
+            None
+        }
         Ok(None) => {
             // github app unavailable
             None
@@ -195,6 +649,7 @@ pub async fn pipeline_new(
         for arch in &archs {
             handles.push(tokio::spawn(create_check_run(
                 crab.clone(),
+                repo.full_name(),
                 arch.to_string(),
                 git_sha.to_string(),
             )));
@@ -219,12 +674,15 @@ pub async fn pipeline_new(
             packages: packages.to_string(),
             arch: arch.to_string(),
             creation_time: chrono::Utc::now(),
-            status: "created".to_string(),
+            status: initial_status(arch, canary).to_string(),
             github_check_run_id: check_run_id.map(|id| id as i64),
             require_min_core: env_req_current.min_core,
             require_min_total_mem: env_req_current.min_total_mem,
             require_min_total_mem_per_core: env_req_current.min_total_mem_per_core,
             require_min_disk: env_req_current.min_disk,
+            retry_count: 0,
+            priority,
+            require_label: require_label.clone(),
         };
         diesel::insert_into(jobs::table)
             .values(&new_job)
@@ -232,241 +690,3165 @@ pub async fn pipeline_new(
             .context("Failed to create job")?;
     }
 
-    Ok(pipeline)
+    Ok((pipeline, notes))
+}
+
+/// Inputs of a new pipeline rebuilding every arch of an earlier one
+#[derive(Debug, PartialEq, Eq)]
+pub struct PipelineRetry<'a> {
+    pub git_branch: &'a str,
+    pub github_pr: Option<u64>,
+    pub packages: &'a str,
+    pub archs: &'a str,
+    pub priority: i32,
+    pub parent_pipeline_id: i32,
+}
+
+/// Same packages and archs as the parent, with the branch resolved again
+pub fn plan_retry(parent: &Pipeline, priority: i32) -> PipelineRetry<'_> {
+    PipelineRetry {
+        git_branch: &parent.git_branch,
+        github_pr: parent.github_pr.map(|pr| pr as u64),
+        packages: &parent.packages,
+        archs: &parent.archs,
+        priority,
+        parent_pipeline_id: parent.id,
+    }
 }
 
+/// Rebuild every arch of a pipeline as a new pipeline linked to it, while
+/// /restart and "Retry all failed" only restart failed jobs
 #[tracing::instrument(skip(pool))]
-pub async fn pipeline_new_pr(
+pub async fn pipeline_retry(
     pool: DbPool,
-    pr: u64,
-    archs: Option<&str>,
+    pipeline_id: i32,
     source: JobSource,
-) -> anyhow::Result<Pipeline> {
-    match octocrab::instance()
-        .pulls("AOSC-Dev", "aosc-os-abbs")
-        .get(pr)
-        .await
-    {
-        Ok(pr) => {
-            // If the pull request has been merged,
-            // build and push packages based on stable
-            let (git_branch, git_sha) = if pr.merged_at.is_some() {
-                (
-                    "stable",
-                    pr.merge_commit_sha
-                        .as_ref()
-                        .context("merge_commit_sha should not be None")?,
-                )
-            } else {
-                (pr.head.ref_field.as_str(), &pr.head.sha)
-            };
-
-            if pr.head.repo.as_ref().and_then(|x| x.fork).unwrap_or(false) {
-                return Err(anyhow!("Failed to create job: Pull request is a fork"));
-            }
+    requested_by: Option<&str>,
+) -> anyhow::Result<(Pipeline, PipelineNotes)> {
+    let (parent, priority) = {
+        let mut conn = pool
+            .get()
+            .context("Failed to get db connection from pool")?;
+        let parent = crate::schema::pipelines::dsl::pipelines
+            .find(pipeline_id)
+            .first::<Pipeline>(&mut conn)
+            .optional()?
+            .ok_or_else(|| anyhow!("Pipeline #{pipeline_id} not found"))?;
+        let priority = crate::schema::jobs::dsl::jobs
+            .filter(crate::schema::jobs::dsl::pipeline_id.eq(pipeline_id))
+            .select(diesel::dsl::max(crate::schema::jobs::dsl::priority))
+            .first::<Option<i32>>(&mut conn)?
+            .unwrap_or(0);
+        (parent, priority)
+    };
+    let repo = repo_by_full_name(&parent.repo)
+        .ok_or_else(|| anyhow!("Repo {} is not configured", parent.repo))?;
 
-            // find lines starting with #buildit
-            let packages = get_packages_from_pr(&pr);
-            if !packages.is_empty() {
-                let mut skip_git_fetch = false;
-                let archs = if let Some(archs) = archs {
-                    archs.to_string()
-                } else {
-                    let path = &ARGS.abbs_path;
+    let retry = plan_retry(&parent, priority);
+    pipeline_new(
+        pool,
+        &repo,
+        retry.git_branch,
+        None,
+        retry.github_pr,
+        retry.packages,
+        retry.archs,
+        source,
+        requested_by,
+        false,
+        retry.priority,
+        false,
+        Some(retry.parent_pipeline_id),
+        None,
+    )
+    .await
+}
 
-                    let _lock = ABBS_REPO_LOCK.lock().await;
-                    update_abbs(git_branch, &ARGS.abbs_path, false)
-                        .await
-                        .context("Failed to update ABBS tree")?;
-                    // skip next git fetch in pipeline_new
-                    skip_git_fetch = true;
+/// Stable hash of what a pipeline builds: packages in build order, commit and archs
+///
+/// Pipelines with the same hash produce the same packages, so results of the
+/// earlier one still apply.
+pub fn build_plan_hash(packages: &[String], git_sha: &str, archs: &[&str]) -> String {
+    let mut sorted = packages.to_vec();
+    sorted.sort();
 
-                    let resolved_packages =
-                        resolve_packages(&packages, path).context("Failed to resolve packages")?;
-
-                    get_archs(path, &resolved_packages).join(",")
-                };
-
-                pipeline_new(
-                    pool,
-                    git_branch,
-                    Some(git_sha),
-                    Some(pr.number),
-                    &packages.join(","),
-                    &archs,
-                    source,
-                    skip_git_fetch,
-                )
-                .await
-            } else {
-                Err(anyhow!(
-                    "Please list packages to build in pr info starting with '#buildit'"
-                ))
-            }
-        }
-        Err(err) => Err(anyhow!("Failed to get pr info: {err:?}")),
+    let mut hasher = Sha256::new();
+    // separate fields so that e.g. moving a package across them changes the hash
+    for field in [
+        sorted.join(","),
+        packages.join(","),
+        git_sha.to_string(),
+        archs.join(","),
+    ] {
+        hasher.update(field.as_bytes());
+        hasher.update([0]);
     }
+    format!("{:x}", hasher.finalize())
 }
 
-#[derive(Serialize)]
-pub struct PipelineStatus {
-    pub arch: String,
-    pub pending: u64,
-    pub running: u64,
-    pub available_servers: u64,
+/// Find the previous pipeline of the same pull request with identical inputs
+#[tracing::instrument(skip(pool, pipeline))]
+pub async fn unchanged_since(pool: DbPool, pipeline: &Pipeline) -> anyhow::Result<Option<i32>> {
+    let (Some(github_pr), Some(plan_hash)) = (pipeline.github_pr, &pipeline.build_plan_hash) else {
+        return Ok(None);
+    };
+
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+    Ok(crate::schema::pipelines::dsl::pipelines
+        .filter(crate::schema::pipelines::dsl::repo.eq(&pipeline.repo))
+        .filter(crate::schema::pipelines::dsl::github_pr.eq(github_pr))
+        .filter(crate::schema::pipelines::dsl::build_plan_hash.eq(plan_hash))
+        .filter(crate::schema::pipelines::dsl::id.lt(pipeline.id))
+        .order(crate::schema::pipelines::dsl::id.desc())
+        .select(crate::schema::pipelines::dsl::id)
+        .first::<i32>(&mut conn)
+        .optional()?)
 }
 
+/// Find the latest pipeline of a pull request of the repo (as owner/repo)
 #[tracing::instrument(skip(pool))]
-pub async fn pipeline_status(pool: DbPool) -> anyhow::Result<Vec<PipelineStatus>> {
+pub async fn pipeline_latest_of_pr(
+    pool: DbPool,
+    repo: &str,
+    pr: u64,
+) -> anyhow::Result<Option<Pipeline>> {
     let mut conn = pool
         .get()
         .context("Failed to get db connection from pool")?;
-    // find pending/running jobs
-    let mut pending: BTreeMap<String, i64> = crate::schema::jobs::dsl::jobs
-        .filter(crate::schema::jobs::dsl::status.eq("created"))
-        .group_by(crate::schema::jobs::dsl::arch)
-        .select((
-            crate::schema::jobs::dsl::arch,
-            count(crate::schema::jobs::dsl::id),
-        ))
-        .load::<(String, i64)>(&mut conn)?
-        .into_iter()
-        .collect();
-    let mut running: BTreeMap<String, i64> = crate::schema::jobs::dsl::jobs
-        .filter(crate::schema::jobs::dsl::status.eq("running"))
-        .group_by(crate::schema::jobs::dsl::arch)
-        .select((
-            crate::schema::jobs::dsl::arch,
-            count(crate::schema::jobs::dsl::id),
-        ))
-        .load::<(String, i64)>(&mut conn)?
-        .into_iter()
-        .collect();
-
-    use crate::schema::workers::dsl::*;
-    let available_servers: BTreeMap<String, i64> = workers
-        .group_by(arch)
-        .select((arch, count(id)))
-        .load::<(String, i64)>(&mut conn)?
-        .into_iter()
-        .collect();
-
-    // fold noarch into amd64
-    let pending_noarch = *pending.get("noarch").unwrap_or(&0);
-    *pending.entry("amd64".to_string()).or_default() += pending_noarch;
-    let running_noarch = *running.get("noarch").unwrap_or(&0);
-    *running.entry("amd64".to_string()).or_default() += running_noarch;
-
-    let mut res = vec![];
-    for a in ALL_ARCH {
-        res.push(PipelineStatus {
-            arch: a.to_string(),
-            pending: *pending.get(*a).unwrap_or(&0) as u64,
-            running: *running.get(*a).unwrap_or(&0) as u64,
-            available_servers: *available_servers.get(*a).unwrap_or(&0) as u64,
-        });
-    }
-
-    Ok(res)
+    Ok(crate::schema::pipelines::dsl::pipelines
+        .filter(crate::schema::pipelines::dsl::repo.eq(repo))
+        .filter(crate::schema::pipelines::dsl::github_pr.eq(pr as i64))
+        .order(crate::schema::pipelines::dsl::id.desc())
+        .first::<Pipeline>(&mut conn)
+        .optional()?)
 }
 
+/// Latest pipeline of a pull request with all of its jobs
 #[tracing::instrument(skip(pool))]
-pub async fn worker_status(pool: DbPool) -> anyhow::Result<Vec<Worker>> {
+pub async fn pr_latest_build(
+    pool: DbPool,
+    repo: &str,
+    pr: u64,
+) -> anyhow::Result<Option<(Pipeline, Vec<Job>)>> {
+    let Some(pipeline) = pipeline_latest_of_pr(pool.clone(), repo, pr).await? else {
+        return Ok(None);
+    };
+
     let mut conn = pool
         .get()
         .context("Failed to get db connection from pool")?;
-
-    let workers = crate::schema::workers::dsl::workers.load::<Worker>(&mut conn)?;
-    Ok(workers)
+    let jobs = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::pipeline_id.eq(pipeline.id))
+        .load::<Job>(&mut conn)?;
+    Ok(Some((pipeline, jobs)))
 }
 
-async fn job_restart_in_transaction(job_id: i32, conn: &mut PgConnection) -> anyhow::Result<Job> {
-    let job = crate::schema::jobs::dsl::jobs
-        .find(job_id)
-        .get_result::<Job>(conn)?;
-    let pipeline = crate::schema::pipelines::dsl::pipelines
-        .find(job.pipeline_id)
-        .get_result::<Pipeline>(conn)?;
-
-    // job must be failed
-    if job.status != "failed" {
-        bail!("Cannot restart the job unless it was failed");
+/// Pick the git branch and commit to build a pull request on
+///
+/// Merged pull requests are built on stable, since their head branch is
+/// usually deleted after merge. Commit is `None` if it should be resolved from
+/// the branch.
+pub fn resolve_pr_ref<'a>(
+    pr: &'a PullRequest,
+    head_exists: bool,
+    default_branch: &'a str,
+) -> anyhow::Result<(&'a str, Option<&'a str>)> {
+    if pr.merged_at.is_some() {
+        Ok((default_branch, pr.merge_commit_sha.as_deref()))
+    } else if head_exists {
+        Ok((pr.head.ref_field.as_str(), Some(pr.head.sha.as_str())))
+    } else {
+        Err(anyhow!(
+            "Branch {} of pull request #{} has been deleted",
+            pr.head.ref_field,
+            pr.number
+        ))
     }
+}
 
-    // create a new job
-    use crate::schema::jobs;
-    let mut new_job = NewJob {
-        pipeline_id: job.pipeline_id,
-        packages: job.packages,
-        arch: job.arch.clone(),
-        creation_time: chrono::Utc::now(),
-        status: "created".to_string(),
-        github_check_run_id: None,
-        require_min_core: job.require_min_core,
-        require_min_total_mem: job.require_min_total_mem,
-        require_min_total_mem_per_core: job.require_min_total_mem_per_core,
-        require_min_disk: job.require_min_disk,
+/// Run pre-build lint checks on the spec and defines files changed by a pull request
+#[tracing::instrument]
+pub async fn pr_validate(pr: u64) -> anyhow::Result<Vec<LintReport>> {
+    let crab = octocrab::instance();
+    let page = crab
+        .pulls("AOSC-Dev", "aosc-os-abbs")
+        .list_files(pr)
+        .await
+        .context("Failed to list files of pull request")?;
+    let entries = crab.all_pages(page).await?;
+
+    let client = reqwest::Client::builder().user_agent("buildit").build()?;
+    let mut files = vec![];
+    for entry in entries {
+        if entry.status == DiffEntryStatus::Removed
+            || !(is_spec(&entry.filename) || is_defines(&entry.filename))
+        {
+            continue;
+        }
+
+        let Some(raw_url) = entry.raw_url else {
+            continue;
+        };
+        let content = client
+            .get(raw_url)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())?
+            .text()
+            .await?;
+        files.push(LintFile {
+            path: entry.filename,
+            content,
+        });
+    }
+
+    let mut packages = {
+        let _lock = ABBS_REPO_LOCK.lock().await;
+        list_package_names(&ARGS.abbs_path)
+    };
+    // packages added by the pull request itself
+    for file in &files {
+        packages.extend(file.path.split('/').nth(1).map(|s| s.to_string()));
+        packages.extend(
+            file.content
+                .lines()
+                .find_map(|line| line.trim().strip_prefix("PKGNAME="))
+                .map(|name| name.trim_matches(['"', '\'']).to_string()),
+        );
+    }
+    let ctx = LintContext {
+        packages: packages.into_iter().collect(),
     };
 
-    // create new github check run if the restarted job has one
-    if job.github_check_run_id.is_some() {
-        // authenticate with github app
-        match get_crab_github_installation().await {
-            Ok(Some(crab)) => {
-                match crab
-                    .checks("AOSC-Dev", "aosc-os-abbs")
-                    .create_check_run(format!("buildit {}", job.arch), &pipeline.git_sha)
-                    .status(octocrab::params::checks::CheckRunStatus::Queued)
-                    .send()
+    Ok(lint_files(&files, &ctx))
+}
+
+/// Why a pull request should not be built unless forced
+pub fn pr_skip_reason(pr: &PullRequest, force: bool) -> Option<&'static str> {
+    if force {
+        return None;
+    }
+
+    let title = pr.title.as_deref().unwrap_or("").trim_start();
+    if pr.draft == Some(true) {
+        Some("a draft")
+    } else if title.starts_with("WIP:") || title.starts_with("[WIP]") {
+        Some("a work in progress")
+    } else {
+        None
+    }
+}
+
+/// Open pull requests carrying the label
+#[tracing::instrument]
+pub async fn open_prs_with_label(label: &str) -> anyhow::Result<Vec<PullRequest>> {
+    let crab = octocrab::instance();
+    let page = crab
+        .pulls("AOSC-Dev", "aosc-os-abbs")
+        .list()
+        .state(octocrab::params::State::Open)
+        .per_page(100)
+        .send()
+        .await
+        .context("Failed to list pull requests")?;
+    let prs = crab.all_pages(page).await?;
+    Ok(prs
+        .into_iter()
+        .filter(|pr| {
+            pr.labels
+                .iter()
+                .flatten()
+                .any(|pr_label| pr_label.name == label)
+        })
+        .collect())
+}
+
+/// What /buildlabeled does with a pull request
+#[derive(Debug, PartialEq, Eq)]
+pub enum LabeledPr {
+    Build(u64),
+    Skip(u64, &'static str),
+}
+
+/// Pick the labeled pull requests that can be built, in ascending order
+pub fn plan_labeled_builds(prs: &[PullRequest]) -> Vec<LabeledPr> {
+    let mut prs = prs.iter().collect::<Vec<_>>();
+    prs.sort_by_key(|pr| pr.number);
+    prs.into_iter()
+        .map(|pr| {
+            if let Some(reason) = pr_skip_reason(pr, false) {
+                LabeledPr::Skip(pr.number, reason)
+            } else if pr.head.repo.as_ref().and_then(|x| x.fork).unwrap_or(false) {
+                LabeledPr::Skip(pr.number, "a fork")
+            } else if get_packages_from_pr(pr).is_empty() {
+                LabeledPr::Skip(pr.number, "missing a #buildit line")
+            } else {
+                LabeledPr::Build(pr.number)
+            }
+        })
+        .collect()
+}
+
+/// Packages left out of a pull request build since they did not change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnchangedPackages {
+    /// The successful pipeline that built them
+    pub since: i32,
+    pub packages: Vec<String>,
+}
+
+/// What was left out of a new pipeline, to tell the requester
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PipelineNotes {
+    /// Packages listed more than once
+    pub duplicates: Vec<String>,
+    /// Unknown packages dropped with BUILDIT_DROP_UNKNOWN_PACKAGES
+    pub dropped_packages: Vec<String>,
+    /// Archs excluded for the branch by BUILDIT_EXCLUDED_ARCHS
+    pub excluded_archs: Option<String>,
+    /// Archs skipped with BUILDIT_PARTIAL_ENQUEUE, as "queue for X is full (N jobs)"
+    pub full_queues: Vec<String>,
+    /// Set by `pipeline_new_pr` with BUILDIT_BUILD_CHANGED_ONLY
+    pub unchanged: Option<UnchangedPackages>,
+}
+
+/// Latest pipeline of a pull request in which every arch of `archs` built successfully
+fn pr_green_pipeline(
+    conn: &mut PgConnection,
+    repo: &str,
+    pr: u64,
+    archs: &str,
+) -> anyhow::Result<Option<Pipeline>> {
+    let pipelines = crate::schema::pipelines::dsl::pipelines
+        .filter(crate::schema::pipelines::dsl::repo.eq(repo))
+        .filter(crate::schema::pipelines::dsl::github_pr.eq(pr as i64))
+        .order(crate::schema::pipelines::dsl::id.desc())
+        .limit(20)
+        .load::<Pipeline>(conn)?;
+
+    for pipeline in pipelines {
+        let jobs = crate::schema::jobs::dsl::jobs
+            .filter(crate::schema::jobs::dsl::pipeline_id.eq(pipeline.id))
+            .order(crate::schema::jobs::dsl::id.desc())
+            .load::<Job>(conn)?;
+        // restarted jobs replace the failed ones
+        let mut latest = BTreeMap::new();
+        for job in &jobs {
+            latest
+                .entry(job.arch.as_str())
+                .or_insert(job.status.as_str());
+        }
+        if archs
+            .split(',')
+            .all(|arch| latest.get(arch) == Some(&"success"))
+        {
+            return Ok(Some(pipeline));
+        }
+    }
+    Ok(None)
+}
+
+/// Split packages into those to build and those built by an earlier pipeline
+/// with none of their files changed since
+pub fn split_unchanged_packages(
+    packages: &[String],
+    built: &[&str],
+    changed_paths: &[String],
+    index: &PackageIndex,
+) -> (Vec<String>, Vec<String>) {
+    packages.iter().cloned().partition(|pkg| {
+        // groups may list other packages, always rebuild them
+        if pkg.starts_with("groups/") || !built.contains(&pkg.as_str()) {
+            return true;
+        }
+        let name = parse_qualified_package(pkg).name;
+        changed_paths
+            .iter()
+            .any(|path| index.lookup(path) == Some(name))
+    })
+}
+
+/// Packages of a pull request that do not need to be built again, `None` to build all
+async fn find_unchanged_packages(
+    pool: DbPool,
+    repo: &RepoConfig,
+    pr: u64,
+    packages: &[String],
+    archs: &str,
+    git_sha: &str,
+    index: &PackageIndex,
+) -> anyhow::Result<Option<UnchangedPackages>> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+    let Some(green) = pr_green_pipeline(&mut conn, &repo.full_name(), pr, archs)? else {
+        return Ok(None);
+    };
+
+    // e.g. commits of the earlier pipeline were force pushed away
+    let paths = match changed_paths(&repo.abbs_path, &green.git_sha, git_sha).await {
+        Ok(paths) => paths,
+        Err(err) => {
+            warn!(
+                "Failed to compare with pipeline #{}, building all packages: {err}",
+                green.id
+            );
+            return Ok(None);
+        }
+    };
+
+    let (_, unchanged) = split_unchanged_packages(
+        packages,
+        &green.packages.split(',').collect::<Vec<_>>(),
+        &paths,
+        index,
+    );
+    Ok((!unchanged.is_empty()).then_some(UnchangedPackages {
+        since: green.id,
+        packages: unchanged,
+    }))
+}
+
+/// A pull request that GitHub does not know of
+#[derive(Debug, PartialEq, Eq)]
+pub struct PrNotFound {
+    pub pr: u64,
+    /// As owner/repo
+    pub repo: String,
+}
+
+impl std::fmt::Display for PrNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PR #{} not found in {}", self.pr, self.repo)
+    }
+}
+
+impl std::error::Error for PrNotFound {}
+
+/// Get a pull request, failing with `PrNotFound` if it does not exist
+pub async fn fetch_pr(
+    crab: &octocrab::Octocrab,
+    owner: &str,
+    repo: &str,
+    pr: u64,
+) -> anyhow::Result<PullRequest> {
+    match crab.pulls(owner, repo).get(pr).await {
+        Ok(pr) => Ok(pr),
+        Err(octocrab::Error::GitHub { source, .. }) if source.status_code.as_u16() == 404 => {
+            Err(PrNotFound {
+                pr,
+                repo: format!("{owner}/{repo}"),
+            }
+            .into())
+        }
+        Err(err) => Err(anyhow!("Failed to get pr info: {err:?}")),
+    }
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn pipeline_new_pr(
+    pool: DbPool,
+    repo: &RepoConfig,
+    pr: u64,
+    archs: Option<&str>,
+    source: JobSource,
+    requested_by: Option<&str>,
+    force: bool,
+) -> anyhow::Result<(Pipeline, PipelineNotes)> {
+    match fetch_pr(&octocrab::instance(), &repo.owner, &repo.repo, pr).await {
+        Ok(pr) => {
+            if let Some(reason) = pr_skip_reason(&pr, force) {
+                bail!("Skipped building: pull request is {reason}, add --force to build anyway");
+            }
+
+            // before looking up the head branch, which is not in the repo for forks
+            if pr.head.repo.as_ref().and_then(|x| x.fork).unwrap_or(false) {
+                return Err(anyhow!("Failed to create job: Pull request is a fork"));
+            }
+
+            let head_exists = pr.merged_at.is_some()
+                || octocrab::instance()
+                    .repos(&repo.owner, &repo.repo)
+                    .get_ref(&octocrab::params::repos::Reference::Branch(
+                        pr.head.ref_field.clone(),
+                    ))
                     .await
-                {
-                    Ok(check_run) => {
-                        new_job.github_check_run_id = Some(check_run.id.0 as i64);
+                    .is_ok();
+            let (git_branch, git_sha) = resolve_pr_ref(&pr, head_exists, &repo.default_branch)?;
+
+            // find lines starting with #buildit
+            let mut packages = get_packages_from_pr(&pr);
+            // archs are resolved from the packages before pipeline_new checks them
+            check_packages(&packages.join(","))?;
+
+            let changed_only = ARGS.build_changed_only == Some(true);
+            let mut skip_git_fetch = false;
+            let mut unchanged = None;
+            let archs = match archs {
+                Some(archs) if !changed_only => normalize_archs(archs)?.join(","),
+                archs => {
+                    let path = &repo.abbs_path;
+
+                    let _lock = ABBS_REPO_LOCK.lock().await;
+                    let (_, resolved_sha) = update_abbs(git_branch, path, false)
+                        .await
+                        .context("Failed to update ABBS tree")?;
+                    // skip next git fetch in pipeline_new
+                    skip_git_fetch = true;
+                    let index = refresh_package_index(path);
+
+                    let resolve_archs = |packages: &[String]| -> anyhow::Result<String> {
+                        let resolved_packages = resolve_packages(packages, path)
+                            .context("Failed to resolve packages")?;
+                        // same order as archs given explicitly
+                        Ok(
+                            normalize_archs(&get_archs(path, &resolved_packages).join(","))?
+                                .join(","),
+                        )
+                    };
+                    let mut res = match archs {
+                        Some(archs) => normalize_archs(archs)?.join(","),
+                        None => resolve_archs(&packages)?,
+                    };
+
+                    if changed_only {
+                        unchanged = find_unchanged_packages(
+                            pool.clone(),
+                            repo,
+                            pr.number,
+                            &packages,
+                            &res,
+                            git_sha.unwrap_or(&resolved_sha),
+                            &index,
+                        )
+                        .await?;
                     }
-                    Err(err) => {
-                        warn!("Failed to create check run: {}", err);
+                    if let Some(unchanged) = &unchanged {
+                        packages.retain(|pkg| !unchanged.packages.contains(pkg));
+                        if packages.is_empty() {
+                            bail!(
+                                "No packages changed since pipeline #{} (previously {SUCCESS}), nothing to build",
+                                unchanged.since
+                            );
+                        }
+                        if archs.is_none() {
+                            res = resolve_archs(&packages)?;
+                        }
                     }
+                    res
                 }
-            }
-            Ok(None) => {
-                // github app unavailable
-            }
-            Err(err) => {
-                warn!("Failed to get installation token: {}", err);
+            };
+
+            let (pipeline, mut notes) = pipeline_new(
+                pool,
+                repo,
+                git_branch,
+                git_sha,
+                Some(pr.number),
+                &packages.join(","),
+                &archs,
+                source,
+                requested_by,
+                skip_git_fetch,
+                0,
+                false,
+                None,
+                None,
+            )
+            .await?;
+            notes.unchanged = unchanged;
+            Ok((pipeline, notes))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct PipelineStatus {
+    pub arch: String,
+    pub pending: u64,
+    pub running: u64,
+    pub available_servers: u64,
+}
+
+/// A value recomputed at most once per TTL, concurrent callers wait for the same refresh
+pub struct TtlCache<T> {
+    value: tokio::sync::Mutex<Option<(Instant, T)>>,
+}
+
+impl<T: Clone> TtlCache<T> {
+    pub const fn new() -> Self {
+        Self {
+            value: tokio::sync::Mutex::const_new(None),
+        }
+    }
+
+    pub async fn get_or_refresh<F, Fut>(&self, ttl: Duration, f: F) -> anyhow::Result<T>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut value = self.value.lock().await;
+        if let Some((time, value)) = value.as_ref() {
+            if time.elapsed() < ttl {
+                return Ok(value.clone());
             }
         }
+
+        let res = f().await?;
+        *value = Some((Instant::now(), res.clone()));
+        Ok(res)
     }
 
-    let new_job: Job = diesel::insert_into(jobs::table)
-        .values(&new_job)
-        .get_result(conn)
-        .context("Failed to create job")?;
-    Ok(new_job)
+    /// Recompute the value on the next call
+    pub async fn invalidate(&self) {
+        *self.value.lock().await = None;
+    }
+}
+
+impl<T: Clone> Default for TtlCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static PIPELINE_STATUS_CACHE: TtlCache<Vec<PipelineStatus>> = TtlCache::new();
+
+/// `pipeline_status`, cached for `status_cache_ttl` seconds for frequent /status calls
+pub async fn pipeline_status_cached(pool: DbPool) -> anyhow::Result<Vec<PipelineStatus>> {
+    PIPELINE_STATUS_CACHE
+        .get_or_refresh(Duration::from_secs(ARGS.status_cache_ttl), || {
+            pipeline_status(pool)
+        })
+        .await
+}
+
+/// Make the next /status read the queue again, e.g. after workers restarted
+pub async fn pipeline_status_invalidate() {
+    PIPELINE_STATUS_CACHE.invalidate().await;
 }
 
 #[tracing::instrument(skip(pool))]
-pub async fn job_restart(pool: DbPool, job_id: i32) -> anyhow::Result<Job> {
+pub async fn pipeline_status(pool: DbPool) -> anyhow::Result<Vec<PipelineStatus>> {
     let mut conn = pool
         .get()
         .context("Failed to get db connection from pool")?;
+    // find pending/running jobs
+    let mut pending: BTreeMap<String, i64> = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::status.eq("created"))
+        .group_by(crate::schema::jobs::dsl::arch)
+        .select((
+            crate::schema::jobs::dsl::arch,
+            count(crate::schema::jobs::dsl::id),
+        ))
+        .load::<(String, i64)>(&mut conn)?
+        .into_iter()
+        .collect();
+    let mut running: BTreeMap<String, i64> = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::status.eq("running"))
+        .group_by(crate::schema::jobs::dsl::arch)
+        .select((
+            crate::schema::jobs::dsl::arch,
+            count(crate::schema::jobs::dsl::id),
+        ))
+        .load::<(String, i64)>(&mut conn)?
+        .into_iter()
+        .collect();
 
-    // manually handle transaction, since we want to use async in transaction
-    PoolTransactionManager::<AnsiTransactionManager>::begin_transaction(&mut conn)?;
-    match job_restart_in_transaction(job_id, &mut conn).await {
-        Ok(new_job) => {
-            PoolTransactionManager::<AnsiTransactionManager>::commit_transaction(&mut conn)?;
-            return Ok(new_job);
-        }
-        Err(err) => {
-            match PoolTransactionManager::<AnsiTransactionManager>::rollback_transaction(&mut conn)
-            {
-                Ok(()) => {
-                    return Err(err.into());
-                }
-                Err(rollback_err) => {
-                    return Err(err.context(rollback_err).into());
-                }
-            }
+    use crate::schema::workers::dsl::*;
+    let available_servers: BTreeMap<String, i64> = workers
+        .group_by(arch)
+        .select((arch, count(id)))
+        .load::<(String, i64)>(&mut conn)?
+        .into_iter()
+        .collect();
+
+    // fold noarch into amd64
+    let pending_noarch = *pending.get("noarch").unwrap_or(&0);
+    *pending.entry("amd64".to_string()).or_default() += pending_noarch;
+    let running_noarch = *running.get("noarch").unwrap_or(&0);
+    *running.entry("amd64".to_string()).or_default() += running_noarch;
+
+    let mut res = vec![];
+    for a in ALL_ARCH {
+        res.push(PipelineStatus {
+            arch: a.to_string(),
+            pending: *pending.get(*a).unwrap_or(&0) as u64,
+            running: *running.get(*a).unwrap_or(&0) as u64,
+            available_servers: *available_servers.get(*a).unwrap_or(&0) as u64,
+        });
+    }
+
+    Ok(res)
+}
+
+/// Latest finished and oldest queued job of an arch
+#[derive(Debug, Clone, Default)]
+pub struct ArchProgress {
+    pub last_finish: Option<chrono::DateTime<chrono::Utc>>,
+    pub oldest_pending: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn arch_progress(pool: DbPool) -> anyhow::Result<BTreeMap<String, ArchProgress>> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    use crate::schema::jobs::dsl::*;
+    let last_finish = jobs
+        .filter(finish_time.is_not_null())
+        .group_by(arch)
+        .select((arch, diesel::dsl::max(finish_time)))
+        .load::<(String, Option<chrono::DateTime<chrono::Utc>>)>(&mut conn)?;
+    let oldest_pending = jobs
+        .filter(status.eq("created"))
+        .group_by(arch)
+        .select((arch, diesel::dsl::min(creation_time)))
+        .load::<(String, Option<chrono::DateTime<chrono::Utc>>)>(&mut conn)?;
+
+    // fold noarch into amd64, like the queue status
+    let fold = |a: String| {
+        if a == "noarch" {
+            "amd64".to_string()
+        } else {
+            a
         }
+    };
+    let mut res: BTreeMap<String, ArchProgress> = BTreeMap::new();
+    for (a, time) in last_finish {
+        let entry = res.entry(fold(a)).or_default();
+        entry.last_finish = entry.last_finish.max(time);
+    }
+    for (a, time) in oldest_pending {
+        let entry = res.entry(fold(a)).or_default();
+        entry.oldest_pending = match (entry.oldest_pending, time) {
+            (Some(x), Some(y)) => Some(x.min(y)),
+            (x, y) => x.or(y),
+        };
+    }
+    Ok(res)
+}
+
+/// Whether an arch has queued jobs and servers, but no job has finished
+/// within `window` while the jobs were queued
+pub fn is_arch_stalled(
+    status: &PipelineStatus,
+    progress: &ArchProgress,
+    now: chrono::DateTime<chrono::Utc>,
+    window: chrono::Duration,
+) -> bool {
+    if status.pending == 0 || status.available_servers == 0 {
+        return false;
+    }
+    // an idle arch only starts to stall once jobs are queued
+    match progress.last_finish.max(progress.oldest_pending) {
+        Some(since) => now - since > window,
+        None => false,
     }
 }
+
+#[tracing::instrument(skip(pool))]
+pub async fn worker_status(pool: DbPool) -> anyhow::Result<Vec<Worker>> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let workers = crate::schema::workers::dsl::workers.load::<Worker>(&mut conn)?;
+    Ok(workers)
+}
+
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    version
+        .trim()
+        .trim_start_matches('v')
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect()
+}
+
+/// Is the worker older than the minimum version, unknown versions are treated as outdated
+pub fn is_worker_outdated(version: Option<&str>, min_version: Option<&str>) -> bool {
+    let Some(min_version) = min_version.and_then(parse_version) else {
+        return false;
+    };
+    match version.and_then(parse_version) {
+        Some(version) => version < min_version,
+        None => true,
+    }
+}
+
+/// Statuses a job can be in
+pub const JOB_STATUS: &[&str] = &[
+    "created",
+    "running",
+    "success",
+    "failed",
+    "error",
+    "cancelled",
+    "expired",
+    "lost",
+    "held",
+];
+
+/// Jobs listed per page of /history
+pub const HISTORY_PAGE_SIZE: i64 = 10;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct HistoryQuery<'a> {
+    pub package: &'a str,
+    pub arch: Option<&'a str>,
+    pub status: Option<&'a str>,
+    /// starts from 1
+    pub page: i64,
+}
+
+fn history_filter<'a>(query: &HistoryQuery<'a>) -> crate::schema::jobs::BoxedQuery<'a, Pg> {
+    use crate::schema::jobs::dsl::*;
+
+    // packages are stored comma separated
+    let package = query.package;
+    let mut sql = jobs
+        .filter(
+            packages
+                .eq(package)
+                .or(packages.like(format!("{package},%")))
+                .or(packages.like(format!("%,{package}")))
+                .or(packages.like(format!("%,{package},%"))),
+        )
+        .into_boxed();
+    if let Some(a) = query.arch {
+        sql = sql.filter(arch.eq(a));
+    }
+    if let Some(s) = query.status {
+        sql = sql.filter(status.eq(s));
+    }
+    sql
+}
+
+/// A job and who requested it
+pub type HistoryEntry = (Job, Option<String>);
+
+/// Jobs building the package on the requested page with their requesters, newest first,
+/// and the number of all matches
+#[tracing::instrument(skip(pool))]
+pub async fn job_history(
+    pool: DbPool,
+    query: &HistoryQuery<'_>,
+) -> anyhow::Result<(Vec<HistoryEntry>, i64)> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let total = history_filter(query).count().get_result::<i64>(&mut conn)?;
+    let jobs = history_filter(query)
+        .order(crate::schema::jobs::dsl::id.desc())
+        .offset((query.page - 1) * HISTORY_PAGE_SIZE)
+        .limit(HISTORY_PAGE_SIZE)
+        .load::<Job>(&mut conn)?;
+
+    let requesters: BTreeMap<i32, Option<String>> = crate::schema::pipelines::dsl::pipelines
+        .filter(crate::schema::pipelines::dsl::id.eq_any(jobs.iter().map(|job| job.pipeline_id)))
+        .select((
+            crate::schema::pipelines::dsl::id,
+            crate::schema::pipelines::dsl::requested_by,
+        ))
+        .load::<(i32, Option<String>)>(&mut conn)?
+        .into_iter()
+        .collect();
+    let jobs = jobs
+        .into_iter()
+        .map(|job| {
+            let requested_by = requesters.get(&job.pipeline_id).cloned().flatten();
+            (job, requested_by)
+        })
+        .collect();
+    Ok((jobs, total))
+}
+
+pub struct ArchStatus {
+    pub arch: String,
+    pub pending: u64,
+    pub running_jobs: Vec<Job>,
+    pub workers: Vec<Worker>,
+    pub live_workers: u64,
+    pub idle_workers: u64,
+    /// finished jobs in the last 7 days
+    pub recent_total: u64,
+    pub recent_success: u64,
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn arch_status(pool: DbPool, arch: &str) -> anyhow::Result<ArchStatus> {
+    if !ALL_ARCH.contains(&arch) {
+        bail!(
+            "Unknown arch: {arch}, valid archs are: {}",
+            ALL_ARCH.join(", ")
+        );
+    }
+
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    // noarch jobs are built on amd64
+    let job_archs = if arch == "amd64" {
+        vec!["amd64", "noarch"]
+    } else {
+        vec![arch]
+    };
+
+    let pending = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::arch.eq_any(&job_archs))
+        .filter(crate::schema::jobs::dsl::status.eq("created"))
+        .count()
+        .get_result::<i64>(&mut conn)?;
+
+    let running_jobs = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::arch.eq_any(&job_archs))
+        .filter(crate::schema::jobs::dsl::status.eq("running"))
+        .order(crate::schema::jobs::dsl::id.asc())
+        .load::<Job>(&mut conn)?;
+
+    let since = chrono::Utc::now() - chrono::Duration::try_days(7).unwrap();
+    let recent = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::arch.eq_any(&job_archs))
+        .filter(crate::schema::jobs::dsl::finish_time.ge(since))
+        .group_by(crate::schema::jobs::dsl::status)
+        .select((
+            crate::schema::jobs::dsl::status,
+            count(crate::schema::jobs::dsl::id),
+        ))
+        .load::<(String, i64)>(&mut conn)?;
+    let recent_total = recent
+        .iter()
+        .filter(|(status, _)| status == "success" || status == "failed")
+        .map(|(_, count)| *count)
+        .sum::<i64>();
+    let recent_success = recent
+        .iter()
+        .filter(|(status, _)| status == "success")
+        .map(|(_, count)| *count)
+        .sum::<i64>();
+
+    let workers = crate::schema::workers::dsl::workers
+        .filter(crate::schema::workers::dsl::arch.eq(arch))
+        .filter(crate::schema::workers::dsl::visible.eq(true))
+        .order(crate::schema::workers::dsl::hostname.asc())
+        .load::<Worker>(&mut conn)?;
+
+    let deadline =
+        chrono::Utc::now() - chrono::Duration::try_seconds(crate::HEARTBEAT_TIMEOUT).unwrap();
+    let live = workers
+        .iter()
+        .filter(|worker| worker.last_heartbeat_time > deadline)
+        .collect::<Vec<_>>();
+    let idle_workers = live
+        .iter()
+        .filter(|worker| {
+            !running_jobs
+                .iter()
+                .any(|job| job.assigned_worker_id == Some(worker.id))
+        })
+        .count();
+
+    Ok(ArchStatus {
+        arch: arch.to_string(),
+        pending: pending as u64,
+        live_workers: live.len() as u64,
+        idle_workers: idle_workers as u64,
+        running_jobs,
+        workers,
+        recent_total: recent_total as u64,
+        recent_success: recent_success as u64,
+    })
+}
+
+#[derive(Serialize)]
+pub struct RunningJob {
+    pub job: Job,
+    pub worker_hostname: Option<String>,
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn running_jobs(pool: DbPool) -> anyhow::Result<Vec<RunningJob>> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let jobs = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::status.eq("running"))
+        .load::<Job>(&mut conn)?;
+
+    let worker_ids = jobs
+        .iter()
+        .filter_map(|job| job.assigned_worker_id)
+        .collect::<Vec<_>>();
+    let workers = crate::schema::workers::dsl::workers
+        .filter(crate::schema::workers::dsl::id.eq_any(&worker_ids))
+        .load::<Worker>(&mut conn)?;
+
+    Ok(jobs
+        .into_iter()
+        .map(|job| {
+            let worker_hostname = workers
+                .iter()
+                .find(|worker| Some(worker.id) == job.assigned_worker_id)
+                .map(|worker| worker.hostname.clone());
+            RunningJob {
+                job,
+                worker_hostname,
+            }
+        })
+        .collect())
+}
+
+#[derive(diesel::QueryableByName)]
+struct MedianRow {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    packages: String,
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    arch: String,
+    #[diesel(sql_type = diesel::sql_types::Double)]
+    median_elapsed_secs: f64,
+}
+
+/// Median duration of previous successful builds of the running jobs, keyed by packages and arch
+#[tracing::instrument(skip(pool))]
+pub async fn running_job_medians(pool: DbPool) -> anyhow::Result<BTreeMap<(String, String), f64>> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    Ok(diesel::sql_query(
+        "SELECT packages, arch, \
+        percentile_cont(0.5) WITHIN GROUP (ORDER BY elapsed_secs) AS median_elapsed_secs \
+        FROM jobs \
+        WHERE status = 'success' AND elapsed_secs IS NOT NULL \
+        AND (packages, arch) IN (SELECT packages, arch FROM jobs WHERE status = 'running') \
+        GROUP BY packages, arch",
+    )
+    .load::<MedianRow>(&mut conn)?
+    .into_iter()
+    .map(|row| ((row.packages, row.arch), row.median_elapsed_secs))
+    .collect())
+}
+
+/// Point-in-time state of queues, workers and jobs, for postmortems
+#[derive(Serialize)]
+pub struct Snapshot {
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub queues: Vec<PipelineStatus>,
+    pub workers: Vec<Worker>,
+    pub running_jobs: Vec<RunningJob>,
+    pub queued_jobs: Vec<Job>,
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn snapshot(pool: DbPool) -> anyhow::Result<Snapshot> {
+    let time = chrono::Utc::now();
+    let queues = pipeline_status(pool.clone()).await?;
+    let workers = worker_status(pool.clone()).await?;
+    let running_jobs = running_jobs(pool.clone()).await?;
+
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+    let queued_jobs = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::status.eq("created"))
+        .order((
+            crate::schema::jobs::dsl::priority.desc(),
+            crate::schema::jobs::dsl::id.asc(),
+        ))
+        .load::<Job>(&mut conn)?;
+
+    Ok(Snapshot {
+        time,
+        queues,
+        workers,
+        running_jobs,
+        queued_jobs,
+    })
+}
+
+/// Most jobs shown by /queuepeek
+pub const QUEUE_PEEK_MAX: i64 = 20;
+
+/// Next queued jobs handed out to workers of the arch, in the order of worker_poll
+///
+/// Only reads the queue, so no job is held back from workers meanwhile.
+/// Worker requirements and the per-submitter cap are not considered, so a
+/// worker may skip some of them.
+#[tracing::instrument(skip(pool))]
+pub async fn queue_peek(pool: DbPool, arch: &str, n: i64) -> anyhow::Result<Vec<(Job, Pipeline)>> {
+    if !ALL_ARCH.contains(&arch) {
+        bail!(
+            "Unknown arch: {arch}, valid archs are: {}",
+            ALL_ARCH.join(", ")
+        );
+    }
+
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let mut sql = crate::schema::jobs::dsl::jobs
+        .inner_join(crate::schema::pipelines::dsl::pipelines)
+        .order_by(crate::schema::jobs::dsl::priority.desc())
+        .then_order_by(
+            crate::schema::pipelines::dsl::git_branch
+                .eq("stable")
+                .desc(),
+        )
+        .then_order_by(crate::schema::jobs::dsl::id.asc())
+        .filter(crate::schema::jobs::dsl::status.eq("created"))
+        .into_boxed();
+    if arch == "amd64" {
+        // noarch is routed to amd64
+        sql = sql.filter(
+            crate::schema::jobs::dsl::arch
+                .eq(arch)
+                .or(crate::schema::jobs::dsl::arch.eq("noarch")),
+        );
+    } else {
+        sql = sql.filter(crate::schema::jobs::dsl::arch.eq(arch));
+    }
+    Ok(sql
+        .limit(n.clamp(1, QUEUE_PEEK_MAX))
+        .load::<(Job, Pipeline)>(&mut conn)?)
+}
+
+/// Find the job to cancel on `from_arch` and the job to create on `to_arch`
+pub fn plan_queue_move<'a>(
+    jobs: &'a [Job],
+    from_arch: &str,
+    to_arch: &str,
+) -> anyhow::Result<(&'a Job, NewJob)> {
+    for arch in [from_arch, to_arch] {
+        if !ALL_ARCH.contains(&arch) && arch != "noarch" {
+            bail!(
+                "Unknown arch: {arch}, valid archs are: noarch, {}",
+                ALL_ARCH.join(", ")
+            );
+        }
+    }
+    if from_arch == to_arch {
+        bail!("Source and target arch are the same");
+    }
+
+    let is_active = |job: &&Job| job.status == "created" || job.status == "running";
+    let job = jobs
+        .iter()
+        .filter(|job| job.arch == from_arch)
+        .find(is_active)
+        .ok_or_else(|| anyhow!("No queued or running job on {from_arch}"))?;
+    if jobs
+        .iter()
+        .filter(|job| job.arch == to_arch)
+        .any(|job| is_active(&job))
+    {
+        bail!("Pipeline already has a queued or running job on {to_arch}");
+    }
+
+    Ok((
+        job,
+        NewJob {
+            pipeline_id: job.pipeline_id,
+            packages: job.packages.clone(),
+            arch: to_arch.to_string(),
+            creation_time: chrono::Utc::now(),
+            status: "created".to_string(),
+            github_check_run_id: None,
+            require_min_core: job.require_min_core,
+            require_min_total_mem: job.require_min_total_mem,
+            require_min_total_mem_per_core: job.require_min_total_mem_per_core,
+            require_min_disk: job.require_min_disk,
+            retry_count: 0,
+            priority: job.priority,
+            require_label: job.require_label.clone(),
+        },
+    ))
+}
+
+/// Archs of a pipeline after moving its job from one arch to another
+pub fn moved_archs(archs: &str, from_arch: &str, to_arch: &str) -> String {
+    let mut res = vec![];
+    for arch in archs.split(',') {
+        let arch = if arch == from_arch { to_arch } else { arch };
+        if !res.contains(&arch) {
+            res.push(arch);
+        }
+    }
+    res.join(",")
+}
+
+/// Cancel the job of a pipeline on one arch and build the same packages on another
+#[tracing::instrument(skip(pool, bot, ws_state_map))]
+pub async fn queue_move(
+    pool: DbPool,
+    bot: Option<Bot>,
+    ws_state_map: &WSStateMap,
+    pipeline_id: i32,
+    from_arch: &str,
+    to_arch: &str,
+    moved_by: &str,
+) -> anyhow::Result<(Job, Job)> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let (pipeline, moved, old_job, new_job) = conn.transaction::<_, anyhow::Error, _>(|conn| {
+        let pipeline = crate::schema::pipelines::dsl::pipelines
+            .find(pipeline_id)
+            .get_result::<Pipeline>(conn)
+            .optional()?
+            .ok_or_else(|| anyhow!("Pipeline #{pipeline_id} not found"))?;
+        let jobs = crate::schema::jobs::dsl::jobs
+            .filter(crate::schema::jobs::dsl::pipeline_id.eq(pipeline_id))
+            .load::<Job>(conn)?;
+
+        let (old_job, new_job) = plan_queue_move(&jobs, from_arch, to_arch)?;
+        let old_id = old_job.id;
+        let new_job: Job = diesel::insert_into(crate::schema::jobs::table)
+            .values(&new_job)
+            .get_result(conn)
+            .context("Failed to create job")?;
+
+        // keep the reroute in history
+        let old_job = diesel::update(crate::schema::jobs::dsl::jobs.find(old_id))
+            .set((
+                crate::schema::jobs::dsl::status.eq("cancelled"),
+                crate::schema::jobs::dsl::finish_time.eq(chrono::Utc::now()),
+                crate::schema::jobs::dsl::error_message
+                    .eq(format!("Moved to {to_arch} as job #{}", new_job.id)),
+                crate::schema::jobs::dsl::assigned_worker_id.eq(None::<i32>),
+            ))
+            .get_result::<Job>(conn)?;
+        // the state before, to stop its worker
+        let moved = jobs.into_iter().find(|job| job.id == old_id).unwrap();
+        // so that the moved job does not count as a failure of the pipeline
+        diesel::update(crate::schema::pipelines::dsl::pipelines.find(pipeline_id))
+            .set(crate::schema::pipelines::dsl::archs.eq(moved_archs(
+                &pipeline.archs,
+                from_arch,
+                to_arch,
+            )))
+            .execute(conn)?;
+        record_audit(
+            conn,
+            moved_by,
+            "queuemove",
+            &format!(
+                "pipeline #{pipeline_id}: job #{} on {from_arch} moved to #{} on {to_arch}",
+                old_job.id, new_job.id
+            ),
+        )?;
+        Ok((pipeline, moved, old_job, new_job))
+    })?;
+
+    cancelled_jobs_finished(pool, bot, ws_state_map, &pipeline, &[moved]).await;
+    Ok((old_job, new_job))
+}
+
+/// Queued, held or running jobs that cancelling the pipeline would stop
+pub fn plan_pipeline_cancel(jobs: &[Job]) -> Vec<&Job> {
+    jobs.iter()
+        .filter(|job| job.status == "created" || job.status == "running" || job.status == HELD)
+        .collect()
+}
+
+/// Cancel the latest pipeline of a pull request that still has queued or running jobs
+///
+/// Queued jobs are no longer handed out to workers, running jobs are marked
+/// cancelled so that the results reported by their workers are rejected.
+#[tracing::instrument(skip(pool, bot, ws_state_map))]
+pub async fn pipeline_cancel_pr(
+    pool: DbPool,
+    bot: Option<Bot>,
+    ws_state_map: &WSStateMap,
+    repo: &str,
+    pr: u64,
+    cancelled_by: &str,
+) -> anyhow::Result<Option<(Pipeline, Vec<Job>)>> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let res = conn.transaction::<Option<(Pipeline, Vec<Job>)>, anyhow::Error, _>(|conn| {
+        let pipelines = crate::schema::pipelines::dsl::pipelines
+            .filter(crate::schema::pipelines::dsl::repo.eq(repo))
+            .filter(crate::schema::pipelines::dsl::github_pr.eq(pr as i64))
+            .order(crate::schema::pipelines::dsl::id.desc())
+            .load::<Pipeline>(conn)?;
+
+        for pipeline in pipelines {
+            let jobs = crate::schema::jobs::dsl::jobs
+                .filter(crate::schema::jobs::dsl::pipeline_id.eq(pipeline.id))
+                .load::<Job>(conn)?;
+            let ids = plan_pipeline_cancel(&jobs)
+                .into_iter()
+                .map(|job| job.id)
+                .collect::<Vec<_>>();
+            if ids.is_empty() {
+                continue;
+            }
+
+            let cancelled = cancel_jobs(
+                conn,
+                &pipeline,
+                jobs,
+                &ids,
+                &github_actor(cancelled_by),
+                &format!("Cancelled by {cancelled_by}"),
+            )?;
+            return Ok(Some((pipeline, cancelled)));
+        }
+        Ok(None)
+    })?;
+
+    if let Some((pipeline, cancelled)) = &res {
+        cancelled_jobs_finished(pool, bot, ws_state_map, pipeline, cancelled).await;
+    }
+    Ok(res)
+}
+
+/// Mark the jobs as cancelled with the reason, return them in the state before
+fn cancel_jobs(
+    conn: &mut PgConnection,
+    pipeline: &Pipeline,
+    jobs: Vec<Job>,
+    ids: &[i32],
+    actor: &str,
+    reason: &str,
+) -> anyhow::Result<Vec<Job>> {
+    // report the state before cancelling
+    let mut cancelled = jobs
+        .into_iter()
+        .filter(|job| ids.contains(&job.id))
+        .collect::<Vec<_>>();
+    cancelled.sort_by_key(|job| job.id);
+
+    diesel::update(crate::schema::jobs::dsl::jobs.filter(crate::schema::jobs::dsl::id.eq_any(ids)))
+        .set((
+            crate::schema::jobs::dsl::status.eq("cancelled"),
+            crate::schema::jobs::dsl::finish_time.eq(chrono::Utc::now()),
+            crate::schema::jobs::dsl::error_message.eq(reason),
+            crate::schema::jobs::dsl::assigned_worker_id.eq(None::<i32>),
+        ))
+        .execute(conn)?;
+    let (actor, details) = cancel_audit(pipeline, &cancelled, actor);
+    record_audit(conn, &actor, "cancel", &details)?;
+    Ok(cancelled)
+}
+
+/// Ask the workers still building the cancelled jobs to stop, complete their
+/// check runs, then settle the pipeline as when a worker reports a result
+async fn cancelled_jobs_finished(
+    pool: DbPool,
+    bot: Option<Bot>,
+    ws_state_map: &WSStateMap,
+    pipeline: &Pipeline,
+    cancelled: &[Job],
+) {
+    let worker_ids = cancelled
+        .iter()
+        .filter(|job| job.status == "running")
+        .filter_map(|job| job.assigned_worker_id)
+        .collect::<Vec<_>>();
+    if !worker_ids.is_empty() {
+        let workers = pool
+            .get()
+            .context("Failed to get db connection from pool")
+            .and_then(|mut conn| {
+                Ok(crate::schema::workers::dsl::workers
+                    .filter(crate::schema::workers::dsl::id.eq_any(&worker_ids))
+                    .load::<Worker>(&mut conn)?)
+            });
+        match workers {
+            Ok(workers) => {
+                for job in cancelled {
+                    let Some(worker) = workers
+                        .iter()
+                        .find(|worker| Some(worker.id) == job.assigned_worker_id)
+                    else {
+                        continue;
+                    };
+                    if !cancel_on_worker(ws_state_map, &worker.hostname, job.id) {
+                        info!(
+                            "Worker {} is not connected, job {} is left to finish",
+                            worker.hostname, job.id
+                        );
+                    }
+                }
+            }
+            Err(err) => warn!("Failed to find workers of cancelled jobs: {err:?}"),
+        }
+    }
+
+    complete_check_runs(&pipeline.repo, cancelled, CheckRunConclusion::Cancelled).await;
+    job_finished(pool, bot, pipeline.id).await;
+}
+
+/// Reason recorded on jobs of a pull request build made stale by a new push
+pub const SUPERSEDED: &str = "Superseded by newer commit";
+
+/// Jobs to cancel of a pipeline of a pull request whose head is now `head_sha`,
+/// empty if the pipeline builds the head or has finished
+pub fn plan_supersede<'a>(pipeline: &Pipeline, jobs: &'a [Job], head_sha: &str) -> Vec<&'a Job> {
+    if pipeline.git_sha == head_sha {
+        return vec![];
+    }
+    plan_pipeline_cancel(jobs)
+}
+
+/// Cancel the queued and running pipelines of a pull request built from
+/// commits before `head_sha`
+#[tracing::instrument(skip(pool, bot, ws_state_map))]
+pub async fn pipeline_supersede_pr(
+    pool: DbPool,
+    bot: Option<Bot>,
+    ws_state_map: &WSStateMap,
+    repo: &str,
+    pr: u64,
+    head_sha: &str,
+    pushed_by: &str,
+) -> anyhow::Result<Vec<(Pipeline, Vec<Job>)>> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let res = conn.transaction::<Vec<(Pipeline, Vec<Job>)>, anyhow::Error, _>(|conn| {
+        let pipelines = crate::schema::pipelines::dsl::pipelines
+            .filter(crate::schema::pipelines::dsl::repo.eq(repo))
+            .filter(crate::schema::pipelines::dsl::github_pr.eq(pr as i64))
+            .order(crate::schema::pipelines::dsl::id.desc())
+            .load::<Pipeline>(conn)?;
+
+        let mut res = vec![];
+        for pipeline in pipelines {
+            let jobs = crate::schema::jobs::dsl::jobs
+                .filter(crate::schema::jobs::dsl::pipeline_id.eq(pipeline.id))
+                .load::<Job>(conn)?;
+            let ids = plan_supersede(&pipeline, &jobs, head_sha)
+                .into_iter()
+                .map(|job| job.id)
+                .collect::<Vec<_>>();
+            if ids.is_empty() {
+                continue;
+            }
+
+            let reason = format!("{SUPERSEDED} {}", &head_sha[..head_sha.len().min(8)]);
+            let cancelled = cancel_jobs(
+                conn,
+                &pipeline,
+                jobs,
+                &ids,
+                &github_actor(pushed_by),
+                &reason,
+            )?;
+            res.push((pipeline, cancelled));
+        }
+        Ok(res)
+    })?;
+
+    for (pipeline, cancelled) in &res {
+        cancelled_jobs_finished(pool.clone(), bot.clone(), ws_state_map, pipeline, cancelled).await;
+    }
+    Ok(res)
+}
+
+/// Whether the pipeline was requested from the chat, or by the GitHub account
+/// linked to it
+pub fn is_own_pipeline(pipeline: &Pipeline, chat_id: i64, user: Option<&User>) -> bool {
+    if pipeline.telegram_user == Some(chat_id) {
+        return true;
+    }
+    let Some(user) = user else {
+        return false;
+    };
+    pipeline.creator_user_id == Some(user.id)
+        || (pipeline.source == "github"
+            && user.github_login.is_some()
+            && pipeline.requested_by == user.github_login)
+}
+
+/// Queued, held or running jobs of each pipeline owned by the chat, as
+/// pipeline id and job ids
+pub fn plan_cancel_mine(
+    pipelines: &[(Pipeline, Vec<Job>)],
+    chat_id: i64,
+    user: Option<&User>,
+) -> Vec<(i32, Vec<i32>)> {
+    pipelines
+        .iter()
+        .filter(|(pipeline, _)| is_own_pipeline(pipeline, chat_id, user))
+        .map(|(pipeline, jobs)| {
+            let ids = plan_pipeline_cancel(jobs)
+                .into_iter()
+                .map(|job| job.id)
+                .collect::<Vec<_>>();
+            (pipeline.id, ids)
+        })
+        .filter(|(_, ids)| !ids.is_empty())
+        .collect()
+}
+
+/// Pipelines with queued, held or running jobs, with all of their jobs
+fn active_pipelines(conn: &mut PgConnection) -> anyhow::Result<Vec<(Pipeline, Vec<Job>)>> {
+    let ids = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::status.eq_any(["created", "running", HELD]))
+        .select(crate::schema::jobs::dsl::pipeline_id)
+        .distinct()
+        .load::<i32>(conn)?;
+    let pipelines = crate::schema::pipelines::dsl::pipelines
+        .filter(crate::schema::pipelines::dsl::id.eq_any(&ids))
+        .order(crate::schema::pipelines::dsl::id.asc())
+        .load::<Pipeline>(conn)?;
+
+    let mut jobs = BTreeMap::<i32, Vec<Job>>::new();
+    for job in crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::pipeline_id.eq_any(&ids))
+        .load::<Job>(conn)?
+    {
+        jobs.entry(job.pipeline_id).or_default().push(job);
+    }
+    Ok(pipelines
+        .into_iter()
+        .map(|pipeline| {
+            let jobs = jobs.remove(&pipeline.id).unwrap_or_default();
+            (pipeline, jobs)
+        })
+        .collect())
+}
+
+/// Account linked to the chat by /login, if any
+fn chat_user(conn: &mut PgConnection, chat_id: i64) -> anyhow::Result<Option<User>> {
+    Ok(crate::schema::users::dsl::users
+        .filter(crate::schema::users::dsl::telegram_chat_id.eq(chat_id))
+        .first::<User>(conn)
+        .optional()?)
+}
+
+/// Keep the planned jobs of the planned pipelines
+fn select_planned(
+    pipelines: Vec<(Pipeline, Vec<Job>)>,
+    plan: &[(i32, Vec<i32>)],
+) -> Vec<(Pipeline, Vec<Job>, Vec<i32>)> {
+    pipelines
+        .into_iter()
+        .filter_map(|(pipeline, jobs)| {
+            let (_, ids) = plan.iter().find(|(id, _)| *id == pipeline.id)?;
+            Some((pipeline, jobs, ids.clone()))
+        })
+        .collect()
+}
+
+/// Pipelines of the chat that are still building, with their queued, held
+/// or running jobs
+#[tracing::instrument(skip(pool))]
+pub async fn my_builds(pool: DbPool, chat_id: i64) -> anyhow::Result<Vec<(Pipeline, Vec<Job>)>> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let user = chat_user(&mut conn, chat_id)?;
+    let pipelines = active_pipelines(&mut conn)?;
+    let plan = plan_cancel_mine(&pipelines, chat_id, user.as_ref());
+    Ok(select_planned(pipelines, &plan)
+        .into_iter()
+        .map(|(pipeline, jobs, ids)| {
+            let jobs = jobs
+                .into_iter()
+                .filter(|job| ids.contains(&job.id))
+                .collect();
+            (pipeline, jobs)
+        })
+        .collect())
+}
+
+/// Cancel the queued and running pipelines of the chat, leaving those of
+/// others untouched
+#[tracing::instrument(skip(pool, bot, ws_state_map))]
+pub async fn pipeline_cancel_mine(
+    pool: DbPool,
+    bot: Option<Bot>,
+    ws_state_map: &WSStateMap,
+    chat_id: i64,
+    actor: &str,
+    cancelled_by: &str,
+) -> anyhow::Result<Vec<(Pipeline, Vec<Job>)>> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let res = conn.transaction::<Vec<(Pipeline, Vec<Job>)>, anyhow::Error, _>(|conn| {
+        let user = chat_user(conn, chat_id)?;
+        let pipelines = active_pipelines(conn)?;
+        let plan = plan_cancel_mine(&pipelines, chat_id, user.as_ref());
+
+        let mut res = vec![];
+        for (pipeline, jobs, ids) in select_planned(pipelines, &plan) {
+            let cancelled = cancel_jobs(
+                conn,
+                &pipeline,
+                jobs,
+                &ids,
+                actor,
+                &format!("Cancelled by {cancelled_by}"),
+            )?;
+            res.push((pipeline, cancelled));
+        }
+        Ok(res)
+    })?;
+
+    for (pipeline, cancelled) in &res {
+        cancelled_jobs_finished(pool.clone(), bot.clone(), ws_state_map, pipeline, cancelled).await;
+    }
+    Ok(res)
+}
+
+async fn job_restart_in_transaction(job_id: i32, conn: &mut PgConnection) -> anyhow::Result<Job> {
+    let job = crate::schema::jobs::dsl::jobs
+        .find(job_id)
+        .get_result::<Job>(conn)?;
+    let pipeline = crate::schema::pipelines::dsl::pipelines
+        .find(job.pipeline_id)
+        .get_result::<Pipeline>(conn)?;
+
+    // job must be failed
+    if job.status != "failed" {
+        bail!("Cannot restart the job unless it was failed");
+    }
+
+    // create a new job
+    use crate::schema::jobs;
+    let mut new_job = NewJob {
+        pipeline_id: job.pipeline_id,
+        packages: job.packages,
+        arch: job.arch.clone(),
+        creation_time: chrono::Utc::now(),
+        status: "created".to_string(),
+        github_check_run_id: None,
+        require_min_core: job.require_min_core,
+        require_min_total_mem: job.require_min_total_mem,
+        require_min_total_mem_per_core: job.require_min_total_mem_per_core,
+        require_min_disk: job.require_min_disk,
+        retry_count: 0,
+        priority: job.priority,
+        require_label: job.require_label,
+    };
+
+    // create new github check run if the restarted job has one
+    if job.github_check_run_id.is_some() {
+        // authenticate with github app
+        match get_crab_github_installation().await {
+            Ok(Some(crab)) => {
+                let (owner, repo) = owner_repo(&pipeline.repo);
+                match crab
+                    .checks(owner, repo)
+                    .create_check_run(check_run_name(&job.arch), &pipeline.git_sha)
+                    .status(octocrab::params::checks::CheckRunStatus::Queued)
+                    .send()
+                    .await
+                {
+                    Ok(check_run) => {
+                        new_job.github_check_run_id = Some(check_run.id.0 as i64);
+                    }
+                    Err(err) => {
+                        warn!("Failed to create check run: {}", err);
+                    }
+                }
+            }
+            Ok(None) => {
+                // github app unavailable
+            }
+            Err(err) => {
+                warn!("Failed to get installation token: {}", err);
+            }
+        }
+    }
+
+    let new_job: Job = diesel::insert_into(jobs::table)
+        .values(&new_job)
+        .get_result(conn)
+        .context("Failed to create job")?;
+    Ok(new_job)
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn job_restart(pool: DbPool, job_id: i32, restarted_by: &str) -> anyhow::Result<Job> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    // manually handle transaction, since we want to use async in transaction
+    PoolTransactionManager::<AnsiTransactionManager>::begin_transaction(&mut conn)?;
+    let res = match job_restart_in_transaction(job_id, &mut conn).await {
+        Ok(new_job) => record_audit(
+            &mut conn,
+            restarted_by,
+            "restart",
+            &format!(
+                "pipeline #{}: job #{job_id} restarted as #{} ({})",
+                new_job.pipeline_id, new_job.id, new_job.arch
+            ),
+        )
+        .map(|_| new_job),
+        Err(err) => Err(err),
+    };
+    match res {
+        Ok(new_job) => {
+            PoolTransactionManager::<AnsiTransactionManager>::commit_transaction(&mut conn)?;
+            return Ok(new_job);
+        }
+        Err(err) => {
+            match PoolTransactionManager::<AnsiTransactionManager>::rollback_transaction(&mut conn)
+            {
+                Ok(()) => {
+                    return Err(err.into());
+                }
+                Err(rollback_err) => {
+                    return Err(err.context(rollback_err).into());
+                }
+            }
+        }
+    }
+}
+
+/// Find jobs in the pipeline whose latest run of the arch has failed
+#[tracing::instrument(skip(pool))]
+pub async fn failed_jobs(
+    pool: DbPool,
+    pipeline_id: i32,
+    arch: Option<&str>,
+) -> anyhow::Result<Vec<Job>> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let mut query = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::pipeline_id.eq(pipeline_id))
+        .into_boxed();
+    if let Some(arch) = arch {
+        query = query.filter(crate::schema::jobs::dsl::arch.eq(arch));
+    }
+    let jobs = query
+        .order(crate::schema::jobs::dsl::id.asc())
+        .load::<Job>(&mut conn)?;
+
+    // restarted jobs are superseded by the later ones
+    let mut latest: BTreeMap<String, Job> = BTreeMap::new();
+    for job in jobs {
+        latest.insert(job.arch.clone(), job);
+    }
+    Ok(latest
+        .into_values()
+        .filter(|job| job.status == "failed")
+        .collect())
+}
+
+/// Check whether packages built by the pipeline have reached the local repo
+#[tracing::instrument(skip(pool))]
+pub async fn pipeline_mirror_status(
+    pool: DbPool,
+    pipeline_id: i32,
+) -> anyhow::Result<Vec<MirrorStatus>> {
+    let Some(repo) = &ARGS.local_repo else {
+        bail!("Local repo is not configured");
+    };
+
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+    let pipeline = crate::schema::pipelines::dsl::pipelines
+        .find(pipeline_id)
+        .get_result::<Pipeline>(&mut conn)
+        .optional()?
+        .ok_or_else(|| anyhow!("Pipeline #{pipeline_id} not found"))?;
+    let jobs = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::pipeline_id.eq(pipeline_id))
+        .filter(crate::schema::jobs::dsl::status.eq("success"))
+        .order(crate::schema::jobs::dsl::id.asc())
+        .load::<Job>(&mut conn)?;
+    if jobs.is_empty() {
+        bail!("No job of pipeline #{pipeline_id} has succeeded yet");
+    }
+
+    // versions are read from the tree at the built commit
+    let abbs_path = repo_by_full_name(&pipeline.repo)
+        .map(|repo| repo.abbs_path)
+        .unwrap_or_else(|| ARGS.abbs_path.clone());
+    let _lock = ABBS_REPO_LOCK.lock().await;
+    update_abbs(&pipeline.git_sha, &abbs_path, false)
+        .await
+        .context("Failed to update ABBS tree")?;
+
+    let mut res = vec![];
+    for job in jobs {
+        let packages = job
+            .successful_packages
+            .as_deref()
+            .unwrap_or("")
+            .split(',')
+            .filter(|pkg| !pkg.is_empty())
+            .map(|pkg| pkg.to_string())
+            .collect::<Vec<_>>();
+        let index = read_packages_index(repo, &pipeline.git_branch, &job.arch)?;
+        for (package, version) in find_version_by_packages(&packages, &abbs_path) {
+            res.push(MirrorStatus {
+                state: mirror_state(&index, &package, &version),
+                package,
+                version,
+                arch: job.arch.clone(),
+            });
+        }
+    }
+    Ok(res)
+}
+
+/// Find the latest finished job of `arch` in the pipeline and its build environment
+#[tracing::instrument(skip(pool))]
+pub async fn job_environment(
+    pool: DbPool,
+    pipeline_id: i32,
+    arch: &str,
+) -> anyhow::Result<(Job, BTreeMap<String, String>)> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let job = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::pipeline_id.eq(pipeline_id))
+        .filter(crate::schema::jobs::dsl::arch.eq(arch))
+        .filter(crate::schema::jobs::dsl::status.eq_any(["success", "failed"]))
+        .order(crate::schema::jobs::dsl::id.desc())
+        .first::<Job>(&mut conn)
+        .optional()?
+        .with_context(|| format!("No finished {arch} job in pipeline #{pipeline_id}"))?;
+
+    let environment = match &job.environment {
+        Some(environment) => serde_json::from_str(environment)?,
+        None => bail!("Job #{} did not report its build environment", job.id),
+    };
+    Ok((job, environment))
+}
+
+/// Seconds spent on each package, in build order
+pub type PackageTimings = Vec<(String, i64)>;
+
+/// Time spent on each package by the latest finished job of each arch
+///
+/// Timings are `None` for jobs of workers that do not report them.
+#[tracing::instrument(skip(pool))]
+pub async fn pipeline_timings(
+    pool: DbPool,
+    pipeline_id: i32,
+) -> anyhow::Result<Vec<(String, Option<PackageTimings>)>> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    crate::schema::pipelines::dsl::pipelines
+        .find(pipeline_id)
+        .get_result::<Pipeline>(&mut conn)
+        .optional()?
+        .ok_or_else(|| anyhow!("Pipeline #{pipeline_id} not found"))?;
+    let jobs = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::pipeline_id.eq(pipeline_id))
+        .filter(crate::schema::jobs::dsl::status.eq_any(["success", "failed"]))
+        .order((
+            crate::schema::jobs::dsl::arch,
+            crate::schema::jobs::dsl::id.desc(),
+        ))
+        .load::<Job>(&mut conn)?;
+
+    let mut res: Vec<(String, Option<PackageTimings>)> = vec![];
+    for job in jobs {
+        // retried jobs come after the latest one
+        if res.last().is_some_and(|(arch, _)| *arch == job.arch) {
+            continue;
+        }
+        let timings = job
+            .package_timings
+            .as_deref()
+            .map(serde_json::from_str)
+            .transpose()?;
+        res.push((job.arch, timings));
+    }
+    Ok(res)
+}
+
+/// When to send build results to a Telegram chat
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NotifyMode {
+    #[default]
+    All,
+    Failures,
+    None,
+}
+
+impl NotifyMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotifyMode::All => "all",
+            NotifyMode::Failures => "failures",
+            NotifyMode::None => "none",
+        }
+    }
+
+    pub fn should_notify(&self, success: bool) -> bool {
+        match self {
+            NotifyMode::All => true,
+            NotifyMode::Failures => !success,
+            NotifyMode::None => false,
+        }
+    }
+}
+
+impl FromStr for NotifyMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(NotifyMode::All),
+            "failures" => Ok(NotifyMode::Failures),
+            "none" => Ok(NotifyMode::None),
+            _ => bail!("Unknown notify mode: {s}, expected all, failures or none"),
+        }
+    }
+}
+
+pub fn notify_mode_get(conn: &mut PgConnection, chat_id: i64) -> anyhow::Result<NotifyMode> {
+    use crate::schema::chat_settings::dsl::*;
+    match chat_settings
+        .find(chat_id)
+        .select(notify_mode)
+        .first::<String>(conn)
+        .optional()?
+    {
+        Some(mode) => mode.parse(),
+        None => Ok(NotifyMode::default()),
+    }
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn notify_mode_set(pool: DbPool, chat_id: i64, mode: NotifyMode) -> anyhow::Result<()> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let setting = ChatSetting {
+        telegram_chat_id: chat_id,
+        notify_mode: mode.as_str().to_string(),
+    };
+    diesel::insert_into(crate::schema::chat_settings::table)
+        .values(&setting)
+        .on_conflict(crate::schema::chat_settings::telegram_chat_id)
+        .do_update()
+        .set(&setting)
+        .execute(&mut conn)?;
+    Ok(())
+}
+
+/// Pull requests listed by /myprs
+pub const OPENED_PR_LIMIT: i64 = 10;
+
+/// Remember a pull request opened by /openpr
+#[tracing::instrument(skip(pool))]
+pub async fn opened_pr_record(
+    pool: DbPool,
+    pr_number: u64,
+    title: &str,
+    git_ref: &str,
+    chat_id: i64,
+) -> anyhow::Result<()> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    diesel::insert_into(crate::schema::opened_prs::table)
+        .values(&NewOpenedPr {
+            pr_number: pr_number as i64,
+            title: title.to_string(),
+            git_ref: git_ref.to_string(),
+            telegram_chat_id: chat_id,
+            creation_time: chrono::Utc::now(),
+        })
+        .execute(&mut conn)?;
+    Ok(())
+}
+
+/// Latest pull requests opened by /openpr from the chat, or from anyone if `None`
+#[tracing::instrument(skip(pool))]
+pub async fn opened_pr_list(pool: DbPool, chat_id: Option<i64>) -> anyhow::Result<Vec<OpenedPr>> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let mut query = crate::schema::opened_prs::dsl::opened_prs.into_boxed();
+    if let Some(chat_id) = chat_id {
+        query = query.filter(crate::schema::opened_prs::dsl::telegram_chat_id.eq(chat_id));
+    }
+    Ok(query
+        .order(crate::schema::opened_prs::dsl::id.desc())
+        .limit(OPENED_PR_LIMIT)
+        .load::<OpenedPr>(&mut conn)?)
+}
+
+pub fn pr_state(pr: &PullRequest) -> &'static str {
+    if pr.merged_at.is_some() {
+        "merged"
+    } else if pr.state == Some(IssueState::Closed) {
+        "closed"
+    } else {
+        "open"
+    }
+}
+
+/// Current state of each pull request on GitHub, `None` if it cannot be fetched
+pub async fn opened_pr_states(prs: &[OpenedPr]) -> Vec<Option<&'static str>> {
+    let crab = octocrab::instance();
+    let mut res = vec![];
+    for pr in prs {
+        match crab
+            .pulls("AOSC-Dev", "aosc-os-abbs")
+            .get(pr.pr_number as u64)
+            .await
+        {
+            Ok(pr) => res.push(Some(pr_state(&pr))),
+            Err(err) => {
+                warn!("Failed to get pull request #{}: {}", pr.pr_number, err);
+                res.push(None);
+            }
+        }
+    }
+    res
+}
+
+/// Keys created before this time are expired
+pub fn idempotency_cutoff(
+    now: chrono::DateTime<chrono::Utc>,
+    ttl_secs: i64,
+) -> chrono::DateTime<chrono::Utc> {
+    now - chrono::Duration::try_seconds(ttl_secs).unwrap_or(chrono::Duration::zero())
+}
+
+/// Pipeline created by an earlier request with the same idempotency key
+pub fn idempotency_key_get(
+    conn: &mut PgConnection,
+    key: &str,
+    now: chrono::DateTime<chrono::Utc>,
+    ttl_secs: i64,
+) -> anyhow::Result<Option<i32>> {
+    Ok(crate::schema::idempotency_keys::dsl::idempotency_keys
+        .find(key)
+        .filter(
+            crate::schema::idempotency_keys::dsl::creation_time
+                .gt(idempotency_cutoff(now, ttl_secs)),
+        )
+        .select(crate::schema::idempotency_keys::dsl::pipeline_id)
+        .first::<i32>(conn)
+        .optional()?)
+}
+
+/// Remember the pipeline created for the idempotency key, and forget expired keys
+pub fn idempotency_key_set(
+    conn: &mut PgConnection,
+    key: &str,
+    pipeline_id: i32,
+    now: chrono::DateTime<chrono::Utc>,
+    ttl_secs: i64,
+) -> anyhow::Result<()> {
+    diesel::delete(
+        crate::schema::idempotency_keys::dsl::idempotency_keys.filter(
+            crate::schema::idempotency_keys::dsl::creation_time
+                .le(idempotency_cutoff(now, ttl_secs)),
+        ),
+    )
+    .execute(conn)?;
+
+    let record = IdempotencyKey {
+        key: key.to_string(),
+        pipeline_id,
+        creation_time: now,
+    };
+    diesel::insert_into(crate::schema::idempotency_keys::table)
+        .values(&record)
+        .on_conflict(crate::schema::idempotency_keys::key)
+        .do_update()
+        .set(&record)
+        .execute(conn)?;
+    Ok(())
+}
+
+#[test]
+fn test_resolve_pr_ref() {
+    let mut pr: PullRequest = serde_json::from_value(serde_json::json!({
+        "url": "https://api.github.com/repos/AOSC-Dev/aosc-os-abbs/pulls/4992",
+        "id": 1,
+        "number": 4992,
+        "head": { "ref": "fd-9.0.0", "sha": "34acef168fc5ec454d3825fc864964951b130b49" },
+        "base": { "ref": "stable", "sha": "0123456789abcdef0123456789abcdef01234567" },
+    }))
+    .unwrap();
+
+    // open
+    assert_eq!(
+        resolve_pr_ref(&pr, true, "stable").unwrap(),
+        ("fd-9.0.0", Some("34acef168fc5ec454d3825fc864964951b130b49"))
+    );
+
+    // closed with branch deleted
+    assert!(resolve_pr_ref(&pr, false, "stable").is_err());
+
+    // merged, branch deleted or not
+    pr.merged_at = Some(chrono::DateTime::from_timestamp(61, 0).unwrap());
+    pr.merge_commit_sha = Some("fedcba9876543210fedcba9876543210fedcba98".to_string());
+    assert_eq!(
+        resolve_pr_ref(&pr, false, "stable").unwrap(),
+        ("stable", Some("fedcba9876543210fedcba9876543210fedcba98"))
+    );
+    pr.merge_commit_sha = None;
+    assert_eq!(
+        resolve_pr_ref(&pr, true, "stable").unwrap(),
+        ("stable", None)
+    );
+}
+
+#[test]
+fn test_environment_round_trip() {
+    use common::{JobOk, JobResult};
+
+    let environment = BTreeMap::from([
+        ("gcc".to_string(), "13.2.0-3".to_string()),
+        ("kernel".to_string(), "6.9.3-aosc-main".to_string()),
+    ]);
+    let result = JobResult::Ok(JobOk {
+        build_success: true,
+        successful_packages: vec!["fd".to_string()],
+        failed_package: None,
+        skipped_packages: vec![],
+        log_url: None,
+        elapsed_secs: 10,
+        pushpkg_success: true,
+        log_tail: None,
+        environment: Some(environment.clone()),
+        peak_memory_bytes: None,
+        disk_bytes: None,
+        package_timings: None,
+    });
+
+    // worker to server
+    let result: JobResult = serde_json::from_str(&serde_json::to_string(&result).unwrap()).unwrap();
+    let JobResult::Ok(job_ok) = result else {
+        panic!("expected JobResult::Ok");
+    };
+
+    // server to db column and back
+    let column = job_ok
+        .environment
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()
+        .unwrap()
+        .unwrap();
+    let parsed: BTreeMap<String, String> = serde_json::from_str(&column).unwrap();
+    assert_eq!(parsed, environment);
+
+    // old workers do not report environment
+    let job_ok: JobOk = serde_json::from_str(
+        r#"{"build_success":true,"successful_packages":[],"failed_package":null,"skipped_packages":[],"log_url":null,"elapsed_secs":1,"pushpkg_success":true}"#,
+    )
+    .unwrap();
+    assert!(job_ok.environment.is_none());
+}
+
+#[test]
+fn test_notify_mode() {
+    let mode: NotifyMode = "failures".parse().unwrap();
+    assert!(mode.should_notify(false));
+    assert!(!mode.should_notify(true));
+
+    assert!(NotifyMode::default().should_notify(true));
+    assert!(!NotifyMode::None.should_notify(false));
+    assert!("sometimes".parse::<NotifyMode>().is_err());
+}
+
+#[test]
+fn test_pr_skip_reason() {
+    let mut pr: PullRequest = serde_json::from_value(serde_json::json!({
+        "url": "https://api.github.com/repos/AOSC-Dev/aosc-os-abbs/pulls/4992",
+        "id": 1,
+        "number": 4992,
+        "title": "fd: update to 9.0.0",
+        "draft": false,
+        "head": { "ref": "fd-9.0.0", "sha": "34acef168fc5ec454d3825fc864964951b130b49" },
+        "base": { "ref": "stable", "sha": "0123456789abcdef0123456789abcdef01234567" },
+    }))
+    .unwrap();
+    assert_eq!(pr_skip_reason(&pr, false), None);
+
+    // draft
+    pr.draft = Some(true);
+    assert_eq!(pr_skip_reason(&pr, false), Some("a draft"));
+    assert_eq!(pr_skip_reason(&pr, true), None);
+
+    // wip title
+    pr.draft = Some(false);
+    pr.title = Some("WIP: fd: update to 9.0.0".to_string());
+    assert_eq!(pr_skip_reason(&pr, false), Some("a work in progress"));
+    pr.title = Some("[WIP] fd: update to 9.0.0".to_string());
+    assert_eq!(pr_skip_reason(&pr, false), Some("a work in progress"));
+    assert_eq!(pr_skip_reason(&pr, true), None);
+}
+
+#[test]
+fn test_is_worker_outdated() {
+    // no minimum version
+    assert!(!is_worker_outdated(None, None));
+    assert!(!is_worker_outdated(Some("0.1.0"), None));
+
+    // up to date
+    assert!(!is_worker_outdated(Some("0.2.0"), Some("0.2.0")));
+    assert!(!is_worker_outdated(Some("0.10.1"), Some("0.2.0")));
+    assert!(!is_worker_outdated(Some("v1.0.0"), Some("0.2.0")));
+
+    // outdated
+    assert!(is_worker_outdated(Some("0.1.9"), Some("0.2.0")));
+    assert!(is_worker_outdated(None, Some("0.2.0")));
+    assert!(is_worker_outdated(Some("unknown"), Some("0.2.0")));
+}
+
+#[test]
+fn test_sort_archs() {
+    let mut archs = vec![
+        "riscv64",
+        "amd64",
+        "noarch",
+        "loongson3",
+        "amd64",
+        "arm64",
+        "riscv64",
+    ];
+    sort_archs(&mut archs);
+    assert_eq!(
+        archs,
+        vec!["amd64", "arm64", "loongson3", "riscv64", "noarch"]
+    );
+
+    let mut archs = ALL_ARCH.iter().rev().copied().collect::<Vec<_>>();
+    archs.extend(ALL_ARCH.iter());
+    sort_archs(&mut archs);
+    assert_eq!(archs, ALL_ARCH);
+}
+
+#[tokio::test]
+async fn test_ttl_cache() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let cache = TtlCache::new();
+    let calls = AtomicUsize::new(0);
+    let compute = || async { Ok(calls.fetch_add(1, Ordering::SeqCst)) };
+
+    let ttl = Duration::from_secs(60);
+    assert_eq!(cache.get_or_refresh(ttl, compute).await.unwrap(), 0);
+    assert_eq!(cache.get_or_refresh(ttl, compute).await.unwrap(), 0);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // stale values are recomputed
+    assert_eq!(
+        cache.get_or_refresh(Duration::ZERO, compute).await.unwrap(),
+        1
+    );
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    // failures are not cached
+    let failing = || async { Err(anyhow!("database is down")) };
+    assert!(cache.get_or_refresh(Duration::ZERO, failing).await.is_err());
+    assert_eq!(cache.get_or_refresh(ttl, compute).await.unwrap(), 1);
+
+    // /reping recomputes the value before it is stale
+    cache.invalidate().await;
+    assert_eq!(cache.get_or_refresh(ttl, compute).await.unwrap(), 2);
+    assert_eq!(cache.get_or_refresh(ttl, compute).await.unwrap(), 2);
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn test_package_labels() {
+    let labels = parse_package_labels("chromium=bigmem, llvm*=bigmem,cuda-*=gpu").unwrap();
+    assert_eq!(labels.required_label(&["chromium"]), Some("bigmem"));
+    assert_eq!(labels.required_label(&["bash", "llvm-18"]), Some("bigmem"));
+    assert_eq!(labels.required_label(&["cuda-toolkit"]), Some("gpu"));
+    // no required label, any worker can take it
+    assert_eq!(labels.required_label(&["chromium-widevine"]), None);
+    assert_eq!(labels.required_label(&["bash"]), None);
+    // first matching pattern wins
+    assert_eq!(
+        labels.required_label(&["cuda-toolkit", "chromium"]),
+        Some("bigmem")
+    );
+
+    assert!(parse_package_labels("").unwrap().0.is_empty());
+    assert!(parse_package_labels("chromium").is_err());
+    assert!(parse_package_labels("chromium=").is_err());
+}
+
+#[test]
+fn test_excluded_archs() {
+    let excluded = parse_excluded_archs("experimental-*=riscv64,loongarch64; retro=amd64").unwrap();
+
+    // dropped for a matching ref
+    assert_eq!(
+        apply_excluded_archs(
+            vec!["amd64", "arm64", "riscv64"],
+            "experimental-gcc",
+            &excluded
+        )
+        .unwrap(),
+        (
+            vec!["amd64", "arm64"],
+            Some("riscv64 excluded for branch experimental-gcc by policy".to_string())
+        )
+    );
+    // mainline expands without them
+    let archs = apply_excluded_archs(
+        normalize_archs("mainline").unwrap(),
+        "experimental-gcc",
+        &excluded,
+    )
+    .unwrap()
+    .0;
+    assert!(!archs.contains(&"riscv64") && !archs.contains(&"loongarch64"));
+    assert!(archs.contains(&"amd64"));
+
+    // retained for other refs
+    assert_eq!(
+        apply_excluded_archs(vec!["amd64", "riscv64"], "stable", &excluded).unwrap(),
+        (vec!["amd64", "riscv64"], None)
+    );
+    assert_eq!(
+        apply_excluded_archs(vec!["amd64"], "retro-gcc", &excluded).unwrap(),
+        (vec!["amd64"], None)
+    );
+
+    // refuse when nothing is left
+    assert_eq!(
+        apply_excluded_archs(vec!["amd64"], "retro", &excluded)
+            .unwrap_err()
+            .to_string(),
+        "amd64 excluded for branch retro by policy, nothing to build"
+    );
+
+    assert!(parse_excluded_archs("").unwrap().0.is_empty());
+    assert!(parse_excluded_archs("experimental-*").is_err());
+    assert!(parse_excluded_archs("experimental-*=").is_err());
+    assert!(parse_excluded_archs("experimental-*=i486").is_err());
+}
+
+#[test]
+fn test_apply_queue_cap() {
+    let depths = BTreeMap::from([("amd64".to_string(), 3), ("arm64".to_string(), 10)]);
+
+    // under cap
+    assert_eq!(
+        apply_queue_cap(vec!["amd64", "riscv64"], &depths, 10, false).unwrap(),
+        (vec!["amd64", "riscv64"], vec![])
+    );
+
+    // over cap
+    assert_eq!(
+        apply_queue_cap(vec!["amd64", "arm64"], &depths, 10, false)
+            .unwrap_err()
+            .to_string(),
+        "queue for arm64 is full (10 jobs), try later"
+    );
+
+    // partial acceptance
+    assert_eq!(
+        apply_queue_cap(vec!["amd64", "arm64"], &depths, 10, true).unwrap(),
+        (
+            vec!["amd64"],
+            vec!["queue for arm64 is full (10 jobs)".to_string()]
+        )
+    );
+    assert!(apply_queue_cap(vec!["arm64"], &depths, 10, true).is_err());
+}
+
+#[test]
+fn test_is_arch_stalled() {
+    let time = |secs: i64| chrono::DateTime::from_timestamp(secs, 0).unwrap();
+    let now = time(86400);
+    let window = chrono::Duration::hours(1);
+    let status = |pending: u64, available_servers: u64| PipelineStatus {
+        arch: "riscv64".to_string(),
+        pending,
+        running: 1,
+        available_servers,
+    };
+
+    // nothing finished for hours while jobs were waiting
+    let stale = ArchProgress {
+        last_finish: Some(time(3600)),
+        oldest_pending: Some(time(7200)),
+    };
+    assert!(is_arch_stalled(&status(3, 2), &stale, now, window));
+    // no servers or no backlog is not a stall
+    assert!(!is_arch_stalled(&status(3, 0), &stale, now, window));
+    assert!(!is_arch_stalled(&status(0, 2), &stale, now, window));
+
+    // healthy arch
+    let healthy = ArchProgress {
+        last_finish: Some(time(86400 - 600)),
+        oldest_pending: Some(time(7200)),
+    };
+    assert!(!is_arch_stalled(&status(3, 2), &healthy, now, window));
+
+    // jobs just queued after a quiet day
+    let idle = ArchProgress {
+        last_finish: Some(time(3600)),
+        oldest_pending: Some(time(86400 - 60)),
+    };
+    assert!(!is_arch_stalled(&status(3, 2), &idle, now, window));
+    assert!(!is_arch_stalled(
+        &status(3, 2),
+        &ArchProgress::default(),
+        now,
+        window
+    ));
+}
+
+#[test]
+fn test_snapshot_serialize() {
+    let snapshot = Snapshot {
+        time: chrono::DateTime::from_timestamp(61, 0).unwrap(),
+        queues: vec![PipelineStatus {
+            arch: "amd64".to_string(),
+            pending: 2,
+            running: 1,
+            available_servers: 3,
+        }],
+        workers: vec![],
+        running_jobs: vec![],
+        queued_jobs: vec![],
+    };
+
+    let json = serde_json::to_value(&snapshot).unwrap();
+    let sections = json.as_object().unwrap().keys().collect::<Vec<_>>();
+    assert_eq!(
+        sections,
+        vec!["queued_jobs", "queues", "running_jobs", "time", "workers"]
+    );
+    assert_eq!(json["queues"][0]["arch"], "amd64");
+    assert_eq!(json["queues"][0]["pending"], 2);
+}
+
+#[test]
+fn test_plan_queue_move() {
+    let job = |id: i32, arch: &str, status: &str| Job {
+        id,
+        pipeline_id: 1,
+        packages: "fd,fish".to_string(),
+        arch: arch.to_string(),
+        status: status.to_string(),
+        require_min_core: Some(4),
+        priority: 3,
+        ..Job::fixture()
+    };
+    let jobs = vec![
+        job(1, "amd64", "success"),
+        job(2, "loongson3", "created"),
+        job(3, "riscv64", "running"),
+    ];
+
+    let (old_job, new_job) = plan_queue_move(&jobs, "loongson3", "loongarch64").unwrap();
+    assert_eq!(old_job.id, 2);
+    assert_eq!(new_job.pipeline_id, 1);
+    assert_eq!(new_job.arch, "loongarch64");
+    assert_eq!(new_job.packages, "fd,fish");
+    assert_eq!(new_job.status, "created");
+    assert_eq!(new_job.require_min_core, Some(4));
+    assert_eq!(new_job.priority, 3);
+
+    // finished job cannot be moved
+    assert!(plan_queue_move(&jobs, "amd64", "arm64").is_err());
+    // target arch busy
+    assert!(plan_queue_move(&jobs, "loongson3", "riscv64").is_err());
+    // invalid arches
+    assert!(plan_queue_move(&jobs, "loongson3", "i486").is_err());
+    assert!(plan_queue_move(&jobs, "loongson3", "loongson3").is_err());
+    assert!(plan_queue_move(&jobs, "arm64", "amd64").is_err());
+
+    assert_eq!(
+        moved_archs("amd64,loongson3,riscv64", "loongson3", "loongarch64"),
+        "amd64,loongarch64,riscv64"
+    );
+    assert_eq!(moved_archs("amd64,arm64", "arm64", "amd64"), "amd64");
+}
+
+#[test]
+fn test_plan_pipeline_cancel() {
+    let job = |id: i32, arch: &str, status: &str| Job {
+        id,
+        pipeline_id: 1,
+        arch: arch.to_string(),
+        status: status.to_string(),
+        ..Job::fixture()
+    };
+    let jobs = vec![
+        job(1, "amd64", "success"),
+        job(2, "arm64", "running"),
+        job(3, "loongson3", "created"),
+        job(4, "riscv64", "failed"),
+        job(5, "loongarch64", "cancelled"),
+        job(6, "ppc64el", "held"),
+    ];
+    let ids = plan_pipeline_cancel(&jobs)
+        .into_iter()
+        .map(|job| job.id)
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec![2, 3, 6]);
+
+    // finished pipeline has nothing to cancel
+    assert!(plan_pipeline_cancel(&jobs[..1]).is_empty());
+
+    // new commits pushed while the pipeline is building
+    let pipeline = Pipeline {
+        id: 1,
+        archs: "amd64,arm64,loongson3,riscv64,loongarch64,ppc64el".to_string(),
+        source: "github".to_string(),
+        github_pr: Some(4992),
+        requested_by: Some("cyan".to_string()),
+        ..Pipeline::fixture()
+    };
+    let ids = plan_supersede(&pipeline, &jobs, "ffe2f8c8b5a4d4d8c6e1a3f1a79e3c8c4d4b1a2e")
+        .into_iter()
+        .map(|job| job.id)
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec![2, 3, 6]);
+    // the pipeline of the new head is kept
+    assert!(plan_supersede(&pipeline, &jobs, &pipeline.git_sha).is_empty());
+    // and so is a finished one
+    assert!(plan_supersede(&pipeline, &jobs[..1], "ffe2f8c8").is_empty());
+
+    // /cancelmine only touches the pipelines of the caller
+    let pipeline =
+        |id: i32, source: &str, telegram_user: Option<i64>, requested_by: &str| Pipeline {
+            id,
+            archs: "amd64,arm64".to_string(),
+            source: source.to_string(),
+            telegram_user,
+            requested_by: Some(requested_by.to_string()),
+            ..Pipeline::fixture()
+        };
+    let user = User {
+        id: 7,
+        github_login: Some("cyan".to_string()),
+        github_id: None,
+        github_name: None,
+        github_avatar_url: None,
+        github_email: None,
+        telegram_chat_id: Some(1234),
+    };
+    let jobs_of = |pipeline_id: i32, ids: [(i32, &str); 2]| {
+        ids.map(|(id, status)| Job {
+            pipeline_id,
+            ..job(id, "amd64", status)
+        })
+        .into()
+    };
+    let pipelines = vec![
+        // queued and running from the chat
+        (
+            pipeline(10, "telegram", Some(1234), "@cyan"),
+            jobs_of(10, [(11, "created"), (12, "running")]),
+        ),
+        // someone else's
+        (
+            pipeline(20, "telegram", Some(5678), "@Yerus"),
+            jobs_of(20, [(21, "created"), (22, "running")]),
+        ),
+        // requested on GitHub by the linked account
+        (
+            pipeline(30, "github", None, "cyan"),
+            jobs_of(30, [(31, "success"), (32, "held")]),
+        ),
+        // requested on GitHub by someone else
+        (
+            pipeline(40, "github", None, "Yerus"),
+            jobs_of(40, [(41, "created"), (42, "created")]),
+        ),
+        // finished already
+        (
+            pipeline(50, "telegram", Some(1234), "@cyan"),
+            jobs_of(50, [(51, "success"), (52, "failed")]),
+        ),
+    ];
+    assert_eq!(
+        plan_cancel_mine(&pipelines, 1234, Some(&user)),
+        vec![(10, vec![11, 12]), (30, vec![32])]
+    );
+    // without a linked account only the pipelines of the chat are found
+    assert_eq!(
+        plan_cancel_mine(&pipelines, 1234, None),
+        vec![(10, vec![11, 12])]
+    );
+    assert_eq!(
+        plan_cancel_mine(&pipelines, 5678, None),
+        vec![(20, vec![21, 22])]
+    );
+    assert!(plan_cancel_mine(&pipelines, 4321, None).is_empty());
+}
+
+#[test]
+fn test_check_packages() {
+    use crate::github::get_packages_from_pr_body;
+
+    assert!(check_packages("bash,fish").is_ok());
+
+    // /build and the api with an empty package field
+    let err = check_packages("").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "No packages to build; specify packages or add a #buildit line"
+    );
+    assert!(check_packages(",").is_err());
+    assert!(check_packages(" , ").is_err());
+
+    // /pr and webhook builds of a pull request
+    let packages = get_packages_from_pr_body("Topic description\n#buildit fd ripgrep");
+    assert_eq!(packages, vec!["fd", "ripgrep"]);
+    assert!(check_packages(&packages.join(",")).is_ok());
+    for body in [
+        "Topic description",
+        "Topic description\n#buildit",
+        "#buildit   ",
+    ] {
+        let packages = get_packages_from_pr_body(body);
+        assert!(packages.is_empty());
+        assert!(check_packages(&packages.join(",")).is_err());
+    }
+
+    // repeated packages are built once
+    assert_eq!(
+        dedup_packages("bash,fish,bash,Bash,fish,bash"),
+        ("bash,fish,Bash".to_string(), vec!["bash", "fish"])
+    );
+    assert_eq!(
+        dedup_packages("fd,ripgrep"),
+        ("fd,ripgrep".to_string(), vec![])
+    );
+}
+
+#[test]
+fn test_idempotency_cutoff() {
+    let now = chrono::DateTime::from_timestamp(86400 * 2, 0).unwrap();
+    assert_eq!(
+        idempotency_cutoff(now, 86400),
+        chrono::DateTime::from_timestamp(86400, 0).unwrap()
+    );
+    assert_eq!(idempotency_cutoff(now, 0), now);
+}
+
+#[test]
+fn test_build_plan_hash() {
+    let packages = vec!["llvm".to_string(), "rustc".to_string()];
+    let sha = "34acef168fc5ec454d3825fc864964951b130b49";
+    let hash = build_plan_hash(&packages, sha, &["amd64", "arm64"]);
+    assert_eq!(hash.len(), 64);
+    assert_eq!(hash, build_plan_hash(&packages, sha, &["amd64", "arm64"]));
+
+    // build order
+    assert_ne!(
+        hash,
+        build_plan_hash(
+            &["rustc".to_string(), "llvm".to_string()],
+            sha,
+            &["amd64", "arm64"]
+        )
+    );
+    // package set
+    assert_ne!(
+        hash,
+        build_plan_hash(&packages[..1], sha, &["amd64", "arm64"])
+    );
+    // commit
+    assert_ne!(
+        hash,
+        build_plan_hash(
+            &packages,
+            "0000000000000000000000000000000000000000",
+            &["amd64", "arm64"]
+        )
+    );
+    // archs
+    assert_ne!(hash, build_plan_hash(&packages, sha, &["amd64"]));
+}
+
+#[test]
+fn test_pr_state() {
+    let mut pr: PullRequest = serde_json::from_value(serde_json::json!({
+        "url": "https://api.github.com/repos/AOSC-Dev/aosc-os-abbs/pulls/4992",
+        "id": 1,
+        "number": 4992,
+        "state": "open",
+        "head": { "ref": "fd-9.0.0", "sha": "34acef168fc5ec454d3825fc864964951b130b49" },
+        "base": { "ref": "stable", "sha": "0123456789abcdef0123456789abcdef01234567" },
+    }))
+    .unwrap();
+    assert_eq!(pr_state(&pr), "open");
+
+    pr.state = Some(IssueState::Closed);
+    assert_eq!(pr_state(&pr), "closed");
+
+    pr.merged_at = Some(chrono::DateTime::from_timestamp(61, 0).unwrap());
+    assert_eq!(pr_state(&pr), "merged");
+}
+
+#[test]
+fn test_plan_labeled_builds() {
+    let pr = |number: u64, title: &str, draft: bool, body: &str| -> PullRequest {
+        serde_json::from_value(serde_json::json!({
+            "url": format!("https://api.github.com/repos/AOSC-Dev/aosc-os-abbs/pulls/{number}"),
+            "id": number,
+            "number": number,
+            "title": title,
+            "draft": draft,
+            "body": body,
+            "labels": [{
+                "id": 1,
+                "node_id": "LA_1",
+                "url": "https://api.github.com/repos/AOSC-Dev/aosc-os-abbs/labels/ready-to-build",
+                "name": "ready-to-build",
+                "color": "0e8a16",
+                "default": false,
+            }],
+            "head": { "ref": "fd-9.0.0", "sha": "34acef168fc5ec454d3825fc864964951b130b49" },
+            "base": { "ref": "stable", "sha": "0123456789abcdef0123456789abcdef01234567" },
+        }))
+        .unwrap()
+    };
+    let prs = [
+        pr(
+            4995,
+            "fish: update to 3.7.1",
+            false,
+            "Topic\n\n#buildit fish",
+        ),
+        pr(4992, "fd: update to 9.0.0", false, "#buildit fd ripgrep"),
+        pr(4993, "bat: update to 0.24.0", false, "no packages listed"),
+        pr(4994, "WIP: llvm: update to 18", false, "#buildit llvm"),
+        pr(4996, "gcc: update to 14", true, "#buildit gcc"),
+    ];
+
+    assert_eq!(
+        plan_labeled_builds(&prs),
+        vec![
+            LabeledPr::Build(4992),
+            LabeledPr::Skip(4993, "missing a #buildit line"),
+            LabeledPr::Skip(4994, "a work in progress"),
+            LabeledPr::Build(4995),
+            LabeledPr::Skip(4996, "a draft"),
+        ]
+    );
+    assert!(plan_labeled_builds(&[]).is_empty());
+}
+
+#[test]
+fn test_split_unchanged_packages() {
+    let packages = [
+        "fd",
+        "ripgrep",
+        "bat",
+        "llvm:+stage2",
+        "groups/rust-tools",
+        "fish",
+    ]
+    .map(|pkg| pkg.to_string());
+    // fish is new in this iteration of the pull request
+    let built = ["fd", "ripgrep", "bat", "llvm:+stage2", "groups/rust-tools"];
+    let changed_paths = [
+        "app-utils/ripgrep/spec",
+        "runtime-devel/llvm/autobuild/defines",
+        "groups/rust-tools",
+        "README.md",
+    ]
+    .map(|path| path.to_string());
+    let index = PackageIndex::from_dirs(
+        [
+            "app-utils/fd",
+            "app-utils/ripgrep",
+            "app-utils/bat",
+            "runtime-devel/llvm",
+            "app-shells/fish",
+        ]
+        .map(str::to_string),
+    );
+
+    let (build, unchanged) = split_unchanged_packages(&packages, &built, &changed_paths, &index);
+    assert_eq!(
+        build,
+        ["ripgrep", "llvm:+stage2", "groups/rust-tools", "fish"]
+    );
+    assert_eq!(unchanged, ["fd", "bat"]);
+
+    // nothing changed
+    let (build, unchanged) = split_unchanged_packages(&packages[..3], &built, &[], &index);
+    assert!(build.is_empty());
+    assert_eq!(unchanged, ["fd", "ripgrep", "bat"]);
+}
+
+#[test]
+fn test_offline_archs() {
+    let online = ["amd64".to_string(), "arm64".to_string()];
+
+    // every arch has workers
+    let offline = offline_archs(&["amd64", "arm64"], &online);
+    assert!(offline.is_empty());
+    assert!(check_offline_archs(&offline, true).is_ok());
+    assert_eq!(offline_archs(&["noarch"], &online), Vec::<&str>::new());
+
+    // queued with a warning, or refused
+    let offline = offline_archs(&["amd64", "riscv64", "loongarch64"], &online);
+    assert_eq!(offline, ["riscv64", "loongarch64"]);
+    assert!(check_offline_archs(&offline, false).is_ok());
+    assert_eq!(
+        check_offline_archs(&offline, true).unwrap_err().to_string(),
+        "No workers currently online for riscv64, loongarch64, try later"
+    );
+    assert_eq!(offline_archs(&["noarch"], &[]), ["noarch"]);
+}
+
+#[test]
+fn test_plan_retry() {
+    let parent = Pipeline {
+        packages: "fd,ripgrep".to_string(),
+        archs: "amd64,arm64,loongarch64,riscv64".to_string(),
+        source: "github".to_string(),
+        github_pr: Some(4992),
+        ..Pipeline::fixture()
+    };
+    // every arch, not only failed ones, and the branch instead of the old commit
+    assert_eq!(
+        plan_retry(&parent, 10),
+        PipelineRetry {
+            git_branch: "fd-9.0.0",
+            github_pr: Some(4992),
+            packages: "fd,ripgrep",
+            archs: "amd64,arm64,loongarch64,riscv64",
+            priority: 10,
+            parent_pipeline_id: 12,
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_fetch_pr() {
+    use axum::{http::StatusCode, routing::get, Json, Router};
+
+    // mocked GitHub without any pull request
+    let app = Router::new().route(
+        "/repos/AOSC-Dev/aosc-os-abbs/pulls/:pr",
+        get(|| async {
+            (
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({
+                    "message": "Not Found",
+                    "documentation_url": "https://docs.github.com/rest/pulls/pulls#get-a-pull-request",
+                })),
+            )
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    let crab = octocrab::Octocrab::builder()
+        .base_uri(format!("http://{addr}"))
+        .unwrap()
+        .build()
+        .unwrap();
+
+    let err = fetch_pr(&crab, "AOSC-Dev", "aosc-os-abbs", 99999)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<PrNotFound>(),
+        Some(&PrNotFound {
+            pr: 99999,
+            repo: "AOSC-Dev/aosc-os-abbs".to_string()
+        })
+    );
+    assert_eq!(
+        err.to_string(),
+        "PR #99999 not found in AOSC-Dev/aosc-os-abbs"
+    );
+
+    // network errors keep the cause
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let down = format!("http://{}", listener.local_addr().unwrap());
+    drop(listener);
+    let crab = octocrab::Octocrab::builder()
+        .base_uri(down)
+        .unwrap()
+        .build()
+        .unwrap();
+    let err = fetch_pr(&crab, "AOSC-Dev", "aosc-os-abbs", 4992)
+        .await
+        .unwrap_err();
+    assert!(err.downcast_ref::<PrNotFound>().is_none());
+    assert!(err.to_string().starts_with("Failed to get pr info: "));
+}
+
+#[tokio::test]
+async fn test_job_history() {
+    let Some(pool) = crate::test_db() else {
+        return;
+    };
+    let mut conn = pool.get().unwrap();
+    diesel::insert_into(crate::schema::pipelines::table)
+        .values(&Pipeline {
+            requested_by: Some("octocat".to_string()),
+            ..Pipeline::fixture()
+        })
+        .execute(&mut conn)
+        .unwrap();
+    let job = |id: i32, packages: &str, arch: &str, status: &str| Job {
+        id,
+        packages: packages.to_string(),
+        arch: arch.to_string(),
+        status: status.to_string(),
+        ..Job::fixture()
+    };
+    let mut jobs = (1..=21)
+        .map(|id| {
+            job(
+                id,
+                "fd",
+                if id % 2 == 0 { "arm64" } else { "amd64" },
+                "success",
+            )
+        })
+        .collect::<Vec<_>>();
+    jobs.extend([
+        job(22, "fd,ripgrep", "amd64", "failed"),
+        job(23, "bat,fd", "amd64", "success"),
+        job(24, "bat,fd,ripgrep", "amd64", "success"),
+        // other packages with fd in their names
+        job(25, "fdisk", "amd64", "success"),
+        job(26, "bat,xfd,ripgrep", "amd64", "success"),
+    ]);
+    diesel::insert_into(crate::schema::jobs::table)
+        .values(&jobs)
+        .execute(&mut conn)
+        .unwrap();
+
+    let history = |package, arch, status, page| {
+        let pool = pool.clone();
+        async move {
+            let query = HistoryQuery {
+                package,
+                arch,
+                status,
+                page,
+            };
+            let (jobs, total) = job_history(pool, &query).await.unwrap();
+            for (_, requested_by) in &jobs {
+                assert_eq!(requested_by.as_deref(), Some("octocat"));
+            }
+            let ids = jobs.into_iter().map(|(job, _)| job.id).collect::<Vec<_>>();
+            (ids, total)
+        }
+    };
+
+    // the package alone or anywhere in the list, newest first
+    assert_eq!(
+        history("fd", None, None, 1).await,
+        ((15..=24).rev().collect(), 24)
+    );
+    assert_eq!(
+        history("fd", None, None, 2).await,
+        ((5..=14).rev().collect(), 24)
+    );
+    assert_eq!(history("fd", None, None, 3).await, (vec![4, 3, 2, 1], 24));
+    assert_eq!(history("fd", None, None, 4).await, (vec![], 24));
+    assert_eq!(history("fdisk", None, None, 1).await, (vec![25], 1));
+    assert_eq!(
+        history("ripgrep", None, None, 1).await,
+        (vec![26, 24, 22], 3)
+    );
+    assert_eq!(history("ripgre", None, None, 1).await, (vec![], 0));
+
+    // filters
+    assert_eq!(
+        history("fd", Some("arm64"), None, 1).await,
+        ((1..=10).rev().map(|id| id * 2).collect(), 10)
+    );
+    assert_eq!(history("fd", Some("arm64"), None, 2).await, (vec![], 10));
+    assert_eq!(
+        history("fd", Some("amd64"), Some("failed"), 1).await,
+        (vec![22], 1)
+    );
+    assert_eq!(
+        history("fd", Some("arm64"), Some("failed"), 1).await,
+        (vec![], 0)
+    );
+}
+
+#[test]
+fn test_idempotency_key_retry() {
+    let Some(pool) = crate::test_db() else {
+        return;
+    };
+    let mut conn = pool.get().unwrap();
+    diesel::insert_into(crate::schema::pipelines::table)
+        .values(&[
+            Pipeline::fixture(),
+            Pipeline {
+                id: 13,
+                ..Pipeline::fixture()
+            },
+        ])
+        .execute(&mut conn)
+        .unwrap();
+    let now = chrono::DateTime::from_timestamp(86400 * 2, 0).unwrap();
+    let later = |secs| now + chrono::Duration::try_seconds(secs).unwrap();
+
+    // the first request creates the pipeline, retries get it back
+    assert_eq!(
+        idempotency_key_get(&mut conn, "ci-1", now, 3600).unwrap(),
+        None
+    );
+    idempotency_key_set(&mut conn, "ci-1", 12, now, 3600).unwrap();
+    assert_eq!(
+        idempotency_key_get(&mut conn, "ci-1", later(60), 3600).unwrap(),
+        Some(12)
+    );
+    assert_eq!(
+        idempotency_key_get(&mut conn, "ci-2", later(60), 3600).unwrap(),
+        None
+    );
+
+    // until the key expires, then the retry creates another pipeline
+    assert_eq!(
+        idempotency_key_get(&mut conn, "ci-1", later(3600), 3600).unwrap(),
+        None
+    );
+    idempotency_key_set(&mut conn, "ci-1", 13, later(3600), 3600).unwrap();
+    assert_eq!(
+        idempotency_key_get(&mut conn, "ci-1", later(3660), 3600).unwrap(),
+        Some(13)
+    );
+
+    // expired keys are forgotten when new ones are recorded
+    idempotency_key_set(&mut conn, "ci-2", 12, later(7200), 3600).unwrap();
+    assert_eq!(
+        crate::schema::idempotency_keys::dsl::idempotency_keys
+            .select(crate::schema::idempotency_keys::dsl::key)
+            .load::<String>(&mut conn)
+            .unwrap(),
+        vec!["ci-2".to_string()]
+    );
+}
+
+#[tokio::test]
+async fn test_pipeline_supersede_pr() {
+    let Some(pool) = crate::test_db() else {
+        return;
+    };
+    let mut conn = pool.get().unwrap();
+    let old_sha = "34acef168fc5ec454d3825fc864964951b130b49";
+    let head_sha = "0123456789abcdef0123456789abcdef01234567";
+    let pipeline = |id: i32, pr: i64, git_sha: &str| Pipeline {
+        id,
+        github_pr: Some(pr),
+        git_sha: git_sha.to_string(),
+        source: "github".to_string(),
+        ..Pipeline::fixture()
+    };
+    let job = |id: i32, pipeline_id: i32, status: &str| Job {
+        id,
+        pipeline_id,
+        status: status.to_string(),
+        ..Job::fixture()
+    };
+    diesel::insert_into(crate::schema::pipelines::table)
+        .values(&[pipeline(12, 4992, old_sha), pipeline(13, 4993, old_sha)])
+        .execute(&mut conn)
+        .unwrap();
+    diesel::insert_into(crate::schema::jobs::table)
+        .values(&[
+            job(1, 12, "running"),
+            job(2, 12, "created"),
+            job(3, 12, "success"),
+            job(4, 13, "created"),
+        ])
+        .execute(&mut conn)
+        .unwrap();
+    let supersede = |head_sha| {
+        let pool = pool.clone();
+        async move {
+            pipeline_supersede_pr(
+                pool,
+                None,
+                &WSStateMap::default(),
+                "AOSC-Dev/aosc-os-abbs",
+                4992,
+                head_sha,
+                "octocat",
+            )
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(pipeline, jobs)| {
+                let ids = jobs.into_iter().map(|job| job.id).collect::<Vec<_>>();
+                (pipeline.id, ids)
+            })
+            .collect::<Vec<_>>()
+        }
+    };
+    let find_job = |conn: &mut PgConnection, id: i32| {
+        crate::schema::jobs::dsl::jobs
+            .find(id)
+            .first::<Job>(conn)
+            .unwrap()
+    };
+
+    // the push cancels the unfinished jobs of the old commit only
+    assert_eq!(supersede(head_sha).await, vec![(12, vec![1, 2])]);
+    for id in [1, 2] {
+        let job = find_job(&mut conn, id);
+        assert_eq!(job.status, "cancelled");
+        assert_eq!(
+            job.error_message.as_deref(),
+            Some("Superseded by newer commit 01234567")
+        );
+    }
+    assert_eq!(find_job(&mut conn, 3).status, "success");
+    assert_eq!(find_job(&mut conn, 4).status, "created");
+    let actors = crate::schema::audit_log::dsl::audit_log
+        .select(crate::schema::audit_log::dsl::actor)
+        .load::<String>(&mut conn)
+        .unwrap();
+    assert_eq!(actors, vec!["github:octocat".to_string()]);
+
+    // the rebuild of the head is left alone when the push is delivered again
+    diesel::insert_into(crate::schema::pipelines::table)
+        .values(&pipeline(14, 4992, head_sha))
+        .execute(&mut conn)
+        .unwrap();
+    diesel::insert_into(crate::schema::jobs::table)
+        .values(&job(5, 14, "created"))
+        .execute(&mut conn)
+        .unwrap();
+    assert_eq!(supersede(head_sha).await, vec![]);
+    assert_eq!(find_job(&mut conn, 5).status, "created");
+
+    // and superseded by the next push
+    assert_eq!(
+        supersede("89abcdef0123456789abcdef0123456789abcdef").await,
+        vec![(14, vec![5])]
+    );
+    assert_eq!(find_job(&mut conn, 5).status, "cancelled");
+}
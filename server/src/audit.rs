@@ -0,0 +1,247 @@
+use crate::{
+    models::{AuditLog, Job, NewAuditLog, Pipeline},
+    DbPool,
+};
+use anyhow::Context;
+use chrono::Utc;
+use diesel::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+
+/// Privileged actions that are recorded
+pub const AUDIT_ACTIONS: &[&str] = &[
+    "build",
+    "cancel",
+    "restart",
+    "queuemove",
+    "drain",
+    "undrain",
+];
+
+/// Entries shown by /audit
+pub const AUDIT_PAGE_SIZE: i64 = 20;
+
+/// Actor of requests to the HTTP API, which are not tied to a user
+pub const API_ACTOR: &str = "api";
+
+/// Telegram user, as `@username` or user id
+pub fn telegram_actor(user: &str) -> String {
+    format!("telegram:{user}")
+}
+
+pub fn github_actor(login: &str) -> String {
+    format!("github:{login}")
+}
+
+/// Record who did what, in the transaction of the action if any
+pub fn record_audit(
+    conn: &mut PgConnection,
+    actor: &str,
+    action: &str,
+    details: &str,
+) -> anyhow::Result<()> {
+    diesel::insert_into(crate::schema::audit_log::table)
+        .values(&NewAuditLog {
+            time: Utc::now(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            details: details.to_string(),
+        })
+        .execute(conn)
+        .context("Failed to record audit log")?;
+    Ok(())
+}
+
+/// Actor and details of a new pipeline
+pub fn build_audit(pipeline: &Pipeline) -> (String, String) {
+    let requester = pipeline
+        .requested_by
+        .clone()
+        .or(pipeline.telegram_user.map(|chat_id| chat_id.to_string()))
+        .unwrap_or_else(|| "unknown".to_string());
+    let actor = match pipeline.source.as_str() {
+        "telegram" => telegram_actor(&requester),
+        "github" => github_actor(&requester),
+        // set to the workflow for builds requested by GitHub Actions
+        _ => pipeline
+            .requested_by
+            .clone()
+            .unwrap_or_else(|| API_ACTOR.to_string()),
+    };
+
+    let mut details = format!(
+        "pipeline #{}: {} on {} for {}",
+        pipeline.id, pipeline.packages, pipeline.git_branch, pipeline.archs
+    );
+    if let Some(pr) = pipeline.github_pr {
+        details.push_str(&format!(" (PR #{pr})"));
+    }
+    (actor, details)
+}
+
+/// Actor and details of cancelling jobs of a pipeline
+pub fn cancel_audit(pipeline: &Pipeline, jobs: &[Job], actor: &str) -> (String, String) {
+    let jobs = jobs
+        .iter()
+        .map(|job| format!("#{} ({})", job.id, job.arch))
+        .collect::<Vec<_>>();
+    (
+        actor.to_string(),
+        format!("pipeline #{}: jobs {}", pipeline.id, jobs.join(", ")),
+    )
+}
+
+#[derive(Debug, PartialEq, Eq, Default)]
+pub struct AuditQuery<'a> {
+    pub actor: Option<&'a str>,
+    pub action: Option<&'a str>,
+}
+
+/// Parse `[actor=actor] [action=action]`
+pub fn parse_audit_query(arguments: &str) -> Result<AuditQuery<'_>, String> {
+    let mut query = AuditQuery::default();
+    for part in arguments.split_ascii_whitespace() {
+        match part.split_once('=') {
+            Some(("actor", value)) => query.actor = Some(value),
+            Some(("action", value)) => {
+                if !AUDIT_ACTIONS.contains(&value) {
+                    return Err(format!(
+                        "Unknown action: {value}, valid actions are: {}",
+                        AUDIT_ACTIONS.join(", ")
+                    ));
+                }
+                query.action = Some(value);
+            }
+            Some((key, _)) => {
+                return Err(format!("Unknown filter: {key}, expected actor= or action="))
+            }
+            None => return Err(format!("Unexpected argument: {part}")),
+        }
+    }
+    Ok(query)
+}
+
+/// Latest audit log entries matching the query
+pub async fn audit_log_list(pool: DbPool, query: &AuditQuery<'_>) -> anyhow::Result<Vec<AuditLog>> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    use crate::schema::audit_log::dsl::*;
+    let mut sql = audit_log.into_boxed();
+    if let Some(value) = query.actor {
+        sql = sql.filter(actor.eq(value));
+    }
+    if let Some(value) = query.action {
+        sql = sql.filter(action.eq(value));
+    }
+    Ok(sql
+        .order(id.desc())
+        .limit(AUDIT_PAGE_SIZE)
+        .load::<AuditLog>(&mut conn)?)
+}
+
+pub fn format_audit_log(entries: &[AuditLog]) -> String {
+    if entries.is_empty() {
+        return "No audit log entries found".to_string();
+    }
+
+    entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "{} {} {}: {}",
+                entry.time.format("%Y-%m-%d %H:%M:%S UTC"),
+                entry.actor,
+                entry.action,
+                entry.details
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[test]
+fn test_audit_entries() {
+    use chrono::DateTime;
+
+    // build submitted from Telegram
+    let mut pipeline = Pipeline {
+        packages: "fd,ripgrep".to_string(),
+        archs: "amd64,arm64".to_string(),
+        telegram_user: Some(1234),
+        requested_by: Some("@cyan".to_string()),
+        ..Pipeline::fixture()
+    };
+    assert_eq!(
+        build_audit(&pipeline),
+        (
+            "telegram:@cyan".to_string(),
+            "pipeline #12: fd,ripgrep on fd-9.0.0 for amd64,arm64".to_string()
+        )
+    );
+
+    // and from a GitHub comment
+    pipeline.source = "github".to_string();
+    pipeline.github_pr = Some(4992);
+    pipeline.telegram_user = None;
+    pipeline.requested_by = Some("cyan".to_string());
+    assert_eq!(
+        build_audit(&pipeline),
+        (
+            "github:cyan".to_string(),
+            "pipeline #12: fd,ripgrep on fd-9.0.0 for amd64,arm64 (PR #4992)".to_string()
+        )
+    );
+
+    pipeline.source = "manual".to_string();
+    pipeline.requested_by = None;
+    assert_eq!(build_audit(&pipeline).0, "api");
+    pipeline.requested_by = Some("github-actions:cyan@AOSC-Dev/aosc-os-abbs".to_string());
+    assert_eq!(
+        build_audit(&pipeline).0,
+        "github-actions:cyan@AOSC-Dev/aosc-os-abbs"
+    );
+
+    // cancel
+    let job = |id: i32, arch: &str| Job {
+        id,
+        packages: "fd,ripgrep".to_string(),
+        arch: arch.to_string(),
+        status: "running".to_string(),
+        ..Job::fixture()
+    };
+    assert_eq!(
+        cancel_audit(
+            &pipeline,
+            &[job(34, "amd64"), job(35, "arm64")],
+            &github_actor("cyan")
+        ),
+        (
+            "github:cyan".to_string(),
+            "pipeline #12: jobs #34 (amd64), #35 (arm64)".to_string()
+        )
+    );
+
+    assert_eq!(
+        parse_audit_query("actor=github:cyan action=cancel"),
+        Ok(AuditQuery {
+            actor: Some("github:cyan"),
+            action: Some("cancel"),
+        })
+    );
+    assert_eq!(parse_audit_query(""), Ok(AuditQuery::default()));
+    assert!(parse_audit_query("action=delete").is_err());
+    assert!(parse_audit_query("cyan").is_err());
+
+    let entries = [AuditLog {
+        id: 1,
+        time: DateTime::from_timestamp(61, 0).unwrap(),
+        actor: "telegram:@cyan".to_string(),
+        action: "drain".to_string(),
+        details: "server draining".to_string(),
+    }];
+    assert_eq!(
+        format_audit_log(&entries),
+        "1970-01-01 00:01:01 UTC telegram:@cyan drain: server draining"
+    );
+    assert_eq!(format_audit_log(&[]), "No audit log entries found");
+}
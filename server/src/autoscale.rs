@@ -0,0 +1,123 @@
+use crate::{api::pipeline_status, DbPool, ARGS};
+use serde::Serialize;
+use std::{collections::BTreeMap, time::Duration};
+use tracing::{info, warn};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoscaleEvent {
+    /// queue depth reached the high-water mark
+    High,
+    /// queue depth dropped to the low-water mark
+    Low,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AutoscaleRequest {
+    pub event: AutoscaleEvent,
+    pub arch: String,
+    pub pending: u64,
+    pub running: u64,
+    pub available_servers: u64,
+}
+
+/// Remember which arch queues are backed up, so that each crossing is reported once
+pub struct AutoscaleState {
+    high_water: u64,
+    low_water: u64,
+    backed_up: BTreeMap<String, bool>,
+}
+
+impl AutoscaleState {
+    pub fn new(high_water: u64, low_water: u64) -> Self {
+        Self {
+            high_water,
+            low_water,
+            backed_up: BTreeMap::new(),
+        }
+    }
+
+    /// Feed the current queue depth of `arch`, return the event to report if any
+    pub fn update(&mut self, arch: &str, pending: u64) -> Option<AutoscaleEvent> {
+        let backed_up = self.backed_up.entry(arch.to_string()).or_default();
+        if !*backed_up && pending >= self.high_water {
+            *backed_up = true;
+            Some(AutoscaleEvent::High)
+        } else if *backed_up && pending <= self.low_water {
+            *backed_up = false;
+            Some(AutoscaleEvent::Low)
+        } else {
+            None
+        }
+    }
+}
+
+pub async fn autoscale_worker_inner(pool: DbPool, url: &str) -> anyhow::Result<()> {
+    let client = reqwest::Client::new();
+    let mut state = AutoscaleState::new(ARGS.autoscale_high_water, ARGS.autoscale_low_water);
+
+    loop {
+        for status in pipeline_status(pool.clone()).await? {
+            let Some(event) = state.update(&status.arch, status.pending) else {
+                continue;
+            };
+
+            info!(
+                "Queue of {} has {} pending job(s), sending {:?} autoscale event",
+                status.arch, status.pending, event
+            );
+            let req = AutoscaleRequest {
+                event,
+                arch: status.arch,
+                pending: status.pending,
+                running: status.running,
+                available_servers: status.available_servers,
+            };
+            if let Err(err) = client
+                .post(url)
+                .json(&req)
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status())
+            {
+                warn!("Failed to send autoscale event: {}", err);
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+    }
+}
+
+pub async fn autoscale_worker(pool: DbPool, url: String) {
+    loop {
+        info!("Starting autoscale worker");
+        if let Err(err) = autoscale_worker_inner(pool.clone(), &url).await {
+            warn!("Got error running autoscale worker: {}", err);
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+#[test]
+fn test_autoscale_state() {
+    let mut state = AutoscaleState::new(10, 2);
+
+    // below high-water mark
+    assert_eq!(state.update("amd64", 5), None);
+
+    // crossing is reported once
+    assert_eq!(state.update("amd64", 10), Some(AutoscaleEvent::High));
+    assert_eq!(state.update("amd64", 15), None);
+
+    // hovering between the marks does not fire again
+    assert_eq!(state.update("amd64", 5), None);
+    assert_eq!(state.update("amd64", 12), None);
+
+    // other arches are tracked separately
+    assert_eq!(state.update("riscv64", 30), Some(AutoscaleEvent::High));
+
+    // drained
+    assert_eq!(state.update("amd64", 2), Some(AutoscaleEvent::Low));
+    assert_eq!(state.update("amd64", 0), None);
+    assert_eq!(state.update("amd64", 11), Some(AutoscaleEvent::High));
+}
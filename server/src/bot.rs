@@ -1,20 +1,46 @@
 use crate::{
-    api::{job_restart, pipeline_new, pipeline_new_pr, pipeline_status, worker_status, JobSource},
-    formatter::to_html_new_pipeline_summary,
-    github::{get_github_token, login_github},
-    models::{NewUser, User},
-    DbPool, ALL_ARCH, ARGS,
+    api::{
+        active_job_counts, arch_progress, arch_status, failed_jobs, fetch_pr, is_arch_stalled,
+        is_worker_outdated, job_environment, job_history, job_restart, my_builds, normalize_archs,
+        notify_mode_set, open_prs_with_label, opened_pr_list, opened_pr_record, opened_pr_states,
+        pipeline_cancel_mine, pipeline_mirror_status, pipeline_new, pipeline_new_pr,
+        pipeline_offline_archs, pipeline_retry, pipeline_status_cached, pipeline_status_invalidate,
+        pipeline_timings, plan_labeled_builds, pr_latest_build, pr_validate, queue_move,
+        queue_peek, running_jobs, snapshot, unchanged_since, worker_status, ArchStatus,
+        HistoryEntry, HistoryQuery, JobSource, LabeledPr, NotifyMode, PackageTimings,
+        PipelineStatus, PrNotFound, RunningJob, HISTORY_PAGE_SIZE, JOB_STATUS,
+    },
+    audit::{audit_log_list, format_audit_log, parse_audit_query, record_audit, telegram_actor},
+    build_and_pr::open_pr_auth,
+    compare::{format_compare, parse_compare_request, pipeline_with_jobs},
+    dashboard::summary_message_set,
+    drain::set_draining,
+    formatter::{
+        format_pr_status, format_resource_usage, new_pipeline_summary, page_count, page_footer,
+        page_out_of_range, paginate, parse_page, to_html_offline_archs, to_html_pipeline_notes,
+        to_html_retry_of,
+    },
+    github::{get_crab_github_bot, get_github_token, is_org_user, login_github},
+    lint::LintReport,
+    log_buffer::LOG_BUFFER,
+    mirror::format_mirror_status,
+    models::{Job, NewUser, OpenedPr, PendingPr, Pipeline, User, Worker},
+    repo::{repo_of, RepoConfig, PRIMARY_REPO_FULL_NAME},
+    routes::{format_cancelled, ping_workers, request_live_log, WSStateMap, LIVE_LOG_TIMEOUT},
+    DbPool, Secret, ALL_ARCH, ARGS,
 };
 use anyhow::{bail, Context};
 use buildit_utils::{find_update_and_update_checksum, github::OpenPRRequest};
 use chrono::Local;
 use diesel::{Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use once_cell::sync::Lazy;
 use rand::prelude::SliceRandom;
 use rand::thread_rng;
 use reqwest::ClientBuilder;
 use serde::{Deserialize, Serialize};
 use std::{
     borrow::{Borrow, Cow},
+    collections::{BTreeMap, HashSet},
     fmt::Display,
     future::Future,
     sync::{
@@ -25,11 +51,14 @@ use std::{
 };
 use teloxide::{
     prelude::*,
-    types::{ChatAction, ParseMode},
+    types::{
+        CallbackQuery, ChatAction, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, ParseMode,
+    },
     utils::command::BotCommands,
+    ApiError, RequestError,
 };
 use tokio::time::sleep;
-use tracing::{warn, Instrument};
+use tracing::{warn, Instrument, Level};
 
 #[derive(BotCommands, Clone, Debug)]
 #[command(
@@ -40,19 +69,35 @@ pub enum Command {
     #[command(description = "Display usage: /help")]
     Help,
     #[command(
-        description = "Start a build job: /build branch packages archs (e.g., /build stable bash,fish amd64,arm64)"
+        description = "Start a build job: /build branch packages [archs] [pr=pr-number] [priority=n] [repo=name] [--canary] (e.g., /build stable bash,fish amd64,arm64)"
     )]
     Build(String),
     #[command(
-        description = "Start one or more build jobs from GitHub PR: /pr pr-numbers [archs] (e.g., /pr 12,34 amd64,arm64)"
+        description = "Start one or more build jobs from GitHub PR, draft/WIP PRs are skipped unless forced: /pr pr-numbers [archs] [--force] [repo=name] (e.g., /pr 12,34 amd64,arm64)"
     )]
     PR(String),
-    #[command(description = "Show queue and server status: /status")]
-    Status,
     #[command(
-        description = "Open Pull Request by git-ref: /openpr title;git-ref;packages;[labels];[architectures] (e.g., /openpr VSCode Survey 1.85.0;vscode-1.85.0;vscode,vscodium;;amd64,arm64"
+        description = "Build all open GitHub PRs with a label (admin only): /buildlabeled label (e.g., /buildlabeled ready-to-build)"
+    )]
+    BuildLabeled(String),
+    #[command(
+        description = "Show the latest build of each arch of a GitHub PR: /prstatus pr-number"
+    )]
+    PRStatus(String),
+    #[command(description = "Show queue and server status: /status [page=n]")]
+    Status(String),
+    #[command(
+        description = "Show queue and server status of one architecture: /archstatus arch (e.g., /archstatus riscv64)"
+    )]
+    ArchStatus(String),
+    #[command(
+        description = "Open Pull Request by git-ref: /openpr title;git-ref;packages;[labels];[architectures];[base-branch] (e.g., /openpr VSCode Survey 1.85.0;vscode-1.85.0;vscode,vscodium;;amd64,arm64"
     )]
     OpenPR(String),
+    #[command(
+        description = "Build packages and open Pull Request once all architectures succeed: /buildandpr title;git-ref;packages;architectures (e.g., /buildandpr fd: update to 9.0.0;fd-9.0.0;fd;amd64,arm64)"
+    )]
+    BuildAndPR(String),
     #[command(description = "Login to github")]
     Login,
     #[command(description = "Start bot")]
@@ -65,10 +110,82 @@ pub enum Command {
     QA(String),
     #[command(description = "Restart failed job: /restart job-id")]
     Restart(String),
+    #[command(
+        description = "Rebuild every arch of a pipeline as a new pipeline, not only failed jobs: /retrypipeline pipeline-id (e.g., /retrypipeline 1234)"
+    )]
+    RetryPipeline(String),
     #[command(description = "Find update and bump package version: /bump package-name")]
     Bump(String),
     #[command(description = "Roll anicca 10 packages")]
     Roll,
+    #[command(
+        description = "Show toolchain versions used by a build: /env pipeline-id arch (e.g., /env 1234 amd64)"
+    )]
+    Env(String),
+    #[command(
+        description = "Set which build results are sent to this chat: /notify all/failures/none"
+    )]
+    Notify(String),
+    #[command(
+        description = "Show recent server logs (admin only): /tail [level] [count] (e.g., /tail warn 20)"
+    )]
+    Tail(String),
+    #[command(description = "Show running jobs, longest first: /building [page=n]")]
+    Building(String),
+    #[command(
+        description = "Show build history of a package: /history package [arch=arch] [status=status] [page=n] (e.g., /history bash arch=riscv64 status=failed)"
+    )]
+    History(String),
+    #[command(description = "Export queue, worker and job state as JSON (admin only): /snapshot")]
+    Snapshot,
+    #[command(
+        description = "Move a queued job of a pipeline to another arch (admin only): /queuemove pipeline-id from-arch to-arch (e.g., /queuemove 1234 loongson3 loongarch64)"
+    )]
+    QueueMove(String),
+    #[command(
+        description = "Show the next queued jobs of an architecture without taking them (admin only): /queuepeek arch [n] (e.g., /queuepeek riscv64 5)"
+    )]
+    QueuePeek(String),
+    #[command(
+        description = "Check spec syntax, versions, checksums and dependencies of a GitHub PR before building: /validate pr-number"
+    )]
+    Validate(String),
+    #[command(
+        description = "Show whether packages built by a pipeline have reached the repo: /mirrorstatus pipeline-id"
+    )]
+    MirrorStatus(String),
+    #[command(
+        description = "List pull requests you opened with /openpr, or everyone's (admin only): /myprs [all]"
+    )]
+    MyPRs(String),
+    #[command(
+        description = "Show time spent on each package of a pipeline, slowest first: /timings pipeline-id"
+    )]
+    Timings(String),
+    #[command(
+        description = "Compare the result of each arch of two pipelines: /compare pipeline-id pipeline-id (e.g., /compare 1234 1240)"
+    )]
+    Compare(String),
+    #[command(
+        description = "Stop accepting new builds but finish queued ones, before a restart (admin only): /drain"
+    )]
+    Drain,
+    #[command(description = "Accept new builds again after /drain (admin only): /undrain")]
+    Undrain,
+    #[command(description = "Show the latest log lines of a running job: /logs job-id")]
+    Logs(String),
+    #[command(
+        description = "Show recent privileged actions (admin only): /audit [actor=actor] [action=action] (e.g., /audit actor=github:cyan action=cancel)"
+    )]
+    Audit(String),
+    #[command(
+        description = "Ask connected workers for a heartbeat now and show fresh queue status (admin only): /reping"
+    )]
+    Reping,
+    #[command(description = "List your queued and running pipelines: /mybuilds")]
+    MyBuilds,
+    #[command(description = "Cancel all of your queued and running pipelines: /cancelmine")]
+    CancelMine,
 }
 
 async fn wait_with_send_typing<T, F: Future<Output = T>, B: Borrow<Bot>>(
@@ -101,263 +218,1392 @@ async fn wait_with_send_typing<T, F: Future<Output = T>, B: Borrow<Bot>>(
     res
 }
 
-fn handle_archs_args(archs: Vec<&str>) -> Vec<&str> {
-    let mut archs = archs;
-    if archs.contains(&"mainline") {
-        // archs
-        archs.extend(ALL_ARCH.iter());
-        archs.retain(|arch| *arch != "mainline");
+/// Remove formatting from a message, leaving text and link urls
+fn strip_markup(text: &str, parse_mode: ParseMode) -> String {
+    static HTML_TAG: Lazy<regex::Regex> = Lazy::new(|| regex::Regex::new("<[^>]*>").unwrap());
+
+    match parse_mode {
+        ParseMode::Html => HTML_TAG
+            .replace_all(text, "")
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&amp;", "&"),
+        _ => {
+            let mut res = String::new();
+            let mut in_url = false;
+            let mut chars = text.chars().peekable();
+            while let Some(ch) = chars.next() {
+                match ch {
+                    '\\' => res.extend(chars.next()),
+                    ')' if in_url => {
+                        in_url = false;
+                        res.push(ch);
+                    }
+                    _ if in_url => res.push(ch),
+                    ']' if chars.peek() == Some(&'(') => {
+                        chars.next();
+                        in_url = true;
+                        res.push_str(" (");
+                    }
+                    '*' | '_' | '~' | '`' | '|' | '[' | ']' => {}
+                    _ => res.push(ch),
+                }
+            }
+            res
+        }
     }
-    archs.sort();
-    archs.dedup();
+}
+
+/// Send a formatted message, retry as plain text if telegram cannot parse it
+pub async fn send_message_with_fallback(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    parse_mode: ParseMode,
+) -> ResponseResult<Message> {
+    send_message_with_markup(bot, chat_id, text, parse_mode, None).await
+}
 
-    archs
+/// `send_message_with_fallback` with an optional inline keyboard
+pub async fn send_message_with_markup(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    parse_mode: ParseMode,
+    reply_markup: Option<InlineKeyboardMarkup>,
+) -> ResponseResult<Message> {
+    let mut req = bot
+        .send_message(chat_id, text)
+        .parse_mode(parse_mode)
+        .disable_web_page_preview(true);
+    if let Some(reply_markup) = &reply_markup {
+        req = req.reply_markup(reply_markup.clone());
+    }
+    match req.await {
+        Err(RequestError::Api(err))
+            if matches!(err, ApiError::CantParseEntities)
+                || matches!(&err, ApiError::Unknown(desc) if desc.contains("can't parse entities")) =>
+        {
+            warn!(
+                "Failed to send message as {:?}, sending as plain text instead: {}\n{}",
+                parse_mode, err, text
+            );
+            let mut req = bot
+                .send_message(chat_id, strip_markup(text, parse_mode))
+                .disable_web_page_preview(true);
+            if let Some(reply_markup) = reply_markup {
+                req = req.reply_markup(reply_markup);
+            }
+            req.await
+        }
+        res => res,
+    }
 }
 
-#[tracing::instrument(skip(pool))]
-async fn status(pool: DbPool) -> anyhow::Result<String> {
-    let mut res = String::from("__*Queue Status*__\n\n");
+/// Follow-up action of an inline keyboard button on a build result
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallbackAction {
+    /// restart the failed job of the arch in the pipeline
+    RetryArch { pipeline_id: i32, arch: String },
+    /// restart all failed jobs of the pipeline
+    RetryFailed { pipeline_id: i32 },
+}
 
-    for status in pipeline_status(pool.clone()).await? {
-        res += &format!(
-            "*{}*: {} job\\(s\\) pending, {} job\\(s\\) running, {} available server\\(s\\)\n",
-            teloxide::utils::markdown::escape(&status.arch),
-            status.pending,
-            status.running,
-            status.available_servers
-        );
+impl CallbackAction {
+    /// Encode as callback data, which telegram limits to 64 bytes
+    pub fn encode(&self) -> String {
+        match self {
+            CallbackAction::RetryArch { pipeline_id, arch } => {
+                format!("retry:{pipeline_id}:{arch}")
+            }
+            CallbackAction::RetryFailed { pipeline_id } => format!("retryfailed:{pipeline_id}"),
+        }
     }
 
-    res += "\n__*Server Status*__\n\n";
-    let fmt = timeago::Formatter::new();
-    for status in worker_status(pool).await? {
-        res += &teloxide::utils::markdown::escape(&format!(
-            "{} ({} {}, {} core(s), {} memory): Online as of {}\n",
-            status.hostname,
-            status.arch,
-            status.git_commit,
-            status.logical_cores,
-            size::Size::from_bytes(status.memory_bytes),
-            fmt.convert_chrono(status.last_heartbeat_time, Local::now())
-        ));
+    pub fn decode(data: &str) -> Option<Self> {
+        let parts = data.split(':').collect::<Vec<_>>();
+        match parts.as_slice() {
+            ["retry", pipeline_id, arch] if ALL_ARCH.contains(arch) || *arch == "noarch" => {
+                Some(CallbackAction::RetryArch {
+                    pipeline_id: pipeline_id.parse().ok()?,
+                    arch: arch.to_string(),
+                })
+            }
+            ["retryfailed", pipeline_id] => Some(CallbackAction::RetryFailed {
+                pipeline_id: pipeline_id.parse().ok()?,
+            }),
+            _ => None,
+        }
     }
-    Ok(res)
 }
 
-#[derive(Deserialize)]
-pub struct QAResponsePackage {
-    name: String,
+/// Buttons attached to a failed build result
+pub fn failed_job_keyboard(
+    pipeline_id: i32,
+    arch: &str,
+    log_url: Option<&str>,
+) -> InlineKeyboardMarkup {
+    let mut row = vec![];
+    if let Some(url) = log_url.and_then(|url| reqwest::Url::parse(url).ok()) {
+        row.push(InlineKeyboardButton::url("View Log", url));
+    }
+    row.push(InlineKeyboardButton::callback(
+        "Retry this arch",
+        CallbackAction::RetryArch {
+            pipeline_id,
+            arch: arch.to_string(),
+        }
+        .encode(),
+    ));
+    row.push(InlineKeyboardButton::callback(
+        "Retry all failed",
+        CallbackAction::RetryFailed { pipeline_id }.encode(),
+    ));
+    InlineKeyboardMarkup::new([row])
 }
 
-#[derive(Deserialize)]
-pub struct QAResponse {
-    packages: Vec<QAResponsePackage>,
+/// Restart the failed jobs selected by the action, return a reply for the user
+async fn run_callback_action(
+    pool: DbPool,
+    action: &CallbackAction,
+    actor: &str,
+) -> anyhow::Result<String> {
+    let (pipeline_id, arch) = match action {
+        CallbackAction::RetryArch { pipeline_id, arch } => (*pipeline_id, Some(arch.as_str())),
+        CallbackAction::RetryFailed { pipeline_id } => (*pipeline_id, None),
+    };
+
+    let jobs = failed_jobs(pool.clone(), pipeline_id, arch).await?;
+    if jobs.is_empty() {
+        bail!("No failed job to retry in pipeline #{pipeline_id}");
+    }
+
+    let mut restarted = vec![];
+    for job in jobs {
+        let new_job = job_restart(pool.clone(), job.id, actor).await?;
+        restarted.push(format!("#{} ({})", new_job.id, new_job.arch));
+    }
+    Ok(format!("Restarted as job {}", restarted.join(", ")))
 }
 
-#[tracing::instrument(skip(bot, pool, msg))]
-async fn pipeline_new_and_report(
-    bot: &Bot,
-    pool: DbPool,
-    git_branch: &str,
-    packages: &str,
-    archs: &str,
-    msg: &Message,
-) -> ResponseResult<()> {
-    match wait_with_send_typing(
-        pipeline_new(
-            pool,
-            git_branch,
-            None,
-            None,
-            packages,
-            archs,
-            JobSource::Telegram(msg.chat.id.0),
-            false,
-        ),
-        bot,
-        msg.chat.id.0,
-    )
-    .await
-    {
-        Ok(pipeline) => {
-            bot.send_message(
-                msg.chat.id,
-                to_html_new_pipeline_summary(
-                    pipeline.id,
-                    &pipeline.git_branch,
-                    &pipeline.git_sha,
-                    pipeline.github_pr.map(|n| n as u64),
-                    &pipeline.archs.split(',').collect::<Vec<_>>(),
-                    &pipeline.packages.split(',').collect::<Vec<_>>(),
-                ),
-            )
-            .parse_mode(ParseMode::Html)
-            .disable_web_page_preview(true)
+/// Handle presses of inline keyboard buttons
+pub async fn answer_callback(bot: Bot, query: CallbackQuery, pool: DbPool) -> ResponseResult<()> {
+    // same checks as text commands
+    let allowed = query.message.as_ref().map(|msg| {
+        is_chat_allowed(
+            msg.chat.id,
+            msg.chat.is_private(),
+            ARGS.telegram_chats.as_deref(),
+        )
+    });
+    if allowed != Some(true) {
+        bot.answer_callback_query(query.id)
+            .text("This instance is restricted")
             .await?;
-        }
-        Err(err) => {
-            bot.send_message(msg.chat.id, truncate(&format!("{err:?}")))
-                .await?;
-        }
+        return Ok(());
     }
 
+    let Some(action) = query.data.as_deref().and_then(CallbackAction::decode) else {
+        bot.answer_callback_query(query.id)
+            .text("Unknown action")
+            .await?;
+        return Ok(());
+    };
+
+    let text =
+        match run_callback_action(pool, &action, &telegram_actor(&user_name(&query.from))).await {
+            Ok(text) => text,
+            Err(err) => format!("Failed to retry: {err}"),
+        };
+    bot.answer_callback_query(query.id).await?;
+    if let Some(msg) = query.message {
+        bot.send_message(msg.chat.id, truncate(&text))
+            .reply_to_message_id(msg.id)
+            .await?;
+    }
     Ok(())
 }
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
-pub struct GitHubUser {
-    pub login: String,
-    pub id: i64,
-    pub email: Option<String>,
-    pub avatar_url: String,
-    pub name: String,
+/// Whether the bot serves the chat according to the allowlist
+fn is_chat_allowed(chat_id: ChatId, is_private: bool, allowlist: Option<&str>) -> bool {
+    let Some(allowlist) = allowlist.filter(|allowlist| !allowlist.trim().is_empty()) else {
+        return true;
+    };
+    allowlist
+        .split(',')
+        .map(str::trim)
+        .any(|chat| (chat == "private" && is_private) || chat == chat_id.0.to_string())
 }
 
-#[tracing::instrument(skip(pool, access_token))]
-async fn sync_github_info_inner(
-    pool: DbPool,
-    telegram_chat: ChatId,
-    access_token: String,
-) -> anyhow::Result<()> {
-    let crab = octocrab::Octocrab::builder()
-        .user_access_token(access_token)
-        .build()?;
-    let author: GitHubUser = crab.get("/user", None::<&()>).await?;
-    let mut conn = pool
-        .get()
-        .context("Failed to get db connection from pool")?;
-
-    conn.transaction::<(), diesel::result::Error, _>(|conn| {
-        use crate::schema::users::dsl::*;
-        match users
-            .filter(telegram_chat_id.eq(&telegram_chat.0))
-            .first::<User>(conn)
-            .optional()?
-        {
-            Some(user) => {
-                diesel::update(users.find(user.id))
-                    .set((
-                        github_login.eq(author.login),
-                        github_id.eq(author.id),
-                        github_avatar_url.eq(author.avatar_url.to_string()),
-                        github_email.eq(author.email),
-                        github_name.eq(author.name),
-                    ))
-                    .execute(conn)?;
-            }
-            None => {
-                let new_user = NewUser {
-                    github_login: Some(author.login),
-                    github_id: Some(author.id),
-                    github_name: Some(author.name),
-                    github_avatar_url: Some(author.avatar_url.to_string()),
-                    github_email: author.email,
-                    telegram_chat_id: Some(telegram_chat.0),
-                };
-                diesel::insert_into(crate::schema::users::table)
-                    .values(&new_user)
-                    .execute(conn)?;
-            }
-        }
+// chats already told that this instance is restricted
+static RESTRICTED_CHATS: Lazy<std::sync::Mutex<HashSet<i64>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashSet::new()));
+
+fn is_admin(chat_id: ChatId) -> bool {
+    ARGS.telegram_admins
+        .as_deref()
+        .map(|admins| {
+            admins
+                .split(',')
+                .any(|admin| admin.trim() == chat_id.0.to_string())
+        })
+        .unwrap_or(false)
+}
 
-        Ok(())
-    })?;
-    Ok(())
+/// Who may use a command, in increasing order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    Anyone,
+    /// GitHub account in aosc-dev linked with /login
+    Member,
+    Admin,
 }
 
-#[tracing::instrument(skip(pool, access_token))]
-async fn sync_github_info(pool: DbPool, telegram_chat_id: ChatId, access_token: String) {
-    if let Err(err) = sync_github_info_inner(pool, telegram_chat_id, access_token).await {
-        warn!(
-            "Failed to sync github info for telegram chat {}: {}",
-            telegram_chat_id, err
-        );
+/// Permission required by a command, by its name without the slash
+fn command_permission(command: &str) -> Permission {
+    match command {
+        "openpr" | "buildandpr" | "bump" => Permission::Member,
+        "buildlabeled" | "tail" | "snapshot" | "queuemove" | "queuepeek" | "drain" | "undrain"
+        | "audit" | "reping" => Permission::Admin,
+        _ => Permission::Anyone,
     }
 }
 
-#[tracing::instrument(skip(pool, access_token))]
-async fn get_user(pool: DbPool, chat_id: ChatId, access_token: String) -> anyhow::Result<User> {
+async fn caller_permission(pool: DbPool, chat_id: ChatId) -> anyhow::Result<Permission> {
+    if is_admin(chat_id) {
+        return Ok(Permission::Admin);
+    }
+
     let mut conn = pool
         .get()
         .context("Failed to get db connection from pool")?;
 
     use crate::schema::users::dsl::*;
-    if let Some(user) = users
+    let login = users
         .filter(telegram_chat_id.eq(&chat_id.0))
-        .first::<User>(&mut conn)
+        .select(github_login)
+        .first::<Option<String>>(&mut conn)
         .optional()?
-    {
-        return Ok(user);
-    }
+        .flatten();
 
-    // not found, try to fetch user info
-    sync_github_info_inner(pool, chat_id, access_token).await?;
+    Ok(match login {
+        Some(login) if is_org_user(&login).await? => Permission::Member,
+        _ => Permission::Anyone,
+    })
+}
 
-    // try again
-    if let Some(user) = users
-        .filter(telegram_chat_id.eq(&chat_id.0))
-        .first::<User>(&mut conn)
-        .optional()?
-    {
-        return Ok(user);
+/// Whether the chat has the permission, telling it `denied` otherwise
+async fn require_permission(
+    bot: &Bot,
+    msg: &Message,
+    pool: DbPool,
+    required: Permission,
+    denied: &str,
+) -> ResponseResult<bool> {
+    let permission = match required {
+        Permission::Anyone => return Ok(true),
+        // admins are configured, no need to look up the account
+        Permission::Admin if !is_admin(msg.chat.id) => Permission::Anyone,
+        _ => match wait_with_send_typing(caller_permission(pool, msg.chat.id), bot, msg.chat.id.0)
+            .await
+        {
+            Ok(permission) => permission,
+            Err(err) => {
+                warn!("Failed to get permission of {}: {err:?}", msg.chat.id);
+                Permission::Anyone
+            }
+        },
+    };
+    if permission < required {
+        bot.send_message(msg.chat.id, denied).await?;
+        return Ok(false);
     }
+    Ok(true)
+}
 
-    bail!("Failed to get user info")
+/// Command list with only the commands usable with the permission
+fn format_help(permission: Permission) -> String {
+    let mut res = Command::descriptions()
+        .to_string()
+        .lines()
+        .filter(|line| {
+            let Some(command) = line.strip_prefix('/') else {
+                return true;
+            };
+            let name = command.split(' ').next().unwrap_or_default();
+            command_permission(name) <= permission
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    if permission == Permission::Anyone {
+        res.push_str(
+            "\n\nSome commands are hidden, /login with a GitHub account in aosc-dev to use them.",
+        );
+    }
+    res
 }
 
-async fn create_pipeline_from_pr(
-    pool: DbPool,
-    pr_number: u64,
-    archs: Option<&str>,
-    msg: &Message,
-    bot: &Bot,
-) -> ResponseResult<()> {
-    match wait_with_send_typing(
-        pipeline_new_pr(pool, pr_number, archs, JobSource::Telegram(msg.chat.id.0)),
-        bot,
-        msg.chat.id.0,
-    )
-    .await
-    {
-        Ok(pipeline) => {
-            bot.send_message(
-                msg.chat.id,
-                to_html_new_pipeline_summary(
-                    pipeline.id,
-                    &pipeline.git_branch,
-                    &pipeline.git_sha,
-                    pipeline.github_pr.map(|n| n as u64),
-                    &pipeline.archs.split(',').collect::<Vec<_>>(),
-                    &pipeline.packages.split(',').collect::<Vec<_>>(),
-                ),
-            )
-            .parse_mode(ParseMode::Html)
-            .disable_web_page_preview(true)
-            .send()
-            .instrument(tracing::info_span!("send_message"))
-            .await?;
-        }
-        Err(err) => {
-            bot.send_message(
-                msg.chat.id,
-                truncate(&format!("Failed to create pipeline from pr: {err:?}")),
-            )
-            .await?;
+/// Latest lines of a running job's log that fit in a telegram message
+fn format_live_log(job: &RunningJob, log: &str) -> String {
+    let mut res = format!(
+        "Latest log of job #{} ({}) on {}:\n",
+        job.job.id,
+        job.job.arch,
+        job.worker_hostname.as_deref().unwrap_or("unknown worker")
+    );
+    if log.trim().is_empty() {
+        res.push_str("No output yet");
+        return res;
+    }
+
+    let mut lines = vec![];
+    let mut len = res.len();
+    for line in log.lines().rev() {
+        len += line.len() + 1;
+        if len > 4000 {
+            break;
         }
+        lines.push(line);
     }
+    lines.reverse();
+    res.push_str(&lines.join("\n"));
+    res
+}
 
-    Ok(())
+/// Ask the worker running the job for its latest log lines
+async fn live_log(pool: DbPool, ws_state_map: &WSStateMap, job_id: i32) -> anyhow::Result<String> {
+    let job = running_jobs(pool)
+        .await?
+        .into_iter()
+        .find(|job| job.job.id == job_id)
+        .ok_or_else(|| anyhow::anyhow!("Job #{job_id} is not running"))?;
+    let Some(hostname) = &job.worker_hostname else {
+        bail!("Job #{job_id} is not assigned to a worker");
+    };
+    let log = request_live_log(ws_state_map, hostname, job_id, LIVE_LOG_TIMEOUT).await?;
+    Ok(format_live_log(&job, &log))
 }
 
-#[tracing::instrument(skip(bot, msg, pool))]
-pub async fn answer(bot: Bot, msg: Message, cmd: Command, pool: DbPool) -> ResponseResult<()> {
-    match cmd {
+fn tail_logs(arguments: &str) -> anyhow::Result<String> {
+    let mut min_level = Level::INFO;
+    let mut count = 20;
+    for part in arguments.split_ascii_whitespace() {
+        if let Ok(n) = part.parse::<usize>() {
+            count = n;
+        } else {
+            min_level = part
+                .parse::<Level>()
+                .map_err(|_| anyhow::anyhow!("Unknown log level: {part}"))?;
+        }
+    }
+
+    let records = LOG_BUFFER.tail(min_level, count);
+    if records.is_empty() {
+        return Ok("No log records".to_string());
+    }
+
+    // keep the latest records that fit in a telegram message
+    let mut lines = vec![];
+    let mut len = 0;
+    for record in records.iter().rev() {
+        let line = format!(
+            "{} {} {}: {}",
+            record.time.format("%H:%M:%S"),
+            record.level,
+            record.target,
+            record.message
+        );
+        len += line.len() + 1;
+        if len > 4000 {
+            break;
+        }
+        lines.push(line);
+    }
+    lines.reverse();
+
+    Ok(lines.join("\n"))
+}
+
+/// Time for workers to answer /reping with a heartbeat
+const REPING_WAIT: Duration = Duration::from_secs(3);
+
+/// /status after connected workers sent a heartbeat, skipping the cached queue
+async fn reping(pool: DbPool, ws_state_map: &WSStateMap) -> anyhow::Result<String> {
+    let pinged = ping_workers(ws_state_map);
+    if pinged > 0 {
+        sleep(REPING_WAIT).await;
+    }
+    pipeline_status_invalidate().await;
+    Ok(format!(
+        "Pinged {pinged} connected worker\\(s\\)\n\n{}",
+        status(pool, 1).await?
+    ))
+}
+
+#[tracing::instrument(skip(pool))]
+async fn status(pool: DbPool, page: usize) -> anyhow::Result<String> {
+    // the worker list is still useful without queue counts
+    let queue = match pipeline_status_cached(pool.clone()).await {
+        Ok(queue) => Some(queue),
+        Err(err) => {
+            warn!("Failed to get queue status: {:?}", err);
+            None
+        }
+    };
+    let progress = match arch_progress(pool.clone()).await {
+        Ok(progress) => progress,
+        Err(err) => {
+            warn!("Failed to get arch progress: {:?}", err);
+            BTreeMap::new()
+        }
+    };
+    let stalled = queue
+        .iter()
+        .flatten()
+        .filter(|status| {
+            is_arch_stalled(
+                status,
+                &progress.get(&status.arch).cloned().unwrap_or_default(),
+                chrono::Utc::now(),
+                chrono::Duration::seconds(ARGS.stall_warning_secs),
+            )
+        })
+        .map(|status| status.arch.as_str())
+        .collect::<Vec<_>>();
+    let workers = worker_status(pool).await?;
+    Ok(format_status(
+        queue.as_deref(),
+        &stalled,
+        &workers,
+        ARGS.min_worker_version.as_deref(),
+        page,
+    ))
+}
+
+/// Format /status, `queue` is `None` if the job counts are unavailable,
+/// workers are listed `page` by page
+fn format_status(
+    queue: Option<&[PipelineStatus]>,
+    stalled: &[&str],
+    workers: &[Worker],
+    min_worker_version: Option<&str>,
+    page: usize,
+) -> String {
+    let mut res = String::from("__*Queue Status*__\n\n");
+
+    match queue {
+        Some(queue) => {
+            for status in queue {
+                res += &format!(
+                    "*{}*: {} job\\(s\\) pending, {} job\\(s\\) running, {} available server\\(s\\){}\n",
+                    teloxide::utils::markdown::escape(&status.arch),
+                    status.pending,
+                    status.running,
+                    status.available_servers,
+                    if stalled.contains(&status.arch.as_str()) {
+                        ", ⚠️ consumers present but no progress"
+                    } else {
+                        ""
+                    }
+                );
+            }
+        }
+        None => {
+            for arch in ALL_ARCH {
+                res += &format!(
+                    "*{}*: {} available server\\(s\\)\n",
+                    teloxide::utils::markdown::escape(arch),
+                    workers.iter().filter(|worker| worker.arch == *arch).count()
+                );
+            }
+            res += "Job counts are unavailable\n";
+        }
+    }
+
+    res += "\n__*Server Status*__\n\n";
+    let pages = page_count(workers.len(), STATUS_PAGE_SIZE);
+    if page > pages {
+        res += &teloxide::utils::markdown::escape(&page_out_of_range(page, pages));
+        return res;
+    }
+    let fmt = timeago::Formatter::new();
+    let (rows, _) = paginate(workers, page, STATUS_PAGE_SIZE, |status| {
+        let outdated = is_worker_outdated(status.version.as_deref(), min_worker_version);
+        format!(
+            "{} ({} {}, {} core(s), {} memory): Online as of {}{}",
+            status.hostname,
+            status.arch,
+            status.git_commit,
+            status.logical_cores,
+            size::Size::from_bytes(status.memory_bytes),
+            fmt.convert_chrono(status.last_heartbeat_time, Local::now()),
+            if outdated { ", outdated" } else { "" }
+        )
+    });
+    if !rows.is_empty() {
+        res += &teloxide::utils::markdown::escape(&rows);
+        res += "\n";
+    }
+    if pages > 1 {
+        res += &teloxide::utils::markdown::escape(&format!(
+            "\n{}\n",
+            page_footer(page, pages, "/status")
+        ));
+    }
+    res
+}
+
+fn format_environment(environment: &BTreeMap<String, String>) -> String {
+    let width = environment.keys().map(|key| key.len()).max().unwrap_or(0);
+    let lines = environment
+        .iter()
+        .map(|(key, value)| format!("{key:width$}  {value}"))
+        .collect::<Vec<_>>();
+    format!(
+        "<pre>{}</pre>",
+        teloxide::utils::html::escape(&lines.join("\n"))
+    )
+}
+
+fn format_opened_prs(prs: &[OpenedPr], states: &[Option<&str>]) -> String {
+    use teloxide::utils::html::escape;
+
+    if prs.is_empty() {
+        return "No pull requests opened with /openpr yet".to_string();
+    }
+
+    let lines = prs
+        .iter()
+        .zip(states)
+        .map(|(pr, state)| {
+            format!(
+                "<a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/pull/{}\">#{}</a> {} ({}, {}), {}",
+                pr.pr_number,
+                pr.pr_number,
+                escape(&pr.title),
+                escape(&pr.git_ref),
+                pr.creation_time.format("%Y-%m-%d"),
+                state.unwrap_or("unknown")
+            )
+        })
+        .collect::<Vec<_>>();
+    lines.join("\n")
+}
+
+/// Pipelines of the chat that are still building, with the state of each arch
+fn format_my_builds(pipelines: &[(Pipeline, Vec<Job>)]) -> String {
+    if pipelines.is_empty() {
+        return "You have no queued or running pipelines".to_string();
+    }
+
+    let mut res = pipelines
+        .iter()
+        .map(|(pipeline, jobs)| {
+            let jobs = jobs
+                .iter()
+                .map(|job| {
+                    let state = match job.status.as_str() {
+                        "created" => "queued",
+                        status => status,
+                    };
+                    format!("{} ({state})", job.arch)
+                })
+                .collect::<Vec<_>>();
+            format!(
+                "Pipeline #{} {} ({}): {}",
+                pipeline.id,
+                pipeline.packages,
+                pipeline.git_branch,
+                jobs.join(", ")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    res += "\n\nCancel all of them with /cancelmine";
+    res
+}
+
+/// What /cancelmine stopped, one pipeline per line
+fn format_cancel_mine(cancelled: &[(Pipeline, Vec<Job>)]) -> String {
+    if cancelled.is_empty() {
+        return "You have no queued or running pipelines to cancel".to_string();
+    }
+    cancelled
+        .iter()
+        .map(|(pipeline, jobs)| format_cancelled(pipeline.id, jobs))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn format_arch_status(status: &ArchStatus) -> String {
+    let mut res = format!(
+        "__*{} Status*__\n\n",
+        teloxide::utils::markdown::escape(&status.arch)
+    );
+
+    res += &teloxide::utils::markdown::escape(&format!(
+        "{} job(s) pending, {} job(s) running\n{} live worker(s) of {}, {} idle\n",
+        status.pending,
+        status.running_jobs.len(),
+        status.live_workers,
+        status.workers.len(),
+        status.idle_workers,
+    ));
+    if status.recent_total > 0 {
+        res += &teloxide::utils::markdown::escape(&format!(
+            "Success rate in 7 days: {:.1}% ({}/{})\n",
+            status.recent_success as f64 * 100.0 / status.recent_total as f64,
+            status.recent_success,
+            status.recent_total,
+        ));
+    } else {
+        res += "No jobs finished in 7 days\n";
+    }
+
+    res += "\n__*Running Jobs*__\n\n";
+    for job in &status.running_jobs {
+        let hostname = status
+            .workers
+            .iter()
+            .find(|worker| Some(worker.id) == job.assigned_worker_id)
+            .map(|worker| worker.hostname.as_str())
+            .unwrap_or("unknown");
+        res += &teloxide::utils::markdown::escape(&format!(
+            "#{}: {} on {}\n",
+            job.id,
+            job.packages.replace(',', ", "),
+            hostname
+        ));
+    }
+
+    res += "\n__*Server Status*__\n\n";
+    let fmt = timeago::Formatter::new();
+    for worker in &status.workers {
+        res += &teloxide::utils::markdown::escape(&format!(
+            "{} ({}, {} core(s), {} memory): Online as of {}\n",
+            worker.hostname,
+            worker.git_commit,
+            worker.logical_cores,
+            size::Size::from_bytes(worker.memory_bytes),
+            fmt.convert_chrono(worker.last_heartbeat_time, Local::now())
+        ));
+    }
+    res
+}
+
+/// Jobs listed per page of /building
+const BUILDING_PAGE_SIZE: usize = 20;
+
+/// Workers listed per page of /status
+const STATUS_PAGE_SIZE: usize = 20;
+
+/// Parse the arguments of list commands taking only `[page=n]`
+fn parse_page_argument(arguments: &str) -> Result<usize, String> {
+    match arguments
+        .split_ascii_whitespace()
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [] => Ok(1),
+        [part] => match part.split_once('=') {
+            Some(("page", value)) => parse_page(value),
+            _ => Err(format!("Unexpected argument: {part}, expected page=")),
+        },
+        _ => Err(format!("Unexpected arguments: {arguments}, expected page=")),
+    }
+}
+
+pub(crate) fn format_duration(secs: i64) -> String {
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
+
+/// Package timings of each arch, slowest package first
+fn format_timings(pipeline_id: i32, timings: &[(String, Option<PackageTimings>)]) -> String {
+    if timings.is_empty() {
+        return format!("No finished jobs in pipeline #{pipeline_id}");
+    }
+
+    let mut res = format!("Package timings of pipeline #{pipeline_id}:");
+    for (arch, timings) in timings {
+        let Some(timings) = timings else {
+            res.push_str(&format!("\n\n{arch}: no timing data"));
+            continue;
+        };
+        res.push_str(&format!("\n\n{arch}:"));
+        let mut timings = timings.iter().collect::<Vec<_>>();
+        timings.sort_by_key(|(_, secs)| std::cmp::Reverse(*secs));
+        for (package, secs) in timings {
+            res.push_str(&format!("\n{package}: {}", format_duration(*secs)));
+        }
+    }
+    res
+}
+
+/// Jobs shown by /queuepeek without n
+const QUEUE_PEEK_DEFAULT: i64 = 5;
+
+fn format_queue_peek(arch: &str, jobs: &[(Job, Pipeline)]) -> String {
+    if jobs.is_empty() {
+        return format!("No queued jobs on {arch}");
+    }
+
+    let mut lines = vec![format!("Next {} queued job(s) on {arch}:", jobs.len())];
+    for (job, pipeline) in jobs {
+        let mut line = format!(
+            "#{} ({}): {} on {}",
+            job.id,
+            job.arch,
+            job.packages.replace(',', ", "),
+            pipeline.git_branch
+        );
+        if job.priority != 0 {
+            line.push_str(&format!(", priority {}", job.priority));
+        }
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+fn format_building(jobs: &[RunningJob], now: chrono::DateTime<chrono::Utc>, page: usize) -> String {
+    if jobs.is_empty() {
+        return "No active builds".to_string();
+    }
+    let pages = page_count(jobs.len(), BUILDING_PAGE_SIZE);
+    if page > pages {
+        return teloxide::utils::markdown::escape(&page_out_of_range(page, pages));
+    }
+
+    let mut jobs = jobs
+        .iter()
+        .map(|running| {
+            let elapsed = running
+                .job
+                .assign_time
+                .map(|time| (now - time).num_seconds().max(0))
+                .unwrap_or(0);
+            (running, elapsed)
+        })
+        .collect::<Vec<_>>();
+    jobs.sort_by_key(|(running, elapsed)| (std::cmp::Reverse(*elapsed), running.job.id));
+
+    let (rows, _) = paginate(&jobs, page, BUILDING_PAGE_SIZE, |(running, elapsed)| {
+        format!(
+            "#{} ({}): {} on {}, {}",
+            running.job.id,
+            running.job.arch,
+            running.job.packages.replace(',', ", "),
+            running.worker_hostname.as_deref().unwrap_or("unknown"),
+            format_duration(*elapsed),
+        )
+    });
+    let mut res = format!(
+        "__*{} Running Job\\(s\\)*__\n\n{}\n",
+        jobs.len(),
+        teloxide::utils::markdown::escape(&rows)
+    );
+    if pages > 1 {
+        res += &teloxide::utils::markdown::escape(&format!(
+            "\n{}\n",
+            page_footer(page, pages, "/building")
+        ));
+    }
+    res
+}
+
+fn parse_history_request(arguments: &str) -> Result<HistoryQuery<'_>, String> {
+    let mut package = None;
+    let mut arch = None;
+    let mut status = None;
+    let mut page = 1;
+
+    for part in arguments.split_ascii_whitespace() {
+        match part.split_once('=') {
+            Some(("arch", value)) => {
+                if value != "noarch" && !ALL_ARCH.contains(&value) {
+                    return Err(format!(
+                        "Unknown arch: {value}, valid archs are: noarch, {}",
+                        ALL_ARCH.join(", ")
+                    ));
+                }
+                arch = Some(value);
+            }
+            Some(("status", value)) => {
+                if !JOB_STATUS.contains(&value) {
+                    return Err(format!(
+                        "Unknown status: {value}, valid statuses are: {}",
+                        JOB_STATUS.join(", ")
+                    ));
+                }
+                status = Some(value);
+            }
+            Some(("page", value)) => page = parse_page(value)? as i64,
+            Some((key, _)) => {
+                return Err(format!(
+                    "Unknown filter: {key}, expected arch=, status= or page="
+                ))
+            }
+            None if package.is_none() => package = Some(part),
+            None => return Err(format!("Unexpected argument: {part}")),
+        }
+    }
+
+    Ok(HistoryQuery {
+        package: package.ok_or("Missing package")?,
+        arch,
+        status,
+        page,
+    })
+}
+
+fn format_history(query: &HistoryQuery, jobs: &[HistoryEntry], total: i64) -> String {
+    if total == 0 {
+        return teloxide::utils::markdown::escape(&format!("No jobs found for {}", query.package));
+    }
+
+    // paged by the database already
+    let page = query.page as usize;
+    let pages = page_count(total as usize, HISTORY_PAGE_SIZE as usize);
+    if jobs.is_empty() {
+        return teloxide::utils::markdown::escape(&page_out_of_range(page, pages));
+    }
+
+    let mut res = format!(
+        "__*History of {}, {} job\\(s\\)*__\n\n",
+        teloxide::utils::markdown::escape(query.package),
+        total
+    );
+    for (job, requested_by) in jobs {
+        res += &teloxide::utils::markdown::escape(&format!(
+            "#{} ({}): {}, created at {}{}{}\n",
+            job.id,
+            job.arch,
+            job.status,
+            job.creation_time.format("%Y-%m-%d %H:%M:%S"),
+            job.elapsed_secs
+                .map(|secs| format!(", took {}", format_duration(secs)))
+                .unwrap_or_default(),
+            requested_by
+                .as_ref()
+                .map(|requester| format!(", by {requester}"))
+                .unwrap_or_default(),
+        ));
+    }
+
+    if pages > 1 {
+        let mut command = format!("/history {}", query.package);
+        if let Some(arch) = query.arch {
+            command += &format!(" arch={arch}");
+        }
+        if let Some(status) = query.status {
+            command += &format!(" status={status}");
+        }
+        res += &teloxide::utils::markdown::escape(&format!(
+            "\n{}",
+            page_footer(page, pages, &command)
+        ));
+    }
+    res
+}
+
+#[derive(Deserialize)]
+pub struct QAResponsePackage {
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct QAResponse {
+    packages: Vec<QAResponsePackage>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct BuildRequest<'a> {
+    git_branch: &'a str,
+    packages: &'a str,
+    /// `None` to use the default archs
+    archs: Option<&'a str>,
+    github_pr: Option<u64>,
+    priority: i32,
+    /// `None` for the primary repo
+    repo: Option<&'a str>,
+    /// Build the canary arch before the others
+    canary: bool,
+}
+
+/// Largest priority that can be requested, either way
+const MAX_PRIORITY: i32 = 10;
+
+/// Parse `/build branch packages [archs]`, with `archs=`, `pr=`, `priority=`, `repo=` and
+/// `--canary` flags anywhere
+///
+/// Priorities are clamped to `-MAX_PRIORITY..=MAX_PRIORITY`.
+fn parse_build_request(arguments: &str) -> Result<BuildRequest<'_>, String> {
+    let mut positional = vec![];
+    let mut archs = None;
+    let mut github_pr = None;
+    let mut priority = 0;
+    let mut repo = None;
+    let mut canary = false;
+
+    for part in arguments.split_ascii_whitespace() {
+        if part == "--canary" {
+            canary = true;
+            continue;
+        }
+        match part.split_once('=') {
+            Some(("archs", value)) => archs = Some(value),
+            Some(("pr", value)) => {
+                github_pr = Some(
+                    value
+                        .trim_start_matches('#')
+                        .parse::<u64>()
+                        .map_err(|_| format!("Invalid PR number: {value}"))?,
+                )
+            }
+            Some(("priority", value)) => {
+                priority = value
+                    .parse::<i32>()
+                    .map_err(|_| format!("Invalid priority: {value}"))?
+                    .clamp(-MAX_PRIORITY, MAX_PRIORITY)
+            }
+            Some(("repo", value)) => repo = Some(value),
+            Some((key, _)) => {
+                return Err(format!(
+                    "Unknown flag: {key}, expected archs=, pr=, priority= or repo="
+                ))
+            }
+            None => positional.push(part),
+        }
+    }
+
+    match positional.as_slice() {
+        [git_branch, packages] => Ok(BuildRequest {
+            git_branch,
+            packages,
+            archs,
+            github_pr,
+            priority,
+            repo,
+            canary,
+        }),
+        [git_branch, packages, positional_archs] => {
+            if archs.is_some() {
+                return Err("Architectures are given both as argument and archs=".to_string());
+            }
+            Ok(BuildRequest {
+                git_branch,
+                packages,
+                archs: Some(positional_archs),
+                github_pr,
+                priority,
+                repo,
+                canary,
+            })
+        }
+        [] => Err("Missing branch and packages".to_string()),
+        [_] => Err("Missing packages".to_string()),
+        _ => Err(format!(
+            "Too many arguments: expected branch, packages and archs, got {}",
+            positional.join(" ")
+        )),
+    }
+}
+
+/// Permission required by the flags of a build request: jumping the queue
+/// and reporting to a pull request are up to members
+fn build_request_permission(req: &BuildRequest) -> Permission {
+    if req.priority != 0 || req.github_pr.is_some() {
+        Permission::Member
+    } else {
+        Permission::Anyone
+    }
+}
+
+/// Pass/fail list of the lint results of a pull request
+fn format_validation(pr: u64, reports: &[LintReport]) -> String {
+    if reports.is_empty() {
+        return format!("PR #{pr} changes no spec or defines files");
+    }
+
+    let failed = reports.iter().filter(|r| !r.problems.is_empty()).count();
+    if failed == 0 {
+        return format!(
+            "PR #{pr} passed all checks ({} file(s))\n\n{}",
+            reports.len(),
+            reports
+                .iter()
+                .map(|r| format!("✅ {}", r.path))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+    }
+
+    let mut res = format!(
+        "PR #{pr} failed checks in {failed} of {} file(s)\n",
+        reports.len()
+    );
+    for report in reports {
+        if report.problems.is_empty() {
+            res += &format!("\n✅ {}", report.path);
+        } else {
+            res += &format!("\n❌ {}", report.path);
+            for problem in &report.problems {
+                res += &format!("\n  - {problem}");
+            }
+        }
+    }
+    res
+}
+
+/// Who sent the message, recorded as the requester of builds
+fn requester_of(msg: &Message) -> Option<String> {
+    msg.from().map(user_name)
+}
+
+fn user_name(user: &teloxide::types::User) -> String {
+    match &user.username {
+        Some(username) => format!("@{username}"),
+        None => user.id.to_string(),
+    }
+}
+
+/// Who sent the message, as recorded in the audit log
+fn audit_actor(msg: &Message) -> String {
+    telegram_actor(&requester_of(msg).unwrap_or_else(|| msg.chat.id.to_string()))
+}
+
+/// Record an action done outside of a transaction, failures are only logged
+fn audit(pool: &DbPool, msg: &Message, action: &str, details: &str) {
+    let res = pool
+        .get()
+        .context("Failed to get db connection from pool")
+        .and_then(|mut conn| record_audit(&mut conn, &audit_actor(msg), action, details));
+    if let Err(err) = res {
+        warn!("Failed to record {action} by {}: {err:?}", msg.chat.id);
+    }
+}
+
+/// Keep the summary message of a new pipeline, to edit it as jobs progress
+async fn remember_summary(pool: DbPool, pipeline: &mut Pipeline, sent: &Message) {
+    if ARGS.live_summary != Some(true) {
+        return;
+    }
+    if let Err(err) = summary_message_set(pool, pipeline, sent.id).await {
+        warn!(
+            "Failed to save summary message of pipeline #{}: {err:?}",
+            pipeline.id
+        );
+    }
+}
+
+#[tracing::instrument(skip(bot, pool, msg))]
+async fn pipeline_new_and_report(
+    bot: &Bot,
+    pool: DbPool,
+    req: &BuildRequest<'_>,
+    msg: &Message,
+) -> ResponseResult<Option<Pipeline>> {
+    let repo = match repo_of(req.repo) {
+        Ok(repo) => repo,
+        Err(err) => {
+            bot.send_message(msg.chat.id, truncate(&format!("{err:?}")))
+                .await?;
+            return Ok(None);
+        }
+    };
+
+    match wait_with_send_typing(
+        pipeline_new(
+            pool.clone(),
+            &repo,
+            req.git_branch,
+            None,
+            req.github_pr,
+            req.packages,
+            req.archs.unwrap_or(&ARGS.default_archs),
+            JobSource::Telegram(msg.chat.id.0),
+            requester_of(msg).as_deref(),
+            false,
+            req.priority,
+            req.canary,
+            None,
+            None,
+        ),
+        bot,
+        msg.chat.id.0,
+    )
+    .await
+    {
+        Ok((mut pipeline, notes)) => {
+            let unchanged_since = unchanged_since(pool.clone(), &pipeline)
+                .await
+                .unwrap_or_else(|err| {
+                    warn!("Failed to compare build plans: {err}");
+                    None
+                });
+            let offline = pipeline_offline_archs(pool.clone(), &pipeline)
+                .await
+                .unwrap_or_else(|err| {
+                    warn!("Failed to find online workers: {err}");
+                    vec![]
+                });
+            let sent = send_message_with_fallback(
+                bot,
+                msg.chat.id,
+                &(new_pipeline_summary(
+                    &pipeline,
+                    unchanged_since,
+                    ARGS.new_pipeline_template.as_ref(),
+                ) + &to_html_pipeline_notes(&notes)
+                    + &to_html_offline_archs(&offline)),
+                ParseMode::Html,
+            )
+            .await?;
+            remember_summary(pool, &mut pipeline, &sent).await;
+            Ok(Some(pipeline))
+        }
+        Err(err) => {
+            bot.send_message(msg.chat.id, truncate(&format!("{err:?}")))
+                .await?;
+            Ok(None)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+pub struct GitHubUser {
+    pub login: String,
+    pub id: i64,
+    pub email: Option<String>,
+    pub avatar_url: String,
+    pub name: String,
+}
+
+#[tracing::instrument(skip(pool, access_token))]
+async fn sync_github_info_inner(
+    pool: DbPool,
+    telegram_chat: ChatId,
+    access_token: String,
+) -> anyhow::Result<()> {
+    let crab = octocrab::Octocrab::builder()
+        .user_access_token(access_token)
+        .build()?;
+    let author: GitHubUser = crab.get("/user", None::<&()>).await?;
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    conn.transaction::<(), diesel::result::Error, _>(|conn| {
+        use crate::schema::users::dsl::*;
+        match users
+            .filter(telegram_chat_id.eq(&telegram_chat.0))
+            .first::<User>(conn)
+            .optional()?
+        {
+            Some(user) => {
+                diesel::update(users.find(user.id))
+                    .set((
+                        github_login.eq(author.login),
+                        github_id.eq(author.id),
+                        github_avatar_url.eq(author.avatar_url.to_string()),
+                        github_email.eq(author.email),
+                        github_name.eq(author.name),
+                    ))
+                    .execute(conn)?;
+            }
+            None => {
+                let new_user = NewUser {
+                    github_login: Some(author.login),
+                    github_id: Some(author.id),
+                    github_name: Some(author.name),
+                    github_avatar_url: Some(author.avatar_url.to_string()),
+                    github_email: author.email,
+                    telegram_chat_id: Some(telegram_chat.0),
+                };
+                diesel::insert_into(crate::schema::users::table)
+                    .values(&new_user)
+                    .execute(conn)?;
+            }
+        }
+
+        Ok(())
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool, access_token))]
+async fn sync_github_info(pool: DbPool, telegram_chat_id: ChatId, access_token: String) {
+    if let Err(err) = sync_github_info_inner(pool, telegram_chat_id, access_token).await {
+        warn!(
+            "Failed to sync github info for telegram chat {}: {}",
+            telegram_chat_id, err
+        );
+    }
+}
+
+/// Save the GitHub account of a chat that just logged in, so that later
+/// commands find it without asking minzhengbu
+async fn remember_login(pool: DbPool, chat_id: ChatId) {
+    let Some(secret) = ARGS.github_secret.as_ref().map(Secret::expose) else {
+        return;
+    };
+    match get_github_token(&chat_id, secret).await {
+        Ok(token) => sync_github_info(pool, chat_id, token.access_token).await,
+        Err(err) => warn!("Failed to get github token of telegram chat {chat_id}: {err:?}"),
+    }
+}
+
+#[tracing::instrument(skip(pool, access_token))]
+async fn get_user(pool: DbPool, chat_id: ChatId, access_token: String) -> anyhow::Result<User> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    use crate::schema::users::dsl::*;
+    if let Some(user) = users
+        .filter(telegram_chat_id.eq(&chat_id.0))
+        .first::<User>(&mut conn)
+        .optional()?
+    {
+        return Ok(user);
+    }
+
+    // not found, try to fetch user info
+    sync_github_info_inner(pool, chat_id, access_token).await?;
+
+    // try again
+    if let Some(user) = users
+        .filter(telegram_chat_id.eq(&chat_id.0))
+        .first::<User>(&mut conn)
+        .optional()?
+    {
+        return Ok(user);
+    }
+
+    bail!("Failed to get user info")
+}
+
+async fn create_pipeline_from_pr(
+    pool: DbPool,
+    repo: &RepoConfig,
+    pr_number: u64,
+    archs: Option<&str>,
+    force: bool,
+    msg: &Message,
+    bot: &Bot,
+) -> ResponseResult<()> {
+    match wait_with_send_typing(
+        pipeline_new_pr(
+            pool.clone(),
+            repo,
+            pr_number,
+            archs,
+            JobSource::Telegram(msg.chat.id.0),
+            requester_of(msg).as_deref(),
+            force,
+        ),
+        bot,
+        msg.chat.id.0,
+    )
+    .await
+    {
+        Ok((mut pipeline, notes)) => {
+            let unchanged_since = unchanged_since(pool.clone(), &pipeline)
+                .await
+                .unwrap_or_else(|err| {
+                    warn!("Failed to compare build plans: {err}");
+                    None
+                });
+            let offline = pipeline_offline_archs(pool.clone(), &pipeline)
+                .await
+                .unwrap_or_else(|err| {
+                    warn!("Failed to find online workers: {err}");
+                    vec![]
+                });
+            let sent = send_message_with_fallback(
+                bot,
+                msg.chat.id,
+                &(new_pipeline_summary(
+                    &pipeline,
+                    unchanged_since,
+                    ARGS.new_pipeline_template.as_ref(),
+                ) + &to_html_pipeline_notes(&notes)
+                    + &to_html_offline_archs(&offline)),
+                ParseMode::Html,
+            )
+            .instrument(tracing::info_span!("send_message"))
+            .await?;
+            remember_summary(pool, &mut pipeline, &sent).await;
+        }
+        Err(err) => {
+            let text = match err.downcast_ref::<PrNotFound>() {
+                Some(not_found) => not_found.to_string(),
+                None => format!("Failed to create pipeline from pr: {err:?}"),
+            };
+            bot.send_message(msg.chat.id, truncate(&text)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(bot, msg, pool, ws_state_map))]
+pub async fn answer(
+    bot: Bot,
+    msg: Message,
+    cmd: Command,
+    pool: DbPool,
+    ws_state_map: WSStateMap,
+) -> ResponseResult<()> {
+    if !is_chat_allowed(
+        msg.chat.id,
+        msg.chat.is_private(),
+        ARGS.telegram_chats.as_deref(),
+    ) {
+        // reply once, then stay silent
+        if RESTRICTED_CHATS.lock().unwrap().insert(msg.chat.id.0) {
+            bot.send_message(msg.chat.id, "This instance is restricted")
+                .await?;
+        }
+        return Ok(());
+    }
+
+    match cmd {
         Command::Help => {
-            bot.send_message(msg.chat.id, Command::descriptions().to_string())
+            let permission = match wait_with_send_typing(
+                caller_permission(pool, msg.chat.id),
+                &bot,
+                msg.chat.id.0,
+            )
+            .await
+            {
+                Ok(permission) => permission,
+                Err(err) => {
+                    warn!("Failed to get permission of {}: {err:?}", msg.chat.id);
+                    Permission::Anyone
+                }
+            };
+            bot.send_message(msg.chat.id, format_help(permission))
                 .await?;
         }
         Command::PR(arguments) => {
-            let parts = arguments.split_ascii_whitespace().collect::<Vec<_>>();
+            let mut parts = arguments.split_ascii_whitespace().collect::<Vec<_>>();
+            let force = parts.contains(&"--force");
+            parts.retain(|part| *part != "--force");
+            let repo = parts.iter().find_map(|part| part.strip_prefix("repo="));
+            parts.retain(|part| !part.starts_with("repo="));
+            let repo = match repo_of(repo) {
+                Ok(repo) => repo,
+                Err(err) => {
+                    bot.send_message(msg.chat.id, truncate(&format!("{err:?}")))
+                        .await?;
+                    return Ok(());
+                }
+            };
             if !(1..=2).contains(&parts.len()) {
                 bot.send_message(
                     msg.chat.id,
@@ -397,51 +1643,228 @@ pub async fn answer(bot: Bot, msg: Message, cmd: Command, pool: DbPool) -> Respo
                     Some(parts[1])
                 };
                 for pr_number in pr_numbers {
-                    create_pipeline_from_pr(pool.clone(), pr_number, archs, &msg, &bot).await?;
+                    create_pipeline_from_pr(
+                        pool.clone(),
+                        &repo,
+                        pr_number,
+                        archs,
+                        force,
+                        &msg,
+                        &bot,
+                    )
+                    .await?;
                 }
             }
         }
-        Command::Build(arguments) => {
-            let parts: Vec<&str> = arguments.split(' ').collect();
-            if parts.len() == 3 {
-                let git_branch = parts[0];
-                let packages = parts[1];
-                let archs = parts[2];
-
-                pipeline_new_and_report(&bot, pool, git_branch, packages, archs, &msg).await?;
+        Command::BuildLabeled(label) => {
+            if !require_permission(
+                &bot,
+                &msg,
+                pool.clone(),
+                command_permission("buildlabeled"),
+                "Only admins can build labeled pull requests",
+            )
+            .await?
+            {
+                return Ok(());
+            }
 
+            let label = label.trim();
+            if label.is_empty() {
+                bot.send_message(
+                    msg.chat.id,
+                    format!("Missing label.\n\n{}", Command::descriptions()),
+                )
+                .await?;
                 return Ok(());
             }
 
-            bot.send_message(
-                msg.chat.id,
-                format!(
-                    "Got invalid job description: {arguments}. \n\n{}",
-                    Command::descriptions()
-                ),
-            )
-            .await?;
+            match wait_with_send_typing(open_prs_with_label(label), &bot, msg.chat.id.0).await {
+                Ok(prs) => {
+                    let mut lines = vec![];
+                    // one at a time, so that the queue depth cap sees earlier ones
+                    let primary = RepoConfig::primary(&ARGS.abbs_path);
+                    for plan in plan_labeled_builds(&prs) {
+                        lines.push(match plan {
+                            LabeledPr::Build(pr) => match wait_with_send_typing(
+                                pipeline_new_pr(
+                                    pool.clone(),
+                                    &primary,
+                                    pr,
+                                    None,
+                                    JobSource::Telegram(msg.chat.id.0),
+                                    requester_of(&msg).as_deref(),
+                                    false,
+                                ),
+                                &bot,
+                                msg.chat.id.0,
+                            )
+                            .await
+                            {
+                                Ok((pipeline, _)) => format!("#{pr}: pipeline #{}", pipeline.id),
+                                Err(err) => format!("#{pr}: failed, {err}"),
+                            },
+                            LabeledPr::Skip(pr, reason) => {
+                                format!("#{pr}: skipped, pull request is {reason}")
+                            }
+                        });
+                    }
+
+                    let text = if lines.is_empty() {
+                        format!("No open pull requests labeled {label}")
+                    } else {
+                        format!("Pull requests labeled {label}:\n{}", lines.join("\n"))
+                    };
+                    bot.send_message(msg.chat.id, truncate(&text)).await?;
+                }
+                Err(err) => {
+                    bot.send_message(
+                        msg.chat.id,
+                        truncate(&format!("Failed to list pull requests: {err:?}")),
+                    )
+                    .await?;
+                }
+            }
+        }
+        Command::Build(arguments) => match parse_build_request(&arguments) {
+            Ok(req) => {
+                if !require_permission(
+                    &bot,
+                    &msg,
+                    pool.clone(),
+                    build_request_permission(&req),
+                    "Only members of aosc-dev can set pr= or priority=, /login first",
+                )
+                .await?
+                {
+                    return Ok(());
+                }
+                pipeline_new_and_report(&bot, pool, &req, &msg).await?;
+            }
+            Err(err) => {
+                bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Got invalid job description: {err}. \n\n{}",
+                        Command::descriptions()
+                    ),
+                )
+                .await?;
+            }
+        },
+        Command::Status(arguments) => {
+            let page = match parse_page_argument(&arguments) {
+                Ok(page) => page,
+                Err(err) => {
+                    bot.send_message(msg.chat.id, err).await?;
+                    return Ok(());
+                }
+            };
+            match wait_with_send_typing(status(pool, page), &bot, msg.chat.id.0).await {
+                Ok(status) => {
+                    send_message_with_fallback(&bot, msg.chat.id, &status, ParseMode::MarkdownV2)
+                        .await?;
+                }
+                Err(err) => {
+                    bot.send_message(
+                        msg.chat.id,
+                        truncate(&format!("Failed to get status: {:?}", err)),
+                    )
+                    .await?;
+                }
+            }
         }
-        Command::Status => match wait_with_send_typing(status(pool), &bot, msg.chat.id.0).await {
-            Ok(status) => {
-                bot.send_message(msg.chat.id, status)
+        Command::Building(arguments) => {
+            let page = match parse_page_argument(&arguments) {
+                Ok(page) => page,
+                Err(err) => {
+                    bot.send_message(msg.chat.id, err).await?;
+                    return Ok(());
+                }
+            };
+            match wait_with_send_typing(running_jobs(pool), &bot, msg.chat.id.0).await {
+                Ok(jobs) => {
+                    bot.send_message(
+                        msg.chat.id,
+                        format_building(&jobs, chrono::Utc::now(), page),
+                    )
                     .parse_mode(ParseMode::MarkdownV2)
                     .await?;
+                }
+                Err(err) => {
+                    bot.send_message(
+                        msg.chat.id,
+                        truncate(&format!("Failed to get running jobs: {:?}", err)),
+                    )
+                    .await?;
+                }
+            }
+        }
+        Command::History(arguments) => match parse_history_request(&arguments) {
+            Ok(query) => {
+                match wait_with_send_typing(job_history(pool, &query), &bot, msg.chat.id.0).await {
+                    Ok((jobs, total)) => {
+                        bot.send_message(msg.chat.id, format_history(&query, &jobs, total))
+                            .parse_mode(ParseMode::MarkdownV2)
+                            .await?;
+                    }
+                    Err(err) => {
+                        bot.send_message(
+                            msg.chat.id,
+                            truncate(&format!("Failed to get history: {:?}", err)),
+                        )
+                        .await?;
+                    }
+                }
             }
             Err(err) => {
+                bot.send_message(msg.chat.id, format!("{err}\n\n{}", Command::descriptions()))
+                    .await?;
+            }
+        },
+        Command::ArchStatus(arguments) => {
+            let arch = arguments.trim();
+            if !ALL_ARCH.contains(&arch) {
                 bot.send_message(
                     msg.chat.id,
-                    truncate(&format!("Failed to get status: {:?}", err)),
+                    format!(
+                        "Unknown arch: {arch}, valid archs are: {}",
+                        ALL_ARCH.join(", ")
+                    ),
                 )
                 .await?;
+                return Ok(());
             }
-        },
+
+            match wait_with_send_typing(arch_status(pool, arch), &bot, msg.chat.id.0).await {
+                Ok(status) => {
+                    bot.send_message(msg.chat.id, format_arch_status(&status))
+                        .parse_mode(ParseMode::MarkdownV2)
+                        .await?;
+                }
+                Err(err) => {
+                    bot.send_message(
+                        msg.chat.id,
+                        truncate(&format!("Failed to get arch status: {:?}", err)),
+                    )
+                    .await?;
+                }
+            }
+        }
         Command::OpenPR(arguments) => {
-            let (title, mut parts) = split_open_pr_message(&arguments);
+            if !require_permission(
+                &bot,
+                &msg,
+                pool.clone(),
+                command_permission("openpr"),
+                "Only members of aosc-dev can use /openpr, /login first",
+            )
+            .await?
+            {
+                return Ok(());
+            }
 
-            if let Some(title) = title {
-                parts.insert(0, title);
-            } else {
+            let Some(open_pr_args) = parse_open_pr_args(&arguments) else {
                 bot.send_message(
                     msg.chat.id,
                     format!(
@@ -451,9 +1874,9 @@ pub async fn answer(bot: Bot, msg: Message, cmd: Command, pool: DbPool) -> Respo
                 )
                 .await?;
                 return Ok(());
-            }
+            };
 
-            let secret = match ARGS.github_secret.as_ref() {
+            let secret = match ARGS.github_secret.as_ref().map(Secret::expose) {
                 Some(s) => s,
                 None => {
                     bot.send_message(msg.chat.id, "GITHUB_SECRET is not set")
@@ -472,126 +1895,355 @@ pub async fn answer(bot: Bot, msg: Message, cmd: Command, pool: DbPool) -> Respo
             };
 
             // sync github info, but do not wait for result
-            tokio::spawn(sync_github_info(pool, msg.chat.id, token.clone()));
+            tokio::spawn(sync_github_info(pool.clone(), msg.chat.id, token.clone()));
 
-            if (3..=5).contains(&parts.len()) {
-                let tags = if parts.len() >= 4 {
-                    if parts[3].is_empty() {
-                        None
-                    } else {
-                        Some(
-                            parts[3]
-                                .split(',')
-                                .map(|x| x.to_string())
-                                .collect::<Vec<_>>(),
-                        )
-                    }
-                } else {
-                    None
-                };
+            let OpenPrArgs {
+                title,
+                git_ref,
+                packages,
+                tags,
+                archs,
+                base,
+            } = open_pr_args;
 
-                let archs = if parts.len() == 5 {
-                    let archs = parts[4].split(',').collect::<Vec<_>>();
-                    Some(handle_archs_args(archs))
-                } else {
-                    // deduce archs later
-                    None
-                };
+            let id = match ARGS
+                .github_app_id
+                .as_ref()
+                .and_then(|x| x.parse::<u64>().ok())
+            {
+                Some(id) => id,
+                None => {
+                    bot.send_message(msg.chat.id, "Got Error: GITHUB_APP_ID is not set")
+                        .await?;
+                    return Ok(());
+                }
+            };
 
-                let id = match ARGS
-                    .github_app_id
-                    .as_ref()
-                    .and_then(|x| x.parse::<u64>().ok())
-                {
-                    Some(id) => id,
-                    None => {
-                        bot.send_message(msg.chat.id, "Got Error: GITHUB_APP_ID is not set")
-                            .await?;
-                        return Ok(());
-                    }
-                };
+            let app_private_key = match ARGS.github_app_key.as_ref() {
+                Some(p) => p,
+                None => {
+                    bot.send_message(msg.chat.id, "Got Error: GITHUB_APP_ID is not set")
+                        .await?;
+                    return Ok(());
+                }
+            };
 
-                let app_private_key = match ARGS.github_app_key.as_ref() {
-                    Some(p) => p,
-                    None => {
-                        bot.send_message(msg.chat.id, "Got Error: GITHUB_APP_ID is not set")
-                            .await?;
-                        return Ok(());
+            match wait_with_send_typing(
+                buildit_utils::github::open_pr(
+                    app_private_key,
+                    &token,
+                    id,
+                    OpenPRRequest {
+                        git_ref: git_ref.to_owned(),
+                        abbs_path: ARGS.abbs_path.clone(),
+                        packages: packages.to_owned(),
+                        title: title.to_string(),
+                        tags,
+                        archs,
+                        base: base.to_string(),
+                        checked_archs: vec![],
+                    },
+                ),
+                &bot,
+                msg.chat.id.0,
+            )
+            .await
+            {
+                Ok((pr_number, url)) => {
+                    if let Err(err) =
+                        opened_pr_record(pool, pr_number, title, git_ref, msg.chat.id.0).await
+                    {
+                        warn!("Failed to record opened PR #{pr_number}: {err}");
                     }
-                };
+                    bot.send_message(msg.chat.id, format!("Successfully opened PR: {url}"))
+                        .await?;
+                }
+                Err(e) => {
+                    bot.send_message(msg.chat.id, truncate(&format!("Failed to open pr: {e}")))
+                        .await?;
+                }
+            }
+        }
+        Command::BuildAndPR(arguments) => {
+            if !require_permission(
+                &bot,
+                &msg,
+                pool.clone(),
+                command_permission("buildandpr"),
+                "Only members of aosc-dev can use /buildandpr, /login first",
+            )
+            .await?
+            {
+                return Ok(());
+            }
 
-                match wait_with_send_typing(
-                    buildit_utils::github::open_pr(
-                        app_private_key,
-                        &token,
-                        id,
-                        OpenPRRequest {
-                            git_ref: parts[1].to_owned(),
-                            abbs_path: ARGS.abbs_path.clone(),
-                            packages: parts[2].to_owned(),
-                            title: parts[0].to_string(),
-                            tags: tags.clone(),
-                            archs: archs.clone(),
-                        },
+            let Some(args) = parse_build_and_pr_args(&arguments) else {
+                bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Got invalid job description: {arguments}. \n\n{}",
+                        Command::descriptions()
                     ),
-                    &bot,
-                    msg.chat.id.0,
                 )
-                .await
-                {
-                    Ok((_id, url)) => {
-                        bot.send_message(msg.chat.id, format!("Successfully opened PR: {url}"))
-                            .await?;
-                        return Ok(());
-                    }
-                    Err(e) => {
-                        bot.send_message(msg.chat.id, truncate(&format!("Failed to open pr: {e}")))
-                            .await?;
-                        return Ok(());
-                    }
-                }
+                .await?;
+                return Ok(());
+            };
+
+            // check now rather than after the build
+            if let Err(err) = open_pr_auth(msg.chat.id).await {
+                bot.send_message(msg.chat.id, truncate(&format!("Got error: {err:?}")))
+                    .await?;
+                return Ok(());
             }
 
-            bot.send_message(
-                msg.chat.id,
-                format!(
-                    "Got invalid job description: {arguments}. \n\n{}",
-                    Command::descriptions()
+            let archs = args.archs.join(",");
+            let req = BuildRequest {
+                git_branch: args.git_ref,
+                packages: args.packages,
+                archs: Some(&archs),
+                github_pr: None,
+                priority: 0,
+                repo: None,
+                canary: false,
+            };
+            let Some(pipeline) = pipeline_new_and_report(&bot, pool.clone(), &req, &msg).await?
+            else {
+                return Ok(());
+            };
+
+            let res = pool
+                .get()
+                .context("Failed to get db connection from pool")
+                .and_then(|mut conn| {
+                    diesel::insert_into(crate::schema::pending_prs::table)
+                        .values(&PendingPr {
+                            pipeline_id: pipeline.id,
+                            title: args.title.to_string(),
+                            git_ref: args.git_ref.to_string(),
+                            packages: args.packages.to_string(),
+                            telegram_chat_id: msg.chat.id.0,
+                            creation_time: chrono::Utc::now(),
+                        })
+                        .execute(&mut conn)
+                        .context("Failed to record pending PR")
+                });
+            let text = match res {
+                Ok(_) => format!(
+                    "PR \"{}\" will be opened once pipeline #{} succeeds on all architectures",
+                    args.title, pipeline.id
                 ),
-            )
-            .await?;
+                Err(err) => format!("{err:?}"),
+            };
+            bot.send_message(msg.chat.id, truncate(&text)).await?;
         }
         Command::Login => {
             bot.send_message(msg.chat.id, "https://github.com/login/oauth/authorize?client_id=Iv1.bf26f3e9dd7883ae&redirect_uri=https://minzhengbu.aosc.io/login").await?;
         }
         Command::Start(arguments) => {
-            if arguments.len() != 20 {
+            if arguments.is_empty() {
                 bot.send_message(msg.chat.id, Command::descriptions().to_string())
                     .await?;
                 return Ok(());
+            } else if arguments.len() != 20 {
+                bot.send_message(
+                    msg.chat.id,
+                    "That login link is not valid, please run /login again",
+                )
+                .await?;
+                return Ok(());
             } else {
                 let resp =
                     wait_with_send_typing(login_github(&msg, arguments), &bot, msg.chat.id.0).await;
 
-                match resp {
-                    Ok(_) => bot.send_message(msg.chat.id, "Login successful!").await?,
-                    Err(e) => {
-                        bot.send_message(
-                            msg.chat.id,
-                            truncate(&format!("Login failed with error: {e}")),
-                        )
-                        .await?
-                    }
-                };
+                match resp {
+                    Ok(()) => {
+                        tokio::spawn(remember_login(pool.clone(), msg.chat.id));
+                        bot.send_message(msg.chat.id, "Login successful!").await?
+                    }
+                    Err(e) => {
+                        warn!("Failed to link telegram chat {}: {e:?}", msg.chat.id);
+                        bot.send_message(msg.chat.id, truncate(&format!("Login failed: {e}")))
+                            .await?
+                    }
+                };
+            }
+        }
+        Command::Validate(arguments) => match str::parse::<u64>(arguments.trim()) {
+            Ok(pr_number) => {
+                match wait_with_send_typing(pr_validate(pr_number), &bot, msg.chat.id.0).await {
+                    Ok(reports) => {
+                        bot.send_message(
+                            msg.chat.id,
+                            truncate(&format_validation(pr_number, &reports)),
+                        )
+                        .await?;
+                    }
+                    Err(err) => {
+                        bot.send_message(
+                            msg.chat.id,
+                            truncate(&format!("Failed to validate PR #{pr_number}: {err:?}")),
+                        )
+                        .await?;
+                    }
+                }
+            }
+            Err(err) => {
+                bot.send_message(msg.chat.id, format!("Bad PR number: {err}"))
+                    .await?;
+            }
+        },
+        Command::PRStatus(arguments) => match str::parse::<u64>(arguments.trim()) {
+            Ok(pr) => {
+                match wait_with_send_typing(
+                    pr_latest_build(pool, PRIMARY_REPO_FULL_NAME, pr),
+                    &bot,
+                    msg.chat.id.0,
+                )
+                .await
+                {
+                    Ok(Some((pipeline, jobs))) => {
+                        bot.send_message(
+                            msg.chat.id,
+                            truncate(&format_pr_status(pr, &pipeline, &jobs)),
+                        )
+                        .await?;
+                    }
+                    Ok(None) => {
+                        bot.send_message(msg.chat.id, format!("PR #{pr} has not been built yet"))
+                            .await?;
+                    }
+                    Err(err) => {
+                        bot.send_message(
+                            msg.chat.id,
+                            truncate(&format!("Failed to get build of PR #{pr}: {err:?}")),
+                        )
+                        .await?;
+                    }
+                }
+            }
+            Err(err) => {
+                bot.send_message(msg.chat.id, format!("Bad PR number: {err}"))
+                    .await?;
+            }
+        },
+        Command::Timings(arguments) => match str::parse::<i32>(arguments.trim()) {
+            Ok(pipeline_id) => {
+                match wait_with_send_typing(
+                    pipeline_timings(pool, pipeline_id),
+                    &bot,
+                    msg.chat.id.0,
+                )
+                .await
+                {
+                    Ok(timings) => {
+                        bot.send_message(
+                            msg.chat.id,
+                            truncate(&format_timings(pipeline_id, &timings)),
+                        )
+                        .await?;
+                    }
+                    Err(err) => {
+                        bot.send_message(msg.chat.id, truncate(&format!("{err}")))
+                            .await?;
+                    }
+                }
+            }
+            Err(err) => {
+                bot.send_message(msg.chat.id, format!("Bad pipeline id: {err}"))
+                    .await?;
+            }
+        },
+        Command::Compare(arguments) => match parse_compare_request(&arguments) {
+            Ok((before, after)) => {
+                let res = wait_with_send_typing(
+                    async {
+                        let before = pipeline_with_jobs(pool.clone(), before).await?;
+                        let after = pipeline_with_jobs(pool, after).await?;
+                        anyhow::Ok(format_compare((&before.0, &before.1), (&after.0, &after.1)))
+                    },
+                    &bot,
+                    msg.chat.id.0,
+                )
+                .await;
+                let text = match res {
+                    Ok(text) => text,
+                    Err(err) => format!("Failed to compare pipelines: {err}"),
+                };
+                bot.send_message(msg.chat.id, truncate(&text)).await?;
+            }
+            Err(err) => {
+                bot.send_message(msg.chat.id, format!("{err}\n\n{}", Command::descriptions()))
+                    .await?;
+            }
+        },
+        Command::MirrorStatus(arguments) => match str::parse::<i32>(arguments.trim()) {
+            Ok(pipeline_id) => {
+                match wait_with_send_typing(
+                    pipeline_mirror_status(pool, pipeline_id),
+                    &bot,
+                    msg.chat.id.0,
+                )
+                .await
+                {
+                    Ok(statuses) => {
+                        bot.send_message(
+                            msg.chat.id,
+                            truncate(&format_mirror_status(pipeline_id, &statuses)),
+                        )
+                        .await?;
+                    }
+                    Err(err) => {
+                        bot.send_message(msg.chat.id, truncate(&format!("{err}")))
+                            .await?;
+                    }
+                }
+            }
+            Err(err) => {
+                bot.send_message(msg.chat.id, format!("Bad pipeline id: {err}"))
+                    .await?;
+            }
+        },
+        Command::MyPRs(arguments) => {
+            let chat_id = match arguments.trim() {
+                "" => Some(msg.chat.id.0),
+                "all" if is_admin(msg.chat.id) => None,
+                "all" => {
+                    bot.send_message(msg.chat.id, "Only admins can list all pull requests")
+                        .await?;
+                    return Ok(());
+                }
+                _ => {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!(
+                            "Got invalid arguments: {arguments}\n\n{}",
+                            Command::descriptions()
+                        ),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+            };
+
+            match opened_pr_list(pool, chat_id).await {
+                Ok(prs) => {
+                    let states =
+                        wait_with_send_typing(opened_pr_states(&prs), &bot, msg.chat.id.0).await;
+                    bot.send_message(msg.chat.id, format_opened_prs(&prs, &states))
+                        .parse_mode(ParseMode::Html)
+                        .disable_web_page_preview(true)
+                        .await?;
+                }
+                Err(err) => {
+                    bot.send_message(msg.chat.id, truncate(&format!("{err}")))
+                        .await?;
+                }
             }
         }
         Command::Dickens(arguments) => match str::parse::<u64>(&arguments) {
             Ok(pr_number) => {
                 // create octocrab instance
-                let crab = match octocrab::Octocrab::builder()
-                    .user_access_token(ARGS.github_access_token.clone())
-                    .build()
-                {
+                let crab = match get_crab_github_bot().await {
                     Ok(v) => v,
                     Err(err) => {
                         bot.send_message(
@@ -605,7 +2257,7 @@ pub async fn answer(bot: Bot, msg: Message, cmd: Command, pool: DbPool) -> Respo
 
                 // get topic of pr
                 match wait_with_send_typing(
-                    crab.pulls("AOSC-Dev", "aosc-os-abbs").get(pr_number),
+                    fetch_pr(&crab, "AOSC-Dev", "aosc-os-abbs", pr_number),
                     &bot,
                     msg.chat.id.0,
                 )
@@ -657,11 +2309,8 @@ pub async fn answer(bot: Bot, msg: Message, cmd: Command, pool: DbPool) -> Respo
                         }
                     },
                     Err(err) => {
-                        bot.send_message(
-                            msg.chat.id,
-                            truncate(&format!("Failed to get pr info: {err:?}.")),
-                        )
-                        .await?;
+                        bot.send_message(msg.chat.id, truncate(&format!("{err}.")))
+                            .await?;
                     }
                 }
             }
@@ -695,15 +2344,16 @@ pub async fn answer(bot: Bot, msg: Message, cmd: Command, pool: DbPool) -> Respo
                     Ok(resp) => match resp.json::<QAResponse>().await {
                         Ok(qa) => {
                             for pkg in qa.packages {
-                                pipeline_new_and_report(
-                                    &bot,
-                                    pool.clone(),
-                                    "stable",
-                                    &pkg.name,
-                                    arch,
-                                    &msg,
-                                )
-                                .await?;
+                                let req = BuildRequest {
+                                    git_branch: "stable",
+                                    packages: &pkg.name,
+                                    archs: Some(arch),
+                                    github_pr: None,
+                                    priority: 0,
+                                    repo: None,
+                                    canary: false,
+                                };
+                                pipeline_new_and_report(&bot, pool.clone(), &req, &msg).await?;
                             }
                         }
                         Err(err) => {
@@ -736,7 +2386,13 @@ pub async fn answer(bot: Bot, msg: Message, cmd: Command, pool: DbPool) -> Respo
         }
         Command::Restart(arguments) => match str::parse::<i32>(&arguments) {
             Ok(job_id) => {
-                match wait_with_send_typing(job_restart(pool, job_id), &bot, msg.chat.id.0).await {
+                match wait_with_send_typing(
+                    job_restart(pool, job_id, &audit_actor(&msg)),
+                    &bot,
+                    msg.chat.id.0,
+                )
+                .await
+                {
                     Ok(new_job) => {
                         bot.send_message(
                             msg.chat.id,
@@ -758,7 +2414,71 @@ pub async fn answer(bot: Bot, msg: Message, cmd: Command, pool: DbPool) -> Respo
                     .await?;
             }
         },
+        Command::RetryPipeline(arguments) => {
+            match str::parse::<i32>(arguments.trim().trim_start_matches('#')) {
+                Ok(pipeline_id) => {
+                    match wait_with_send_typing(
+                        pipeline_retry(
+                            pool.clone(),
+                            pipeline_id,
+                            JobSource::Telegram(msg.chat.id.0),
+                            requester_of(&msg).as_deref(),
+                        ),
+                        &bot,
+                        msg.chat.id.0,
+                    )
+                    .await
+                    {
+                        Ok((mut pipeline, notes)) => {
+                            let offline = pipeline_offline_archs(pool.clone(), &pipeline)
+                                .await
+                                .unwrap_or_else(|err| {
+                                    warn!("Failed to find online workers: {err}");
+                                    vec![]
+                                });
+                            let sent = send_message_with_fallback(
+                                &bot,
+                                msg.chat.id,
+                                &(new_pipeline_summary(
+                                    &pipeline,
+                                    None,
+                                    ARGS.new_pipeline_template.as_ref(),
+                                ) + &to_html_retry_of(pipeline.parent_pipeline_id)
+                                    + &to_html_pipeline_notes(&notes)
+                                    + &to_html_offline_archs(&offline)),
+                                ParseMode::Html,
+                            )
+                            .await?;
+                            remember_summary(pool, &mut pipeline, &sent).await;
+                        }
+                        Err(err) => {
+                            bot.send_message(
+                                msg.chat.id,
+                                truncate(&format!("Failed to retry pipeline: {err:?}")),
+                            )
+                            .await?;
+                        }
+                    }
+                }
+                Err(err) => {
+                    bot.send_message(msg.chat.id, truncate(&format!("Bad pipeline ID: {err:?}")))
+                        .await?;
+                }
+            }
+        }
         Command::Bump(package) => {
+            if !require_permission(
+                &bot,
+                &msg,
+                pool.clone(),
+                command_permission("bump"),
+                "Only members of aosc-dev can use /bump, /login first",
+            )
+            .await?
+            {
+                return Ok(());
+            }
+
             let app_private_key = match ARGS.github_app_key.as_ref() {
                 Some(p) => p,
                 None => {
@@ -768,7 +2488,7 @@ pub async fn answer(bot: Bot, msg: Message, cmd: Command, pool: DbPool) -> Respo
                 }
             };
 
-            let secret = match ARGS.github_secret.as_ref() {
+            let secret = match ARGS.github_secret.as_ref().map(Secret::expose) {
                 Some(s) => s,
                 None => {
                     bot.send_message(msg.chat.id, "GITHUB_SECRET is not set")
@@ -854,6 +2574,8 @@ pub async fn answer(bot: Bot, msg: Message, cmd: Command, pool: DbPool) -> Respo
                             title: f.title,
                             tags: None,
                             archs: None,
+                            base: "stable".to_string(),
+                            checked_archs: vec![],
                         },
                     )
                     .await
@@ -865,8 +2587,16 @@ pub async fn answer(bot: Bot, msg: Message, cmd: Command, pool: DbPool) -> Respo
                             )
                             .await?;
 
-                            create_pipeline_from_pr(pool.clone(), pr_number, None, &msg, &bot)
-                                .await?;
+                            create_pipeline_from_pr(
+                                pool.clone(),
+                                &RepoConfig::primary(&ARGS.abbs_path),
+                                pr_number,
+                                None,
+                                false,
+                                &msg,
+                                &bot,
+                            )
+                            .await?;
                         }
                         Err(e) => {
                             bot.send_message(
@@ -877,33 +2607,412 @@ pub async fn answer(bot: Bot, msg: Message, cmd: Command, pool: DbPool) -> Respo
                         }
                     }
                 }
-                Err(e) => {
+                Err(e) => {
+                    bot.send_message(
+                        msg.chat.id,
+                        truncate(&format!("Failed to find update: {:?}", e)),
+                    )
+                    .await?;
+                }
+            };
+        }
+        Command::Roll => match wait_with_send_typing(roll(), &bot, msg.chat.id.0).await {
+            Ok(pkgs) => {
+                let mut s = String::new();
+                for i in pkgs {
+                    s.push_str(&i.to_string());
+                    s.push_str("\n");
+                }
+
+                bot.send_message(msg.chat.id, truncate(&s)).await?;
+            }
+            Err(e) => {
+                bot.send_message(
+                    msg.chat.id,
+                    truncate(&format!("Failed to roll packages: {}", e)),
+                )
+                .await?;
+            }
+        },
+        Command::Env(arguments) => {
+            let parts = arguments.split_ascii_whitespace().collect::<Vec<_>>();
+            let pipeline_id = match parts.as_slice() {
+                [pipeline_id, _arch] => pipeline_id.parse::<i32>().ok(),
+                _ => None,
+            };
+            let Some(pipeline_id) = pipeline_id else {
+                bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Got invalid arguments: {arguments}\n\n{}",
+                        Command::descriptions()
+                    ),
+                )
+                .await?;
+                return Ok(());
+            };
+
+            match job_environment(pool, pipeline_id, parts[1]).await {
+                Ok((job, environment)) => {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!(
+                            "Build environment of job #{} ({}):\n\n{}{}",
+                            job.id,
+                            teloxide::utils::html::escape(&job.arch),
+                            format_environment(&environment),
+                            format_resource_usage(job.peak_memory_bytes, job.disk_bytes)
+                                .map(|usage| format!("\nResource usage: {usage}"))
+                                .unwrap_or_default()
+                        ),
+                    )
+                    .parse_mode(ParseMode::Html)
+                    .await?;
+                }
+                Err(err) => {
+                    bot.send_message(msg.chat.id, truncate(&format!("{err}")))
+                        .await?;
+                }
+            }
+        }
+        Command::Notify(arguments) => match arguments.trim().parse::<NotifyMode>() {
+            Ok(mode) => match notify_mode_set(pool, msg.chat.id.0, mode).await {
+                Ok(()) => {
+                    bot.send_message(msg.chat.id, format!("Notify mode set to {}", mode.as_str()))
+                        .await?;
+                }
+                Err(err) => {
+                    bot.send_message(
+                        msg.chat.id,
+                        truncate(&format!("Failed to set notify mode: {err:?}")),
+                    )
+                    .await?;
+                }
+            },
+            Err(err) => {
+                bot.send_message(msg.chat.id, format!("{err}")).await?;
+            }
+        },
+        Command::Tail(arguments) => {
+            if !require_permission(
+                &bot,
+                &msg,
+                pool.clone(),
+                command_permission("tail"),
+                "Only admins can view server logs",
+            )
+            .await?
+            {
+                return Ok(());
+            }
+
+            match tail_logs(&arguments) {
+                Ok(logs) => {
+                    bot.send_message(msg.chat.id, logs).await?;
+                }
+                Err(err) => {
+                    bot.send_message(msg.chat.id, truncate(&format!("{err}")))
+                        .await?;
+                }
+            }
+        }
+        Command::QueueMove(arguments) => {
+            if !require_permission(
+                &bot,
+                &msg,
+                pool.clone(),
+                command_permission("queuemove"),
+                "Only admins can move jobs",
+            )
+            .await?
+            {
+                return Ok(());
+            }
+
+            let parts = arguments.split_ascii_whitespace().collect::<Vec<_>>();
+            let [pipeline_id, from_arch, to_arch] = parts.as_slice() else {
+                bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Got invalid arguments: {arguments}\n\n{}",
+                        Command::descriptions()
+                    ),
+                )
+                .await?;
+                return Ok(());
+            };
+            let Ok(pipeline_id) = pipeline_id.parse::<i32>() else {
+                bot.send_message(msg.chat.id, format!("Bad pipeline ID: {pipeline_id}"))
+                    .await?;
+                return Ok(());
+            };
+
+            match queue_move(
+                pool,
+                Some(bot.clone()),
+                &ws_state_map,
+                pipeline_id,
+                from_arch,
+                to_arch,
+                &audit_actor(&msg),
+            )
+            .await
+            {
+                Ok((old_job, new_job)) => {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!(
+                            "Cancelled job #{} on {}, queued as job #{} on {}",
+                            old_job.id, old_job.arch, new_job.id, new_job.arch
+                        ),
+                    )
+                    .await?;
+                }
+                Err(err) => {
+                    bot.send_message(
+                        msg.chat.id,
+                        truncate(&format!("Failed to move job: {err:?}")),
+                    )
+                    .await?;
+                }
+            }
+        }
+        Command::QueuePeek(arguments) => {
+            if !require_permission(
+                &bot,
+                &msg,
+                pool.clone(),
+                command_permission("queuepeek"),
+                "Only admins can peek at queues",
+            )
+            .await?
+            {
+                return Ok(());
+            }
+
+            let parts = arguments.split_ascii_whitespace().collect::<Vec<_>>();
+            let (arch, n) = match parts.as_slice() {
+                [arch] => (*arch, Some(QUEUE_PEEK_DEFAULT)),
+                [arch, n] => (*arch, n.parse::<i64>().ok().filter(|n| *n >= 1)),
+                _ => ("", None),
+            };
+            let Some(n) = n else {
+                bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Got invalid arguments: {arguments}\n\n{}",
+                        Command::descriptions()
+                    ),
+                )
+                .await?;
+                return Ok(());
+            };
+
+            let text = match queue_peek(pool, arch, n).await {
+                Ok(jobs) => format_queue_peek(arch, &jobs),
+                Err(err) => format!("Failed to peek at queue: {err:?}"),
+            };
+            bot.send_message(msg.chat.id, truncate(&text)).await?;
+        }
+        Command::Snapshot => {
+            if !require_permission(
+                &bot,
+                &msg,
+                pool.clone(),
+                command_permission("snapshot"),
+                "Only admins can export snapshots",
+            )
+            .await?
+            {
+                return Ok(());
+            }
+
+            let res = wait_with_send_typing(snapshot(pool), &bot, msg.chat.id.0)
+                .await
+                .and_then(|snapshot| {
+                    let file_name = format!(
+                        "buildit-snapshot-{}.json",
+                        snapshot.time.format("%Y%m%d-%H%M%S")
+                    );
+                    Ok((file_name, serde_json::to_vec_pretty(&snapshot)?))
+                });
+            match res {
+                Ok((file_name, json)) => {
+                    bot.send_document(msg.chat.id, InputFile::memory(json).file_name(file_name))
+                        .await?;
+                }
+                Err(err) => {
+                    bot.send_message(
+                        msg.chat.id,
+                        truncate(&format!("Failed to take snapshot: {:?}", err)),
+                    )
+                    .await?;
+                }
+            }
+        }
+        Command::Drain => {
+            if !require_permission(
+                &bot,
+                &msg,
+                pool.clone(),
+                command_permission("drain"),
+                "Only admins can drain the server",
+            )
+            .await?
+            {
+                return Ok(());
+            }
+
+            set_draining(true);
+            audit(&pool, &msg, "drain", "new builds are refused");
+            let text = match active_job_counts(pool).await {
+                Ok((queued, running)) => format!(
+                    "Draining: new builds are refused, waiting for {queued} queued and {running} running jobs"
+                ),
+                Err(err) => {
+                    warn!("Failed to count active jobs: {err}");
+                    "Draining: new builds are refused".to_string()
+                }
+            };
+            let text = if ARGS.ops_chat.is_some() {
+                format!("{text}, the ops chat will be notified when done")
+            } else {
+                text
+            };
+            bot.send_message(msg.chat.id, text).await?;
+        }
+        Command::Undrain => {
+            if !require_permission(
+                &bot,
+                &msg,
+                pool.clone(),
+                command_permission("undrain"),
+                "Only admins can undrain the server",
+            )
+            .await?
+            {
+                return Ok(());
+            }
+
+            set_draining(false);
+            audit(&pool, &msg, "undrain", "new builds are accepted");
+            bot.send_message(msg.chat.id, "New builds are accepted again")
+                .await?;
+        }
+        Command::Logs(arguments) => match str::parse::<i32>(arguments.trim()) {
+            Ok(job_id) => {
+                let text = match wait_with_send_typing(
+                    live_log(pool, &ws_state_map, job_id),
+                    &bot,
+                    msg.chat.id.0,
+                )
+                .await
+                {
+                    Ok(text) => text,
+                    Err(err) => format!("Failed to get log: {err:?}"),
+                };
+                bot.send_message(msg.chat.id, truncate(&text)).await?;
+            }
+            Err(err) => {
+                bot.send_message(msg.chat.id, truncate(&format!("Bad job ID: {err:?}")))
+                    .await?;
+            }
+        },
+        Command::Audit(arguments) => {
+            if !require_permission(
+                &bot,
+                &msg,
+                pool.clone(),
+                command_permission("audit"),
+                "Only admins can read the audit log",
+            )
+            .await?
+            {
+                return Ok(());
+            }
+
+            match parse_audit_query(&arguments) {
+                Ok(query) => match audit_log_list(pool, &query).await {
+                    Ok(entries) => {
+                        bot.send_message(msg.chat.id, truncate(&format_audit_log(&entries)))
+                            .await?;
+                    }
+                    Err(err) => {
+                        bot.send_message(
+                            msg.chat.id,
+                            truncate(&format!("Failed to get audit log: {err:?}")),
+                        )
+                        .await?;
+                    }
+                },
+                Err(err) => {
+                    bot.send_message(msg.chat.id, format!("{err}\n\n{}", Command::descriptions()))
+                        .await?;
+                }
+            }
+        }
+        Command::Reping => {
+            if !require_permission(
+                &bot,
+                &msg,
+                pool.clone(),
+                command_permission("reping"),
+                "Only admins can ping workers",
+            )
+            .await?
+            {
+                return Ok(());
+            }
+
+            match wait_with_send_typing(reping(pool, &ws_state_map), &bot, msg.chat.id.0).await {
+                Ok(status) => {
+                    send_message_with_fallback(&bot, msg.chat.id, &status, ParseMode::MarkdownV2)
+                        .await?;
+                }
+                Err(err) => {
                     bot.send_message(
                         msg.chat.id,
-                        truncate(&format!("Failed to find update: {:?}", e)),
+                        truncate(&format!("Failed to get status: {:?}", err)),
                     )
                     .await?;
                 }
-            };
+            }
         }
-        Command::Roll => match wait_with_send_typing(roll(), &bot, msg.chat.id.0).await {
-            Ok(pkgs) => {
-                let mut s = String::new();
-                for i in pkgs {
-                    s.push_str(&i.to_string());
-                    s.push_str("\n");
-                }
-
-                bot.send_message(msg.chat.id, truncate(&s)).await?;
+        Command::MyBuilds => match my_builds(pool, msg.chat.id.0).await {
+            Ok(pipelines) => {
+                bot.send_message(msg.chat.id, truncate(&format_my_builds(&pipelines)))
+                    .await?;
             }
-            Err(e) => {
-                bot.send_message(
-                    msg.chat.id,
-                    truncate(&format!("Failed to roll packages: {}", e)),
-                )
-                .await?;
+            Err(err) => {
+                bot.send_message(msg.chat.id, truncate(&format!("{err}")))
+                    .await?;
             }
         },
+        Command::CancelMine => {
+            let cancelled_by = requester_of(&msg).unwrap_or_else(|| msg.chat.id.to_string());
+            match pipeline_cancel_mine(
+                pool,
+                Some(bot.clone()),
+                &ws_state_map,
+                msg.chat.id.0,
+                &audit_actor(&msg),
+                &cancelled_by,
+            )
+            .await
+            {
+                Ok(cancelled) => {
+                    bot.send_message(msg.chat.id, truncate(&format_cancel_mine(&cancelled)))
+                        .await?;
+                }
+                Err(err) => {
+                    bot.send_message(
+                        msg.chat.id,
+                        truncate(&format!("Failed to cancel pipelines: {err}")),
+                    )
+                    .await?;
+                }
+            }
+        }
     };
 
     Ok(())
@@ -967,6 +3076,127 @@ fn split_open_pr_message(arguments: &str) -> (Option<&str>, Vec<&str>) {
     (title, parts)
 }
 
+/// Arguments of /openpr
+#[derive(Debug, PartialEq, Eq)]
+struct OpenPrArgs<'a> {
+    title: &'a str,
+    git_ref: &'a str,
+    packages: &'a str,
+    tags: Option<Vec<String>>,
+    /// None to deduce from the packages
+    archs: Option<Vec<&'a str>>,
+    base: &'a str,
+}
+
+/// Parse `title;git-ref;packages;[labels];[architectures];[base-branch]`
+fn parse_open_pr_args(arguments: &str) -> Option<OpenPrArgs<'_>> {
+    let (title, parts) = split_open_pr_message(arguments);
+    let title = title?;
+    if !(2..=5).contains(&parts.len()) {
+        return None;
+    }
+
+    // optional fields may be left empty to skip them
+    let optional = |i: usize| parts.get(i).copied().filter(|x| !x.is_empty());
+    Some(OpenPrArgs {
+        title,
+        git_ref: parts[0],
+        packages: parts[1],
+        tags: optional(2).map(|tags| tags.split(',').map(|x| x.to_string()).collect()),
+        archs: match optional(3) {
+            Some(archs) => Some(normalize_archs(archs).ok()?),
+            None => None,
+        },
+        base: optional(4).unwrap_or("stable"),
+    })
+}
+
+/// Arguments of /buildandpr
+#[derive(Debug, PartialEq, Eq)]
+struct BuildAndPrArgs<'a> {
+    title: &'a str,
+    git_ref: &'a str,
+    packages: &'a str,
+    archs: Vec<&'a str>,
+}
+
+/// Parse `title;git-ref;packages;architectures`
+fn parse_build_and_pr_args(arguments: &str) -> Option<BuildAndPrArgs<'_>> {
+    let (title, parts) = split_open_pr_message(arguments);
+    let title = title?;
+    let [git_ref, packages, archs] = parts[..] else {
+        return None;
+    };
+
+    Some(BuildAndPrArgs {
+        title,
+        git_ref,
+        packages,
+        archs: normalize_archs(archs).ok()?,
+    })
+}
+
+#[test]
+fn test_parse_open_pr_args() {
+    assert_eq!(
+        parse_open_pr_args("fd: update to 9.0.0;fd-9.0.0;fd"),
+        Some(OpenPrArgs {
+            title: "fd: update to 9.0.0",
+            git_ref: "fd-9.0.0",
+            packages: "fd",
+            tags: None,
+            archs: None,
+            base: "stable",
+        })
+    );
+    assert_eq!(
+        parse_open_pr_args("VSCode Survey 1.85.0;vscode-1.85.0;vscode,vscodium;;amd64,arm64"),
+        Some(OpenPrArgs {
+            title: "VSCode Survey 1.85.0",
+            git_ref: "vscode-1.85.0",
+            packages: "vscode,vscodium",
+            tags: None,
+            archs: Some(vec!["amd64", "arm64"]),
+            base: "stable",
+        })
+    );
+    assert_eq!(
+        parse_open_pr_args("glibc: update to 2.39;glibc-2.39;glibc;upgrade;;core-devel"),
+        Some(OpenPrArgs {
+            title: "glibc: update to 2.39",
+            git_ref: "glibc-2.39",
+            packages: "glibc",
+            tags: Some(vec!["upgrade".to_string()]),
+            archs: None,
+            base: "core-devel",
+        })
+    );
+
+    // empty base falls back to stable
+    assert_eq!(
+        parse_open_pr_args("fd;fd-9.0.0;fd;;;").map(|args| args.base),
+        Some("stable")
+    );
+    assert_eq!(parse_open_pr_args("fd;fd-9.0.0"), None);
+    assert_eq!(parse_open_pr_args("fd;fd-9.0.0;fd;;;stable;extra"), None);
+}
+
+#[test]
+fn test_parse_build_and_pr_args() {
+    assert_eq!(
+        parse_build_and_pr_args("fd: update to 9.0.0;fd-9.0.0;fd;arm64,amd64"),
+        Some(BuildAndPrArgs {
+            title: "fd: update to 9.0.0",
+            git_ref: "fd-9.0.0",
+            packages: "fd",
+            archs: vec!["amd64", "arm64"],
+        })
+    );
+    assert_eq!(parse_build_and_pr_args("fd;fd-9.0.0;fd"), None);
+    assert_eq!(parse_build_and_pr_args("fd;fd-9.0.0;fd;amd64;stable"), None);
+    assert_eq!(parse_build_and_pr_args("fd;fd-9.0.0;fd;vax"), None);
+}
+
 #[test]
 fn test_split_open_pr_message() {
     let t = split_open_pr_message("clutter fix ftbfs;clutter-fix-ftbfs;clutter");
@@ -987,3 +3217,714 @@ fn test_split_open_pr_message() {
         )
     );
 }
+
+#[test]
+fn test_format_arch_status() {
+    use crate::models::{Job, Worker};
+    use chrono::DateTime;
+
+    let worker = Worker {
+        id: 1,
+        hostname: "riscv-builder".to_string(),
+        arch: "riscv64".to_string(),
+        git_commit: "abcdef".to_string(),
+        memory_bytes: 16 << 30,
+        logical_cores: 8,
+        last_heartbeat_time: DateTime::from_timestamp(61, 0).unwrap(),
+        disk_free_space_bytes: 100 << 30,
+        performance: None,
+        visible: true,
+        internet_connectivity: true,
+        version: None,
+        labels: None,
+    };
+    let job = Job {
+        id: 42,
+        pipeline_id: 1,
+        packages: "fd,fish".to_string(),
+        arch: "riscv64".to_string(),
+        status: "running".to_string(),
+        assigned_worker_id: Some(1),
+        ..Job::fixture()
+    };
+
+    let s = format_arch_status(&ArchStatus {
+        arch: "riscv64".to_string(),
+        pending: 3,
+        running_jobs: vec![job],
+        workers: vec![worker],
+        live_workers: 1,
+        idle_workers: 0,
+        recent_total: 4,
+        recent_success: 3,
+    });
+
+    assert!(s.contains("riscv64"));
+    assert!(s.contains("75\\.0%"));
+    assert!(s.contains("\\#42: fd, fish on riscv\\-builder"));
+    for arch in ALL_ARCH.iter().filter(|arch| **arch != "riscv64") {
+        assert!(!s.contains(arch));
+    }
+}
+
+#[test]
+fn test_format_environment() {
+    let environment = BTreeMap::from([
+        ("gcc".to_string(), "13.2.0-3".to_string()),
+        ("kernel".to_string(), "6.9.3-aosc-main".to_string()),
+    ]);
+    assert_eq!(
+        format_environment(&environment),
+        "<pre>gcc     13.2.0-3\nkernel  6.9.3-aosc-main</pre>"
+    );
+}
+
+#[test]
+fn test_parse_build_request() {
+    // extra spaces
+    assert_eq!(
+        parse_build_request("  stable   bash,fish  amd64,arm64 "),
+        Ok(BuildRequest {
+            git_branch: "stable",
+            packages: "bash,fish",
+            archs: Some("amd64,arm64"),
+            github_pr: None,
+            priority: 0,
+            repo: None,
+            canary: false,
+        })
+    );
+
+    // omitted archs
+    assert_eq!(
+        parse_build_request("stable bash"),
+        Ok(BuildRequest {
+            git_branch: "stable",
+            packages: "bash",
+            archs: None,
+            github_pr: None,
+            priority: 0,
+            repo: None,
+            canary: false,
+        })
+    );
+
+    // flags in any position
+    assert_eq!(
+        parse_build_request("priority=5 fd-9.0.0 archs=riscv64 fd pr=#4992"),
+        Ok(BuildRequest {
+            git_branch: "fd-9.0.0",
+            packages: "fd",
+            archs: Some("riscv64"),
+            github_pr: Some(4992),
+            priority: 5,
+            repo: None,
+            canary: false,
+        })
+    );
+
+    // errors
+    assert_eq!(
+        parse_build_request("stable"),
+        Err("Missing packages".to_string())
+    );
+    assert_eq!(
+        parse_build_request("stable fd amd64 archs=arm64"),
+        Err("Architectures are given both as argument and archs=".to_string())
+    );
+    assert_eq!(
+        parse_build_request("stable fd pr=abc"),
+        Err("Invalid PR number: abc".to_string())
+    );
+    assert_eq!(
+        parse_build_request("stable fd arch=amd64"),
+        Err("Unknown flag: arch, expected archs=, pr=, priority= or repo=".to_string())
+    );
+    assert_eq!(
+        parse_build_request("stable fd priority=1000").map(|req| req.priority),
+        Ok(MAX_PRIORITY)
+    );
+    assert_eq!(
+        parse_build_request("stable fd priority=-1000").map(|req| req.priority),
+        Ok(-MAX_PRIORITY)
+    );
+
+    // another repo
+    assert_eq!(
+        parse_build_request("main fd repo=aoscx").map(|req| req.repo),
+        Ok(Some("aoscx"))
+    );
+
+    // canary first
+    assert_eq!(
+        parse_build_request("fd-9.0.0 fd --canary mainline").map(|req| (req.archs, req.canary)),
+        Ok((Some("mainline"), true))
+    );
+}
+
+#[test]
+fn test_build_request_permission() {
+    let permission =
+        |arguments: &str| build_request_permission(&parse_build_request(arguments).unwrap());
+    assert_eq!(permission("stable fd"), Permission::Anyone);
+    assert_eq!(
+        permission("stable fd archs=amd64 --canary"),
+        Permission::Anyone
+    );
+    assert_eq!(permission("stable fd priority=1"), Permission::Member);
+    assert_eq!(permission("stable fd priority=-1"), Permission::Member);
+    assert_eq!(permission("stable fd pr=4992"), Permission::Member);
+}
+
+#[test]
+fn test_format_queue_peek() {
+    let pipeline = |git_branch: &str| Pipeline {
+        packages: "fd,ripgrep".to_string(),
+        git_branch: git_branch.to_string(),
+        ..Pipeline::fixture()
+    };
+    let job = |id: i32, arch: &str, priority: i32| Job {
+        id,
+        packages: "fd,ripgrep".to_string(),
+        arch: arch.to_string(),
+        priority,
+        ..Job::fixture()
+    };
+
+    assert_eq!(
+        format_queue_peek(
+            "amd64",
+            &[
+                (job(35, "amd64", 10), pipeline("fd-9.0.0")),
+                (job(34, "noarch", 0), pipeline("stable")),
+            ]
+        ),
+        "Next 2 queued job(s) on amd64:\n\
+         #35 (amd64): fd, ripgrep on fd-9.0.0, priority 10\n\
+         #34 (noarch): fd, ripgrep on stable"
+    );
+    assert_eq!(
+        format_queue_peek("riscv64", &[]),
+        "No queued jobs on riscv64"
+    );
+}
+
+#[test]
+fn test_format_building() {
+    use crate::models::Job;
+    use chrono::DateTime;
+
+    assert_eq!(
+        format_building(&[], DateTime::from_timestamp(0, 0).unwrap(), 1),
+        "No active builds"
+    );
+
+    let running = |id: i32, assign_time: i64| RunningJob {
+        job: Job {
+            id,
+            pipeline_id: 1,
+            packages: format!("pkg{id}"),
+            creation_time: DateTime::from_timestamp(0, 0).unwrap(),
+            status: "running".to_string(),
+            assigned_worker_id: Some(1),
+            assign_time: DateTime::from_timestamp(assign_time, 0),
+            ..Job::fixture()
+        },
+        worker_hostname: Some("Yerus".to_string()),
+    };
+    let now = DateTime::from_timestamp(10000, 0).unwrap();
+
+    let s = format_building(
+        &[running(1, 9000), running(2, 2000), running(3, 9940)],
+        now,
+        1,
+    );
+    let pos = |needle: &str| s.find(needle).unwrap();
+    assert!(pos("\\#2 \\(amd64\\): pkg2 on Yerus, 2h13m") < pos("\\#1 "));
+    assert!(pos("\\#1 \\(amd64\\): pkg1 on Yerus, 16m40s") < pos("\\#3 "));
+    assert!(s.contains("\\#3 \\(amd64\\): pkg3 on Yerus, 1m00s"));
+
+    assert!(!s.contains("Page"));
+
+    // longest running first, over two pages
+    let jobs = (0..BUILDING_PAGE_SIZE as i32 + 5)
+        .map(|id| running(id, id as i64))
+        .collect::<Vec<_>>();
+    let s = format_building(&jobs, now, 1);
+    assert!(s.contains("\\#0 "));
+    assert!(!s.contains(&format!("\\#{} ", BUILDING_PAGE_SIZE)));
+    assert!(s.ends_with("Page 1 of 2, use /building page\\=2 for more\n"));
+    let s = format_building(&jobs, now, 2);
+    assert!(s.starts_with("__*25 Running Job\\(s\\)*__\n\n\\#20 "));
+    assert!(s.ends_with("Page 2 of 2\n"));
+    assert_eq!(
+        format_building(&jobs, now, 3),
+        "Nothing on page 3, there are 2 page\\(s\\) in total"
+    );
+}
+
+#[test]
+fn test_parse_history_request() {
+    assert_eq!(
+        parse_history_request("bash"),
+        Ok(HistoryQuery {
+            package: "bash",
+            arch: None,
+            status: None,
+            page: 1,
+        })
+    );
+    assert_eq!(
+        parse_history_request("bash arch=riscv64 status=failed page=2"),
+        Ok(HistoryQuery {
+            package: "bash",
+            arch: Some("riscv64"),
+            status: Some("failed"),
+            page: 2,
+        })
+    );
+    assert_eq!(
+        parse_history_request("status=success bash arch=noarch").map(|query| query.arch),
+        Ok(Some("noarch"))
+    );
+
+    assert!(parse_history_request("").is_err());
+    assert!(parse_history_request("bash fish").is_err());
+    assert!(parse_history_request("bash arch=i486").is_err());
+    assert!(parse_history_request("bash status=done").is_err());
+    assert!(parse_history_request("bash page=0").is_err());
+    assert!(parse_history_request("bash branch=stable").is_err());
+}
+
+#[test]
+fn test_format_history() {
+    use crate::models::Job;
+    let job = |id: i32| Job {
+        id,
+        pipeline_id: 1,
+        packages: "bash".to_string(),
+        arch: "riscv64".to_string(),
+        status: "failed".to_string(),
+        build_success: Some(false),
+        failed_package: Some("bash".to_string()),
+        elapsed_secs: Some(125),
+        ..Job::fixture()
+    };
+    let mut query = parse_history_request("bash arch=riscv64 status=failed").unwrap();
+
+    // 25 matches, first page
+    let mut jobs = (16..=25)
+        .rev()
+        .map(|id| (job(id), None))
+        .collect::<Vec<_>>();
+    jobs[1].1 = Some("@cyan".to_string());
+    let s = format_history(&query, &jobs, 25);
+    assert!(
+        s.contains("\\#25 \\(riscv64\\): failed, created at 1970\\-01\\-01 00:01:01, took 2m05s\n")
+    );
+    assert!(s.contains(
+        "\\#24 \\(riscv64\\): failed, created at 1970\\-01\\-01 00:01:01, took 2m05s, by @cyan\n"
+    ));
+    assert!(s.starts_with("__*History of bash, 25 job\\(s\\)*__\n\n"));
+    assert!(s.ends_with(
+        "Page 1 of 3, use /history bash arch\\=riscv64 status\\=failed page\\=2 for more"
+    ));
+
+    // last page
+    query.page = 3;
+    let jobs = (1..=5).rev().map(|id| (job(id), None)).collect::<Vec<_>>();
+    let s = format_history(&query, &jobs, 25);
+    assert!(s.ends_with("Page 3 of 3"));
+
+    // past the last page
+    query.page = 4;
+    assert_eq!(
+        format_history(&query, &[], 25),
+        "Nothing on page 4, there are 3 page\\(s\\) in total"
+    );
+
+    // exactly one full page
+    query.page = 1;
+    let jobs = (1..=10).rev().map(|id| (job(id), None)).collect::<Vec<_>>();
+    assert!(format_history(&query, &jobs, 10).ends_with("took 2m05s\n"));
+
+    assert_eq!(format_history(&query, &[], 0), "No jobs found for bash");
+}
+
+#[test]
+fn test_is_chat_allowed() {
+    // allow all by default
+    assert!(is_chat_allowed(ChatId(-1001234), false, None));
+    assert!(is_chat_allowed(ChatId(-1001234), false, Some("")));
+
+    // approved
+    let allowlist = Some("-1001234, 5678");
+    assert!(is_chat_allowed(ChatId(-1001234), false, allowlist));
+    assert!(is_chat_allowed(ChatId(5678), true, allowlist));
+
+    // not approved
+    assert!(!is_chat_allowed(ChatId(-1009999), false, allowlist));
+    assert!(!is_chat_allowed(ChatId(4321), true, allowlist));
+
+    // private chats only
+    assert!(is_chat_allowed(ChatId(4321), true, Some("private")));
+    assert!(!is_chat_allowed(ChatId(-1001234), false, Some("private")));
+}
+
+#[test]
+fn test_strip_markup() {
+    assert_eq!(
+        strip_markup(
+            "__*Queue Status*__\n\n*amd64*: 1 job\\(s\\) pending, [\\#1](https://buildit.aosc.io/jobs/1_2)",
+            ParseMode::MarkdownV2
+        ),
+        "Queue Status\n\namd64: 1 job(s) pending, #1 (https://buildit.aosc.io/jobs/1_2)"
+    );
+    assert_eq!(
+        strip_markup(
+            "<b>Job</b>: <a href=\"https://buildit.aosc.io/jobs/1\">#1</a> &lt;fd&gt;",
+            ParseMode::Html
+        ),
+        "Job: #1 <fd>"
+    );
+}
+
+#[tokio::test]
+async fn test_send_message_with_fallback() {
+    use axum::{extract::State, routing::post, Json, Router};
+    use std::sync::Mutex;
+
+    // mocked bot api rejecting formatted messages
+    async fn send_message(
+        State(sent): State<Arc<Mutex<Vec<serde_json::Value>>>>,
+        Json(req): Json<serde_json::Value>,
+    ) -> Json<serde_json::Value> {
+        sent.lock().unwrap().push(req.clone());
+        if req.get("parse_mode").is_some() {
+            Json(serde_json::json!({
+                "ok": false,
+                "error_code": 400,
+                "description": "Bad Request: can't parse entities: Character '.' is reserved and must be escaped with the preceding '\\'",
+            }))
+        } else {
+            Json(serde_json::json!({
+                "ok": true,
+                "result": {
+                    "message_id": 1,
+                    "date": 0,
+                    "chat": { "id": 1234, "type": "private", "first_name": "Yerus" },
+                    "text": req["text"],
+                },
+            }))
+        }
+    }
+
+    let sent = Arc::new(Mutex::new(vec![]));
+    let app = Router::new()
+        .route("/bottoken/SendMessage", post(send_message))
+        .with_state(sent.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let bot =
+        Bot::new("token").set_api_url(reqwest::Url::parse(&format!("http://{addr}/")).unwrap());
+    let msg = send_message_with_fallback(
+        &bot,
+        ChatId(1234),
+        "*Status*: version 1.0",
+        ParseMode::MarkdownV2,
+    )
+    .await
+    .unwrap();
+    assert_eq!(msg.text(), Some("Status: version 1.0"));
+
+    let sent = sent.lock().unwrap();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[0]["parse_mode"], "MarkdownV2");
+    assert_eq!(sent[1]["text"], "Status: version 1.0");
+}
+
+#[test]
+fn test_callback_action() {
+    let action = CallbackAction::RetryArch {
+        pipeline_id: 42,
+        arch: "loongarch64".to_string(),
+    };
+    assert_eq!(action.encode(), "retry:42:loongarch64");
+    assert_eq!(CallbackAction::decode(&action.encode()), Some(action));
+
+    let action = CallbackAction::RetryFailed { pipeline_id: 42 };
+    assert_eq!(action.encode(), "retryfailed:42");
+    assert_eq!(CallbackAction::decode(&action.encode()), Some(action));
+
+    // the longest payload must fit in telegram's limit
+    for arch in ALL_ARCH {
+        let action = CallbackAction::RetryArch {
+            pipeline_id: i32::MAX,
+            arch: arch.to_string(),
+        };
+        assert!(action.encode().len() <= 64);
+    }
+
+    assert_eq!(CallbackAction::decode("retry:42:x86"), None);
+    assert_eq!(CallbackAction::decode("retry:abc:amd64"), None);
+    assert_eq!(CallbackAction::decode("retryfailed:42:amd64"), None);
+    assert_eq!(CallbackAction::decode(""), None);
+
+    // buttons map to actions
+    let keyboard = failed_job_keyboard(42, "amd64", Some("https://pastebin.aosc.io/paste/abc"));
+    let row = &keyboard.inline_keyboard[0];
+    assert_eq!(row.len(), 3);
+    assert_eq!(row[0].text, "View Log");
+    let actions = row[1..]
+        .iter()
+        .map(|button| match &button.kind {
+            teloxide::types::InlineKeyboardButtonKind::CallbackData(data) => {
+                CallbackAction::decode(data)
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        actions,
+        vec![
+            Some(CallbackAction::RetryArch {
+                pipeline_id: 42,
+                arch: "amd64".to_string()
+            }),
+            Some(CallbackAction::RetryFailed { pipeline_id: 42 }),
+        ]
+    );
+
+    // no log button without a log
+    assert_eq!(
+        failed_job_keyboard(42, "amd64", None).inline_keyboard[0].len(),
+        2
+    );
+}
+
+#[test]
+fn test_format_my_builds() {
+    let pipeline = |id: i32| Pipeline {
+        id,
+        packages: "fd,ripgrep".to_string(),
+        archs: "amd64,arm64".to_string(),
+        telegram_user: Some(1234),
+        requested_by: Some("@cyan".to_string()),
+        ..Pipeline::fixture()
+    };
+    let job = |id: i32, arch: &str, status: &str| Job {
+        id,
+        packages: "fd,ripgrep".to_string(),
+        arch: arch.to_string(),
+        status: status.to_string(),
+        ..Job::fixture()
+    };
+
+    let pipelines = [
+        (
+            pipeline(12),
+            vec![job(1, "amd64", "running"), job(2, "arm64", "created")],
+        ),
+        (pipeline(13), vec![job(3, "riscv64", "held")]),
+    ];
+    assert_eq!(
+        format_my_builds(&pipelines),
+        "Pipeline #12 fd,ripgrep (fd-9.0.0): amd64 (running), arm64 (queued)\n\
+         Pipeline #13 fd,ripgrep (fd-9.0.0): riscv64 (held)\n\
+         \n\
+         Cancel all of them with /cancelmine"
+    );
+    assert_eq!(
+        format_cancel_mine(&pipelines),
+        "Cancelled pipeline #12: amd64 (running), arm64 (queued)\n\
+         Cancelled pipeline #13: riscv64 (queued)"
+    );
+    assert_eq!(
+        format_my_builds(&[]),
+        "You have no queued or running pipelines"
+    );
+    assert_eq!(
+        format_cancel_mine(&[]),
+        "You have no queued or running pipelines to cancel"
+    );
+}
+
+#[test]
+fn test_format_opened_prs() {
+    let pr = |pr_number: i64, title: &str, git_ref: &str| OpenedPr {
+        id: 1,
+        pr_number,
+        title: title.to_string(),
+        git_ref: git_ref.to_string(),
+        telegram_chat_id: 1234,
+        creation_time: chrono::DateTime::from_timestamp(1718870400, 0).unwrap(),
+    };
+
+    assert_eq!(
+        format_opened_prs(&[], &[]),
+        "No pull requests opened with /openpr yet"
+    );
+    assert_eq!(
+        format_opened_prs(
+            &[
+                pr(5001, "fd: update to 10.1.0", "fd-10.1.0"),
+                pr(4992, "llvm & clang: update to 18", "llvm-18")
+            ],
+            &[Some("open"), None]
+        ),
+        "<a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/pull/5001\">#5001</a> fd: update to 10.1.0 (fd-10.1.0, 2024-06-20), open\n<a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/pull/4992\">#4992</a> llvm &amp; clang: update to 18 (llvm-18, 2024-06-20), unknown"
+    );
+}
+
+#[test]
+fn test_format_timings() {
+    use common::JobOk;
+
+    let job_ok: JobOk = serde_json::from_str(
+        r#"{"build_success":true,"successful_packages":["llvm","fd","gcc"],"failed_package":null,"skipped_packages":[],"log_url":null,"elapsed_secs":7290,"pushpkg_success":true,"package_timings":[["llvm",3000],["fd",30],["gcc",4260]]}"#,
+    )
+    .unwrap();
+
+    // worker to db column and back
+    let column = job_ok
+        .package_timings
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()
+        .unwrap();
+    let timings: Option<PackageTimings> = column
+        .as_deref()
+        .map(serde_json::from_str)
+        .transpose()
+        .unwrap();
+    assert_eq!(timings, job_ok.package_timings);
+
+    // old workers do not report timings
+    let old: JobOk = serde_json::from_str(
+        r#"{"build_success":true,"successful_packages":[],"failed_package":null,"skipped_packages":[],"log_url":null,"elapsed_secs":1,"pushpkg_success":true}"#,
+    )
+    .unwrap();
+    assert_eq!(old.package_timings, None);
+
+    assert_eq!(
+        format_timings(
+            1,
+            &[
+                ("amd64".to_string(), timings),
+                ("riscv64".to_string(), old.package_timings)
+            ]
+        ),
+        "Package timings of pipeline #1:\n\namd64:\ngcc: 1h11m\nllvm: 50m00s\nfd: 0m30s\n\nriscv64: no timing data"
+    );
+    assert_eq!(format_timings(2, &[]), "No finished jobs in pipeline #2");
+}
+
+#[test]
+fn test_format_status() {
+    use chrono::DateTime;
+
+    let workers = [Worker {
+        id: 1,
+        hostname: "riscv-builder".to_string(),
+        arch: "riscv64".to_string(),
+        git_commit: "abcdef".to_string(),
+        memory_bytes: 16 << 30,
+        logical_cores: 8,
+        last_heartbeat_time: DateTime::from_timestamp(61, 0).unwrap(),
+        disk_free_space_bytes: 100 << 30,
+        performance: None,
+        visible: true,
+        internet_connectivity: true,
+        version: None,
+        labels: None,
+    }];
+
+    let queue = [PipelineStatus {
+        arch: "riscv64".to_string(),
+        pending: 3,
+        running: 1,
+        available_servers: 1,
+    }];
+    let s = format_status(Some(&queue), &[], &workers, None, 1);
+    assert!(s.contains(
+        "*riscv64*: 3 job\\(s\\) pending, 1 job\\(s\\) running, 1 available server\\(s\\)\n"
+    ));
+    assert!(!s.contains("unavailable"));
+    assert!(!s.contains("no progress"));
+
+    // stuck arch
+    let s = format_status(Some(&queue), &["riscv64"], &workers, None, 1);
+    assert!(s.contains(
+        "*riscv64*: 3 job\\(s\\) pending, 1 job\\(s\\) running, 1 available server\\(s\\), ⚠️ consumers present but no progress\n"
+    ));
+
+    // job counts query failed
+    let s = format_status(None, &[], &workers, None, 1);
+    assert!(s.contains("*riscv64*: 1 available server\\(s\\)\n"));
+    assert!(s.contains("*amd64*: 0 available server\\(s\\)\n"));
+    assert!(s.contains("Job counts are unavailable"));
+    assert!(!s.contains("pending"));
+    assert!(s.contains("riscv\\-builder \\(riscv64 abcdef, 8 core\\(s\\)"));
+    assert!(!s.contains("Page"));
+
+    // a single page of workers
+    let s = format_status(Some(&queue), &[], &workers, None, 2);
+    assert!(s.ends_with("Nothing on page 2, there are 1 page\\(s\\) in total"));
+    assert_eq!(parse_page_argument(""), Ok(1));
+    assert_eq!(parse_page_argument(" page=3 "), Ok(3));
+    assert!(parse_page_argument("3").is_err());
+    assert!(parse_page_argument("page=1 page=2").is_err());
+}
+
+#[test]
+fn test_format_help() {
+    let anyone = format_help(Permission::Anyone);
+    let member = format_help(Permission::Member);
+    let admin = format_help(Permission::Admin);
+
+    assert!(anyone.starts_with("BuildIt! supports the following commands:\n\n/help — "));
+    assert!(anyone.contains("\n/build — "));
+    assert!(!anyone.contains("\n/openpr — "));
+    assert!(!anyone.contains("\n/drain — "));
+    assert!(anyone.ends_with("/login with a GitHub account in aosc-dev to use them."));
+
+    assert!(member.contains("\n/build — "));
+    assert!(member.contains("\n/openpr — "));
+    assert!(member.contains("\n/bump — "));
+    assert!(!member.contains("\n/drain — "));
+    assert!(!member.contains("/tail"));
+    assert!(!member.contains("/reping"));
+    assert!(!member.contains("hidden"));
+
+    // admins get everything
+    assert_eq!(admin, Command::descriptions().to_string());
+}
+
+#[test]
+fn test_normalize_archs() {
+    use crate::routes::{parse_bot_request, BotRequest};
+
+    // the same request from Telegram and GitHub
+    let telegram = parse_build_request("fd-9.0.0 fd riscv64,mainline,amd64").unwrap();
+    let Some(BotRequest::Build {
+        archs: webhook,
+        force: false,
+    }) = parse_bot_request("@aosc-buildit-bot build riscv64,mainline,amd64")
+    else {
+        panic!("not a build request");
+    };
+    let telegram = normalize_archs(telegram.archs.unwrap()).unwrap();
+    assert_eq!(telegram, ALL_ARCH);
+    assert_eq!(normalize_archs(webhook.unwrap()).unwrap(), telegram);
+
+    // archs deduced from packages come in package order
+    assert_eq!(
+        normalize_archs("riscv64,amd64,loongson3,amd64").unwrap(),
+        ["amd64", "loongson3", "riscv64"]
+    );
+    assert_eq!(normalize_archs("noarch").unwrap(), ["noarch"]);
+    assert!(normalize_archs("noarch,amd64").is_err());
+    assert!(normalize_archs("amd64,i486").is_err());
+}
@@ -0,0 +1,304 @@
+use crate::{
+    api::opened_pr_record,
+    canary::HELD,
+    github::get_github_token,
+    models::{Job, PendingPr, Pipeline},
+    DbPool, Secret, ARGS,
+};
+use anyhow::Context;
+use buildit_utils::github::{open_pr, OpenPRRequest};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use std::path::Path;
+use teloxide::{prelude::*, types::ChatId};
+use tracing::{info, warn};
+
+/// Credentials for opening a pull request on behalf of a Telegram user
+pub struct OpenPrAuth {
+    pub token: String,
+    pub app_id: u64,
+    pub app_private_key: &'static Path,
+}
+
+/// Check the GitHub app config and get the token the chat logged in with
+pub async fn open_pr_auth(chat_id: ChatId) -> anyhow::Result<OpenPrAuth> {
+    let secret = ARGS
+        .github_secret
+        .as_ref()
+        .map(Secret::expose)
+        .context("GITHUB_SECRET is not set")?;
+    let app_id = ARGS
+        .github_app_id
+        .as_ref()
+        .and_then(|x| x.parse::<u64>().ok())
+        .context("GITHUB_APP_ID is not set")?;
+    let app_private_key = ARGS
+        .github_app_key
+        .as_deref()
+        .context("GITHUB_APP_KEY_PEM_PATH is not set")?;
+    let token = get_github_token(&chat_id, secret)
+        .await
+        .context("Failed to get GitHub token, use /login first")?
+        .access_token;
+
+    Ok(OpenPrAuth {
+        token,
+        app_id,
+        app_private_key,
+    })
+}
+
+/// State of a pipeline by the latest job of each arch
+#[derive(Debug, PartialEq, Eq)]
+pub enum BuildOutcome {
+    Pending,
+    /// Every arch succeeded
+    Green(Vec<String>),
+    /// Archs that failed, errored or were cancelled
+    Failed(Vec<String>),
+}
+
+pub fn build_outcome(archs: &str, jobs: &[Job]) -> BuildOutcome {
+    let mut green = vec![];
+    let mut failed = vec![];
+    let mut pending = false;
+    for arch in archs.split(',') {
+        // restarted jobs replace earlier ones
+        let latest = jobs
+            .iter()
+            .filter(|job| job.arch == arch)
+            .max_by_key(|job| job.id);
+        match latest.map(|job| job.status.as_str()) {
+            Some("success") => green.push(arch.to_string()),
+            Some("created") | Some("running") | Some(HELD) | None => pending = true,
+            Some(_) => failed.push(arch.to_string()),
+        }
+    }
+
+    if !failed.is_empty() {
+        BuildOutcome::Failed(failed)
+    } else if pending {
+        BuildOutcome::Pending
+    } else {
+        BuildOutcome::Green(green)
+    }
+}
+
+/// Open the pull request requested with /buildandpr once the pipeline has finished
+pub async fn pipeline_job_finished(pool: DbPool, bot: Option<Bot>, pipeline_id: i32) {
+    if let Err(err) = pipeline_job_finished_inner(pool, bot, pipeline_id).await {
+        warn!("Failed to handle pending PR of pipeline #{pipeline_id}: {err:?}");
+    }
+}
+
+async fn pipeline_job_finished_inner(
+    pool: DbPool,
+    bot: Option<Bot>,
+    pipeline_id: i32,
+) -> anyhow::Result<()> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    use crate::schema::pending_prs::dsl::pending_prs;
+    if pending_prs
+        .find(pipeline_id)
+        .first::<PendingPr>(&mut conn)
+        .optional()?
+        .is_none()
+    {
+        return Ok(());
+    }
+
+    let pipeline = crate::schema::pipelines::dsl::pipelines
+        .find(pipeline_id)
+        .first::<Pipeline>(&mut conn)?;
+    let jobs = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::pipeline_id.eq(pipeline_id))
+        .load::<Job>(&mut conn)?;
+    let outcome = build_outcome(&pipeline.archs, &jobs);
+    if outcome == BuildOutcome::Pending {
+        return Ok(());
+    }
+
+    // claim the request, jobs of the same pipeline may finish together
+    let Some(pending) = diesel::delete(pending_prs.find(pipeline_id))
+        .get_result::<PendingPr>(&mut conn)
+        .optional()?
+    else {
+        return Ok(());
+    };
+    let chat_id = ChatId(pending.telegram_chat_id);
+
+    let text = match outcome {
+        BuildOutcome::Green(archs) => {
+            info!(
+                "Pipeline #{pipeline_id} succeeded, opening PR for {}",
+                pending.git_ref
+            );
+            match open_pending_pr(&pending, &archs).await {
+                Ok((pr_number, url)) => {
+                    if let Err(err) = opened_pr_record(
+                        pool,
+                        pr_number,
+                        &pending.title,
+                        &pending.git_ref,
+                        pending.telegram_chat_id,
+                    )
+                    .await
+                    {
+                        warn!("Failed to record opened PR #{pr_number}: {err}");
+                    }
+                    format!("Pipeline #{pipeline_id} succeeded on all archs, opened PR: {url}")
+                }
+                Err(err) => format!(
+                    "Pipeline #{pipeline_id} succeeded on all archs, but failed to open PR: {err:?}"
+                ),
+            }
+        }
+        BuildOutcome::Failed(archs) => format!(
+            "Pipeline #{pipeline_id} failed on {}, not opening PR \"{}\"",
+            archs.join(", "),
+            pending.title
+        ),
+        BuildOutcome::Pending => unreachable!(),
+    };
+
+    match bot {
+        Some(bot) => {
+            bot.send_message(chat_id, text).await?;
+        }
+        None => warn!("Telegram bot not configured, dropping message: {text}"),
+    }
+    Ok(())
+}
+
+async fn open_pending_pr(pending: &PendingPr, archs: &[String]) -> anyhow::Result<(u64, String)> {
+    let auth = open_pr_auth(ChatId(pending.telegram_chat_id)).await?;
+    let archs = archs.iter().map(String::as_str).collect::<Vec<_>>();
+    Ok(open_pr(
+        auth.app_private_key,
+        &auth.token,
+        auth.app_id,
+        OpenPRRequest {
+            git_ref: pending.git_ref.clone(),
+            abbs_path: ARGS.abbs_path.clone(),
+            packages: pending.packages.clone(),
+            title: pending.title.clone(),
+            tags: None,
+            archs: Some(archs.clone()),
+            base: "stable".to_string(),
+            checked_archs: archs,
+        },
+    )
+    .await?)
+}
+
+#[test]
+fn test_build_outcome() {
+    let job = |id: i32, arch: &str, status: &str| Job {
+        id,
+        arch: arch.to_string(),
+        status: status.to_string(),
+        ..Job::fixture()
+    };
+
+    // all green, the PR is opened with every arch checked
+    let jobs = [job(1, "amd64", "success"), job(2, "arm64", "success")];
+    assert_eq!(
+        build_outcome("amd64,arm64", &jobs),
+        BuildOutcome::Green(vec!["amd64".to_string(), "arm64".to_string()])
+    );
+
+    // still building
+    let jobs = [job(1, "amd64", "success"), job(2, "arm64", "running")];
+    assert_eq!(build_outcome("amd64,arm64", &jobs), BuildOutcome::Pending);
+    assert_eq!(
+        build_outcome("amd64,arm64", &jobs[..1]),
+        BuildOutcome::Pending
+    );
+
+    // a failure means no PR, even with other archs pending
+    let jobs = [
+        job(1, "amd64", "failed"),
+        job(2, "arm64", "running"),
+        job(3, "riscv64", "error"),
+    ];
+    assert_eq!(
+        build_outcome("amd64,arm64,riscv64", &jobs),
+        BuildOutcome::Failed(vec!["amd64".to_string(), "riscv64".to_string()])
+    );
+
+    // the retried job counts
+    let jobs = [job(1, "amd64", "failed"), job(4, "amd64", "success")];
+    assert_eq!(
+        build_outcome("amd64", &jobs),
+        BuildOutcome::Green(vec!["amd64".to_string()])
+    );
+}
+
+#[tokio::test]
+async fn test_pending_pr_settled() {
+    use crate::routes::WSStateMap;
+    use chrono::Utc;
+
+    let Some(pool) = crate::test_db() else {
+        return;
+    };
+    let mut conn = pool.get().unwrap();
+    let pending_pr = |pipeline_id: i32| PendingPr {
+        pipeline_id,
+        title: "fd: update to 9.0.0".to_string(),
+        git_ref: "fd-9.0.0".to_string(),
+        packages: "fd".to_string(),
+        telegram_chat_id: 1234,
+        creation_time: Utc::now(),
+    };
+    diesel::insert_into(crate::schema::pipelines::table)
+        .values(&[
+            Pipeline {
+                telegram_user: Some(1234),
+                ..Pipeline::fixture()
+            },
+            Pipeline {
+                id: 13,
+                ..Pipeline::fixture()
+            },
+        ])
+        .execute(&mut conn)
+        .unwrap();
+    diesel::insert_into(crate::schema::jobs::table)
+        .values(&Job::fixture())
+        .execute(&mut conn)
+        .unwrap();
+    diesel::insert_into(crate::schema::pending_prs::table)
+        .values(&[pending_pr(12), pending_pr(13)])
+        .execute(&mut conn)
+        .unwrap();
+
+    // cancelled pipelines do not leave their PR pending
+    let cancelled = crate::api::pipeline_cancel_mine(
+        pool.clone(),
+        None,
+        &WSStateMap::default(),
+        1234,
+        "telegram:1234",
+        "1234",
+    )
+    .await
+    .unwrap();
+    assert_eq!(cancelled.len(), 1);
+    let pending = crate::schema::pending_prs::dsl::pending_prs
+        .load::<PendingPr>(&mut conn)
+        .unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].pipeline_id, 13);
+
+    // deleting a pipeline drops its PR request
+    diesel::delete(crate::schema::pipelines::dsl::pipelines.find(13))
+        .execute(&mut conn)
+        .unwrap();
+    assert!(crate::schema::pending_prs::dsl::pending_prs
+        .load::<PendingPr>(&mut conn)
+        .unwrap()
+        .is_empty());
+}
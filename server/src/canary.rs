@@ -0,0 +1,313 @@
+use crate::{
+    github::complete_check_runs,
+    models::{Job, Pipeline},
+    DbPool,
+};
+use anyhow::{bail, Context};
+use chrono::Utc;
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use octocrab::params::checks::CheckRunConclusion;
+use teloxide::{prelude::*, types::ChatId};
+use tracing::{info, warn};
+
+/// Status of jobs waiting for the canary job of their pipeline
+pub const HELD: &str = "held";
+
+/// Arch to build first, `None` if every arch is built right away
+pub fn canary_of<'a>(archs: &[&str], canary_arch: &'a str) -> anyhow::Result<Option<&'a str>> {
+    if !archs.contains(&canary_arch) {
+        bail!(
+            "Canary arch {canary_arch} is not among the archs to build: {}",
+            archs.join(", ")
+        );
+    }
+    // nothing to hold back
+    Ok(Some(canary_arch).filter(|_| archs.len() > 1))
+}
+
+/// Initial status of the job of an arch
+pub fn initial_status(arch: &str, canary: Option<&str>) -> &'static str {
+    match canary {
+        Some(canary) if canary != arch => HELD,
+        _ => "created",
+    }
+}
+
+/// What to do with held jobs after a job of the pipeline changed
+#[derive(Debug)]
+pub enum CanaryStep<'a> {
+    /// No held jobs, or the canary job has not finished
+    Wait,
+    /// The canary job succeeded, queue the held jobs
+    Release(Vec<&'a Job>),
+    /// The canary job failed, skip the held jobs
+    Skip { canary: &'a Job, held: Vec<&'a Job> },
+}
+
+pub fn canary_step(jobs: &[Job]) -> CanaryStep<'_> {
+    let held = jobs
+        .iter()
+        .filter(|job| job.status == HELD)
+        .collect::<Vec<_>>();
+    // the latest attempt of the canary arch counts
+    let Some(canary) = jobs
+        .iter()
+        .filter(|job| job.status != HELD)
+        .max_by_key(|job| job.id)
+    else {
+        return CanaryStep::Wait;
+    };
+    if held.is_empty() {
+        return CanaryStep::Wait;
+    }
+
+    match canary.status.as_str() {
+        "success" => CanaryStep::Release(held),
+        "created" | "running" => CanaryStep::Wait,
+        _ => CanaryStep::Skip { canary, held },
+    }
+}
+
+/// How the canary job ended without success, e.g. failed or expired
+fn canary_outcome(canary: &Job) -> &str {
+    match canary.status.as_str() {
+        "error" => "failed",
+        status => status,
+    }
+}
+
+pub fn format_canary_failed(canary: &Job, held: &[&Job]) -> String {
+    format!(
+        "Canary job #{} of pipeline #{} {} on {}, skipped {}",
+        canary.id,
+        canary.pipeline_id,
+        canary_outcome(canary),
+        canary.arch,
+        held.iter()
+            .map(|job| job.arch.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// Fan out to the held archs once the canary job has succeeded, or skip them
+pub async fn canary_job_finished(pool: DbPool, bot: Option<Bot>, pipeline_id: i32) {
+    if let Err(err) = canary_job_finished_inner(pool, bot, pipeline_id).await {
+        warn!("Failed to handle canary of pipeline #{pipeline_id}: {err:?}");
+    }
+}
+
+async fn canary_job_finished_inner(
+    pool: DbPool,
+    bot: Option<Bot>,
+    pipeline_id: i32,
+) -> anyhow::Result<()> {
+    use crate::schema::jobs::dsl::{creation_time, error_message, finish_time, id, jobs, status};
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let pipeline_jobs = jobs
+        .filter(crate::schema::jobs::dsl::pipeline_id.eq(pipeline_id))
+        .load::<Job>(&mut conn)?;
+    match canary_step(&pipeline_jobs) {
+        CanaryStep::Wait => {}
+        CanaryStep::Release(held) => {
+            let ids = held.iter().map(|job| job.id).collect::<Vec<_>>();
+            // restart the queue time, so the jobs do not expire right away
+            let count = diesel::update(jobs.filter(id.eq_any(&ids)).filter(status.eq(HELD)))
+                .set((status.eq("created"), creation_time.eq(Utc::now())))
+                .execute(&mut conn)?;
+            info!("Canary of pipeline #{pipeline_id} succeeded, queued {count} held job(s)");
+        }
+        CanaryStep::Skip { canary, held } => {
+            let ids = held.iter().map(|job| job.id).collect::<Vec<_>>();
+            let count = diesel::update(jobs.filter(id.eq_any(&ids)).filter(status.eq(HELD)))
+                .set((
+                    status.eq("cancelled"),
+                    finish_time.eq(Utc::now()),
+                    error_message.eq(format!(
+                        "Skipped because canary job #{} {} on {}",
+                        canary.id,
+                        canary_outcome(canary),
+                        canary.arch
+                    )),
+                ))
+                .execute(&mut conn)?;
+            if count == 0 {
+                // handled already
+                return Ok(());
+            }
+            info!(
+                "Canary of pipeline #{pipeline_id} {}, skipped {count} held job(s)",
+                canary_outcome(canary)
+            );
+
+            let pipeline = crate::schema::pipelines::dsl::pipelines
+                .find(pipeline_id)
+                .first::<Pipeline>(&mut conn)?;
+            complete_check_runs(
+                &pipeline.repo,
+                held.iter().copied(),
+                CheckRunConclusion::Skipped,
+            )
+            .await;
+            if let (Some(bot), Some(chat_id)) = (bot, pipeline.telegram_user) {
+                bot.send_message(ChatId(chat_id), format_canary_failed(canary, &held))
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_canary() {
+    let job = |id: i32, arch: &str, status: &str| Job {
+        id,
+        arch: arch.to_string(),
+        status: status.to_string(),
+        ..Job::fixture()
+    };
+
+    // only the canary arch is queued at first
+    let archs = ["amd64", "arm64", "riscv64"];
+    let canary = canary_of(&archs, "amd64").unwrap();
+    assert_eq!(canary, Some("amd64"));
+    assert_eq!(initial_status("amd64", canary), "created");
+    assert_eq!(initial_status("arm64", canary), HELD);
+    assert_eq!(initial_status("arm64", None), "created");
+    assert_eq!(canary_of(&["amd64"], "amd64").unwrap(), None);
+    assert!(canary_of(&["arm64", "riscv64"], "amd64").is_err());
+
+    let step = |jobs: &[Job]| match canary_step(jobs) {
+        CanaryStep::Wait => "wait".to_string(),
+        CanaryStep::Release(held) => {
+            format!(
+                "release {:?}",
+                held.iter().map(|job| job.id).collect::<Vec<_>>()
+            )
+        }
+        CanaryStep::Skip { canary, held } => format!(
+            "skip {:?} after #{}",
+            held.iter().map(|job| job.id).collect::<Vec<_>>(),
+            canary.id
+        ),
+    };
+
+    // the canary is still building
+    let jobs = [
+        job(1, "amd64", "running"),
+        job(2, "arm64", HELD),
+        job(3, "riscv64", HELD),
+    ];
+    assert_eq!(step(&jobs), "wait");
+
+    // the canary passes: fan out to the rest
+    let jobs = [
+        job(1, "amd64", "success"),
+        job(2, "arm64", HELD),
+        job(3, "riscv64", HELD),
+    ];
+    assert_eq!(step(&jobs), "release [2, 3]");
+
+    // the canary fails: no fanout
+    let jobs = [
+        job(1, "amd64", "failed"),
+        job(2, "arm64", HELD),
+        job(3, "riscv64", HELD),
+    ];
+    assert_eq!(step(&jobs), "skip [2, 3] after #1");
+    let CanaryStep::Skip { canary, held } = canary_step(&jobs) else {
+        unreachable!()
+    };
+    assert_eq!(
+        format_canary_failed(canary, &held),
+        "Canary job #1 of pipeline #12 failed on amd64, skipped arm64, riscv64"
+    );
+
+    // a flaky retry of the canary is waited for
+    let jobs = [
+        job(1, "amd64", "failed"),
+        job(2, "arm64", HELD),
+        job(3, "riscv64", HELD),
+        job(4, "amd64", "created"),
+    ];
+    assert_eq!(step(&jobs), "wait");
+
+    // already released or skipped
+    let jobs = [
+        job(1, "amd64", "success"),
+        job(2, "arm64", "running"),
+        job(3, "riscv64", "created"),
+    ];
+    assert_eq!(step(&jobs), "wait");
+}
+
+#[tokio::test]
+async fn test_canary_settled() {
+    use crate::routes::{job_finished, WSStateMap};
+
+    let Some(pool) = crate::test_db() else {
+        return;
+    };
+    let mut conn = pool.get().unwrap();
+    let job = |id: i32, pipeline_id: i32, arch: &str, status: &str| Job {
+        id,
+        pipeline_id,
+        arch: arch.to_string(),
+        status: status.to_string(),
+        ..Job::fixture()
+    };
+    diesel::insert_into(crate::schema::pipelines::table)
+        .values(&[12, 13, 14].map(|id| Pipeline {
+            id,
+            archs: "amd64,arm64".to_string(),
+            ..Pipeline::fixture()
+        }))
+        .execute(&mut conn)
+        .unwrap();
+    diesel::insert_into(crate::schema::jobs::table)
+        .values(&[
+            job(1, 12, "amd64", "expired"),
+            job(2, 12, "arm64", HELD),
+            job(3, 13, "amd64", "lost"),
+            job(4, 13, "arm64", HELD),
+            job(5, 14, "amd64", "created"),
+            job(6, 14, "arm64", HELD),
+        ])
+        .execute(&mut conn)
+        .unwrap();
+    let get = |conn: &mut diesel::PgConnection, job_id: i32| {
+        crate::schema::jobs::dsl::jobs
+            .find(job_id)
+            .first::<Job>(conn)
+            .unwrap()
+    };
+
+    // an expired or lost canary skips the held jobs
+    job_finished(pool.clone(), None, 12).await;
+    job_finished(pool.clone(), None, 13).await;
+    let held = get(&mut conn, 2);
+    assert_eq!(held.status, "cancelled");
+    assert_eq!(
+        held.error_message.as_deref(),
+        Some("Skipped because canary job #1 expired on amd64")
+    );
+    assert_eq!(get(&mut conn, 4).status, "cancelled");
+
+    // a moved canary is waited for
+    crate::api::queue_move(
+        pool.clone(),
+        None,
+        &WSStateMap::default(),
+        14,
+        "amd64",
+        "riscv64",
+        "telegram:1234",
+    )
+    .await
+    .unwrap();
+    assert_eq!(get(&mut conn, 5).status, "cancelled");
+    assert_eq!(get(&mut conn, 6).status, HELD);
+}
@@ -0,0 +1,184 @@
+use crate::{
+    api::sort_archs,
+    bot::format_duration,
+    models::{Job, Pipeline},
+    DbPool,
+};
+use anyhow::{anyhow, Context};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+
+/// Parse `pipeline-id pipeline-id`
+pub fn parse_compare_request(arguments: &str) -> Result<(i32, i32), String> {
+    match arguments.split_ascii_whitespace().collect::<Vec<_>>()[..] {
+        [before, after] => {
+            let parse = |id: &str| {
+                id.trim_start_matches('#')
+                    .parse::<i32>()
+                    .map_err(|err| format!("Bad pipeline id {id}: {err}"))
+            };
+            Ok((parse(before)?, parse(after)?))
+        }
+        _ => Err("Expected two pipeline ids".to_string()),
+    }
+}
+
+/// Pipeline with all of its jobs, including retried ones
+pub async fn pipeline_with_jobs(
+    pool: DbPool,
+    pipeline_id: i32,
+) -> anyhow::Result<(Pipeline, Vec<Job>)> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let pipeline = crate::schema::pipelines::dsl::pipelines
+        .find(pipeline_id)
+        .get_result::<Pipeline>(&mut conn)
+        .optional()?
+        .ok_or_else(|| anyhow!("Pipeline #{pipeline_id} not found"))?;
+    let jobs = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::pipeline_id.eq(pipeline_id))
+        .load::<Job>(&mut conn)?;
+    Ok((pipeline, jobs))
+}
+
+/// Restarted jobs replace earlier ones
+fn latest_job<'a>(jobs: &'a [Job], arch: &str) -> Option<&'a Job> {
+    jobs.iter()
+        .filter(|job| job.arch == arch)
+        .max_by_key(|job| job.id)
+}
+
+fn format_delta(before: i64, after: i64) -> String {
+    let delta = after - before;
+    let sign = if delta < 0 { '-' } else { '+' };
+    format!("{sign}{}", format_duration(delta.abs()))
+}
+
+/// Per-arch differences from the `before` pipeline to the `after` one
+pub fn format_compare(before: (&Pipeline, &[Job]), after: (&Pipeline, &[Job])) -> String {
+    let (before_pipeline, before_jobs) = before;
+    let (after_pipeline, after_jobs) = after;
+    let mut res = format!(
+        "Pipeline #{} ({}) → #{} ({})",
+        before_pipeline.id,
+        &before_pipeline.git_sha[..before_pipeline.git_sha.len().min(8)],
+        after_pipeline.id,
+        &after_pipeline.git_sha[..after_pipeline.git_sha.len().min(8)]
+    );
+
+    // packages built by only one of them
+    let before_packages = before_pipeline.packages.split(',').collect::<Vec<_>>();
+    let after_packages = after_pipeline.packages.split(',').collect::<Vec<_>>();
+    let added = after_packages
+        .iter()
+        .filter(|pkg| !before_packages.contains(pkg))
+        .copied()
+        .collect::<Vec<_>>();
+    let removed = before_packages
+        .iter()
+        .filter(|pkg| !after_packages.contains(pkg))
+        .copied()
+        .collect::<Vec<_>>();
+    if !added.is_empty() {
+        res += &format!("\nAdded package(s): {}", added.join(", "));
+    }
+    if !removed.is_empty() {
+        res += &format!("\nRemoved package(s): {}", removed.join(", "));
+    }
+
+    let mut archs = before_pipeline
+        .archs
+        .split(',')
+        .chain(after_pipeline.archs.split(','))
+        .collect::<Vec<_>>();
+    sort_archs(&mut archs);
+    res += "\n";
+    for arch in archs {
+        let line = match (latest_job(before_jobs, arch), latest_job(after_jobs, arch)) {
+            (None, None) => continue,
+            (Some(job), None) => format!("only in #{}: {}", before_pipeline.id, job.status),
+            (None, Some(job)) => format!("only in #{}: {}", after_pipeline.id, job.status),
+            (Some(before), Some(after)) => {
+                let mut line = if before.status == after.status {
+                    after.status.clone()
+                } else {
+                    format!("{} → {}", before.status, after.status)
+                };
+                if let (Some(before), Some(after)) = (before.elapsed_secs, after.elapsed_secs) {
+                    line += &format!(" ({})", format_delta(before, after));
+                }
+                if let Some(failed) = after
+                    .failed_package
+                    .as_deref()
+                    .filter(|pkg| before.failed_package.as_deref() != Some(*pkg))
+                {
+                    line += &format!(", newly failed: {failed}");
+                }
+                line
+            }
+        };
+        res += &format!("\n{arch}: {line}");
+    }
+    res
+}
+
+#[test]
+fn test_compare() {
+    let pipeline = |id: i32, packages: &str, archs: &str| Pipeline {
+        id,
+        packages: packages.to_string(),
+        archs: archs.to_string(),
+        git_sha: format!("{id:08}34acef168fc5ec454d3825fc864964951b1"),
+        telegram_user: Some(1234),
+        ..Pipeline::fixture()
+    };
+    let job = |id: i32, arch: &str, status: &str, elapsed: i64, failed: Option<&str>| Job {
+        id,
+        arch: arch.to_string(),
+        status: status.to_string(),
+        failed_package: failed.map(str::to_string),
+        elapsed_secs: Some(elapsed),
+        ..Job::fixture()
+    };
+
+    // green before, partially failed after
+    let green = pipeline(12, "fd,ripgrep", "amd64,arm64");
+    let green_jobs = [
+        job(1, "amd64", "success", 600, None),
+        job(2, "arm64", "success", 1200, None),
+    ];
+    let failed = pipeline(13, "fd,ripgrep,bat", "amd64,arm64,riscv64");
+    let failed_jobs = [
+        job(3, "amd64", "success", 540, None),
+        job(4, "arm64", "failed", 1290, Some("ripgrep")),
+        job(5, "riscv64", "running", 30, None),
+    ];
+    assert_eq!(
+        format_compare((&green, &green_jobs), (&failed, &failed_jobs)),
+        "Pipeline #12 (00000012) → #13 (00000013)\n\
+         Added package(s): bat\n\
+         \n\
+         amd64: success (-1m00s)\n\
+         arm64: success → failed (+1m30s), newly failed: ripgrep\n\
+         riscv64: only in #13: running"
+    );
+
+    // the other way around, with a retried job
+    let retried = [
+        job(3, "amd64", "failed", 60, Some("fd")),
+        job(6, "amd64", "success", 600, None),
+    ];
+    assert_eq!(
+        format_compare((&failed, &retried), (&green, &green_jobs)),
+        "Pipeline #13 (00000013) → #12 (00000012)\n\
+         Removed package(s): bat\n\
+         \n\
+         amd64: success (+0m00s)\n\
+         arm64: only in #12: success"
+    );
+
+    assert_eq!(parse_compare_request("12 #13"), Ok((12, 13)));
+    assert!(parse_compare_request("12").is_err());
+    assert!(parse_compare_request("12 fd").is_err());
+}
@@ -0,0 +1,263 @@
+use crate::{
+    canary::HELD,
+    formatter::{to_html_new_pipeline_summary, FAILED, SUCCESS},
+    models::{Job, Pipeline},
+    DbPool, ARGS,
+};
+use anyhow::Context;
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use once_cell::sync::Lazy;
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use teloxide::{
+    prelude::*,
+    types::{MessageId, ParseMode},
+    ApiError, RequestError,
+};
+use tracing::warn;
+
+/// Minimum interval between edits of a pipeline summary, to respect telegram rate limits
+const DASHBOARD_EDIT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Message to edit for the pipeline, if its summary was sent to telegram
+pub fn summary_message(pipeline: &Pipeline) -> Option<(ChatId, MessageId)> {
+    Some((
+        ChatId(pipeline.telegram_user?),
+        MessageId(pipeline.telegram_message_id?),
+    ))
+}
+
+/// Remember the summary message of a new pipeline, to edit it as jobs progress
+pub async fn summary_message_set(
+    pool: DbPool,
+    pipeline: &mut Pipeline,
+    message_id: MessageId,
+) -> anyhow::Result<()> {
+    use crate::schema::pipelines::dsl::{pipelines, telegram_message_id};
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+    diesel::update(pipelines.find(pipeline.id))
+        .set(telegram_message_id.eq(message_id.0))
+        .execute(&mut conn)?;
+    pipeline.telegram_message_id = Some(message_id.0);
+    Ok(())
+}
+
+/// Restarted jobs replace earlier ones
+fn latest_job<'a>(jobs: &'a [Job], arch: &str) -> Option<&'a Job> {
+    jobs.iter()
+        .filter(|job| job.arch == arch)
+        .max_by_key(|job| job.id)
+}
+
+fn is_pending(job: Option<&Job>) -> bool {
+    job.is_none_or(|job| ["created", "running", HELD].contains(&job.status.as_str()))
+}
+
+/// Summary of the pipeline with the state of each arch
+pub fn format_dashboard(pipeline: &Pipeline, jobs: &[Job]) -> String {
+    let mut res = to_html_new_pipeline_summary(pipeline, None) + "\n";
+    for arch in pipeline.archs.split(',') {
+        let state = match latest_job(jobs, arch) {
+            None => "⏳ queued".to_string(),
+            Some(job) => match job.status.as_str() {
+                "created" => "⏳ queued".to_string(),
+                HELD => "⏸ held".to_string(),
+                "running" => "🔨 running".to_string(),
+                "success" => format!("{SUCCESS} success"),
+                status => format!(
+                    "{FAILED} {status}{}",
+                    job.failed_package
+                        .as_deref()
+                        .map(|pkg| format!(" at {}", teloxide::utils::html::escape(pkg)))
+                        .unwrap_or_default()
+                ),
+            },
+        };
+        res += &format!("\n<b>{arch}</b>: {state}");
+    }
+    res
+}
+
+/// Pipelines whose summary was edited recently
+#[derive(Debug, Default)]
+pub struct EditThrottle {
+    last_edit: BTreeMap<i32, Instant>,
+}
+
+impl EditThrottle {
+    /// Whether to edit the summary now, the final state is never skipped
+    pub fn due(&mut self, pipeline_id: i32, now: Instant, finished: bool) -> bool {
+        if finished {
+            self.last_edit.remove(&pipeline_id);
+            return true;
+        }
+        let due = self
+            .last_edit
+            .get(&pipeline_id)
+            .map(|last_edit| now.duration_since(*last_edit) >= DASHBOARD_EDIT_INTERVAL)
+            .unwrap_or(true);
+        if due {
+            self.last_edit.insert(pipeline_id, now);
+        }
+        due
+    }
+}
+
+static DASHBOARD_EDITS: Lazy<Mutex<EditThrottle>> =
+    Lazy::new(|| Mutex::new(EditThrottle::default()));
+
+/// Replace the text of the summary message
+pub async fn edit_summary(
+    bot: &Bot,
+    chat_id: ChatId,
+    message_id: MessageId,
+    text: &str,
+) -> anyhow::Result<()> {
+    match bot
+        .edit_message_text(chat_id, message_id, text)
+        .parse_mode(ParseMode::Html)
+        .disable_web_page_preview(true)
+        .await
+    {
+        // e.g. a job started and finished between two edits
+        Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Edit the summary of the pipeline into the current state of its archs, if
+/// live summaries are enabled
+pub async fn refresh_summary(pool: DbPool, bot: Option<Bot>, pipeline_id: i32) {
+    let Some(bot) = bot.filter(|_| ARGS.live_summary == Some(true)) else {
+        return;
+    };
+    if let Err(err) = refresh_summary_inner(pool, &bot, pipeline_id).await {
+        warn!("Failed to edit summary of pipeline #{pipeline_id}: {err:?}");
+    }
+}
+
+async fn refresh_summary_inner(pool: DbPool, bot: &Bot, pipeline_id: i32) -> anyhow::Result<()> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let pipeline = crate::schema::pipelines::dsl::pipelines
+        .find(pipeline_id)
+        .first::<Pipeline>(&mut conn)?;
+    let Some((chat_id, message_id)) = summary_message(&pipeline) else {
+        return Ok(());
+    };
+    let jobs = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::pipeline_id.eq(pipeline_id))
+        .load::<Job>(&mut conn)?;
+
+    let finished = pipeline
+        .archs
+        .split(',')
+        .all(|arch| !is_pending(latest_job(&jobs, arch)));
+    if !DASHBOARD_EDITS
+        .lock()
+        .unwrap()
+        .due(pipeline_id, Instant::now(), finished)
+    {
+        return Ok(());
+    }
+    edit_summary(
+        bot,
+        chat_id,
+        message_id,
+        &format_dashboard(&pipeline, &jobs),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_dashboard() {
+    use axum::{routing::post, Json, Router};
+    let pipeline = |telegram_message_id: Option<i32>| Pipeline {
+        archs: "amd64,arm64,riscv64".to_string(),
+        telegram_user: Some(1234),
+        telegram_message_id,
+        ..Pipeline::fixture()
+    };
+    let job = |id: i32, arch: &str, status: &str, failed: Option<&str>| Job {
+        id,
+        arch: arch.to_string(),
+        status: status.to_string(),
+        failed_package: failed.map(str::to_string),
+        ..Job::fixture()
+    };
+
+    // the pipeline leads back to its summary message
+    assert_eq!(summary_message(&pipeline(None)), None);
+    let pipeline = pipeline(Some(42));
+    assert_eq!(
+        summary_message(&pipeline),
+        Some((ChatId(1234), MessageId(42)))
+    );
+
+    let jobs = [
+        job(1, "amd64", "failed", Some("fd")),
+        job(2, "arm64", "running", None),
+        job(3, "amd64", "success", None),
+    ];
+    let text = format_dashboard(&pipeline, &jobs);
+    assert!(text.starts_with(&to_html_new_pipeline_summary(&pipeline, None)));
+    assert!(text.ends_with(
+        "\n\n<b>amd64</b>: ✅️ success\n<b>arm64</b>: 🔨 running\n<b>riscv64</b>: ⏳ queued"
+    ));
+    let text = format_dashboard(&pipeline, &[job(4, "riscv64", "failed", Some("fd"))]);
+    assert!(text.ends_with("\n<b>riscv64</b>: ❌ failed at fd"));
+
+    // edits are throttled, but the final state is always shown
+    let mut throttle = EditThrottle::default();
+    let now = Instant::now();
+    assert!(throttle.due(12, now, false));
+    assert!(!throttle.due(12, now + Duration::from_secs(1), false));
+    assert!(throttle.due(13, now + Duration::from_secs(1), false));
+    assert!(throttle.due(12, now + DASHBOARD_EDIT_INTERVAL, false));
+    assert!(throttle.due(12, now + DASHBOARD_EDIT_INTERVAL, true));
+
+    // mocked bot api recording which message is edited
+    let edited = std::sync::Arc::new(Mutex::new(vec![]));
+    let recorder = edited.clone();
+    let app = Router::new().route(
+        "/bottoken/EditMessageText",
+        post(move |Json(req): Json<serde_json::Value>| async move {
+            recorder.lock().unwrap().push((
+                req["chat_id"].as_i64(),
+                req["message_id"].as_i64(),
+                req["parse_mode"].as_str().map(str::to_string),
+            ));
+            Json(serde_json::json!({
+                "ok": false,
+                "error_code": 400,
+                "description": "Bad Request: message is not modified: specified new message content and reply markup are exactly the same as a current content and reply markup of the message",
+            }))
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    let bot =
+        Bot::new("token").set_api_url(reqwest::Url::parse(&format!("http://{addr}/")).unwrap());
+
+    let (chat_id, message_id) = summary_message(&pipeline).unwrap();
+    edit_summary(
+        &bot,
+        chat_id,
+        message_id,
+        &format_dashboard(&pipeline, &jobs),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        *edited.lock().unwrap(),
+        [(Some(1234), Some(42), Some("HTML".to_string()))]
+    );
+}
@@ -0,0 +1,88 @@
+use crate::{DispatchMode, ALL_ARCH};
+
+/// Routing key a job is queued with, noarch jobs are built on amd64
+pub fn routing_key(arch: &str) -> &str {
+    if arch == "noarch" {
+        "amd64"
+    } else {
+        arch
+    }
+}
+
+/// Whether the routing key matches an arch pattern a worker serves, `*`
+/// matches any characters, e.g. `loong*`
+pub fn pattern_matches(pattern: &str, key: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == key,
+        Some((prefix, rest)) => key.strip_prefix(prefix).is_some_and(|key| {
+            (0..=key.len())
+                .filter(|i| key.is_char_boundary(*i))
+                .any(|i| pattern_matches(rest, &key[i..]))
+        }),
+    }
+}
+
+/// Archs of the jobs handed out to a worker of `worker_arch` serving `patterns`
+pub fn worker_job_archs(mode: DispatchMode, worker_arch: &str, patterns: &[String]) -> Vec<String> {
+    let patterns = match mode {
+        // only the queue of its own arch
+        DispatchMode::PerArch => vec![worker_arch.to_string()],
+        DispatchMode::Shared if patterns.is_empty() => vec![worker_arch.to_string()],
+        DispatchMode::Shared => patterns.to_vec(),
+    };
+
+    let mut archs = ALL_ARCH
+        .iter()
+        .copied()
+        .chain([worker_arch, "noarch"])
+        .filter(|arch| {
+            patterns
+                .iter()
+                .any(|pattern| pattern_matches(pattern, routing_key(arch)))
+        })
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    archs.sort();
+    archs.dedup();
+    archs
+}
+
+#[test]
+fn test_dispatch() {
+    // jobs reach workers bound to a pattern matching their routing key
+    assert!(pattern_matches("loongarch64", routing_key("loongarch64")));
+    assert!(pattern_matches("loong*", routing_key("loongson3")));
+    assert!(pattern_matches("*64", routing_key("noarch")));
+    assert!(pattern_matches("*", routing_key("riscv64")));
+    assert!(pattern_matches("r*v*4", "riscv64"));
+    assert!(!pattern_matches("loong*", "amd64"));
+    assert!(!pattern_matches("arm", "arm64"));
+    assert!(!pattern_matches("*el", "riscv64"));
+
+    let archs = |mode: DispatchMode, arch: &str, patterns: &[&str]| {
+        worker_job_archs(
+            mode,
+            arch,
+            &patterns.iter().map(|s| s.to_string()).collect::<Vec<_>>(),
+        )
+    };
+
+    // one queue per arch, patterns are not used
+    assert_eq!(
+        archs(DispatchMode::PerArch, "amd64", &[]),
+        ["amd64", "noarch"]
+    );
+    assert_eq!(archs(DispatchMode::PerArch, "arm64", &["*"]), ["arm64"]);
+
+    // shared queue, routed by the patterns of the worker
+    assert_eq!(
+        archs(DispatchMode::Shared, "loongarch64", &["loong*"]),
+        ["loongarch64", "loongson3"]
+    );
+    assert_eq!(
+        archs(DispatchMode::Shared, "amd64", &["amd64", "arm64"]),
+        ["amd64", "arm64", "noarch"]
+    );
+    assert_eq!(archs(DispatchMode::Shared, "riscv64", &[]), ["riscv64"]);
+    assert_eq!(archs(DispatchMode::Shared, "mips64r6el", &["*"]).len(), 8);
+}
@@ -0,0 +1,119 @@
+use crate::{api::active_job_counts, DbPool};
+use anyhow::bail;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use teloxide::{prelude::*, types::ChatId};
+use tracing::{info, warn};
+
+/// Set by /drain before a planned restart
+///
+/// Unlike stopping the server, draining keeps handing out queued jobs to
+/// workers, only new submissions are refused.
+pub static DRAINING: AtomicBool = AtomicBool::new(false);
+
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+pub fn set_draining(draining: bool) {
+    DRAINING.store(draining, Ordering::SeqCst);
+}
+
+/// Refuse new submissions while draining
+pub fn check_draining(draining: bool) -> anyhow::Result<()> {
+    if draining {
+        bail!("Server is draining for a restart, new builds are not accepted, try later");
+    }
+    Ok(())
+}
+
+/// Report once when a drain has finished
+#[derive(Default)]
+pub struct DrainMonitor {
+    notified: bool,
+}
+
+impl DrainMonitor {
+    /// Whether the drain has just finished
+    pub fn update(&mut self, draining: bool, queued: i64, running: i64) -> bool {
+        if !draining {
+            // report again on the next drain
+            self.notified = false;
+            return false;
+        }
+        if self.notified || queued > 0 || running > 0 {
+            return false;
+        }
+        self.notified = true;
+        true
+    }
+}
+
+pub async fn drain_monitor_inner(
+    pool: DbPool,
+    bot: &Bot,
+    chat_id: ChatId,
+    monitor: &mut DrainMonitor,
+) -> anyhow::Result<()> {
+    loop {
+        let draining = is_draining();
+        let (queued, running) = if draining {
+            active_job_counts(pool.clone()).await?
+        } else {
+            (0, 0)
+        };
+
+        if monitor.update(draining, queued, running) {
+            info!("Queues have drained");
+            if let Err(err) = bot
+                .send_message(
+                    chat_id,
+                    "✅ All queued and running jobs have finished, safe to restart",
+                )
+                .await
+            {
+                warn!("Failed to send drain notification: {}", err);
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(30)).await;
+    }
+}
+
+pub async fn drain_monitor(pool: DbPool, bot: Bot, chat_id: ChatId) {
+    // kept across restarts of the loop to avoid repeating notifications
+    let mut monitor = DrainMonitor::default();
+    loop {
+        info!("Starting drain monitor");
+        if let Err(err) = drain_monitor_inner(pool.clone(), &bot, chat_id, &mut monitor).await {
+            warn!("Got error running drain monitor: {}", err);
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+#[test]
+fn test_drain() {
+    assert!(check_draining(false).is_ok());
+    assert!(check_draining(true).is_err());
+
+    let mut monitor = DrainMonitor::default();
+
+    // idle server that is not draining
+    assert!(!monitor.update(false, 0, 0));
+
+    // backlog is still processed
+    assert!(!monitor.update(true, 3, 2));
+    assert!(!monitor.update(true, 0, 1));
+
+    // drained, reported once
+    assert!(monitor.update(true, 0, 0));
+    assert!(!monitor.update(true, 0, 0));
+
+    // undrain and drain again
+    assert!(!monitor.update(false, 5, 0));
+    assert!(!monitor.update(true, 1, 0));
+    assert!(monitor.update(true, 0, 0));
+}
@@ -1,160 +1,597 @@
-use crate::models::{Job, Pipeline};
+use crate::{
+    api::{sort_archs, PipelineNotes, UnchangedPackages},
+    models::{Job, Pipeline},
+    template::Template,
+    triage::{classify_failure, FailureKind},
+};
 use common::JobOk;
-use std::borrow::Cow;
+use std::{borrow::Cow, collections::BTreeMap};
 
 pub const SUCCESS: &str = "✅️";
 pub const FAILED: &str = "❌";
 pub const SUCCESS_TEXT: &str = "successfully";
 pub const FAILED_TEXT: &str = "unsuccessfully";
 
-pub fn to_html_new_pipeline_summary(
-    pipeline_id: i32,
-    git_branch: &str,
-    git_sha: &str,
-    github_pr: Option<u64>,
-    archs: &[&str],
-    packages: &[&str],
-) -> String {
+pub fn to_html_new_pipeline_summary(pipeline: &Pipeline, unchanged_since: Option<i32>) -> String {
+    let Pipeline {
+        id: pipeline_id,
+        git_branch,
+        git_sha,
+        github_pr,
+        repo,
+        ..
+    } = pipeline;
     format!(
         r#"<b><u>New Pipeline Summary</u></b>
 
 <b>Pipeline</b>: <a href="https://buildit.aosc.io/pipelines/{}">#{}</a>
 <b>Git branch</b>: {}
-<b>Git commit</b>: <a href="https://github.com/AOSC-Dev/aosc-os-abbs/commit/{}">{}</a>{}
+<b>Git commit</b>: <a href="https://github.com/{}/commit/{}">{}</a>{}
 <b>Architecture(s)</b>: {}
-<b>Package(s)</b>: {}"#,
+<b>Package(s)</b>: {}{}"#,
         pipeline_id,
         pipeline_id,
         git_branch,
+        repo,
         git_sha,
         &git_sha[..8],
         if let Some(pr) = github_pr {
-            format!("\n<b>GitHub PR</b>: <a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/pull/{}\">#{}</a>", pr, pr)
+            format!(
+                "\n<b>GitHub PR</b>: <a href=\"https://github.com/{}/pull/{}\">#{}</a>",
+                repo, pr, pr
+            )
+        } else {
+            String::new()
+        },
+        pipeline.archs.replace(',', ", "),
+        pipeline.packages.replace(',', ", "),
+        if let Some(id) = unchanged_since {
+            format!("\n<b>Note</b>: inputs unchanged since pipeline <a href=\"https://buildit.aosc.io/pipelines/{}\">#{}</a>", id, id)
         } else {
             String::new()
         },
-        archs.join(", "),
-        packages.join(", "),
     )
 }
 
-pub fn to_html_build_result(
+/// New pipeline summary, from the template if configured
+pub fn new_pipeline_summary(
     pipeline: &Pipeline,
-    job: &Job,
-    job_ok: &JobOk,
-    worker_hostname: &str,
-    worker_arch: &str,
-    success: bool,
+    unchanged_since: Option<i32>,
+    template: Option<&Template>,
 ) -> String {
-    let JobOk {
-        successful_packages,
-        failed_package,
-        skipped_packages,
-        log_url,
-        elapsed_secs,
-        ..
-    } = job_ok;
+    let Some(template) = template else {
+        return to_html_new_pipeline_summary(pipeline, unchanged_since);
+    };
 
+    template.render(&BTreeMap::from([
+        ("pipeline_id", pipeline.id.to_string()),
+        (
+            "pipeline_url",
+            format!("https://buildit.aosc.io/pipelines/{}", pipeline.id),
+        ),
+        ("git_branch", pipeline.git_branch.clone()),
+        ("git_sha", pipeline.git_sha.clone()),
+        ("git_commit", pipeline.git_sha[..8].to_string()),
+        (
+            "github_pr",
+            pipeline
+                .github_pr
+                .map(|pr| pr.to_string())
+                .unwrap_or_default(),
+        ),
+        ("archs", pipeline.archs.replace(',', ", ")),
+        ("packages", pipeline.packages.replace(',', ", ")),
+        (
+            "unchanged_since",
+            unchanged_since.map(|id| id.to_string()).unwrap_or_default(),
+        ),
+    ]))
+}
+
+/// Line appended to the new pipeline summary for packages left out
+pub fn to_html_unchanged_packages(unchanged: Option<&UnchangedPackages>) -> String {
+    let Some(unchanged) = unchanged else {
+        return String::new();
+    };
     format!(
-        r#"{} Job {} completed on {} ({})
+        "\n<b>Skipped package(s)</b>: {} (unchanged since <a href=\"https://buildit.aosc.io/pipelines/{}\">#{}</a>, previously {})",
+        unchanged.packages.join(", "),
+        unchanged.since,
+        unchanged.since,
+        SUCCESS
+    )
+}
 
-<b>Job</b>: {}
-<b>Pipeline</b>: {}
-<b>Enqueue time</b>: {}
-<b>Time elapsed</b>: {}
-<b>Git commit</b>: {}
-<b>Git branch</b>: {}
-{}<b>Architecture</b>: {}
-<b>Package(s) to build</b>: {}
-<b>Package(s) successfully built</b>: {}
-<b>Package(s) failed to build</b>: {}
-<b>Package(s) not built due to previous build failure</b>: {}
-
-{}"#,
-        if success { SUCCESS } else { FAILED },
-        if success { SUCCESS_TEXT } else { FAILED_TEXT },
-        worker_hostname,
-        worker_arch,
+/// Line appended to the new pipeline summary for unknown packages left out
+pub fn to_html_dropped_packages(dropped: &[String]) -> String {
+    if dropped.is_empty() {
+        return String::new();
+    }
+    format!(
+        "\n<b>Dropped unknown package(s)</b>: {}",
+        teloxide::utils::html::escape(&dropped.join(", "))
+    )
+}
+
+/// Lines appended to the new pipeline summary for what was left out of it
+pub fn to_html_pipeline_notes(notes: &PipelineNotes) -> String {
+    let mut res = String::new();
+    if let Some(note) = &notes.excluded_archs {
+        res += &format!("\n<b>Note</b>: {}", teloxide::utils::html::escape(note));
+    }
+    if !notes.full_queues.is_empty() {
+        res += &format!(
+            "\n<b>Skipped</b>: {}",
+            teloxide::utils::html::escape(&notes.full_queues.join(", "))
+        );
+    }
+    res + &to_html_duplicate_packages(&notes.duplicates)
+        + &to_html_dropped_packages(&notes.dropped_packages)
+        + &to_html_unchanged_packages(notes.unchanged.as_ref())
+}
+
+/// Line appended to the new pipeline summary for packages listed more than once
+pub fn to_html_duplicate_packages(duplicates: &[String]) -> String {
+    if duplicates.is_empty() {
+        return String::new();
+    }
+    format!(
+        "\n<b>Removed duplicate package(s)</b>: {}",
+        teloxide::utils::html::escape(&duplicates.join(", "))
+    )
+}
+
+/// Line appended to the new pipeline summary of a /retrypipeline rebuild
+pub fn to_html_retry_of(parent_pipeline_id: Option<i32>) -> String {
+    let Some(parent) = parent_pipeline_id else {
+        return String::new();
+    };
+    format!(
+        "\n<b>Retry of</b>: <a href=\"https://buildit.aosc.io/pipelines/{parent}\">#{parent}</a>"
+    )
+}
+
+/// Line appended to the new pipeline summary for archs without online workers
+pub fn to_html_offline_archs(offline: &[String]) -> String {
+    if offline.is_empty() {
+        return String::new();
+    }
+    format!(
+        "\n<b>Warning</b>: no workers currently online for {}; job queued and will start when one connects",
+        teloxide::utils::html::escape(&offline.join(", "))
+    )
+}
+
+/// Latest state of each arch of a pull request build, one line per arch
+pub fn format_pr_status(pr: u64, pipeline: &Pipeline, jobs: &[Job]) -> String {
+    // restarted jobs replace the failed ones
+    let mut latest: BTreeMap<&str, &Job> = BTreeMap::new();
+    for job in jobs {
+        let entry = latest.entry(&job.arch).or_insert(job);
+        if job.id > entry.id {
+            *entry = job;
+        }
+    }
+    let mut archs = latest.keys().copied().collect::<Vec<_>>();
+    sort_archs(&mut archs);
+
+    let mut res = format!(
+        "PR #{pr}: pipeline #{} building {} ({})",
+        pipeline.id, pipeline.git_branch, pipeline.git_sha
+    );
+    for arch in archs {
+        let state = match latest[arch].status.as_str() {
+            "created" => "queued",
+            "held" => "waiting for canary",
+            "success" => SUCCESS,
+            "failed" => FAILED,
+            status => status,
+        };
+        res.push_str(&format!("\n{arch}: {state}"));
+    }
+    res
+}
+
+/// Parse the `page=n` argument of list commands, pages start from 1
+pub fn parse_page(value: &str) -> Result<usize, String> {
+    value
+        .parse::<usize>()
+        .ok()
+        .filter(|page| *page >= 1)
+        .ok_or_else(|| format!("Invalid page: {value}"))
+}
+
+/// Number of pages of `total` items, at least one
+pub fn page_count(total: usize, page_size: usize) -> usize {
+    total.div_ceil(page_size).max(1)
+}
+
+/// Rows of the items on `page` formatted one per line, and whether more pages follow
+pub fn paginate<T>(
+    items: &[T],
+    page: usize,
+    page_size: usize,
+    row: impl Fn(&T) -> String,
+) -> (String, bool) {
+    let start = (page - 1).saturating_mul(page_size).min(items.len());
+    let end = start.saturating_add(page_size).min(items.len());
+    let rendered = items[start..end]
+        .iter()
+        .map(row)
+        .collect::<Vec<_>>()
+        .join("\n");
+    (rendered, end < items.len())
+}
+
+/// `Page X of Y`, with the command for the next page if any, e.g. `/history bash`
+pub fn page_footer(page: usize, pages: usize, command: &str) -> String {
+    if page < pages {
         format!(
-            "<a href=\"https://buildit.aosc.io/jobs/{}\">#{}</a>",
-            job.id, job.id
-        ),
+            "Page {page} of {pages}, use {command} page={} for more",
+            page + 1
+        )
+    } else {
+        format!("Page {page} of {pages}")
+    }
+}
+
+/// Reply to a page past the end of a list
+pub fn page_out_of_range(page: usize, pages: usize) -> String {
+    format!("Nothing on page {page}, there are {pages} page(s) in total")
+}
+
+/// Format bytes with binary units and at most one decimal, e.g. 4.2 GiB
+pub fn format_bytes(bytes: i64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    let value = format!("{value:.1}");
+    format!("{} {}", value.trim_end_matches(".0"), UNITS[unit])
+}
+
+/// Describe resources consumed by a build, e.g. peak 4.2 GiB, 12 GiB disk
+pub fn format_resource_usage(
+    peak_memory_bytes: Option<i64>,
+    disk_bytes: Option<i64>,
+) -> Option<String> {
+    let parts = [
+        peak_memory_bytes.map(|bytes| format!("peak {}", format_bytes(bytes))),
+        disk_bytes.map(|bytes| format!("{} disk", format_bytes(bytes))),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(", "))
+    }
+}
+
+/// Lines of the log quoted in comments of failed builds
+const LOG_EXCERPT_LINES: usize = 40;
+
+/// Keep comments well below the 65536 characters limit of GitHub
+const LOG_EXCERPT_MAX_BYTES: usize = 16 * 1024;
+
+enum SummaryValue {
+    Text(String),
+    Link { text: String, url: String },
+}
+
+/// Summary of a finished job, shared by Telegram and GitHub messages
+pub struct JobSummary<'a> {
+    pub pipeline: &'a Pipeline,
+    pub job: &'a Job,
+    pub job_ok: &'a JobOk,
+    pub worker_hostname: &'a str,
+    pub worker_arch: &'a str,
+    pub success: bool,
+    /// Replaces the built-in Telegram message
+    pub template: Option<&'a Template>,
+}
+
+impl JobSummary<'_> {
+    fn title(&self) -> String {
         format!(
-            "<a href=\"https://buildit.aosc.io/pipelines/{}\">#{}</a>",
-            pipeline.id, pipeline.id
-        ),
-        format!("{}", job.creation_time),
-        format!("{}s", elapsed_secs),
+            "{} Job {} completed on {} ({})",
+            if self.success { SUCCESS } else { FAILED },
+            if self.success {
+                SUCCESS_TEXT
+            } else {
+                FAILED_TEXT
+            },
+            self.worker_hostname,
+            self.worker_arch,
+        )
+    }
+
+    fn rows(&self) -> Vec<(&'static str, SummaryValue)> {
+        let JobSummary {
+            pipeline,
+            job,
+            job_ok,
+            ..
+        } = self;
+
+        let mut rows = vec![
+            (
+                "Job",
+                SummaryValue::Link {
+                    text: format!("#{}", job.id),
+                    url: format!("https://buildit.aosc.io/jobs/{}", job.id),
+                },
+            ),
+            (
+                "Pipeline",
+                SummaryValue::Link {
+                    text: format!("#{}", pipeline.id),
+                    url: format!("https://buildit.aosc.io/pipelines/{}", pipeline.id),
+                },
+            ),
+            (
+                "Enqueue time",
+                SummaryValue::Text(job.creation_time.to_string()),
+            ),
+            (
+                "Time elapsed",
+                SummaryValue::Text(format!("{}s", job_ok.elapsed_secs)),
+            ),
+            (
+                "Git commit",
+                SummaryValue::Link {
+                    text: pipeline.git_sha[..8].to_string(),
+                    url: format!(
+                        "https://github.com/{}/commit/{}",
+                        pipeline.repo, pipeline.git_sha
+                    ),
+                },
+            ),
+            (
+                "Git branch",
+                SummaryValue::Link {
+                    text: pipeline.git_branch.clone(),
+                    url: format!(
+                        "https://github.com/{}/tree/{}",
+                        pipeline.repo, pipeline.git_branch
+                    ),
+                },
+            ),
+        ];
+
+        if let Some(pr) = pipeline.github_pr {
+            rows.push((
+                "GitHub PR",
+                SummaryValue::Link {
+                    text: format!("#{}", pr),
+                    url: format!("https://github.com/{}/pull/{}", pipeline.repo, pr),
+                },
+            ));
+        }
+
+        if let Some(requested_by) = &pipeline.requested_by {
+            rows.push(("Requested by", SummaryValue::Text(requested_by.clone())));
+        }
+
+        rows.extend([
+            ("Architecture", SummaryValue::Text(job.arch.clone())),
+            (
+                "Package(s) to build",
+                SummaryValue::Text(job.packages.replace(',', ", ")),
+            ),
+            (
+                "Package(s) successfully built",
+                SummaryValue::Text(job_ok.successful_packages.join(", ")),
+            ),
+            (
+                "Package(s) failed to build",
+                SummaryValue::Text(
+                    job_ok
+                        .failed_package
+                        .clone()
+                        .unwrap_or(String::from("None")),
+                ),
+            ),
+            (
+                "Package(s) not built due to previous build failure",
+                SummaryValue::Text(job_ok.skipped_packages.join(", ")),
+            ),
+        ]);
+
+        if let Some(kind) = failure_kind_of(job_ok) {
+            rows.push(("Failure reason", SummaryValue::Text(kind.to_string())));
+        }
+
+        if let Some(usage) = format_resource_usage(job_ok.peak_memory_bytes, job_ok.disk_bytes) {
+            rows.push(("Resource usage", SummaryValue::Text(usage)));
+        }
+
+        rows
+    }
+
+    /// Values of the variables of the job summary template
+    fn values(&self) -> BTreeMap<&'static str, String> {
+        let JobSummary {
+            pipeline,
+            job,
+            job_ok,
+            ..
+        } = self;
+
+        BTreeMap::from([
+            (
+                "status",
+                if self.success { SUCCESS } else { FAILED }.to_string(),
+            ),
+            (
+                "result",
+                if self.success {
+                    SUCCESS_TEXT
+                } else {
+                    FAILED_TEXT
+                }
+                .to_string(),
+            ),
+            ("job_id", job.id.to_string()),
+            (
+                "job_url",
+                format!("https://buildit.aosc.io/jobs/{}", job.id),
+            ),
+            ("pipeline_id", pipeline.id.to_string()),
+            (
+                "pipeline_url",
+                format!("https://buildit.aosc.io/pipelines/{}", pipeline.id),
+            ),
+            ("worker", self.worker_hostname.to_string()),
+            ("worker_arch", self.worker_arch.to_string()),
+            ("arch", job.arch.clone()),
+            ("packages", job.packages.replace(',', ", ")),
+            ("successful_packages", job_ok.successful_packages.join(", ")),
+            (
+                "failed_package",
+                job_ok.failed_package.clone().unwrap_or_default(),
+            ),
+            ("skipped_packages", job_ok.skipped_packages.join(", ")),
+            ("enqueue_time", job.creation_time.to_string()),
+            ("elapsed", format!("{}s", job_ok.elapsed_secs)),
+            ("git_branch", pipeline.git_branch.clone()),
+            ("git_sha", pipeline.git_sha.clone()),
+            ("git_commit", pipeline.git_sha[..8].to_string()),
+            (
+                "github_pr",
+                pipeline
+                    .github_pr
+                    .map(|pr| pr.to_string())
+                    .unwrap_or_default(),
+            ),
+            (
+                "requested_by",
+                pipeline.requested_by.clone().unwrap_or_default(),
+            ),
+            (
+                "failure_reason",
+                failure_kind_of(job_ok)
+                    .map(|kind| kind.to_string())
+                    .unwrap_or_default(),
+            ),
+            (
+                "resource_usage",
+                format_resource_usage(job_ok.peak_memory_bytes, job_ok.disk_bytes)
+                    .unwrap_or_default(),
+            ),
+            ("log_url", job_ok.log_url.clone().unwrap_or_default()),
+        ])
+    }
+
+    /// Render as Telegram HTML
+    pub fn to_html(&self) -> String {
+        use teloxide::utils::html::escape;
+
+        if let Some(template) = self.template {
+            return template.render(&self.values());
+        }
+
+        let rows = self
+            .rows()
+            .into_iter()
+            .map(|(label, value)| {
+                let value = match value {
+                    SummaryValue::Text(text) => escape(&text),
+                    SummaryValue::Link { text, url } => {
+                        format!("<a href=\"{}\">{}</a>", url, escape(&text))
+                    }
+                };
+                format!("<b>{}</b>: {}", label, value)
+            })
+            .collect::<Vec<_>>();
+
         format!(
-            "<a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/commit/{}\">{}</a>",
-            pipeline.git_sha,
-            &pipeline.git_sha[..8]
-        ),
+            "{}\n\n{}\n\n{}",
+            escape(&self.title()),
+            rows.join("\n"),
+            if let Some(log) = &self.job_ok.log_url {
+                Cow::Owned(format!("<a href=\"{}\">Build Log >></a>", log))
+            } else {
+                Cow::Borrowed("Failed to push log! See <code>/buildroots/buildit/buildit/push_failed_logs</code> to see log.")
+            }
+        )
+    }
+
+    /// Render as Markdown, escaped in the MarkdownV2 way
+    pub fn to_markdown_v2(&self) -> String {
+        use teloxide::utils::markdown::{escape, link};
+
+        let rows = self
+            .rows()
+            .into_iter()
+            .map(|(label, value)| {
+                let value = match value {
+                    SummaryValue::Text(text) => escape(&text),
+                    SummaryValue::Link { text, url } => link(&url, &escape(&text)),
+                };
+                format!("**{}**: {}", escape(label), value)
+            })
+            .collect::<Vec<_>>();
+
         format!(
-            "<a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/tree/{}\">{}</a>",
-            pipeline.git_branch, &pipeline.git_branch
-        ),
-        if let Some(pr) = pipeline.github_pr {
-            format!(
-                "<b>GitHub PR</b>: <a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/pull/{}\">#{}</a>\n",
-                pr, pr
-            )
-        } else {
-            String::new()
-        },
-        job.arch,
-        job.packages.replace(",", ", "),
-        &successful_packages.join(", "),
-        &failed_package.clone().unwrap_or(String::from("None")),
-        &skipped_packages.join(", "),
-        if let Some(log) = log_url {
-            Cow::Owned(format!("<a href=\"{}\">Build Log >></a>", log))
-        } else {
-            Cow::Borrowed("Failed to push log! See <code>/buildroots/buildit/buildit/push_failed_logs</code> to see log.")
+            "{}\n\n{}\n\n{}\n{}",
+            escape(&self.title()),
+            rows.join("\n"),
+            if let Some(log) = &self.job_ok.log_url {
+                Cow::Owned(link(log, "Build Log \\>\\>"))
+            } else {
+                Cow::Borrowed("Failed to push log! See `/buildroots/buildit/buildit/push_failed_logs` to see log.")
+            },
+            self.log_excerpt()
+                .map(|excerpt| format!("\n{excerpt}"))
+                .unwrap_or_default()
+        )
+    }
+
+    /// Last lines of the log of a failed build, folded in a details block
+    fn log_excerpt(&self) -> Option<String> {
+        if self.success {
+            return None;
         }
-    )
-}
+        let log_tail = self.job_ok.log_tail.as_ref()?.to_string_lossy();
 
-pub fn to_markdown_build_result(
-    pipeline: &Pipeline,
-    job: &Job,
-    job_ok: &JobOk,
-    worker_hostname: &str,
-    worker_arch: &str,
-    success: bool,
-) -> String {
-    let JobOk {
-        successful_packages,
-        failed_package,
-        skipped_packages,
-        log_url,
-        elapsed_secs,
-        ..
-    } = job_ok;
+        let lines = log_tail.lines().collect::<Vec<_>>();
+        let excerpt = lines[lines.len().saturating_sub(LOG_EXCERPT_LINES)..].join("\n");
+        // keep the end, where the error is
+        let mut start = excerpt.len().saturating_sub(LOG_EXCERPT_MAX_BYTES);
+        while !excerpt.is_char_boundary(start) {
+            start += 1;
+        }
+        let excerpt = &excerpt[start..];
 
-    format!(
-        "{} Job {} completed on {} \\({}\\)\n\n**Job**: {}\n**Pipeline**: {}\n**Enqueue time**: {}\n**Time elapsed**: {}s\n{}{}**Architecture**: {}\n**Package\\(s\\) to build**: {}\n**Package\\(s\\) successfully built**: {}\n**Package\\(s\\) failed to build**: {}\n**Package\\(s\\) not built due to previous build failure**: {}\n\n{}\n",
-        if success { SUCCESS } else { FAILED },
-        if success { SUCCESS_TEXT } else { FAILED_TEXT },
-        worker_hostname,
-        worker_arch,
-        format!("[#{}](https://buildit.aosc.io/jobs/{})", job.id, job.id),
-        format!("[#{}](https://buildit.aosc.io/pipelines/{})", pipeline.id, pipeline.id),
-        teloxide::utils::markdown::escape(&job.creation_time.to_string()),
-        elapsed_secs,
-        format!("**Git commit**: [{}](https://github.com/AOSC-Dev/aosc-os-abbs/commit/{})\n", &pipeline.git_sha[..8], pipeline.git_sha),
-        format!("**Git branch**: [{}](https://github.com/AOSC-Dev/aosc-os-abbs/tree/{})\n", &pipeline.git_branch, pipeline.git_branch),
-        job.arch,
-        teloxide::utils::markdown::escape(&job.packages.replace(",", ", ")),
-        teloxide::utils::markdown::escape(&successful_packages.join(", ")),
-        teloxide::utils::markdown::escape(&failed_package.clone().unwrap_or(String::from("None"))),
-        teloxide::utils::markdown::escape(&skipped_packages.join(", ")),
-        if let Some(log) = log_url {
-            Cow::Owned(format!("[Build Log \\>\\>]({})", log))
-        } else {
-            Cow::Borrowed("Failed to push log! See `/buildroots/buildit/buildit/push_failed_logs` to see log.")
+        // the fence must be longer than backticks in the log
+        let mut longest = 0;
+        let mut current = 0;
+        for ch in excerpt.chars() {
+            current = if ch == '`' { current + 1 } else { 0 };
+            longest = longest.max(current);
         }
-    )
+        let fence = "`".repeat(longest.max(2) + 1);
+
+        Some(format!(
+            "<details>\n<summary>Log excerpt</summary>\n\n{fence}\n{excerpt}\n{fence}\n\n</details>\n"
+        ))
+    }
+}
+
+/// Classify the failure of an unsuccessful build
+pub fn failure_kind_of(job_ok: &JobOk) -> Option<FailureKind> {
+    if job_ok.build_success && job_ok.pushpkg_success {
+        return None;
+    }
+    job_ok
+        .log_tail
+        .as_ref()
+        .map(|log_tail| classify_failure(&log_tail.to_string_lossy()))
 }
 
 pub fn code_repr_string(s: &str) -> String {
@@ -163,9 +600,64 @@ pub fn code_repr_string(s: &str) -> String {
 
 #[test]
 fn test_format_html_new_pipeline_summary() {
-    let s =
-        to_html_new_pipeline_summary(1, "fd-9.0.0", "123456789", Some(4992), &["amd64"], &["fd"]);
-    assert_eq!(s, "<b><u>New Pipeline Summary</u></b>\n\n<b>Pipeline</b>: <a href=\"https://buildit.aosc.io/pipelines/1\">#1</a>\n<b>Git branch</b>: fd-9.0.0\n<b>Git commit</b>: <a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/commit/123456789\">12345678</a>\n<b>GitHub PR</b>: <a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/pull/4992\">#4992</a>\n<b>Architecture(s)</b>: amd64\n<b>Package(s)</b>: fd")
+    let mut pipeline = Pipeline {
+        id: 1,
+        git_sha: "123456789".to_string(),
+        source: "github".to_string(),
+        github_pr: Some(4992),
+        ..Pipeline::fixture()
+    };
+    let s = to_html_new_pipeline_summary(&pipeline, None);
+    assert_eq!(s, "<b><u>New Pipeline Summary</u></b>\n\n<b>Pipeline</b>: <a href=\"https://buildit.aosc.io/pipelines/1\">#1</a>\n<b>Git branch</b>: fd-9.0.0\n<b>Git commit</b>: <a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/commit/123456789\">12345678</a>\n<b>GitHub PR</b>: <a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/pull/4992\">#4992</a>\n<b>Architecture(s)</b>: amd64\n<b>Package(s)</b>: fd");
+
+    pipeline.id = 2;
+    let s = to_html_new_pipeline_summary(&pipeline, Some(1));
+    assert!(s.ends_with("\n<b>Note</b>: inputs unchanged since pipeline <a href=\"https://buildit.aosc.io/pipelines/1\">#1</a>"));
+
+    // pipelines of secondary repos link there
+    pipeline.repo = "AOSC-Dev/aoscx-abbs".to_string();
+    let s = to_html_new_pipeline_summary(&pipeline, None);
+    assert!(s.contains(
+        "<a href=\"https://github.com/AOSC-Dev/aoscx-abbs/commit/123456789\">12345678</a>"
+    ));
+    assert!(s.contains("<a href=\"https://github.com/AOSC-Dev/aoscx-abbs/pull/4992\">#4992</a>"));
+
+    assert_eq!(to_html_unchanged_packages(None), "");
+    assert_eq!(
+        to_html_unchanged_packages(Some(&UnchangedPackages {
+            since: 2,
+            packages: vec!["bat".to_string(), "ripgrep".to_string()],
+        })),
+        "\n<b>Skipped package(s)</b>: bat, ripgrep (unchanged since <a href=\"https://buildit.aosc.io/pipelines/2\">#2</a>, previously ✅️)"
+    );
+
+    assert_eq!(
+        to_html_duplicate_packages(&["bash".to_string(), "fish".to_string()]),
+        "\n<b>Removed duplicate package(s)</b>: bash, fish"
+    );
+    assert_eq!(to_html_duplicate_packages(&[]), "");
+
+    let mut notes = PipelineNotes::default();
+    assert_eq!(to_html_pipeline_notes(&notes), "");
+    notes.dropped_packages = vec!["fdd".to_string(), "ripgrap".to_string()];
+    assert_eq!(
+        to_html_pipeline_notes(&notes),
+        "\n<b>Dropped unknown package(s)</b>: fdd, ripgrap"
+    );
+    notes.excluded_archs =
+        Some("riscv64 excluded for branch experimental-gcc by policy".to_string());
+    assert_eq!(
+        to_html_pipeline_notes(&notes),
+        "\n<b>Note</b>: riscv64 excluded for branch experimental-gcc by policy\n<b>Dropped unknown package(s)</b>: fdd, ripgrap"
+    );
+    notes = PipelineNotes {
+        full_queues: vec!["queue for arm64 is full (10 jobs)".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(
+        to_html_pipeline_notes(&notes),
+        "\n<b>Skipped</b>: queue for arm64 is full (10 jobs)"
+    );
 }
 
 #[test]
@@ -175,41 +667,25 @@ fn test_format_html_build_result() {
 
     let pipeline = Pipeline {
         id: 1,
-        packages: "fd".to_string(),
-        archs: "amd64".to_string(),
-        git_branch: "fd-9.0.0".to_string(),
-        git_sha: "34acef168fc5ec454d3825fc864964951b130b49".to_string(),
-        creation_time: DateTime::from_timestamp(61, 0).unwrap(),
-        source: "telegram".to_string(),
         github_pr: Some(4992),
-        telegram_user: None,
-        creator_user_id: None,
+        ..Pipeline::fixture()
     };
 
     let job = Job {
-        id: 1,
         pipeline_id: 1,
         packages: "fd,fd2".to_string(),
-        arch: "amd64".to_string(),
-        creation_time: DateTime::from_timestamp(61, 0).unwrap(),
         status: "success".to_string(),
-        github_check_run_id: None,
         build_success: Some(true),
         pushpkg_success: Some(true),
         successful_packages: Some("fd".to_string()),
-        failed_package: None,
         skipped_packages: Some("".to_string()),
         log_url: Some("https://pastebin.aosc.io/paste/c0rWzj4EsSC~CVXs2qXtFw".to_string()),
         finish_time: Some(DateTime::from_timestamp(61, 0).unwrap()),
         assign_time: Some(DateTime::from_timestamp(61, 0).unwrap()),
-        error_message: None,
         elapsed_secs: Some(888),
         assigned_worker_id: Some(1),
         built_by_worker_id: Some(1),
-        require_min_core: None,
-        require_min_disk: None,
-        require_min_total_mem: None,
-        require_min_total_mem_per_core: None,
+        ..Job::fixture()
     };
 
     let job_ok = JobOk {
@@ -220,12 +696,283 @@ fn test_format_html_build_result() {
         log_url: Some("https://pastebin.aosc.io/paste/c0rWzj4EsSC~CVXs2qXtFw".to_string()),
         elapsed_secs: 888,
         pushpkg_success: true,
+        log_tail: None,
+        environment: None,
+        peak_memory_bytes: None,
+        disk_bytes: None,
+        package_timings: None,
     };
 
     let worker_hostname = "Yerus";
     let worker_arch = "amd64";
 
-    let s = to_html_build_result(&pipeline, &job, &job_ok, worker_hostname, worker_arch, true);
+    let summary = JobSummary {
+        pipeline: &pipeline,
+        job: &job,
+        job_ok: &job_ok,
+        worker_hostname,
+        worker_arch,
+        success: true,
+        template: None,
+    };
+    let s = summary.to_html();
+
+    assert_eq!(s, "✅\u{fe0f} Job successfully completed on Yerus (amd64)\n\n<b>Job</b>: <a href=\"https://buildit.aosc.io/jobs/1\">#1</a>\n<b>Pipeline</b>: <a href=\"https://buildit.aosc.io/pipelines/1\">#1</a>\n<b>Enqueue time</b>: 1970-01-01 00:01:01 UTC\n<b>Time elapsed</b>: 888s\n<b>Git commit</b>: <a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/commit/34acef168fc5ec454d3825fc864964951b130b49\">34acef16</a>\n<b>Git branch</b>: <a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/tree/fd-9.0.0\">fd-9.0.0</a>\n<b>GitHub PR</b>: <a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/pull/4992\">#4992</a>\n<b>Architecture</b>: amd64\n<b>Package(s) to build</b>: fd, fd2\n<b>Package(s) successfully built</b>: fd\n<b>Package(s) failed to build</b>: None\n<b>Package(s) not built due to previous build failure</b>: \n\n<a href=\"https://pastebin.aosc.io/paste/c0rWzj4EsSC~CVXs2qXtFw\">Build Log >></a>");
+
+    let s = summary.to_markdown_v2();
+    assert_eq!(s, "✅\u{fe0f} Job successfully completed on Yerus \\(amd64\\)\n\n**Job**: [\\#1](https://buildit.aosc.io/jobs/1)\n**Pipeline**: [\\#1](https://buildit.aosc.io/pipelines/1)\n**Enqueue time**: 1970\\-01\\-01 00:01:01 UTC\n**Time elapsed**: 888s\n**Git commit**: [34acef16](https://github.com/AOSC-Dev/aosc-os-abbs/commit/34acef168fc5ec454d3825fc864964951b130b49)\n**Git branch**: [fd\\-9\\.0\\.0](https://github.com/AOSC-Dev/aosc-os-abbs/tree/fd-9.0.0)\n**GitHub PR**: [\\#4992](https://github.com/AOSC-Dev/aosc-os-abbs/pull/4992)\n**Architecture**: amd64\n**Package\\(s\\) to build**: fd, fd2\n**Package\\(s\\) successfully built**: fd\n**Package\\(s\\) failed to build**: None\n**Package\\(s\\) not built due to previous build failure**: \n\n[Build Log \\>\\>](https://pastebin.aosc.io/paste/c0rWzj4EsSC~CVXs2qXtFw)\n");
+
+    let failed_job_ok = JobOk {
+        build_success: false,
+        failed_package: Some("fd".to_string()),
+        log_tail: Some("src/main.rs:1:1: error: expected item".into()),
+        ..job_ok.clone()
+    };
+    let summary = JobSummary {
+        job_ok: &failed_job_ok,
+        success: false,
+        ..summary
+    };
+    assert!(summary
+        .to_html()
+        .contains("\n<b>Failure reason</b>: compile error\n"));
+
+    let pipeline = Pipeline {
+        requested_by: Some("@cyan".to_string()),
+        ..pipeline
+    };
+    let summary = JobSummary {
+        pipeline: &pipeline,
+        job: &job,
+        job_ok: &failed_job_ok,
+        worker_hostname,
+        worker_arch,
+        success: false,
+        template: None,
+    };
+    assert!(summary
+        .to_html()
+        .contains("\n<b>GitHub PR</b>: <a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/pull/4992\">#4992</a>\n<b>Requested by</b>: @cyan\n"));
+    assert!(summary
+        .to_markdown_v2()
+        .contains("\n**Requested by**: @cyan\n"));
+
+    // failed builds quote the end of the log
+    assert!(summary.to_markdown_v2().ends_with("[Build Log \\>\\>](https://pastebin.aosc.io/paste/c0rWzj4EsSC~CVXs2qXtFw)\n\n<details>\n<summary>Log excerpt</summary>\n\n```\nsrc/main.rs:1:1: error: expected item\n```\n\n</details>\n"));
+
+    let long_job_ok = JobOk {
+        log_tail: Some(
+            (1..=100)
+                .map(|i| format!("line {i} ```"))
+                .collect::<Vec<_>>()
+                .join("\n")
+                .as_str()
+                .into(),
+        ),
+        ..failed_job_ok.clone()
+    };
+    let summary = JobSummary {
+        job_ok: &long_job_ok,
+        ..summary
+    };
+    let s = summary.to_markdown_v2();
+    assert!(s.contains("\n````\nline 61 ```\n"));
+    assert!(s.contains("\nline 100 ```\n````\n"));
+    assert!(!s.contains("line 60 "));
+
+    // successful builds do not
+    let summary = JobSummary {
+        job_ok: &job_ok,
+        success: true,
+        ..summary
+    };
+    assert!(!summary.to_markdown_v2().contains("<details>"));
+
+    // a configured template replaces the built-in message
+    let template = crate::template::parse_job_summary_template(
+        "{status} {packages} on {arch} in {elapsed}: <a href=\"{log_url}\">log</a>",
+    )
+    .unwrap();
+    let summary = JobSummary {
+        template: Some(&template),
+        ..summary
+    };
+    assert_eq!(summary.to_html(), "✅\u{fe0f} fd, fd2 on amd64 in 888s: <a href=\"https://pastebin.aosc.io/paste/c0rWzj4EsSC~CVXs2qXtFw\">log</a>");
+    assert!(!summary.to_markdown_v2().starts_with("✅\u{fe0f} fd, fd2"));
+
+    let template = crate::template::parse_new_pipeline_template(
+        "Pipeline #{pipeline_id} of PR #{github_pr}: {packages} for {archs}",
+    )
+    .unwrap();
+    assert_eq!(
+        new_pipeline_summary(&pipeline, None, Some(&template)),
+        "Pipeline #1 of PR #4992: fd for amd64"
+    );
+    assert!(new_pipeline_summary(&pipeline, None, None).starts_with("<b><u>New Pipeline Summary"));
+}
+
+#[test]
+fn test_paginate() {
+    let items = (1..=25).collect::<Vec<i32>>();
+    let row = |item: &i32| format!("#{item}");
+
+    // first page
+    let (rendered, has_more) = paginate(&items, 1, 10, row);
+    assert_eq!(
+        rendered,
+        (1..=10)
+            .map(|i| format!("#{i}"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+    assert!(has_more);
+    // middle page
+    let (rendered, has_more) = paginate(&items, 2, 10, row);
+    assert!(rendered.starts_with("#11\n") && rendered.ends_with("\n#20"));
+    assert!(has_more);
+    // last, partial page
+    assert_eq!(
+        paginate(&items, 3, 10, row),
+        ("#21\n#22\n#23\n#24\n#25".to_string(), false)
+    );
+    // last page exactly full
+    assert!(!paginate(&items[..20], 2, 10, row).1);
+    // past the end
+    assert_eq!(paginate(&items, 4, 10, row), (String::new(), false));
+    assert_eq!(
+        paginate(&items, usize::MAX, 10, row),
+        (String::new(), false)
+    );
+    // empty list
+    assert_eq!(paginate(&[] as &[i32], 1, 10, row), (String::new(), false));
+
+    assert_eq!(page_count(25, 10), 3);
+    assert_eq!(page_count(20, 10), 2);
+    assert_eq!(page_count(0, 10), 1);
+    assert_eq!(
+        page_footer(1, 3, "/building"),
+        "Page 1 of 3, use /building page=2 for more"
+    );
+    assert_eq!(
+        page_footer(2, 3, "/history bash arch=amd64"),
+        "Page 2 of 3, use /history bash arch=amd64 page=3 for more"
+    );
+    assert_eq!(page_footer(3, 3, "/building"), "Page 3 of 3");
+    assert_eq!(
+        page_out_of_range(4, 3),
+        "Nothing on page 4, there are 3 page(s) in total"
+    );
+    assert_eq!(parse_page("2"), Ok(2));
+    assert!(parse_page("0").is_err());
+    assert!(parse_page("-1").is_err());
+}
+
+#[test]
+fn test_format_resource_usage() {
+    assert_eq!(format_bytes(512), "512 B");
+    assert_eq!(format_bytes(4509715660), "4.2 GiB");
+    assert_eq!(format_bytes(12 * 1024 * 1024 * 1024), "12 GiB");
+    assert_eq!(
+        format_resource_usage(Some(4509715660), Some(12 * 1024 * 1024 * 1024)).as_deref(),
+        Some("peak 4.2 GiB, 12 GiB disk")
+    );
+    assert_eq!(
+        format_resource_usage(None, Some(1536 * 1024)).as_deref(),
+        Some("1.5 MiB disk")
+    );
+    assert_eq!(format_resource_usage(None, None), None);
+
+    // reported by new workers, absent from old ones
+    let job_ok: JobOk = serde_json::from_str(
+        r#"{"build_success":true,"successful_packages":["fd"],"failed_package":null,"skipped_packages":[],"log_url":null,"elapsed_secs":1,"pushpkg_success":true,"peak_memory_bytes":4509715660,"disk_bytes":1024}"#,
+    )
+    .unwrap();
+    assert_eq!(job_ok.peak_memory_bytes, Some(4509715660));
+    let job_ok: JobOk = serde_json::from_value(serde_json::to_value(&job_ok).unwrap()).unwrap();
+    assert_eq!(job_ok.disk_bytes, Some(1024));
+    let job_ok: JobOk = serde_json::from_str(
+        r#"{"build_success":true,"successful_packages":["fd"],"failed_package":null,"skipped_packages":[],"log_url":null,"elapsed_secs":1,"pushpkg_success":true}"#,
+    )
+    .unwrap();
+    assert_eq!((job_ok.peak_memory_bytes, job_ok.disk_bytes), (None, None));
+}
+
+#[test]
+fn test_format_pr_status() {
+    let pipeline = Pipeline {
+        archs: "amd64,arm64,loongson3,riscv64,ppc64el".to_string(),
+        source: "github".to_string(),
+        github_pr: Some(4992),
+        ..Pipeline::fixture()
+    };
+    let job = |id: i32, arch: &str, status: &str| Job {
+        id,
+        arch: arch.to_string(),
+        status: status.to_string(),
+        ..Job::fixture()
+    };
+
+    let jobs = [
+        job(1, "riscv64", "created"),
+        job(2, "loongson3", "failed"),
+        job(3, "arm64", "running"),
+        job(4, "amd64", "success"),
+        job(5, "ppc64el", "failed"),
+        // restarted after failing
+        job(6, "ppc64el", "running"),
+    ];
+    assert_eq!(
+        format_pr_status(4992, &pipeline, &jobs),
+        "PR #4992: pipeline #12 building fd-9.0.0 (34acef168fc5ec454d3825fc864964951b130b49)\namd64: ✅️\narm64: running\nloongson3: ❌\nppc64el: running\nriscv64: queued"
+    );
+}
+
+#[test]
+fn test_log_bytes() {
+    use common::{JobResult, LogBytes, WorkerJobUpdateRequest};
+
+    // compiler output with bytes that are not UTF-8
+    let log = b"CC \xff\xfe.o\nsrc/main.c:42:5: error: '\xc3' undeclared".to_vec();
+    let req = serde_json::json!({
+        "hostname": "Yerus",
+        "arch": "amd64",
+        "job_id": 1,
+        "worker_secret": "secret",
+        "schema_version": 3,
+        "result": {
+            "Ok": {
+                "build_success": false,
+                "successful_packages": [],
+                "failed_package": "fd",
+                "skipped_packages": [],
+                "log_url": null,
+                "elapsed_secs": 888,
+                "pushpkg_success": false,
+                "log_tail": LogBytes(log.clone()),
+            }
+        },
+    });
+    let req: WorkerJobUpdateRequest = serde_json::from_value(req).unwrap();
+    let JobResult::Ok(job_ok) = &req.result else {
+        panic!("expected job ok");
+    };
+    // kept as is
+    assert_eq!(job_ok.log_tail, Some(LogBytes(log)));
+    assert_eq!(
+        serde_json::from_value::<LogBytes>(serde_json::to_value(&job_ok.log_tail).unwrap())
+            .unwrap(),
+        *job_ok.log_tail.as_ref().unwrap()
+    );
+    // text from older workers
+    assert_eq!(
+        serde_json::from_value::<LogBytes>(serde_json::json!("error: fd")).unwrap(),
+        LogBytes::from("error: fd")
+    );
 
-    assert_eq!(s, "✅\u{fe0f} Job successfully completed on Yerus (amd64)\n\n<b>Job</b>: <a href=\"https://buildit.aosc.io/jobs/1\">#1</a>\n<b>Pipeline</b>: <a href=\"https://buildit.aosc.io/pipelines/1\">#1</a>\n<b>Enqueue time</b>: 1970-01-01 00:01:01 UTC\n<b>Time elapsed</b>: 888s\n<b>Git commit</b>: <a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/commit/34acef168fc5ec454d3825fc864964951b130b49\">34acef16</a>\n<b>Git branch</b>: <a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/tree/fd-9.0.0\">fd-9.0.0</a>\n<b>GitHub PR</b>: <a href=\"https://github.com/AOSC-Dev/aosc-os-abbs/pull/4992\">#4992</a>\n<b>Architecture</b>: amd64\n<b>Package(s) to build</b>: fd, fd2\n<b>Package(s) successfully built</b>: fd\n<b>Package(s) failed to build</b>: None\n<b>Package(s) not built due to previous build failure</b>: \n\n<a href=\"https://pastebin.aosc.io/paste/c0rWzj4EsSC~CVXs2qXtFw\">Build Log >></a>")
+    // replaced only when shown
+    assert_eq!(
+        job_ok.log_tail.as_ref().unwrap().to_string_lossy(),
+        "CC \u{fffd}\u{fffd}.o\nsrc/main.c:42:5: error: '\u{fffd}' undeclared"
+    );
+    assert_eq!(failure_kind_of(job_ok), Some(FailureKind::CompileError));
 }
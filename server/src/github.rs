@@ -1,9 +1,19 @@
-use crate::ARGS;
+use crate::{models::Job, repo::owner_repo, routes::is_transient_status, ARGS};
+use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
 use octocrab::models::pulls::PullRequest;
-use octocrab::{models::InstallationId, Octocrab};
+use octocrab::{
+    models::{CheckRunId, InstallationId},
+    params::checks::{CheckRunConclusion, CheckRunStatus},
+    Octocrab,
+};
+use once_cell::sync::Lazy;
+use reqwest::StatusCode;
+use secrecy::ExposeSecret;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use teloxide::types::{ChatId, Message};
-use tracing::info;
+use tracing::{info, warn};
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct GithubToken {
@@ -15,22 +25,87 @@ pub struct GithubToken {
     pub token_type: String,
 }
 
+/// Times linking the chat is tried again after network or server errors
+const LOGIN_RETRIES: u32 = 2;
+
+/// Delay before the first retry, doubled for each further one
+const LOGIN_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[tracing::instrument(skip(msg, arguments))]
-pub async fn login_github(
-    msg: &Message,
-    arguments: String,
-) -> Result<reqwest::Response, reqwest::Error> {
-    let client = reqwest::Client::new();
+pub async fn login_github(msg: &Message, arguments: String) -> anyhow::Result<()> {
+    login_from_telegram(
+        "https://minzhengbu.aosc.io",
+        msg.chat.id.0,
+        &arguments,
+        LOGIN_RETRY_DELAY,
+    )
+    .await
+}
 
-    client
-        .get("https://minzhengbu.aosc.io/login_from_telegram".to_string())
-        .query(&[
-            ("telegram_id", msg.chat.id.0.to_string()),
-            ("rid", arguments),
-        ])
-        .send()
-        .await
-        .and_then(|x| x.error_for_status())
+/// Link the telegram chat to the GitHub account that logged in as `rid`
+///
+/// The error says what went wrong in words for the user, with the cause below.
+pub async fn login_from_telegram(
+    base_url: &str,
+    telegram_id: i64,
+    rid: &str,
+    retry_delay: Duration,
+) -> anyhow::Result<()> {
+    let client = reqwest::Client::builder().timeout(LOGIN_TIMEOUT).build()?;
+
+    let mut attempt = 0;
+    loop {
+        let res = client
+            .get(format!("{base_url}/login_from_telegram"))
+            .query(&[
+                ("telegram_id", telegram_id.to_string()),
+                ("rid", rid.to_string()),
+            ])
+            .send()
+            .await
+            .and_then(|x| x.error_for_status());
+        match res {
+            Ok(_) => return Ok(()),
+            Err(err) if is_transient_login_error(&err) && attempt < LOGIN_RETRIES => {
+                let delay = retry_delay * (1 << attempt);
+                warn!(
+                    "Failed to link telegram chat {telegram_id}, retrying in {}ms: {err}",
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(err) => {
+                let message = login_error_message(&err);
+                return Err(anyhow::Error::new(err).context(message));
+            }
+        }
+    }
+}
+
+fn is_transient_login_error(err: &reqwest::Error) -> bool {
+    err.is_timeout()
+        || err.is_connect()
+        || err
+            .status()
+            .is_some_and(|status| is_transient_status(status.as_u16()))
+}
+
+/// What to tell the user when linking the chat failed
+pub fn login_error_message(err: &reqwest::Error) -> &'static str {
+    match err.status() {
+        // minzhengbu forgets the login after a while
+        Some(status) if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS => {
+            "That login link has expired, please run /login again"
+        }
+        Some(_) => "The login service is not working right now, please try again later",
+        None if err.is_timeout() => {
+            "The login service did not respond in time, please try again later"
+        }
+        None => "Could not reach the login service, please try again later",
+    }
 }
 
 #[tracing::instrument(skip(secret))]
@@ -81,22 +156,47 @@ pub async fn get_github_token(msg_chatid: &ChatId, secret: &str) -> anyhow::Resu
 /// Collect packages to build from pull request
 pub fn get_packages_from_pr(pr: &PullRequest) -> Vec<String> {
     pr.body
-        .as_ref()
-        .and_then(|body| {
-            body.lines()
-                .filter(|line| line.starts_with("#buildit"))
-                .map(|line| {
-                    line.trim()
-                        .split_ascii_whitespace()
-                        .map(str::to_string)
-                        .skip(1)
-                        .collect::<Vec<_>>()
-                })
-                .next()
+        .as_deref()
+        .map(get_packages_from_pr_body)
+        .unwrap_or_default()
+}
+
+/// Packages listed on the first line starting with `#buildit`
+pub fn get_packages_from_pr_body(body: &str) -> Vec<String> {
+    body.lines()
+        .filter(|line| line.starts_with("#buildit"))
+        .map(|line| {
+            line.trim()
+                .split_ascii_whitespace()
+                .map(str::to_string)
+                .skip(1)
+                .collect::<Vec<_>>()
         })
+        .next()
         .unwrap_or_default()
 }
 
+/// Installation tokens expire in one hour
+const INSTALLATION_TOKEN_LIFETIME_SECS: i64 = 3600;
+
+/// Mint a new installation token this long before the cached one expires
+const INSTALLATION_TOKEN_REFRESH_SECS: i64 = 300;
+
+struct InstallationToken {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl InstallationToken {
+    fn needs_refresh(&self, now: DateTime<Utc>) -> bool {
+        now + chrono::Duration::try_seconds(INSTALLATION_TOKEN_REFRESH_SECS).unwrap()
+            >= self.expires_at
+    }
+}
+
+static INSTALLATION_TOKEN: Lazy<tokio::sync::Mutex<Option<InstallationToken>>> =
+    Lazy::new(|| tokio::sync::Mutex::new(None));
+
 /// Create octocrab instance authenticated as github installation
 #[tracing::instrument]
 pub async fn get_crab_github_installation() -> anyhow::Result<Option<Octocrab>> {
@@ -106,20 +206,292 @@ pub async fn get_crab_github_installation() -> anyhow::Result<Option<Octocrab>>
         .and_then(|x| x.parse::<u64>().ok())
     {
         if let Some(app_private_key) = ARGS.github_app_key.as_ref() {
-            let key = tokio::fs::read(app_private_key).await?;
-            let key =
-                tokio::task::spawn_blocking(move || jsonwebtoken::EncodingKey::from_rsa_pem(&key))
+            // reuse the installation token until it is about to expire
+            let mut cached = INSTALLATION_TOKEN.lock().await;
+            let now = Utc::now();
+            let token = match cached.as_ref().filter(|token| !token.needs_refresh(now)) {
+                Some(token) => token.token.clone(),
+                None => {
+                    info!("Minting GitHub installation token");
+                    let key = tokio::fs::read(app_private_key).await?;
+                    let key = tokio::task::spawn_blocking(move || {
+                        jsonwebtoken::EncodingKey::from_rsa_pem(&key)
+                    })
                     .await??;
 
-            let app_crab = octocrab::Octocrab::builder().app(id.into(), key).build()?;
-            // TODO: move to config
+                    let app_crab = octocrab::Octocrab::builder().app(id.into(), key).build()?;
+                    // TODO: move to config
+                    let (_, token) = app_crab
+                        .installation_and_token(InstallationId(45135446))
+                        .await?;
+                    let token = token.expose_secret().to_string();
+                    *cached = Some(InstallationToken {
+                        token: token.clone(),
+                        expires_at: now
+                            + chrono::Duration::try_seconds(INSTALLATION_TOKEN_LIFETIME_SECS)
+                                .unwrap(),
+                    });
+                    token
+                }
+            };
+
             return Ok(Some(
-                app_crab
-                    .installation_and_token(InstallationId(45135446))
-                    .await?
-                    .0,
+                octocrab::Octocrab::builder()
+                    .user_access_token(token)
+                    .build()?,
             ));
         }
     }
     Ok(None)
 }
+
+/// Complete the check runs of jobs that finished without a build result,
+/// e.g. skipped or cancelled ones
+pub async fn complete_check_runs(
+    repo: &str,
+    jobs: impl IntoIterator<Item = &Job>,
+    conclusion: CheckRunConclusion,
+) {
+    let check_run_ids = jobs
+        .into_iter()
+        .filter_map(|job| job.github_check_run_id)
+        .collect::<Vec<_>>();
+    if check_run_ids.is_empty() {
+        return;
+    }
+    let crab = match get_crab_github_installation().await {
+        Ok(Some(crab)) => crab,
+        Ok(None) => return,
+        Err(err) => {
+            warn!("Failed to build octocrab: {}", err);
+            return;
+        }
+    };
+    let (owner, repo) = owner_repo(repo);
+    for check_run_id in check_run_ids {
+        if let Err(err) = crab
+            .checks(owner, repo)
+            .update_check_run(CheckRunId(check_run_id as u64))
+            .status(CheckRunStatus::Completed)
+            .conclusion(conclusion)
+            .send()
+            .await
+        {
+            warn!("Failed to complete check run: {}", err);
+        }
+    }
+}
+
+/// Create octocrab instance for posting comments:
+/// as github installation if configured, otherwise with the access token
+pub async fn get_crab_github_bot() -> anyhow::Result<Octocrab> {
+    match get_crab_github_installation().await? {
+        Some(crab) => Ok(crab),
+        None => Ok(octocrab::Octocrab::builder()
+            .user_access_token(ARGS.github_access_token.expose().to_string())
+            .build()?),
+    }
+}
+
+/// Whether the GitHub user is a member of aosc-dev, private members are
+/// seen with the access token unless only public members are accepted
+pub async fn is_org_user(user: &str) -> anyhow::Result<bool> {
+    let token = Some(ARGS.github_access_token.expose())
+        .filter(|token| !token.is_empty() && ARGS.org_public_members_only != Some(true));
+    org_membership("https://api.github.com", "aosc-dev", user, token).await
+}
+
+/// Check membership in the organization with the GitHub API at `api`,
+/// falling back to public membership if the token cannot see private members
+pub async fn org_membership(
+    api: &str,
+    org: &str,
+    user: &str,
+    token: Option<&str>,
+) -> anyhow::Result<bool> {
+    // GitHub redirects to the public members if the token is not of a member
+    let client = reqwest::Client::builder()
+        .user_agent("buildit")
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+
+    if let Some(token) = token {
+        let status = client
+            .get(format!("{api}/orgs/{org}/members/{user}"))
+            .bearer_auth(token)
+            .send()
+            .await
+            .context("Network is not reachable")?
+            .status();
+        match status {
+            status if status.is_success() => return Ok(true),
+            StatusCode::NOT_FOUND => return Ok(false),
+            StatusCode::FOUND | StatusCode::FORBIDDEN => {
+                warn!("Not allowed to see private members of {org} ({status}), checking public membership of {user}");
+            }
+            status => bail!("Failed to check membership of {user} in {org}: {status}"),
+        }
+    }
+
+    let status = client
+        .get(format!("{api}/orgs/{org}/public_members/{user}"))
+        .send()
+        .await
+        .context("Network is not reachable")?
+        .status();
+    match status {
+        status if status.is_success() => Ok(true),
+        StatusCode::NOT_FOUND => Ok(false),
+        status => bail!("Failed to check public membership of {user} in {org}: {status}"),
+    }
+}
+
+#[test]
+fn test_installation_token_needs_refresh() {
+    let now = DateTime::from_timestamp(10000, 0).unwrap();
+    let token = |expires_at: i64| InstallationToken {
+        token: "ghs_xxx".to_string(),
+        expires_at: DateTime::from_timestamp(expires_at, 0).unwrap(),
+    };
+
+    // freshly minted
+    assert!(!token(10000 + INSTALLATION_TOKEN_LIFETIME_SECS).needs_refresh(now));
+    assert!(!token(10000 + INSTALLATION_TOKEN_REFRESH_SECS + 1).needs_refresh(now));
+
+    // about to expire
+    assert!(token(10000 + INSTALLATION_TOKEN_REFRESH_SECS).needs_refresh(now));
+    assert!(token(10000 + 10).needs_refresh(now));
+
+    // expired
+    assert!(token(9000).needs_refresh(now));
+}
+
+#[tokio::test]
+async fn test_org_membership() {
+    use axum::{
+        extract::Path,
+        http::{header, HeaderMap, StatusCode},
+        response::IntoResponse,
+        routing::get,
+        Router,
+    };
+
+    // mocked GitHub: cyan is a private member, Yerus a public one
+    async fn members(Path(user): Path<String>, headers: HeaderMap) -> impl IntoResponse {
+        match headers
+            .get(header::AUTHORIZATION)
+            .map(|auth| auth.as_bytes())
+        {
+            Some(b"Bearer member-token") if user == "cyan" || user == "Yerus" => {
+                StatusCode::NO_CONTENT.into_response()
+            }
+            Some(b"Bearer member-token") => StatusCode::NOT_FOUND.into_response(),
+            Some(b"Bearer no-scope-token") => StatusCode::FORBIDDEN.into_response(),
+            _ => (
+                StatusCode::FOUND,
+                [(
+                    header::LOCATION,
+                    format!("/orgs/aosc-dev/public_members/{user}"),
+                )],
+            )
+                .into_response(),
+        }
+    }
+    async fn public_members(Path(user): Path<String>) -> StatusCode {
+        if user == "Yerus" {
+            StatusCode::NO_CONTENT
+        } else {
+            StatusCode::NOT_FOUND
+        }
+    }
+
+    let app = Router::new()
+        .route("/orgs/aosc-dev/members/:user", get(members))
+        .route("/orgs/aosc-dev/public_members/:user", get(public_members));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    let api = format!("http://{addr}");
+    let is_member = |user: &'static str, token: Option<&'static str>| {
+        let api = api.clone();
+        async move { org_membership(&api, "aosc-dev", user, token).await.unwrap() }
+    };
+
+    // private member, seen with the token
+    assert!(is_member("cyan", Some("member-token")).await);
+    assert!(!is_member("cyan", None).await);
+    // non-member
+    assert!(!is_member("mallory", Some("member-token")).await);
+    assert!(!is_member("mallory", None).await);
+
+    // tokens that cannot see private members only find public ones
+    assert!(is_member("Yerus", Some("outsider-token")).await);
+    assert!(!is_member("cyan", Some("outsider-token")).await);
+    assert!(is_member("Yerus", Some("no-scope-token")).await);
+    assert!(!is_member("cyan", Some("no-scope-token")).await);
+    assert!(is_member("Yerus", None).await);
+}
+
+#[tokio::test]
+async fn test_login_from_telegram() {
+    use axum::{extract::Query, http::StatusCode, routing::get, Router};
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    // mocked minzhengbu: "flaky" fails once, "expired" is forgotten
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let counter = attempts.clone();
+    let app = Router::new().route(
+        "/login_from_telegram",
+        get(
+            move |Query(query): Query<HashMap<String, String>>| async move {
+                let attempt = counter.fetch_add(1, Ordering::SeqCst);
+                match query["rid"].as_str() {
+                    "flaky" if attempt == 0 => StatusCode::SERVICE_UNAVAILABLE,
+                    "expired" => StatusCode::NOT_FOUND,
+                    _ if query["telegram_id"] == "1234" => StatusCode::OK,
+                    _ => StatusCode::BAD_REQUEST,
+                }
+            },
+        ),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    let base_url = format!("http://{addr}");
+    let delay = Duration::from_millis(1);
+
+    // success after one retry
+    login_from_telegram(&base_url, 1234, "flaky", delay)
+        .await
+        .unwrap();
+    assert_eq!(attempts.swap(0, Ordering::SeqCst), 2);
+
+    // expired login is not retried
+    let err = login_from_telegram(&base_url, 1234, "expired", delay)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "That login link has expired, please run /login again"
+    );
+    assert!(format!("{err:?}").contains("404"));
+    assert_eq!(attempts.swap(0, Ordering::SeqCst), 1);
+
+    // login service down
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let down = format!("http://{}", listener.local_addr().unwrap());
+    drop(listener);
+    let err = login_from_telegram(&down, 1234, "flaky", delay)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Could not reach the login service, please try again later"
+    );
+}
@@ -0,0 +1,201 @@
+use buildit_utils::github::parse_ab;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::{BTreeSet, HashMap};
+
+/// A spec or defines file changed by a pull request
+pub struct LintFile {
+    /// path in the abbs tree, e.g. app-shells/fish/spec
+    pub path: String,
+    pub content: String,
+}
+
+/// What the rules know about the abbs tree
+pub struct LintContext {
+    /// names of all packages, including split packages
+    pub packages: BTreeSet<String>,
+}
+
+/// Problems found in one file, empty if it passes
+pub struct LintReport {
+    pub path: String,
+    pub problems: Vec<String>,
+}
+
+/// A pre-build check, add new ones to `LINT_RULES`
+pub trait LintRule: Sync {
+    /// Whether the rule checks this file
+    fn applies_to(&self, path: &str) -> bool;
+    fn check(&self, file: &LintFile, ctx: &LintContext) -> Vec<String>;
+}
+
+pub const LINT_RULES: &[&dyn LintRule] =
+    &[&SyntaxRule, &VersionRule, &ChecksumRule, &DependencyRule];
+
+pub fn is_spec(path: &str) -> bool {
+    let parts = path.split('/').collect::<Vec<_>>();
+    matches!(parts.as_slice(), [_, _, "spec"])
+}
+
+pub fn is_defines(path: &str) -> bool {
+    let parts = path.split('/').collect::<Vec<_>>();
+    matches!(parts.as_slice(), [_, _, _, "defines"])
+}
+
+/// Variables assigned in the file, without evaluating it
+fn assigned_variables(content: &str) -> BTreeSet<&str> {
+    static ASSIGNMENT: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"(?m)^\s*([A-Za-z_][A-Za-z0-9_]*)=").unwrap());
+    ASSIGNMENT
+        .captures_iter(content)
+        .map(|cap| cap.get(1).unwrap().as_str())
+        .collect()
+}
+
+/// The file can be parsed by apml
+struct SyntaxRule;
+
+impl LintRule for SyntaxRule {
+    fn applies_to(&self, path: &str) -> bool {
+        is_spec(path) || is_defines(path)
+    }
+
+    fn check(&self, file: &LintFile, _ctx: &LintContext) -> Vec<String> {
+        match parse_ab(&file.content, &mut HashMap::new()) {
+            Ok(()) => vec![],
+            Err(err) => vec![format!("Syntax error: {err}")],
+        }
+    }
+}
+
+/// The spec defines its version and revision
+struct VersionRule;
+
+impl LintRule for VersionRule {
+    fn applies_to(&self, path: &str) -> bool {
+        is_spec(path)
+    }
+
+    fn check(&self, file: &LintFile, _ctx: &LintContext) -> Vec<String> {
+        let vars = assigned_variables(&file.content);
+        ["VER", "REL"]
+            .iter()
+            .filter(|var| !vars.contains(*var))
+            .map(|var| format!("{var} is not defined"))
+            .collect()
+    }
+}
+
+/// Every source of the spec has checksums
+struct ChecksumRule;
+
+impl LintRule for ChecksumRule {
+    fn applies_to(&self, path: &str) -> bool {
+        is_spec(path)
+    }
+
+    fn check(&self, file: &LintFile, _ctx: &LintContext) -> Vec<String> {
+        let vars = assigned_variables(&file.content);
+        vars.iter()
+            .filter_map(|var| var.strip_prefix("SRCS"))
+            .filter(|suffix| suffix.is_empty() || suffix.starts_with("__"))
+            .filter(|suffix| !vars.contains(format!("CHKSUMS{suffix}").as_str()))
+            .map(|suffix| format!("SRCS{suffix} has no CHKSUMS{suffix}"))
+            .collect()
+    }
+}
+
+/// Dependencies refer to packages in the tree
+struct DependencyRule;
+
+impl LintRule for DependencyRule {
+    fn applies_to(&self, path: &str) -> bool {
+        is_defines(path)
+    }
+
+    fn check(&self, file: &LintFile, ctx: &LintContext) -> Vec<String> {
+        let mut context = HashMap::new();
+        if parse_ab(&file.content, &mut context).is_err() {
+            // reported by SyntaxRule
+            return vec![];
+        }
+
+        let mut res = vec![];
+        for var in ["PKGDEP", "BUILDDEP"] {
+            for dep in context
+                .get(var)
+                .map(|s| s.as_str())
+                .unwrap_or("")
+                .split_whitespace()
+            {
+                // strip version constraints, e.g. glibc>=2.38
+                let name = dep.split(['<', '>', '=']).next().unwrap_or(dep);
+                if !name.is_empty() && !ctx.packages.contains(name) {
+                    res.push(format!("{var} refers to unknown package {name}"));
+                }
+            }
+        }
+        res
+    }
+}
+
+/// Run all applicable rules on each file
+pub fn lint_files(files: &[LintFile], ctx: &LintContext) -> Vec<LintReport> {
+    files
+        .iter()
+        .filter(|file| is_spec(&file.path) || is_defines(&file.path))
+        .map(|file| LintReport {
+            path: file.path.clone(),
+            problems: LINT_RULES
+                .iter()
+                .filter(|rule| rule.applies_to(&file.path))
+                .flat_map(|rule| rule.check(file, ctx))
+                .collect(),
+        })
+        .collect()
+}
+
+#[test]
+fn test_lint_files() {
+    let ctx = LintContext {
+        packages: BTreeSet::from(["fish".to_string(), "ncurses".to_string()]),
+    };
+    let file = |path: &str, content: &str| LintFile {
+        path: path.to_string(),
+        content: content.to_string(),
+    };
+
+    // well-formed spec
+    let reports = lint_files(
+        &[
+            file(
+                "app-shells/fish/spec",
+                "VER=3.7.1\nREL=1\nSRCS=\"tbl::https://github.com/fish-shell/fish-shell/releases/download/$VER/fish-$VER.tar.xz\"\nCHKSUMS=\"sha256::614c9f5643cd0799df391395fa6bbc3649427bb839722ce3b114d3bbc1a3b250\"\nCHKCUPDATE=\"anitya::id=812\"\n",
+            ),
+            file("README.md", "VER="),
+        ],
+        &ctx,
+    );
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].path, "app-shells/fish/spec");
+    assert!(reports[0].problems.is_empty());
+
+    // missing REL and per-arch checksum
+    let reports = lint_files(
+        &[file(
+            "app-shells/fish/spec",
+            "VER=3.7.1\nSRCS__AMD64=\"file::https://example.org/fish-amd64.tar.xz\"\n",
+        )],
+        &ctx,
+    );
+    assert_eq!(
+        reports[0].problems,
+        vec!["REL is not defined", "SRCS__AMD64 has no CHKSUMS__AMD64"]
+    );
+
+    assert!(is_spec("app-shells/fish/spec"));
+    assert!(!is_spec("app-shells/fish/autobuild/spec"));
+    assert!(is_defines("app-shells/fish/autobuild/defines"));
+    assert!(is_defines("core-devel/gcc/01-runtime/defines"));
+    assert!(!is_defines("app-shells/fish/defines"));
+}
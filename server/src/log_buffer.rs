@@ -0,0 +1,139 @@
+use crate::ARGS;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use std::{collections::VecDeque, fmt::Write, sync::Mutex};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+/// Recent log records of the server, for diagnosis without shell access
+pub static LOG_BUFFER: Lazy<LogBuffer> = Lazy::new(|| LogBuffer::new(ARGS.log_buffer_size));
+
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    pub time: DateTime<Utc>,
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+}
+
+/// Bounded ring buffer keeping the last `capacity` log records
+pub struct LogBuffer {
+    capacity: usize,
+    records: Mutex<VecDeque<LogRecord>>,
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn push(&self, record: LogRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        // the record is formatted before locking,
+        // so the critical section only moves it into the buffer
+        let mut records = self.records.lock().unwrap();
+        if records.len() >= self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// Return the last `count` records whose level is at least as severe as `min_level`
+    pub fn tail(&self, min_level: Level, count: usize) -> Vec<LogRecord> {
+        let records = self.records.lock().unwrap();
+        let mut res = records
+            .iter()
+            .rev()
+            .filter(|record| record.level <= min_level)
+            .take(count)
+            .cloned()
+            .collect::<Vec<_>>();
+        res.reverse();
+        res
+    }
+}
+
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            write!(self.message, "{:?}", value).ok();
+        } else {
+            write!(self.fields, " {}={:?}", field.name(), value).ok();
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            write!(self.fields, " {}={}", field.name(), value).ok();
+        }
+    }
+}
+
+/// tracing layer that copies every event into a `LogBuffer`
+pub struct LogBufferLayer {
+    buffer: &'static LogBuffer,
+}
+
+impl LogBufferLayer {
+    pub fn new(buffer: &'static LogBuffer) -> Self {
+        Self { buffer }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for LogBufferLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+
+        self.buffer.push(LogRecord {
+            time: Utc::now(),
+            level: *event.metadata().level(),
+            target: event.metadata().target().to_string(),
+            message: visitor.message + &visitor.fields,
+        });
+    }
+}
+
+#[test]
+fn test_log_buffer_keeps_latest() {
+    let buffer = LogBuffer::new(3);
+    for i in 0..5 {
+        buffer.push(LogRecord {
+            time: Utc::now(),
+            level: if i % 2 == 0 { Level::INFO } else { Level::WARN },
+            target: "server".to_string(),
+            message: format!("record {i}"),
+        });
+    }
+
+    let messages = buffer
+        .tail(Level::TRACE, 10)
+        .into_iter()
+        .map(|record| record.message)
+        .collect::<Vec<_>>();
+    assert_eq!(messages, vec!["record 2", "record 3", "record 4"]);
+
+    let messages = buffer
+        .tail(Level::WARN, 10)
+        .into_iter()
+        .map(|record| record.message)
+        .collect::<Vec<_>>();
+    assert_eq!(messages, vec!["record 3"]);
+}
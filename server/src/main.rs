@@ -11,14 +11,20 @@ use opentelemetry::KeyValue;
 use opentelemetry_otlp::WithExportConfig;
 use opentelemetry_sdk::trace;
 use opentelemetry_sdk::Resource;
-use server::bot::{answer, Command};
-use server::recycler::recycler_worker;
+use server::autoscale::autoscale_worker;
+use server::bot::{answer, answer_callback, Command};
+use server::drain::drain_monitor;
+use server::log_buffer::{LogBufferLayer, LOG_BUFFER};
+use server::monitor::{slow_job_monitor, worker_monitor, SlowJobThreshold};
+use server::notifier::notifiers;
+use server::recycler::{expiry_worker, reconcile_jobs, recycler_worker, retention_worker};
 use server::routes::{
-    dashboard_status, job_info, job_list, job_restart, ping, pipeline_info, pipeline_list,
-    pipeline_new_pr, webhook_handler, worker_info, worker_job_update, worker_list, worker_poll,
+    dashboard_status, export_history, job_info, job_list, job_restart, log_get, metrics, ping,
+    pipeline_info, pipeline_list, pipeline_new_pr, stats_overview, webhook_handler, worker_info,
+    worker_job_progress, worker_job_update, worker_list, worker_poll, worker_register,
     ws_viewer_handler, ws_worker_handler, AppState, WSStateMap,
 };
-use server::routes::{pipeline_new, worker_heartbeat};
+use server::routes::{pipeline_new, pipeline_new_github_actions, worker_heartbeat};
 use server::routes::{pipeline_status, worker_status};
 use server::{DbPool, RemoteAddr, ARGS};
 use std::collections::HashMap;
@@ -28,7 +34,7 @@ use teloxide::prelude::*;
 use tower::Service;
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::services::{ServeDir, ServeFile};
-use tracing::{info, info_span};
+use tracing::{info, info_span, warn};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::Registry;
@@ -60,31 +66,64 @@ async fn main() -> anyhow::Result<()> {
             .with(env_filter)
             .with(tracing_leyer)
             .with(tracing_subscriber::fmt::Layer::default())
+            .with(LogBufferLayer::new(&LOG_BUFFER))
             .init();
     } else {
         // fallback to stdout
-        tracing_subscriber::fmt::init();
+        tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::from_default_env())
+            .finish()
+            .with(LogBufferLayer::new(&LOG_BUFFER))
+            .init();
+    }
+
+    let disabled = ARGS
+        .validate()
+        .into_iter()
+        .filter(|feature| !feature.is_available())
+        .collect::<Vec<_>>();
+    for feature in &disabled {
+        warn!(
+            "{} disabled due to missing config: {}",
+            feature.name,
+            feature.missing.join(", ")
+        );
+    }
+    if ARGS.strict == Some(true) && !disabled.is_empty() {
+        anyhow::bail!("Refusing to start in strict mode with incomplete config");
     }
 
     tracing::info!("Connecting to database");
-    let manager = ConnectionManager::<PgConnection>::new(&ARGS.database_url);
+    let manager = ConnectionManager::<PgConnection>::new(ARGS.database_url.expose());
     let pool = Pool::builder().test_on_check_out(true).build(manager)?;
 
+    let ws_state_map = WSStateMap::new(Mutex::new(HashMap::new()));
     let mut handles = vec![];
     let bot = if std::env::var("TELOXIDE_TOKEN").is_ok() {
         tracing::info!("Starting telegram bot");
         let bot = Bot::from_env();
 
-        let handler =
-            Update::filter_message().branch(dptree::entry().filter_command::<Command>().endpoint(
-                |bot: Bot, pool: DbPool, msg: Message, cmd: Command| async move {
-                    answer(bot, msg, cmd, pool).await
+        let handler = dptree::entry()
+            .branch(Update::filter_message().branch(
+                dptree::entry().filter_command::<Command>().endpoint(
+                    |bot: Bot,
+                     pool: DbPool,
+                     ws_state_map: WSStateMap,
+                     msg: Message,
+                     cmd: Command| async move {
+                        answer(bot, msg, cmd, pool, ws_state_map).await
+                    },
+                ),
+            ))
+            .branch(Update::filter_callback_query().endpoint(
+                |bot: Bot, pool: DbPool, query: CallbackQuery| async move {
+                    answer_callback(bot, query, pool).await
                 },
             ));
 
         let mut telegram = Dispatcher::builder(bot.clone(), handler)
             // Pass the shared state to the handler as a dependency.
-            .dependencies(dptree::deps![pool.clone()])
+            .dependencies(dptree::deps![pool.clone(), ws_state_map.clone()])
             .enable_ctrlc_handler()
             .build();
 
@@ -94,31 +133,72 @@ async fn main() -> anyhow::Result<()> {
         None
     };
 
+    if let Err(err) = reconcile_jobs(pool.clone(), bot.clone(), ARGS.orphan_policy).await {
+        warn!("Failed to reconcile jobs: {:?}", err);
+    }
+
+    if let (Some(bot), Some(chat_id)) = (&bot, ARGS.ops_chat) {
+        handles.push(tokio::spawn(worker_monitor(
+            pool.clone(),
+            bot.clone(),
+            ChatId(chat_id),
+        )));
+        handles.push(tokio::spawn(drain_monitor(
+            pool.clone(),
+            bot.clone(),
+            ChatId(chat_id),
+        )));
+        handles.push(tokio::spawn(slow_job_monitor(
+            pool.clone(),
+            bot.clone(),
+            ChatId(chat_id),
+            SlowJobThreshold {
+                factor: ARGS.slow_job_factor,
+                cap_secs: ARGS.slow_job_cap,
+            },
+        )));
+    }
+
+    if let Some(ttl) = ARGS.job_ttl {
+        handles.push(tokio::spawn(expiry_worker(pool.clone(), bot.clone(), ttl)));
+    }
+
     tracing::info!("Starting http server");
     // build our application with a route
     let state = AppState {
         pool: pool.clone(),
+        notifiers: notifiers(bot.as_ref())?,
         bot,
-        ws_state_map: WSStateMap::new(Mutex::new(HashMap::new())),
+        ws_state_map,
     };
 
     let mut app = Router::new()
         .route("/api/ping", get(ping))
         .route("/api/pipeline/new", post(pipeline_new))
         .route("/api/pipeline/new_pr", post(pipeline_new_pr))
+        .route(
+            "/api/pipeline/new_github_actions",
+            post(pipeline_new_github_actions),
+        )
         .route("/api/pipeline/status", get(pipeline_status))
         .route("/api/pipeline/list", get(pipeline_list))
         .route("/api/pipeline/info", get(pipeline_info))
         .route("/api/job/list", get(job_list))
         .route("/api/job/info", get(job_info))
         .route("/api/job/restart", post(job_restart))
+        .route("/api/logs/:pipeline/:arch", get(log_get))
+        .route("/api/export", get(export_history))
+        .route("/api/worker/register", post(worker_register))
         .route("/api/worker/heartbeat", post(worker_heartbeat))
         .route("/api/worker/poll", post(worker_poll))
         .route("/api/worker/job_update", post(worker_job_update))
+        .route("/api/worker/job_progress", post(worker_job_progress))
         .route("/api/worker/status", get(worker_status))
         .route("/api/worker/list", get(worker_list))
         .route("/api/worker/info", get(worker_info))
         .route("/api/dashboard/status", get(dashboard_status))
+        .route("/api/stats", get(stats_overview))
+        .route("/metrics", get(metrics))
         .route("/api/ws/viewer/:hostname", get(ws_viewer_handler))
         .route("/api/ws/worker/:hostname", get(ws_worker_handler))
         .route("/api/webhook", post(webhook_handler))
@@ -209,6 +289,12 @@ async fn main() -> anyhow::Result<()> {
         }));
     }
 
+    if let Some(url) = &ARGS.autoscale_webhook {
+        handles.push(tokio::spawn(autoscale_worker(pool.clone(), url.clone())));
+    }
+    if let Some(retention_days) = ARGS.retention_days {
+        handles.push(tokio::spawn(retention_worker(pool.clone(), retention_days)));
+    }
     handles.push(tokio::spawn(recycler_worker(pool)));
 
     for handle in handles {
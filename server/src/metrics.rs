@@ -0,0 +1,111 @@
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write,
+    sync::Mutex,
+};
+
+/// Build results of the last hour, updated as workers report them
+pub static ARCH_SUCCESS: Lazy<SuccessWindow> =
+    Lazy::new(|| SuccessWindow::new(Duration::try_hours(1).unwrap()));
+
+/// Finish time and success of builds, oldest first
+type Results = VecDeque<(DateTime<Utc>, bool)>;
+
+/// Sliding window of build results per arch
+pub struct SuccessWindow {
+    window: Duration,
+    results: Mutex<BTreeMap<String, Results>>,
+}
+
+impl SuccessWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            results: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record a build result, returns the success ratio of the arch afterwards
+    pub fn record(&self, arch: &str, time: DateTime<Utc>, success: bool) -> f64 {
+        let mut results = self.results.lock().unwrap();
+        let queue = results.entry(arch.to_string()).or_default();
+        queue.push_back((time, success));
+        expire(queue, time - self.window);
+        ratio(queue)
+    }
+
+    /// Success ratio of each arch with results in the window
+    pub fn ratios(&self, now: DateTime<Utc>) -> BTreeMap<String, f64> {
+        let mut results = self.results.lock().unwrap();
+        results.retain(|_, queue| {
+            expire(queue, now - self.window);
+            !queue.is_empty()
+        });
+        results
+            .iter()
+            .map(|(arch, queue)| (arch.clone(), ratio(queue)))
+            .collect()
+    }
+}
+
+// results arrive in order, so the oldest are at the front
+fn expire(queue: &mut Results, deadline: DateTime<Utc>) {
+    while queue.front().is_some_and(|(time, _)| *time < deadline) {
+        queue.pop_front();
+    }
+}
+
+fn ratio(queue: &Results) -> f64 {
+    if queue.is_empty() {
+        return 0.0;
+    }
+    let success = queue.iter().filter(|(_, success)| *success).count();
+    success as f64 / queue.len() as f64
+}
+
+/// Render gauges in the Prometheus text format
+pub fn render_metrics(ratios: &BTreeMap<String, f64>) -> String {
+    let mut res = String::new();
+    res.push_str("# HELP buildit_arch_success_ratio Ratio of successful builds in the last hour\n");
+    res.push_str("# TYPE buildit_arch_success_ratio gauge\n");
+    for (arch, ratio) in ratios {
+        writeln!(res, "buildit_arch_success_ratio{{arch=\"{arch}\"}} {ratio}").unwrap();
+    }
+    res
+}
+
+#[test]
+fn test_success_window() {
+    let window = SuccessWindow::new(Duration::try_hours(1).unwrap());
+    let at = |mins: i64| DateTime::from_timestamp(mins * 60, 0).unwrap();
+
+    assert!(window.ratios(at(0)).is_empty());
+
+    assert_eq!(window.record("amd64", at(0), true), 1.0);
+    assert_eq!(window.record("riscv64", at(10), true), 1.0);
+    assert_eq!(window.record("riscv64", at(20), false), 0.5);
+    assert_eq!(window.record("riscv64", at(30), false), 1.0 / 3.0);
+    assert_eq!(window.record("amd64", at(40), false), 0.5);
+
+    let ratios = window.ratios(at(45));
+    assert_eq!(ratios["amd64"], 0.5);
+    assert_eq!(ratios["riscv64"], 1.0 / 3.0);
+
+    // the success at 0 and 10 slide out of the window
+    let ratios = window.ratios(at(75));
+    assert_eq!(ratios["amd64"], 0.0);
+    assert_eq!(ratios["riscv64"], 0.0);
+    assert_eq!(window.record("riscv64", at(80), true), 1.0 / 3.0);
+
+    // archs without recent builds are dropped
+    let ratios = window.ratios(at(101));
+    assert_eq!(ratios.keys().collect::<Vec<_>>(), vec!["riscv64"]);
+    assert_eq!(
+        render_metrics(&ratios),
+        "# HELP buildit_arch_success_ratio Ratio of successful builds in the last hour\n\
+        # TYPE buildit_arch_success_ratio gauge\n\
+        buildit_arch_success_ratio{arch=\"riscv64\"} 1\n"
+    );
+}
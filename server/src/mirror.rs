@@ -0,0 +1,158 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+/// Whether a built package has reached the repo
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MirrorState {
+    InRepo,
+    /// the version in the repo if any
+    Pending {
+        repo_version: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorStatus {
+    pub package: String,
+    pub version: String,
+    pub arch: String,
+    pub state: MirrorState,
+}
+
+/// Path to the Packages index of the branch and arch in the local repo
+pub fn packages_index_path(repo: &Path, branch: &str, arch: &str) -> PathBuf {
+    let arch = if arch == "noarch" { "all" } else { arch };
+    repo.join("debs/dists")
+        .join(branch)
+        .join("main")
+        .join(format!("binary-{arch}"))
+        .join("Packages")
+}
+
+/// Versions of each package listed in a Packages index
+pub fn parse_packages_index(content: &str) -> BTreeMap<String, BTreeSet<String>> {
+    let mut res: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    let mut package = None;
+    let mut version = None;
+    // stanzas are separated by blank lines
+    for line in content.lines().chain([""]) {
+        if line.trim().is_empty() {
+            if let (Some(package), Some(version)) = (package.take(), version.take()) {
+                res.entry(package).or_default().insert(version);
+            }
+        } else if let Some(value) = line.strip_prefix("Package:") {
+            package = Some(value.trim().to_string());
+        } else if let Some(value) = line.strip_prefix("Version:") {
+            version = Some(value.trim().to_string());
+        }
+    }
+    res
+}
+
+/// Read the Packages index, a missing one is treated as empty
+pub fn read_packages_index(
+    repo: &Path,
+    branch: &str,
+    arch: &str,
+) -> anyhow::Result<BTreeMap<String, BTreeSet<String>>> {
+    match std::fs::read_to_string(packages_index_path(repo, branch, arch)) {
+        Ok(content) => Ok(parse_packages_index(&content)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub fn mirror_state(
+    index: &BTreeMap<String, BTreeSet<String>>,
+    package: &str,
+    version: &str,
+) -> MirrorState {
+    match index.get(package) {
+        Some(versions) if versions.contains(version) => MirrorState::InRepo,
+        Some(versions) => MirrorState::Pending {
+            repo_version: versions.last().cloned(),
+        },
+        None => MirrorState::Pending { repo_version: None },
+    }
+}
+
+pub fn format_mirror_status(pipeline_id: i32, statuses: &[MirrorStatus]) -> String {
+    let mut res = format!("Repo sync state of pipeline #{pipeline_id}:");
+    for status in statuses {
+        let state = match &status.state {
+            MirrorState::InRepo => "in repo".to_string(),
+            MirrorState::Pending {
+                repo_version: Some(version),
+            } => format!("pending (repo has {version})"),
+            MirrorState::Pending { repo_version: None } => "pending (not in repo)".to_string(),
+        };
+        res.push_str(&format!(
+            "\n{} {} ({}): {}",
+            status.package, status.version, status.arch, state
+        ));
+    }
+    res
+}
+
+#[test]
+fn test_mirror_status() {
+    let repo = std::env::temp_dir().join(format!("buildit-mirror-{}", std::process::id()));
+    let index = packages_index_path(&repo, "fd-9.0.0", "amd64");
+    assert!(index.ends_with("debs/dists/fd-9.0.0/main/binary-amd64/Packages"));
+    assert!(packages_index_path(&repo, "stable", "noarch").ends_with("binary-all/Packages"));
+
+    std::fs::create_dir_all(index.parent().unwrap()).unwrap();
+    std::fs::write(
+        &index,
+        "Package: fd\nVersion: 9.0.0\nArchitecture: amd64\n\nPackage: fd\nVersion: 8.7.1\nArchitecture: amd64\n\nPackage: ripgrep\nVersion: 14.0.3-1\nArchitecture: amd64\n",
+    )
+    .unwrap();
+
+    let index = read_packages_index(&repo, "fd-9.0.0", "amd64").unwrap();
+    assert_eq!(mirror_state(&index, "fd", "9.0.0"), MirrorState::InRepo);
+    assert_eq!(
+        mirror_state(&index, "ripgrep", "14.1.0"),
+        MirrorState::Pending {
+            repo_version: Some("14.0.3-1".to_string())
+        }
+    );
+    assert_eq!(
+        mirror_state(&index, "bat", "0.24.0"),
+        MirrorState::Pending { repo_version: None }
+    );
+
+    // branch not synced yet
+    assert!(read_packages_index(&repo, "fd-9.1.0", "amd64")
+        .unwrap()
+        .is_empty());
+    std::fs::remove_dir_all(&repo).unwrap();
+
+    let statuses = [
+        MirrorStatus {
+            package: "fd".to_string(),
+            version: "9.0.0".to_string(),
+            arch: "amd64".to_string(),
+            state: MirrorState::InRepo,
+        },
+        MirrorStatus {
+            package: "ripgrep".to_string(),
+            version: "14.1.0".to_string(),
+            arch: "amd64".to_string(),
+            state: MirrorState::Pending {
+                repo_version: Some("14.0.3-1".to_string()),
+            },
+        },
+        MirrorStatus {
+            package: "bat".to_string(),
+            version: "0.24.0".to_string(),
+            arch: "arm64".to_string(),
+            state: MirrorState::Pending { repo_version: None },
+        },
+    ];
+    assert_eq!(
+        format_mirror_status(1, &statuses),
+        "Repo sync state of pipeline #1:\nfd 9.0.0 (amd64): in repo\nripgrep 14.1.0 (amd64): pending (repo has 14.0.3-1)\nbat 0.24.0 (arm64): pending (not in repo)"
+    );
+}
@@ -2,6 +2,7 @@ use diesel::prelude::*;
 use serde::Serialize;
 
 #[derive(Queryable, Selectable, Identifiable, Debug)]
+#[cfg_attr(test, derive(Insertable))]
 #[diesel(table_name = crate::schema::pipelines)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Pipeline {
@@ -15,6 +16,16 @@ pub struct Pipeline {
     pub github_pr: Option<i64>,
     pub telegram_user: Option<i64>,
     pub creator_user_id: Option<i32>,
+    pub requested_by: Option<String>,
+    pub build_plan_hash: Option<String>,
+    /// GitHub repo of the abbs tree, as owner/repo
+    pub repo: String,
+    /// Pipeline rebuilt by this one with /retrypipeline
+    pub parent_pipeline_id: Option<i32>,
+    /// New pipeline summary sent to `telegram_user`, edited as jobs progress
+    pub telegram_message_id: Option<i32>,
+    /// What `git_branch` is: branch, tag or commit
+    pub git_ref_kind: String,
 }
 
 #[derive(Insertable)]
@@ -30,9 +41,20 @@ pub struct NewPipeline {
     pub github_pr: Option<i64>,
     pub telegram_user: Option<i64>,
     pub creator_user_id: Option<i32>,
+    pub requested_by: Option<String>,
+    pub build_plan_hash: Option<String>,
+    /// GitHub repo of the abbs tree, as owner/repo
+    pub repo: String,
+    /// Pipeline rebuilt by this one with /retrypipeline
+    pub parent_pipeline_id: Option<i32>,
+    /// New pipeline summary sent to `telegram_user`, edited as jobs progress
+    pub telegram_message_id: Option<i32>,
+    /// What `git_branch` is: branch, tag or commit
+    pub git_ref_kind: String,
 }
 
-#[derive(Queryable, Selectable, Associations, Identifiable, Debug)]
+#[derive(Queryable, Selectable, Associations, Identifiable, Serialize, Debug)]
+#[cfg_attr(test, derive(Insertable))]
 #[diesel(belongs_to(Pipeline))]
 #[diesel(table_name = crate::schema::jobs)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
@@ -60,6 +82,14 @@ pub struct Job {
     pub require_min_total_mem_per_core: Option<f32>,
     pub require_min_disk: Option<i64>,
     pub assign_time: Option<chrono::DateTime<chrono::Utc>>,
+    pub retry_count: i32,
+    pub environment: Option<String>,
+    pub priority: i32,
+    pub failure_kind: Option<String>,
+    pub require_label: Option<String>,
+    pub peak_memory_bytes: Option<i64>,
+    pub disk_bytes: Option<i64>,
+    pub package_timings: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -76,9 +106,13 @@ pub struct NewJob {
     pub require_min_total_mem: Option<i64>,
     pub require_min_total_mem_per_core: Option<f32>,
     pub require_min_disk: Option<i64>,
+    pub retry_count: i32,
+    pub priority: i32,
+    pub require_label: Option<String>,
 }
 
 #[derive(Queryable, Selectable, Serialize, Debug)]
+#[cfg_attr(test, derive(Insertable))]
 #[diesel(table_name = crate::schema::workers)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Worker {
@@ -93,11 +127,15 @@ pub struct Worker {
     pub performance: Option<i64>,
     pub visible: bool,
     pub internet_connectivity: bool,
+    pub version: Option<String>,
+    pub labels: Option<String>,
 }
 
-#[derive(Insertable, AsChangeset)]
+#[derive(Insertable, AsChangeset, Debug, Clone, PartialEq)]
 #[diesel(table_name = crate::schema::workers)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
+// clear labels and performance when a worker stops sending them
+#[diesel(treat_none_as_null = true)]
 pub struct NewWorker {
     pub hostname: String,
     pub arch: String,
@@ -108,6 +146,8 @@ pub struct NewWorker {
     pub disk_free_space_bytes: i64,
     pub performance: Option<i64>,
     pub internet_connectivity: bool,
+    pub version: Option<String>,
+    pub labels: Option<String>,
 }
 
 #[derive(Queryable, Selectable)]
@@ -134,3 +174,163 @@ pub struct NewUser {
     pub github_email: Option<String>,
     pub telegram_chat_id: Option<i64>,
 }
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset)]
+#[diesel(table_name = crate::schema::chat_settings)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ChatSetting {
+    pub telegram_chat_id: i64,
+    pub notify_mode: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset)]
+#[diesel(table_name = crate::schema::idempotency_keys)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct IdempotencyKey {
+    pub key: String,
+    pub pipeline_id: i32,
+    pub creation_time: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::opened_prs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OpenedPr {
+    pub id: i32,
+    pub pr_number: i64,
+    pub title: String,
+    pub git_ref: String,
+    pub telegram_chat_id: i64,
+    pub creation_time: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::opened_prs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewOpenedPr {
+    pub pr_number: i64,
+    pub title: String,
+    pub git_ref: String,
+    pub telegram_chat_id: i64,
+    pub creation_time: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::audit_log)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct AuditLog {
+    pub id: i32,
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub actor: String,
+    pub action: String,
+    pub details: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::audit_log)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewAuditLog {
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub actor: String,
+    pub action: String,
+    pub details: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug)]
+#[diesel(table_name = crate::schema::pending_prs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct PendingPr {
+    pub pipeline_id: i32,
+    pub title: String,
+    pub git_ref: String,
+    pub packages: String,
+    pub telegram_chat_id: i64,
+    pub creation_time: chrono::DateTime<chrono::Utc>,
+}
+
+#[cfg(test)]
+impl Pipeline {
+    /// Pipeline of fd on amd64 requested from telegram, for tests to override
+    pub fn fixture() -> Self {
+        Self {
+            id: 12,
+            packages: "fd".to_string(),
+            archs: "amd64".to_string(),
+            git_branch: "fd-9.0.0".to_string(),
+            git_sha: "34acef168fc5ec454d3825fc864964951b130b49".to_string(),
+            creation_time: chrono::DateTime::from_timestamp(61, 0).unwrap(),
+            source: "telegram".to_string(),
+            github_pr: None,
+            telegram_user: None,
+            creator_user_id: None,
+            requested_by: None,
+            build_plan_hash: None,
+            repo: "AOSC-Dev/aosc-os-abbs".to_string(),
+            parent_pipeline_id: None,
+            telegram_message_id: None,
+            git_ref_kind: "branch".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Job {
+    /// Queued job of `Pipeline::fixture()`, for tests to override
+    pub fn fixture() -> Self {
+        Self {
+            id: 1,
+            pipeline_id: 12,
+            packages: "fd".to_string(),
+            arch: "amd64".to_string(),
+            creation_time: chrono::DateTime::from_timestamp(61, 0).unwrap(),
+            status: "created".to_string(),
+            github_check_run_id: None,
+            build_success: None,
+            pushpkg_success: None,
+            successful_packages: None,
+            failed_package: None,
+            skipped_packages: None,
+            log_url: None,
+            finish_time: None,
+            error_message: None,
+            elapsed_secs: None,
+            assigned_worker_id: None,
+            built_by_worker_id: None,
+            require_min_core: None,
+            require_min_total_mem: None,
+            require_min_total_mem_per_core: None,
+            require_min_disk: None,
+            assign_time: None,
+            retry_count: 0,
+            environment: None,
+            priority: 0,
+            failure_kind: None,
+            require_label: None,
+            peak_memory_bytes: None,
+            disk_bytes: None,
+            package_timings: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Worker {
+    /// Idle amd64 worker, for tests to override
+    pub fn fixture() -> Self {
+        Self {
+            id: 1,
+            hostname: "Yerus".to_string(),
+            arch: "amd64".to_string(),
+            git_commit: "unknown".to_string(),
+            memory_bytes: 1 << 34,
+            logical_cores: 16,
+            last_heartbeat_time: chrono::DateTime::from_timestamp(61, 0).unwrap(),
+            disk_free_space_bytes: 1 << 40,
+            performance: None,
+            visible: true,
+            internet_connectivity: true,
+            version: None,
+            labels: None,
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use crate::{
+    api::{running_job_medians, running_jobs, worker_status, RunningJob},
+    bot::format_duration,
+    DbPool, HEARTBEAT_TIMEOUT,
+};
+use chrono::{DateTime, Utc};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+use teloxide::{prelude::*, types::ChatId};
+use tracing::{info, warn};
+
+/// Consecutive checks a worker must stay in the new state before it is reported
+const CONFIRM_CHECKS: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkerEvent {
+    Offline,
+    Online,
+}
+
+struct WorkerState {
+    /// last reported state
+    online: bool,
+    /// consecutive checks in the other state
+    flipped: u32,
+}
+
+/// Remember which workers are online, so that each transition is reported once
+pub struct WorkerMonitor {
+    confirm: u32,
+    workers: BTreeMap<i32, WorkerState>,
+}
+
+impl WorkerMonitor {
+    pub fn new(confirm: u32) -> Self {
+        Self {
+            confirm,
+            workers: BTreeMap::new(),
+        }
+    }
+
+    /// Feed whether the worker is online now, return the event to report if any
+    pub fn update(&mut self, worker_id: i32, online: bool) -> Option<WorkerEvent> {
+        // workers seen for the first time, e.g. after a restart, are not reported
+        let state = self
+            .workers
+            .entry(worker_id)
+            .or_insert(WorkerState { online, flipped: 0 });
+
+        if state.online == online {
+            // brief blips are forgotten
+            state.flipped = 0;
+            return None;
+        }
+
+        state.flipped += 1;
+        if state.flipped < self.confirm {
+            return None;
+        }
+
+        state.online = online;
+        state.flipped = 0;
+        Some(if online {
+            WorkerEvent::Online
+        } else {
+            WorkerEvent::Offline
+        })
+    }
+}
+
+pub fn format_worker_event(
+    event: WorkerEvent,
+    hostname: &str,
+    arch: &str,
+    last_seen: DateTime<Utc>,
+) -> String {
+    match event {
+        WorkerEvent::Offline => format!(
+            "⚠️ Worker {hostname} ({arch}) went offline, last seen at {}",
+            last_seen.format("%Y-%m-%d %H:%M:%S UTC")
+        ),
+        WorkerEvent::Online => format!("✅ Worker {hostname} ({arch}) is back online"),
+    }
+}
+
+pub async fn worker_monitor_inner(
+    pool: DbPool,
+    bot: &Bot,
+    chat_id: ChatId,
+    monitor: &mut WorkerMonitor,
+) -> anyhow::Result<()> {
+    loop {
+        let deadline = Utc::now() - chrono::Duration::try_seconds(HEARTBEAT_TIMEOUT).unwrap();
+        for worker in worker_status(pool.clone()).await? {
+            let online = worker.last_heartbeat_time > deadline;
+            let Some(event) = monitor.update(worker.id, online) else {
+                continue;
+            };
+
+            info!(
+                "Worker {} ({}) is now {:?}",
+                worker.hostname, worker.arch, event
+            );
+            let text = format_worker_event(
+                event,
+                &worker.hostname,
+                &worker.arch,
+                worker.last_heartbeat_time,
+            );
+            if let Err(err) = bot.send_message(chat_id, text).await {
+                warn!("Failed to send worker alert: {}", err);
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+    }
+}
+
+pub async fn worker_monitor(pool: DbPool, bot: Bot, chat_id: ChatId) {
+    // kept across restarts of the loop to avoid repeating alerts
+    let mut monitor = WorkerMonitor::new(CONFIRM_CHECKS);
+    loop {
+        info!("Starting worker monitor");
+        if let Err(err) = worker_monitor_inner(pool.clone(), &bot, chat_id, &mut monitor).await {
+            warn!("Got error running worker monitor: {}", err);
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// When a running job is considered stuck
+#[derive(Debug, Clone, Copy)]
+pub struct SlowJobThreshold {
+    /// Multiple of the median duration of previous builds
+    pub factor: f64,
+    /// Limit in seconds, used alone for packages never built before
+    pub cap_secs: Option<i64>,
+}
+
+impl SlowJobThreshold {
+    /// Longest expected run time of a job, `None` if there is nothing to compare with
+    pub fn limit_secs(&self, median_secs: Option<f64>) -> Option<i64> {
+        let relative = median_secs.map(|median| (median * self.factor) as i64);
+        match (relative, self.cap_secs) {
+            (Some(relative), Some(cap)) => Some(relative.min(cap)),
+            (relative, cap) => relative.or(cap),
+        }
+    }
+}
+
+/// Remember which running jobs were reported, so that each is reported once
+#[derive(Default)]
+pub struct SlowJobMonitor {
+    alerted: BTreeSet<i32>,
+}
+
+impl SlowJobMonitor {
+    /// Whether the job has just exceeded the limit
+    pub fn update(&mut self, job_id: i32, elapsed_secs: i64, limit_secs: Option<i64>) -> bool {
+        match limit_secs {
+            Some(limit) if elapsed_secs > limit => self.alerted.insert(job_id),
+            _ => false,
+        }
+    }
+
+    /// Forget jobs that are no longer running
+    pub fn retain(&mut self, running: &BTreeSet<i32>) {
+        self.alerted.retain(|job_id| running.contains(job_id));
+    }
+}
+
+pub fn format_slow_job(job: &RunningJob, elapsed_secs: i64, median_secs: Option<f64>) -> String {
+    let mut res = format!(
+        "🐢 Job #{} of pipeline #{} ({}) on {} has been running for {}",
+        job.job.id,
+        job.job.pipeline_id,
+        job.job.arch,
+        job.worker_hostname.as_deref().unwrap_or("unknown worker"),
+        format_duration(elapsed_secs)
+    );
+    if let Some(median) = median_secs {
+        res.push_str(&format!(
+            ", previous builds took {}",
+            format_duration(median as i64)
+        ));
+    }
+    res
+}
+
+pub async fn slow_job_monitor_inner(
+    pool: DbPool,
+    bot: &Bot,
+    chat_id: ChatId,
+    threshold: SlowJobThreshold,
+    monitor: &mut SlowJobMonitor,
+) -> anyhow::Result<()> {
+    loop {
+        let jobs = running_jobs(pool.clone()).await?;
+        let medians = running_job_medians(pool.clone()).await?;
+        monitor.retain(&jobs.iter().map(|job| job.job.id).collect());
+
+        let now = Utc::now();
+        for job in &jobs {
+            let Some(assign_time) = job.job.assign_time else {
+                continue;
+            };
+            let elapsed_secs = (now - assign_time).num_seconds();
+            let median_secs = medians
+                .get(&(job.job.packages.clone(), job.job.arch.clone()))
+                .copied();
+            if !monitor.update(job.job.id, elapsed_secs, threshold.limit_secs(median_secs)) {
+                continue;
+            }
+
+            info!(
+                "Job {} has been running for {}s, longer than expected",
+                job.job.id, elapsed_secs
+            );
+            let text = format_slow_job(job, elapsed_secs, median_secs);
+            if let Err(err) = bot.send_message(chat_id, text).await {
+                warn!("Failed to send slow job alert: {}", err);
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+    }
+}
+
+pub async fn slow_job_monitor(
+    pool: DbPool,
+    bot: Bot,
+    chat_id: ChatId,
+    threshold: SlowJobThreshold,
+) {
+    // kept across restarts of the loop to avoid repeating alerts
+    let mut monitor = SlowJobMonitor::default();
+    loop {
+        info!("Starting slow job monitor");
+        if let Err(err) =
+            slow_job_monitor_inner(pool.clone(), &bot, chat_id, threshold, &mut monitor).await
+        {
+            warn!("Got error running slow job monitor: {}", err);
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+#[test]
+fn test_worker_monitor() {
+    let mut monitor = WorkerMonitor::new(2);
+
+    // first sighting is not reported
+    assert_eq!(monitor.update(1, true), None);
+    assert_eq!(monitor.update(2, false), None);
+
+    // a single missed check is a blip
+    assert_eq!(monitor.update(1, false), None);
+    assert_eq!(monitor.update(1, true), None);
+    assert_eq!(monitor.update(1, false), None);
+
+    // going offline is reported once
+    assert_eq!(monitor.update(1, false), Some(WorkerEvent::Offline));
+    assert_eq!(monitor.update(1, false), None);
+
+    // and so is the recovery
+    assert_eq!(monitor.update(1, true), None);
+    assert_eq!(monitor.update(1, true), Some(WorkerEvent::Online));
+    assert_eq!(monitor.update(1, true), None);
+
+    // worker that was offline from the start
+    assert_eq!(monitor.update(2, true), None);
+    assert_eq!(monitor.update(2, true), Some(WorkerEvent::Online));
+
+    assert_eq!(
+        format_worker_event(
+            WorkerEvent::Offline,
+            "Yerus",
+            "amd64",
+            DateTime::from_timestamp(61, 0).unwrap()
+        ),
+        "⚠️ Worker Yerus (amd64) went offline, last seen at 1970-01-01 00:01:01 UTC"
+    );
+}
+
+#[test]
+fn test_slow_job_monitor() {
+    let threshold = SlowJobThreshold {
+        factor: 3.0,
+        cap_secs: Some(86400),
+    };
+    assert_eq!(threshold.limit_secs(Some(600.0)), Some(1800));
+    assert_eq!(threshold.limit_secs(Some(40000.0)), Some(86400));
+    assert_eq!(threshold.limit_secs(None), Some(86400));
+    let uncapped = SlowJobThreshold {
+        factor: 3.0,
+        cap_secs: None,
+    };
+    assert_eq!(uncapped.limit_secs(None), None);
+
+    let mut monitor = SlowJobMonitor::default();
+    let limit = threshold.limit_secs(Some(600.0));
+
+    // normal duration
+    assert!(!monitor.update(1, 300, limit));
+    assert!(!monitor.update(1, 1800, limit));
+    assert!(!monitor.update(2, 1200, limit));
+
+    // past the threshold, alerted once
+    assert!(monitor.update(1, 1801, limit));
+    assert!(!monitor.update(1, 2400, limit));
+    assert!(!monitor.update(1, 3600, limit));
+
+    // finished jobs are forgotten
+    monitor.retain(&BTreeSet::from([2]));
+    assert!(monitor.alerted.is_empty());
+
+    let job = RunningJob {
+        job: crate::models::Job {
+            pipeline_id: 2,
+            status: "running".to_string(),
+            assigned_worker_id: Some(1),
+            assign_time: Some(DateTime::from_timestamp(61, 0).unwrap()),
+            ..crate::models::Job::fixture()
+        },
+        worker_hostname: Some("Yerus".to_string()),
+    };
+    assert_eq!(
+        format_slow_job(&job, 1801, Some(600.0)),
+        "🐢 Job #1 of pipeline #2 (amd64) on Yerus has been running for 30m01s, previous builds took 10m00s"
+    );
+}
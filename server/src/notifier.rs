@@ -0,0 +1,431 @@
+use crate::{
+    bot::{failed_job_keyboard, send_message_with_markup},
+    formatter::JobSummary,
+    models::{Job, Pipeline},
+    ARGS,
+};
+use common::JobOk;
+use futures::future::{join_all, BoxFuture};
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+use teloxide::{
+    prelude::*,
+    types::{ChatId, ParseMode},
+    ApiError, RequestError,
+};
+use tracing::{error, warn};
+
+/// Result of a finished job, as delivered to notifiers
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JobResultSummary {
+    pub pipeline_id: i32,
+    pub job_id: i32,
+    pub arch: String,
+    pub packages: String,
+    pub git_branch: String,
+    pub git_sha: String,
+    pub github_pr: Option<i64>,
+    pub worker_hostname: String,
+    pub success: bool,
+    pub successful_packages: Vec<String>,
+    pub failed_package: Option<String>,
+    pub log_url: Option<String>,
+    pub elapsed_secs: Option<i64>,
+    /// Why the job could not be run at all
+    pub error: Option<String>,
+    /// Human readable summary in Telegram flavored HTML
+    pub html: String,
+}
+
+impl JobResultSummary {
+    fn new(job: &Job, pipeline: &Pipeline, worker_hostname: &str, html: String) -> Self {
+        Self {
+            pipeline_id: pipeline.id,
+            job_id: job.id,
+            arch: job.arch.clone(),
+            packages: job.packages.clone(),
+            git_branch: pipeline.git_branch.clone(),
+            git_sha: pipeline.git_sha.clone(),
+            github_pr: pipeline.github_pr,
+            worker_hostname: worker_hostname.to_string(),
+            success: false,
+            successful_packages: vec![],
+            failed_package: None,
+            log_url: None,
+            elapsed_secs: None,
+            error: None,
+            html,
+        }
+    }
+
+    pub fn from_ok(summary: &JobSummary) -> Self {
+        let JobOk {
+            successful_packages,
+            failed_package,
+            log_url,
+            elapsed_secs,
+            ..
+        } = summary.job_ok;
+        Self {
+            success: summary.success,
+            successful_packages: successful_packages.clone(),
+            failed_package: failed_package.clone(),
+            log_url: log_url.clone(),
+            elapsed_secs: Some(*elapsed_secs),
+            ..Self::new(
+                summary.job,
+                summary.pipeline,
+                summary.worker_hostname,
+                summary.to_html(),
+            )
+        }
+    }
+
+    pub fn from_error(job: &Job, pipeline: &Pipeline, worker_hostname: &str, error: &str) -> Self {
+        let text = format!(
+            "{}({}) build packages: {:?} Got Error: {}",
+            worker_hostname, job.arch, pipeline.packages, error
+        );
+        Self {
+            error: Some(error.to_string()),
+            ..Self::new(
+                job,
+                pipeline,
+                worker_hostname,
+                teloxide::utils::html::escape(&text),
+            )
+        }
+    }
+}
+
+/// A channel delivering job results
+pub trait Notifier: Send + Sync {
+    /// Name used in logs
+    fn name(&self) -> &'static str;
+
+    /// Deliver the summary, `dest` is an opaque destination of the pipeline
+    /// (currently the Telegram chat that requested it) if it wants results
+    fn notify<'a>(
+        &'a self,
+        dest: Option<&'a str>,
+        summary: &'a JobResultSummary,
+    ) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+pub type Notifiers = Arc<Vec<Box<dyn Notifier>>>;
+
+/// Notifiers enabled by the config
+pub fn notifiers(bot: Option<&Bot>) -> anyhow::Result<Notifiers> {
+    let mut res: Vec<Box<dyn Notifier>> = vec![];
+    if let Some(bot) = bot {
+        res.push(Box::new(TelegramNotifier { bot: bot.clone() }));
+    }
+    if let Some(url) = &ARGS.notify_webhook {
+        res.push(Box::new(WebhookNotifier::new(url)?));
+    }
+    Ok(Arc::new(res))
+}
+
+/// Deliver the summary through every notifier, log and return the errors of those
+/// that failed
+///
+/// Failures are not retried, a broken notifier must not hold up the GitHub report or
+/// resend the result through the notifiers that delivered it.
+pub async fn notify_all<'a>(
+    notifiers: &'a [Box<dyn Notifier>],
+    dest: Option<&str>,
+    summary: &JobResultSummary,
+) -> Vec<(&'a str, anyhow::Error)> {
+    let results = join_all(
+        notifiers
+            .iter()
+            .map(|notifier| notifier.notify(dest, summary)),
+    )
+    .await;
+    notifiers
+        .iter()
+        .zip(results)
+        .filter_map(|(notifier, res)| res.err().map(|err| (notifier.name(), err)))
+        .inspect(|(name, err)| {
+            error!(
+                "Failed to send result of job #{} to {name}: {err:?}",
+                summary.job_id
+            )
+        })
+        .collect()
+}
+
+/// The user blocked the bot or the chat is gone, sending again cannot succeed
+pub fn is_chat_unreachable(err: &RequestError) -> bool {
+    matches!(
+        err,
+        RequestError::Api(ApiError::BotBlocked | ApiError::ChatNotFound)
+    )
+}
+
+pub struct TelegramNotifier {
+    pub bot: Bot,
+}
+
+impl Notifier for TelegramNotifier {
+    fn name(&self) -> &'static str {
+        "telegram"
+    }
+
+    fn notify<'a>(
+        &'a self,
+        dest: Option<&'a str>,
+        summary: &'a JobResultSummary,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let Some(dest) = dest else {
+                return Ok(());
+            };
+
+            // offer follow-up actions for failed builds
+            let keyboard = (!summary.success && summary.error.is_none()).then(|| {
+                failed_job_keyboard(
+                    summary.pipeline_id,
+                    &summary.arch,
+                    summary.log_url.as_deref(),
+                )
+            });
+            match send_message_with_markup(
+                &self.bot,
+                ChatId(dest.parse()?),
+                &summary.html,
+                ParseMode::Html,
+                keyboard,
+            )
+            .await
+            {
+                // treat as delivered, so that the result is still reported elsewhere
+                Err(err) if is_chat_unreachable(&err) => {
+                    warn!(
+                        "Dropping result of job #{} for chat {dest}: {err}",
+                        summary.job_id
+                    );
+                    Ok(())
+                }
+                res => res.map(|_| ()).map_err(Into::into),
+            }
+        })
+    }
+}
+
+/// POST every job result as JSON to a URL
+pub struct WebhookNotifier {
+    url: String,
+    client: reqwest::Client,
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    destination: Option<&'a str>,
+    #[serde(flatten)]
+    summary: &'a JobResultSummary,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            url: url.to_string(),
+            client: reqwest::Client::builder()
+                .user_agent("buildit")
+                .timeout(Duration::from_secs(30))
+                .build()?,
+        })
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn notify<'a>(
+        &'a self,
+        dest: Option<&'a str>,
+        summary: &'a JobResultSummary,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.client
+                .post(&self.url)
+                .json(&WebhookPayload {
+                    destination: dest,
+                    summary,
+                })
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status())?;
+            Ok(())
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_notifiers() {
+    use std::sync::Mutex;
+
+    let summary = JobResultSummary {
+        pipeline_id: 12,
+        job_id: 34,
+        arch: "amd64".to_string(),
+        packages: "fd,ripgrep".to_string(),
+        git_branch: "fd-9.0.0".to_string(),
+        git_sha: "34acef168fc5ec454d3825fc864964951b130b49".to_string(),
+        github_pr: Some(4992),
+        worker_hostname: "Yerus".to_string(),
+        success: false,
+        successful_packages: vec!["fd".to_string()],
+        failed_package: Some("ripgrep".to_string()),
+        log_url: None,
+        elapsed_secs: Some(888),
+        error: None,
+        html: "<b>failed</b>".to_string(),
+    };
+
+    let payload = serde_json::to_value(WebhookPayload {
+        destination: Some("-1001234"),
+        summary: &summary,
+    })
+    .unwrap();
+    assert_eq!(
+        payload,
+        serde_json::json!({
+            "destination": "-1001234",
+            "pipeline_id": 12,
+            "job_id": 34,
+            "arch": "amd64",
+            "packages": "fd,ripgrep",
+            "git_branch": "fd-9.0.0",
+            "git_sha": "34acef168fc5ec454d3825fc864964951b130b49",
+            "github_pr": 4992,
+            "worker_hostname": "Yerus",
+            "success": false,
+            "successful_packages": ["fd"],
+            "failed_package": "ripgrep",
+            "log_url": null,
+            "elapsed_secs": 888,
+            "error": null,
+            "html": "<b>failed</b>",
+        })
+    );
+
+    // dispatch to multiple notifiers
+    type Sent = Vec<(&'static str, Option<String>, i32)>;
+    struct Recorder {
+        name: &'static str,
+        fail: bool,
+        sent: Arc<Mutex<Sent>>,
+    }
+
+    impl Notifier for Recorder {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn notify<'a>(
+            &'a self,
+            dest: Option<&'a str>,
+            summary: &'a JobResultSummary,
+        ) -> BoxFuture<'a, anyhow::Result<()>> {
+            Box::pin(async move {
+                if self.fail {
+                    anyhow::bail!("unreachable");
+                }
+                self.sent.lock().unwrap().push((
+                    self.name,
+                    dest.map(str::to_string),
+                    summary.job_id,
+                ));
+                Ok(())
+            })
+        }
+    }
+
+    let sent = Arc::new(Mutex::new(vec![]));
+    let notifiers: Vec<Box<dyn Notifier>> = ["telegram", "matrix", "webhook"]
+        .into_iter()
+        .map(|name| {
+            Box::new(Recorder {
+                name,
+                fail: name == "matrix",
+                sent: sent.clone(),
+            }) as Box<dyn Notifier>
+        })
+        .collect();
+
+    let failures = notify_all(&notifiers, Some("-1001234"), &summary).await;
+    assert_eq!(
+        failures
+            .iter()
+            .map(|(name, err)| (*name, err.to_string()))
+            .collect::<Vec<_>>(),
+        [("matrix", "unreachable".to_string())]
+    );
+    // the others still got it
+    assert_eq!(
+        *sent.lock().unwrap(),
+        [
+            ("telegram", Some("-1001234".to_string()), 34),
+            ("webhook", Some("-1001234".to_string()), 34)
+        ]
+    );
+
+    assert!(notify_all(&[], None, &summary).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_telegram_notifier_unreachable_chat() {
+    use axum::{routing::post, Json, Router};
+
+    // mocked bot api, chat 1 blocked the bot and chat 2 was deleted
+    async fn send_message(Json(req): Json<serde_json::Value>) -> Json<serde_json::Value> {
+        let (error_code, description) = match req["chat_id"].as_i64() {
+            Some(1) => (403, "Forbidden: bot was blocked by the user"),
+            Some(2) => (400, "Bad Request: chat not found"),
+            _ => (400, "Bad Request: message is too long"),
+        };
+        Json(serde_json::json!({
+            "ok": false,
+            "error_code": error_code,
+            "description": description,
+        }))
+    }
+
+    let app = Router::new().route("/bottoken/SendMessage", post(send_message));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let bot =
+        Bot::new("token").set_api_url(reqwest::Url::parse(&format!("http://{addr}/")).unwrap());
+    let notifiers: Vec<Box<dyn Notifier>> = vec![Box::new(TelegramNotifier { bot })];
+    let summary = JobResultSummary {
+        pipeline_id: 12,
+        job_id: 34,
+        arch: "amd64".to_string(),
+        packages: "fd".to_string(),
+        git_branch: "fd-9.0.0".to_string(),
+        git_sha: "34acef168fc5ec454d3825fc864964951b130b49".to_string(),
+        github_pr: Some(4992),
+        worker_hostname: "Yerus".to_string(),
+        success: true,
+        successful_packages: vec!["fd".to_string()],
+        failed_package: None,
+        log_url: None,
+        elapsed_secs: Some(888),
+        error: None,
+        html: "<b>success</b>".to_string(),
+    };
+
+    // unreachable chats are not failures
+    assert!(notify_all(&notifiers, Some("1"), &summary).await.is_empty());
+    assert!(notify_all(&notifiers, Some("2"), &summary).await.is_empty());
+
+    // other errors are reported
+    let failures = notify_all(&notifiers, Some("3"), &summary).await;
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].0, "telegram");
+}
@@ -0,0 +1,128 @@
+use buildit_utils::github::for_each_abbs;
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+/// Packages of an abbs tree, found by name or by the paths of their files
+#[derive(Debug, Default)]
+pub struct PackageIndex {
+    /// `section/package` to package
+    dirs: HashMap<String, String>,
+    /// package names, sorted
+    packages: Vec<String>,
+}
+
+impl PackageIndex {
+    /// Index `section/package` directories
+    pub fn from_dirs<I: IntoIterator<Item = String>>(dirs: I) -> Self {
+        let dirs = dirs
+            .into_iter()
+            .filter_map(|dir| {
+                let (_, pkg) = dir.split_once('/')?;
+                let pkg = pkg.to_string();
+                Some((dir, pkg))
+            })
+            .collect::<HashMap<_, _>>();
+        let mut packages = dirs.values().cloned().collect::<Vec<_>>();
+        packages.sort();
+        packages.dedup();
+        Self { dirs, packages }
+    }
+
+    /// Walk the abbs tree once
+    pub fn build(abbs_path: &Path) -> Self {
+        let mut dirs = vec![];
+        for_each_abbs(abbs_path, |pkg, path| {
+            if let Some(section) = path
+                .parent()
+                .and_then(|section| section.file_name())
+                .and_then(|section| section.to_str())
+            {
+                dirs.push(format!("{section}/{pkg}"));
+            }
+        });
+        Self::from_dirs(dirs)
+    }
+
+    /// Package owning a path relative to the tree, e.g. `app-utils/fd/spec`
+    pub fn lookup(&self, path: &str) -> Option<&str> {
+        let mut parts = path.splitn(3, '/');
+        let dir = format!("{}/{}", parts.next()?, parts.next()?);
+        self.dirs.get(&dir).map(|pkg| pkg.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.packages
+            .binary_search_by(|pkg| pkg.as_str().cmp(name))
+            .is_ok()
+    }
+
+    /// Package names, sorted, as `list_packages` returns
+    pub fn packages(&self) -> &[String] {
+        &self.packages
+    }
+}
+
+/// Index of each abbs tree, replaced as a whole on refresh so that readers
+/// keep a consistent one
+static PACKAGE_INDEXES: Lazy<RwLock<HashMap<PathBuf, Arc<PackageIndex>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Index of the tree, built on first use
+pub fn package_index(abbs_path: &Path) -> Arc<PackageIndex> {
+    if let Some(index) = PACKAGE_INDEXES.read().unwrap().get(abbs_path) {
+        return index.clone();
+    }
+    refresh_package_index(abbs_path)
+}
+
+/// Index the tree again after it was updated, call with `ABBS_REPO_LOCK` held
+pub fn refresh_package_index(abbs_path: &Path) -> Arc<PackageIndex> {
+    // walk the tree without blocking readers
+    let index = Arc::new(PackageIndex::build(abbs_path));
+    PACKAGE_INDEXES
+        .write()
+        .unwrap()
+        .insert(abbs_path.to_path_buf(), index.clone());
+    index
+}
+
+#[test]
+fn test_package_index() {
+    let index = PackageIndex::from_dirs(
+        ["app-utils/fd", "app-utils/ripgrep", "runtime-devel/llvm"].map(str::to_string),
+    );
+    assert_eq!(index.lookup("app-utils/fd/spec"), Some("fd"));
+    assert_eq!(
+        index.lookup("runtime-devel/llvm/autobuild/defines"),
+        Some("llvm")
+    );
+    assert_eq!(index.lookup("app-utils/ripgrep"), Some("ripgrep"));
+    assert_eq!(index.lookup("app-utils/bat/spec"), None);
+    assert_eq!(index.lookup("README.md"), None);
+    assert!(index.contains("llvm"));
+    assert!(!index.contains("app-utils"));
+    assert_eq!(index.packages(), ["fd", "llvm", "ripgrep"]);
+
+    // refresh picks up packages added to the tree
+    let tree = std::env::temp_dir().join(format!("buildit-index-{}", std::process::id()));
+    std::fs::create_dir_all(tree.join("app-utils/fd/autobuild")).unwrap();
+    std::fs::write(tree.join("app-utils/fd/spec"), "VER=9.0.0\n").unwrap();
+    let index = package_index(&tree);
+    assert!(index.contains("fd"));
+    assert!(!index.contains("ripgrep"));
+
+    std::fs::create_dir_all(tree.join("app-utils/ripgrep")).unwrap();
+    // readers holding the old index are not affected
+    let refreshed = refresh_package_index(&tree);
+    assert!(!index.contains("ripgrep"));
+    assert!(refreshed.contains("ripgrep"));
+    assert_eq!(
+        package_index(&tree).lookup("app-utils/ripgrep/spec"),
+        Some("ripgrep")
+    );
+    std::fs::remove_dir_all(&tree).unwrap();
+}
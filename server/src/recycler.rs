@@ -1,11 +1,23 @@
 use crate::{
-    models::{Job, Worker},
-    DbPool, HEARTBEAT_TIMEOUT,
+    bot::format_duration,
+    github::complete_check_runs,
+    models::{Job, Pipeline, Worker},
+    routes::{forget_stopped_job_progress, job_finished},
+    schema::jobs,
+    DbPool, OrphanPolicy, HEARTBEAT_TIMEOUT,
 };
 use anyhow::Context;
-use chrono::Utc;
-use diesel::{ExpressionMethods, JoinOnDsl, NullableExpressionMethods, QueryDsl, RunQueryDsl};
-use std::time::Duration;
+use chrono::{DateTime, Utc};
+use diesel::{
+    dsl::{exists, not},
+    pg::Pg,
+    sql_types::{Bool, Nullable},
+    BoolExpressionMethods, BoxableExpression, Connection, ExpressionMethods, JoinOnDsl,
+    NullableExpressionMethods, PgExpressionMethods, QueryDsl, RunQueryDsl,
+};
+use octocrab::params::checks::CheckRunConclusion;
+use std::{collections::BTreeMap, time::Duration};
+use teloxide::{prelude::*, types::ChatId};
 use tracing::{info, warn};
 
 pub async fn recycler_worker_inner(pool: DbPool) -> anyhow::Result<()> {
@@ -24,6 +36,7 @@ pub async fn recycler_worker_inner(pool: DbPool) -> anyhow::Result<()> {
                     .eq(jobs::dsl::assigned_worker_id)),
             )
             .filter(workers::dsl::last_heartbeat_time.lt(deadline))
+            .filter(jobs::dsl::status.eq("running"))
             .load::<(Job, Worker)>(&mut conn)?;
 
         for (job, worker) in res {
@@ -31,13 +44,18 @@ pub async fn recycler_worker_inner(pool: DbPool) -> anyhow::Result<()> {
                 "Job {} was assigned to worker {}, but the worker disappeared",
                 job.id, worker.id
             );
-            diesel::update(jobs::dsl::jobs.find(job.id))
-                .set((
-                    jobs::dsl::status.eq("created"),
-                    jobs::dsl::assigned_worker_id.eq(None::<i32>),
-                ))
-                .execute(&mut conn)?;
+            diesel::update(
+                jobs::dsl::jobs
+                    .find(job.id)
+                    .filter(jobs::dsl::status.eq("running")),
+            )
+            .set((
+                jobs::dsl::status.eq("created"),
+                jobs::dsl::assigned_worker_id.eq(None::<i32>),
+            ))
+            .execute(&mut conn)?;
         }
+        forget_stopped_job_progress(&mut conn).await;
 
         tokio::time::sleep(Duration::from_secs(60)).await;
     }
@@ -52,3 +70,467 @@ pub async fn recycler_worker(pool: DbPool) {
         tokio::time::sleep(Duration::from_secs(5)).await;
     }
 }
+
+/// Jobs queued before `deadline`, counting from the last time they were
+/// queued again, e.g. after their worker disappeared
+fn expired(deadline: DateTime<Utc>) -> JobFilter {
+    Box::new(
+        jobs::dsl::status
+            .eq("created")
+            .and(jobs::dsl::creation_time.lt(deadline))
+            .and(
+                jobs::dsl::assign_time
+                    .is_null()
+                    .or(jobs::dsl::assign_time.lt(deadline)),
+            ),
+    )
+}
+
+pub fn format_expired(job: &Job, ttl: i64) -> String {
+    format!(
+        "Job #{} ({}) of pipeline #{}: no worker picked this up within {}; expired",
+        job.id,
+        job.arch,
+        job.pipeline_id,
+        format_duration(ttl)
+    )
+}
+
+pub async fn expiry_worker_inner(pool: DbPool, bot: Option<&Bot>, ttl: i64) -> anyhow::Result<()> {
+    loop {
+        use crate::schema::pipelines;
+        let mut conn = pool
+            .get()
+            .context("Failed to get db connection from pool")?;
+
+        let deadline = Utc::now() - chrono::Duration::try_seconds(ttl).unwrap();
+        let res = jobs::dsl::jobs
+            .filter(expired(deadline))
+            .load::<Job>(&mut conn)?;
+
+        for job in res {
+            let pipeline = pipelines::dsl::pipelines
+                .find(job.pipeline_id)
+                .first::<Pipeline>(&mut conn)?;
+            // only expire jobs that are still queued
+            let updated = diesel::update(
+                jobs::dsl::jobs
+                    .find(job.id)
+                    .filter(jobs::dsl::status.eq("created")),
+            )
+            .set((
+                jobs::dsl::status.eq("expired"),
+                jobs::dsl::finish_time.eq(Utc::now()),
+            ))
+            .execute(&mut conn)?;
+            if updated == 0 {
+                continue;
+            }
+
+            info!("Job {} expired after waiting for {}s", job.id, ttl);
+            if let (Some(bot), Some(chat_id)) = (bot, pipeline.telegram_user) {
+                if let Err(err) = bot
+                    .send_message(ChatId(chat_id), format_expired(&job, ttl))
+                    .await
+                {
+                    warn!("Failed to notify expired job: {}", err);
+                }
+            }
+            complete_check_runs(&pipeline.repo, [&job], CheckRunConclusion::TimedOut).await;
+            job_finished(pool.clone(), bot.cloned(), pipeline.id).await;
+        }
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+    }
+}
+
+pub async fn expiry_worker(pool: DbPool, bot: Option<Bot>, ttl: i64) {
+    loop {
+        info!("Starting expiry worker");
+        if let Err(err) = expiry_worker_inner(pool.clone(), bot.as_ref(), ttl).await {
+            warn!("Got error running expiry worker: {}", err);
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+// jobs in these states are never touched again
+const TERMINAL_STATUS: &[&str] = &["success", "failed", "error", "cancelled", "expired", "lost"];
+
+/// Condition on jobs, for the queries of the workers here to be tested on their own
+type JobFilter = Box<dyn BoxableExpression<jobs::table, Pg, SqlType = Nullable<Bool>>>;
+
+/// Jobs finished before `cutoff`, which can be deleted
+fn prunable(cutoff: DateTime<Utc>) -> JobFilter {
+    Box::new(
+        jobs::dsl::status.eq_any(TERMINAL_STATUS).and(
+            jobs::dsl::finish_time.lt(cutoff).or(jobs::dsl::finish_time
+                .is_null()
+                .and(jobs::dsl::creation_time.lt(cutoff))),
+        ),
+    )
+}
+
+pub async fn retention_worker_inner(pool: DbPool, retention_days: i64) -> anyhow::Result<()> {
+    loop {
+        use crate::schema::pipelines;
+        let mut conn = pool
+            .get()
+            .context("Failed to get db connection from pool")?;
+
+        let cutoff = Utc::now() - chrono::Duration::try_days(retention_days).unwrap();
+        let (job_count, pipeline_count) = conn
+            .transaction::<(usize, usize), diesel::result::Error, _>(|conn| {
+                let job_count =
+                    diesel::delete(jobs::dsl::jobs.filter(prunable(cutoff))).execute(conn)?;
+
+                // pipelines with active jobs are kept
+                let pipeline_count = diesel::delete(
+                    pipelines::dsl::pipelines
+                        .filter(pipelines::dsl::creation_time.lt(cutoff))
+                        .filter(not(exists(
+                            jobs::dsl::jobs.filter(jobs::dsl::pipeline_id.eq(pipelines::dsl::id)),
+                        ))),
+                )
+                .execute(conn)?;
+
+                Ok((job_count, pipeline_count))
+            })?;
+
+        if job_count > 0 || pipeline_count > 0 {
+            info!(
+                "Deleted {} job(s) and {} pipeline(s) finished before {}",
+                job_count, pipeline_count, cutoff
+            );
+        }
+
+        tokio::time::sleep(Duration::from_secs(3600)).await;
+    }
+}
+
+pub async fn retention_worker(pool: DbPool, retention_days: i64) {
+    loop {
+        info!("Starting retention worker");
+        if let Err(err) = retention_worker_inner(pool.clone(), retention_days).await {
+            warn!("Got error running retention worker: {}", err);
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Inconsistent state of a job left behind by a crash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reconcile {
+    /// Running, but its worker is gone or has moved on to another job
+    Orphaned,
+    /// Queued, but still assigned to a worker
+    Unassign,
+}
+
+/// Find jobs whose state cannot be right, given the known workers
+///
+/// A worker builds one job at a time, so only the latest job assigned to it
+/// can be running. Workers that stopped sending heartbeats are left to the
+/// recycler, they may still be building while the server was down.
+pub fn plan_reconcile(jobs: &[Job], workers: &[Worker]) -> Vec<(i32, Reconcile)> {
+    let mut plan = vec![];
+    let mut latest: BTreeMap<i32, &Job> = BTreeMap::new();
+    for job in jobs {
+        match (job.status.as_str(), job.assigned_worker_id) {
+            ("created", Some(_)) => plan.push((job.id, Reconcile::Unassign)),
+            ("running", Some(worker_id)) if workers.iter().any(|w| w.id == worker_id) => {
+                let key = |job: &Job| (job.assign_time, job.id);
+                match latest.get(&worker_id) {
+                    Some(current) if key(current) > key(job) => {
+                        plan.push((job.id, Reconcile::Orphaned))
+                    }
+                    Some(current) => {
+                        plan.push((current.id, Reconcile::Orphaned));
+                        latest.insert(worker_id, job);
+                    }
+                    None => {
+                        latest.insert(worker_id, job);
+                    }
+                }
+            }
+            ("running", _) => plan.push((job.id, Reconcile::Orphaned)),
+            _ => {}
+        }
+    }
+    plan.sort_by_key(|(id, _)| *id);
+    plan
+}
+
+/// Fix up job states left inconsistent by a crash, run once at startup
+///
+/// Each update checks the state it was planned from, so running this again or
+/// concurrently with workers polling never touches a job twice.
+pub async fn reconcile_jobs(
+    pool: DbPool,
+    bot: Option<Bot>,
+    policy: OrphanPolicy,
+) -> anyhow::Result<()> {
+    use crate::schema::{jobs, workers};
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let active = jobs::dsl::jobs
+        .filter(
+            jobs::dsl::status.eq("running").or(jobs::dsl::status
+                .eq("created")
+                .and(jobs::dsl::assigned_worker_id.is_not_null())),
+        )
+        .load::<Job>(&mut conn)?;
+    let known_workers = workers::dsl::workers.load::<Worker>(&mut conn)?;
+
+    let mut orphaned = 0;
+    let mut unassigned = 0;
+    let mut lost_pipelines = vec![];
+    for (id, action) in plan_reconcile(&active, &known_workers) {
+        let Some(job) = active.iter().find(|job| job.id == id) else {
+            continue;
+        };
+        let unchanged = jobs::dsl::jobs
+            .find(id)
+            .filter(jobs::dsl::status.eq(&job.status))
+            .filter(jobs::dsl::assigned_worker_id.is_not_distinct_from(job.assigned_worker_id));
+        let updated = match (action, policy) {
+            (Reconcile::Unassign, _) => diesel::update(unchanged)
+                .set(jobs::dsl::assigned_worker_id.eq(None::<i32>))
+                .execute(&mut conn)?,
+            (Reconcile::Orphaned, OrphanPolicy::Requeue) => diesel::update(unchanged)
+                .set((
+                    jobs::dsl::status.eq("created"),
+                    jobs::dsl::assigned_worker_id.eq(None::<i32>),
+                ))
+                .execute(&mut conn)?,
+            (Reconcile::Orphaned, OrphanPolicy::Lost) => diesel::update(unchanged)
+                .set((
+                    jobs::dsl::status.eq("lost"),
+                    jobs::dsl::assigned_worker_id.eq(None::<i32>),
+                    jobs::dsl::finish_time.eq(Utc::now()),
+                    jobs::dsl::error_message
+                        .eq("No worker was building the job after a server restart"),
+                ))
+                .execute(&mut conn)?,
+        };
+        if updated == 0 {
+            continue;
+        }
+        match action {
+            Reconcile::Orphaned => {
+                info!(
+                    "Job {} was running on worker {:?}, but no worker is building it",
+                    job.id, job.assigned_worker_id
+                );
+                orphaned += 1;
+                if policy == OrphanPolicy::Lost && !lost_pipelines.contains(&job.pipeline_id) {
+                    lost_pipelines.push(job.pipeline_id);
+                }
+            }
+            Reconcile::Unassign => unassigned += 1,
+        }
+    }
+    for pipeline_id in lost_pipelines {
+        job_finished(pool.clone(), bot.clone(), pipeline_id).await;
+    }
+
+    info!(
+        "Reconciled jobs at startup: {} orphaned running job(s) {}, {} queued job(s) unassigned",
+        orphaned,
+        match policy {
+            OrphanPolicy::Requeue => "requeued",
+            OrphanPolicy::Lost => "marked lost",
+        },
+        unassigned
+    );
+    Ok(())
+}
+
+#[test]
+fn test_prunable() {
+    let Some(pool) = crate::test_db() else {
+        return;
+    };
+    let mut conn = pool.get().unwrap();
+    let now = Utc::now();
+    let cutoff = now - chrono::Duration::try_days(90).unwrap();
+    let job = |id: i32, status: &str, days: i64, finished: bool| {
+        let time = now - chrono::Duration::try_days(days).unwrap();
+        Job {
+            id,
+            creation_time: time,
+            status: status.to_string(),
+            finish_time: if finished { Some(time) } else { None },
+            ..Job::fixture()
+        }
+    };
+    diesel::insert_into(crate::schema::pipelines::table)
+        .values(&Pipeline::fixture())
+        .execute(&mut conn)
+        .unwrap();
+    diesel::insert_into(jobs::table)
+        .values(&[
+            // older than cutoff
+            job(1, "success", 100, true),
+            job(2, "failed", 100, true),
+            job(3, "error", 100, false),
+            job(4, "expired", 100, true),
+            // recent
+            job(5, "success", 10, true),
+            // finished recently after waiting for long
+            Job {
+                finish_time: Some(now),
+                ..job(6, "lost", 100, false)
+            },
+            // active
+            job(7, "created", 100, false),
+            job(8, "running", 100, false),
+        ])
+        .execute(&mut conn)
+        .unwrap();
+
+    let ids = jobs::dsl::jobs
+        .filter(prunable(cutoff))
+        .select(jobs::dsl::id)
+        .order(jobs::dsl::id)
+        .load::<i32>(&mut conn)
+        .unwrap();
+    assert_eq!(ids, [1, 2, 3, 4]);
+}
+
+#[test]
+fn test_expired() {
+    let now = Utc::now();
+    let ago = |secs: i64| now - chrono::Duration::try_seconds(secs).unwrap();
+    let job = |id: i32, status: &str, secs: i64| Job {
+        id,
+        arch: "mips64r6el".to_string(),
+        creation_time: ago(secs),
+        status: status.to_string(),
+        ..Job::fixture()
+    };
+
+    assert_eq!(
+        format_expired(
+            &Job {
+                pipeline_id: 3,
+                ..job(7, "created", 7200)
+            },
+            3600
+        ),
+        "Job #7 (mips64r6el) of pipeline #3: no worker picked this up within 1h00m; expired"
+    );
+
+    let Some(pool) = crate::test_db() else {
+        return;
+    };
+    let mut conn = pool.get().unwrap();
+    diesel::insert_into(crate::schema::pipelines::table)
+        .values(&Pipeline::fixture())
+        .execute(&mut conn)
+        .unwrap();
+    diesel::insert_into(jobs::table)
+        .values(&[
+            job(1, "created", 7200),
+            job(2, "created", 60),
+            // picked up by a worker in time
+            job(3, "running", 7200),
+            // requeued recently after its worker disappeared
+            Job {
+                assign_time: Some(ago(60)),
+                ..job(4, "created", 7200)
+            },
+            Job {
+                assign_time: Some(ago(7000)),
+                ..job(5, "created", 7200)
+            },
+        ])
+        .execute(&mut conn)
+        .unwrap();
+
+    let ids = jobs::dsl::jobs
+        .filter(expired(ago(3600)))
+        .select(jobs::dsl::id)
+        .order(jobs::dsl::id)
+        .load::<i32>(&mut conn)
+        .unwrap();
+    assert_eq!(ids, [1, 5]);
+}
+
+#[test]
+fn test_plan_reconcile() {
+    let now = Utc::now();
+    let job =
+        |id: i32, status: &str, assigned_worker_id: Option<i32>, assigned_secs_ago: i64| Job {
+            id,
+            pipeline_id: 3,
+            creation_time: now - chrono::Duration::try_hours(1).unwrap(),
+            status: status.to_string(),
+            assigned_worker_id,
+            assign_time: assigned_worker_id
+                .map(|_| now - chrono::Duration::try_seconds(assigned_secs_ago).unwrap()),
+            ..Job::fixture()
+        };
+    let worker = |id: i32, heartbeat_secs_ago: i64| Worker {
+        id,
+        hostname: format!("worker{id}"),
+        arch: "amd64".to_string(),
+        git_commit: "unknown".to_string(),
+        memory_bytes: 1 << 34,
+        logical_cores: 16,
+        last_heartbeat_time: now - chrono::Duration::try_seconds(heartbeat_secs_ago).unwrap(),
+        disk_free_space_bytes: 1 << 40,
+        performance: None,
+        visible: true,
+        internet_connectivity: true,
+        version: None,
+        labels: None,
+    };
+
+    // worker 1 is alive, worker 2 has stopped sending heartbeats, worker 3 is gone
+    let workers = [worker(1, 10), worker(2, 3600)];
+    let jobs = [
+        // consistent
+        job(1, "running", Some(1), 60),
+        job(2, "created", None, 0),
+        // left to the recycler
+        job(3, "running", Some(2), 600),
+        // never started by any worker
+        job(4, "running", None, 0),
+        job(5, "running", Some(3), 60),
+        // worker 1 has moved on to job 1
+        job(6, "running", Some(1), 600),
+        // queued again but still assigned
+        job(7, "created", Some(1), 600),
+    ];
+    let plan = vec![
+        (4, Reconcile::Orphaned),
+        (5, Reconcile::Orphaned),
+        (6, Reconcile::Orphaned),
+        (7, Reconcile::Unassign),
+    ];
+    assert_eq!(plan_reconcile(&jobs, &workers), plan);
+    // in any order
+    let jobs_rev = [
+        job(7, "created", Some(1), 600),
+        job(6, "running", Some(1), 600),
+        job(5, "running", Some(3), 60),
+        job(4, "running", None, 0),
+        job(3, "running", Some(2), 600),
+        job(2, "created", None, 0),
+        job(1, "running", Some(1), 60),
+    ];
+    assert_eq!(plan_reconcile(&jobs_rev, &workers), plan);
+
+    // nothing left to do once applied
+    let jobs = [
+        job(1, "running", Some(1), 60),
+        job(3, "running", Some(2), 600),
+        job(4, "created", None, 0),
+        job(6, "lost", None, 0),
+        job(7, "created", None, 0),
+    ];
+    assert_eq!(plan_reconcile(&jobs, &workers), vec![]);
+}
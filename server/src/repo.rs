@@ -0,0 +1,207 @@
+use crate::ARGS;
+use anyhow::{anyhow, bail};
+use std::path::{Path, PathBuf};
+
+/// Name of the primary repo in `repo=` selectors
+pub const PRIMARY_REPO: &str = "abbs";
+
+/// GitHub repo of the primary abbs tree
+pub const PRIMARY_REPO_FULL_NAME: &str = "AOSC-Dev/aosc-os-abbs";
+
+/// An abbs tree on GitHub that packages are built from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepoConfig {
+    /// Name used in `repo=` selectors
+    pub name: String,
+    pub owner: String,
+    pub repo: String,
+    pub default_branch: String,
+    /// Local checkout of the repo
+    pub abbs_path: PathBuf,
+}
+
+impl RepoConfig {
+    pub fn primary(abbs_path: &Path) -> Self {
+        Self {
+            name: PRIMARY_REPO.to_string(),
+            owner: "AOSC-Dev".to_string(),
+            repo: "aosc-os-abbs".to_string(),
+            default_branch: "stable".to_string(),
+            abbs_path: abbs_path.to_path_buf(),
+        }
+    }
+
+    /// `owner/repo`, as recorded in pipelines
+    pub fn full_name(&self) -> String {
+        format!("{}/{}", self.owner, self.repo)
+    }
+
+    pub fn git_url(&self) -> String {
+        format!("https://github.com/{}/{}.git", self.owner, self.repo)
+    }
+}
+
+/// Split `owner/repo` of a pipeline
+pub fn owner_repo(full_name: &str) -> (&str, &str) {
+    full_name
+        .split_once('/')
+        .unwrap_or(("AOSC-Dev", "aosc-os-abbs"))
+}
+
+/// Repos besides the primary one
+#[derive(Debug, Clone)]
+pub struct SecondaryRepos(Vec<RepoConfig>);
+
+/// Parse `name=owner/repo@default-branch:abbs-path` entries separated by semicolons
+pub fn parse_secondary_repos(s: &str) -> anyhow::Result<SecondaryRepos> {
+    let mut res: Vec<RepoConfig> = vec![];
+    for entry in s
+        .split(';')
+        .map(|entry| entry.trim())
+        .filter(|entry| !entry.is_empty())
+    {
+        let invalid = || anyhow!("Expected name=owner/repo@default-branch:abbs-path, got {entry}");
+        let (name, rest) = entry.split_once('=').ok_or_else(invalid)?;
+        let (full_name, rest) = rest.split_once('@').ok_or_else(invalid)?;
+        let (default_branch, abbs_path) = rest.split_once(':').ok_or_else(invalid)?;
+        let (owner, repo) = full_name.split_once('/').ok_or_else(invalid)?;
+        if [name, owner, repo, default_branch, abbs_path]
+            .iter()
+            .any(|part| part.trim().is_empty())
+        {
+            return Err(invalid());
+        }
+
+        let name = name.trim();
+        if name == PRIMARY_REPO || res.iter().any(|repo| repo.name == name) {
+            bail!("Duplicate repo name: {name}");
+        }
+        res.push(RepoConfig {
+            name: name.to_string(),
+            owner: owner.trim().to_string(),
+            repo: repo.trim().to_string(),
+            default_branch: default_branch.trim().to_string(),
+            abbs_path: PathBuf::from(abbs_path.trim()),
+        });
+    }
+    Ok(SecondaryRepos(res))
+}
+
+/// Find the repo selected by `repo=name`, the primary one if not given
+pub fn select_repo(
+    name: Option<&str>,
+    primary: RepoConfig,
+    secondary: Option<&SecondaryRepos>,
+) -> anyhow::Result<RepoConfig> {
+    let secondary = secondary.map(|repos| repos.0.as_slice()).unwrap_or(&[]);
+    match name {
+        None => Ok(primary),
+        Some(name) if name == primary.name => Ok(primary),
+        Some(name) => secondary
+            .iter()
+            .find(|repo| repo.name == name)
+            .cloned()
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unknown repo: {name}, valid repos are: {}",
+                    std::iter::once(primary.name.as_str())
+                        .chain(secondary.iter().map(|repo| repo.name.as_str()))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            }),
+    }
+}
+
+/// Find the configured repo of a GitHub `owner/repo`, e.g. of a webhook event
+pub fn find_repo_by_full_name(
+    full_name: &str,
+    primary: RepoConfig,
+    secondary: Option<&SecondaryRepos>,
+) -> Option<RepoConfig> {
+    std::iter::once(primary)
+        .chain(secondary.iter().flat_map(|repos| repos.0.iter().cloned()))
+        .find(|repo| repo.full_name().eq_ignore_ascii_case(full_name))
+}
+
+/// Configured repo selected by `repo=name`
+pub fn repo_of(name: Option<&str>) -> anyhow::Result<RepoConfig> {
+    select_repo(
+        name,
+        RepoConfig::primary(&ARGS.abbs_path),
+        ARGS.secondary_repos.as_ref(),
+    )
+}
+
+/// Configured repo of a GitHub `owner/repo`
+pub fn repo_by_full_name(full_name: &str) -> Option<RepoConfig> {
+    find_repo_by_full_name(
+        full_name,
+        RepoConfig::primary(&ARGS.abbs_path),
+        ARGS.secondary_repos.as_ref(),
+    )
+}
+
+#[test]
+fn test_select_repo() {
+    let primary = RepoConfig::primary(Path::new("/buildroots/abbs"));
+    let secondary =
+        parse_secondary_repos("aoscx=AOSC-Dev/aoscx-abbs@main:/buildroots/aoscx; retro=AOSC-Dev/retro-abbs@retro:/buildroots/retro")
+            .unwrap();
+
+    // a selector routes to the settings of that repo
+    let repo = select_repo(Some("aoscx"), primary.clone(), Some(&secondary)).unwrap();
+    assert_eq!(
+        repo,
+        RepoConfig {
+            name: "aoscx".to_string(),
+            owner: "AOSC-Dev".to_string(),
+            repo: "aoscx-abbs".to_string(),
+            default_branch: "main".to_string(),
+            abbs_path: PathBuf::from("/buildroots/aoscx"),
+        }
+    );
+    assert_eq!(repo.full_name(), "AOSC-Dev/aoscx-abbs");
+    assert_eq!(repo.git_url(), "https://github.com/AOSC-Dev/aoscx-abbs.git");
+
+    // no selector means the primary repo
+    assert_eq!(
+        select_repo(None, primary.clone(), Some(&secondary)).unwrap(),
+        primary
+    );
+    assert_eq!(select_repo(None, primary.clone(), None).unwrap(), primary);
+    assert_eq!(
+        select_repo(Some("abbs"), primary.clone(), Some(&secondary)).unwrap(),
+        primary
+    );
+    assert_eq!(
+        select_repo(Some("aoscx"), primary.clone(), None)
+            .unwrap_err()
+            .to_string(),
+        "Unknown repo: aoscx, valid repos are: abbs"
+    );
+
+    // webhook events
+    assert_eq!(
+        find_repo_by_full_name("aosc-dev/retro-abbs", primary.clone(), Some(&secondary))
+            .map(|repo| repo.name),
+        Some("retro".to_string())
+    );
+    assert_eq!(
+        find_repo_by_full_name("AOSC-Dev/aosc-os-abbs", primary.clone(), Some(&secondary)),
+        Some(primary.clone())
+    );
+    assert_eq!(
+        find_repo_by_full_name("AOSC-Dev/other", primary, Some(&secondary)),
+        None
+    );
+
+    assert_eq!(
+        owner_repo("AOSC-Dev/aoscx-abbs"),
+        ("AOSC-Dev", "aoscx-abbs")
+    );
+    assert!(parse_secondary_repos("").unwrap().0.is_empty());
+    assert!(parse_secondary_repos("aoscx=AOSC-Dev/aoscx-abbs").is_err());
+    assert!(parse_secondary_repos("aoscx=aoscx-abbs@main:/buildroots/aoscx").is_err());
+    assert!(parse_secondary_repos("abbs=AOSC-Dev/aoscx-abbs@main:/buildroots/aoscx").is_err());
+}
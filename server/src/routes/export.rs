@@ -0,0 +1,239 @@
+use crate::routes::AppState;
+use crate::DbPool;
+use anyhow::{anyhow, Context};
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use diesel::{
+    pg::PgRowByRowLoadingMode, ExpressionMethods, JoinOnDsl, NullableExpressionMethods, QueryDsl,
+    Queryable, RunQueryDsl,
+};
+use futures::{channel::mpsc, SinkExt};
+use serde::Deserialize;
+use std::borrow::Cow;
+use tracing::warn;
+
+pub const EXPORT_HEADER: &str =
+    "job_id,pipeline_id,packages,arch,status,elapsed_secs,worker,git_sha,creation_time\n";
+
+/// A job in the build history export
+#[derive(Debug, Queryable)]
+pub struct ExportRow {
+    pub job_id: i32,
+    pub pipeline_id: i32,
+    pub packages: String,
+    pub arch: String,
+    pub status: String,
+    pub elapsed_secs: Option<i64>,
+    /// Hostname of the worker that built the job
+    pub worker: Option<String>,
+    pub git_sha: String,
+    pub creation_time: DateTime<Utc>,
+}
+
+/// Quote a field containing separators, quotes or line breaks
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+impl ExportRow {
+    /// CSV line in the order of `EXPORT_HEADER`
+    pub fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{},{}\n",
+            self.job_id,
+            self.pipeline_id,
+            csv_field(&self.packages),
+            csv_field(&self.arch),
+            csv_field(&self.status),
+            self.elapsed_secs
+                .map(|secs| secs.to_string())
+                .unwrap_or_default(),
+            csv_field(self.worker.as_deref().unwrap_or_default()),
+            csv_field(&self.git_sha),
+            self.creation_time.to_rfc3339(),
+        )
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportRequest {
+    /// First day to export, as YYYY-MM-DD
+    from: Option<String>,
+    /// Last day to export, inclusive
+    to: Option<String>,
+}
+
+/// Creation time range of exported jobs, the end is exclusive
+pub type ExportRange = (Option<DateTime<Utc>>, Option<DateTime<Utc>>);
+
+pub fn parse_export_range(from: Option<&str>, to: Option<&str>) -> anyhow::Result<ExportRange> {
+    let day = |s: &str| {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d")
+            .map_err(|_| anyhow!("Invalid date: {s}, expected YYYY-MM-DD"))
+    };
+    let from = from.map(day).transpose()?;
+    let to = to.map(day).transpose()?;
+    if let (Some(from), Some(to)) = (from, to) {
+        if from > to {
+            return Err(anyhow!("Start date {from} is after end date {to}"));
+        }
+    }
+    Ok((
+        from.map(|from| from.and_time(chrono::NaiveTime::MIN).and_utc()),
+        to.and_then(|to| to.succ_opt())
+            .map(|to| to.and_time(chrono::NaiveTime::MIN).and_utc()),
+    ))
+}
+
+/// Send the jobs created in the range as CSV lines, row by row
+fn export_rows(
+    pool: DbPool,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    mut tx: mpsc::Sender<Result<String, std::io::Error>>,
+) -> anyhow::Result<()> {
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let mut sql = crate::schema::jobs::dsl::jobs
+        .inner_join(crate::schema::pipelines::dsl::pipelines)
+        .left_join(
+            crate::schema::workers::dsl::workers.on(crate::schema::jobs::dsl::built_by_worker_id
+                .eq(crate::schema::workers::dsl::id.nullable())),
+        )
+        .select((
+            crate::schema::jobs::dsl::id,
+            crate::schema::jobs::dsl::pipeline_id,
+            crate::schema::jobs::dsl::packages,
+            crate::schema::jobs::dsl::arch,
+            crate::schema::jobs::dsl::status,
+            crate::schema::jobs::dsl::elapsed_secs,
+            crate::schema::workers::dsl::hostname.nullable(),
+            crate::schema::pipelines::dsl::git_sha,
+            crate::schema::jobs::dsl::creation_time,
+        ))
+        .order(crate::schema::jobs::dsl::id.asc())
+        .into_boxed();
+    if let Some(from) = from {
+        sql = sql.filter(crate::schema::jobs::dsl::creation_time.ge(from));
+    }
+    if let Some(to) = to {
+        sql = sql.filter(crate::schema::jobs::dsl::creation_time.lt(to));
+    }
+
+    for row in sql.load_iter::<ExportRow, PgRowByRowLoadingMode>(&mut conn)? {
+        let line = match row {
+            Ok(row) => Ok(row.to_csv()),
+            // cut the download short, so the client sees it is incomplete
+            Err(err) => Err(std::io::Error::other(err.to_string())),
+        };
+        let failed = line.is_err();
+        futures::executor::block_on(tx.send(line)).context("Client went away")?;
+        if failed {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Download the build history as CSV, optionally limited to a date range
+pub async fn export_history(
+    Query(query): Query<ExportRequest>,
+    State(AppState { pool, .. }): State<AppState>,
+) -> Response {
+    let (from, to) = match parse_export_range(query.from.as_deref(), query.to.as_deref()) {
+        Ok(range) => range,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+
+    let (mut tx, rx) = mpsc::channel(64);
+    tokio::task::spawn_blocking(move || {
+        if futures::executor::block_on(tx.send(Ok(EXPORT_HEADER.to_string()))).is_err() {
+            return;
+        }
+        if let Err(err) = export_rows(pool, from, to, tx) {
+            warn!("Failed to export build history: {err:?}");
+        }
+    });
+
+    (
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"buildit-history.csv\"",
+            ),
+        ],
+        Body::from_stream(rx),
+    )
+        .into_response()
+}
+
+#[test]
+fn test_export_csv() {
+    let time = |secs: i64| DateTime::from_timestamp(secs, 0).unwrap();
+    let rows = [
+        ExportRow {
+            job_id: 1,
+            pipeline_id: 12,
+            packages: "fd".to_string(),
+            arch: "amd64".to_string(),
+            status: "success".to_string(),
+            elapsed_secs: Some(888),
+            worker: Some("Yerus".to_string()),
+            git_sha: "34acef168fc5ec454d3825fc864964951b130b49".to_string(),
+            creation_time: time(61),
+        },
+        ExportRow {
+            job_id: 2,
+            pipeline_id: 12,
+            packages: "fd,ripgrep".to_string(),
+            arch: "arm64".to_string(),
+            status: "running".to_string(),
+            elapsed_secs: None,
+            worker: None,
+            git_sha: "34acef168fc5ec454d3825fc864964951b130b49".to_string(),
+            creation_time: time(3661),
+        },
+        ExportRow {
+            job_id: 3,
+            pipeline_id: 13,
+            packages: "say\"hi\"".to_string(),
+            arch: "riscv64".to_string(),
+            status: "failed".to_string(),
+            elapsed_secs: Some(0),
+            worker: Some("riscv-builder".to_string()),
+            git_sha: "ffe2f8c8".to_string(),
+            creation_time: time(86400),
+        },
+    ];
+    let csv = std::iter::once(EXPORT_HEADER.to_string())
+        .chain(rows.iter().map(ExportRow::to_csv))
+        .collect::<String>();
+    assert_eq!(
+        csv,
+        "job_id,pipeline_id,packages,arch,status,elapsed_secs,worker,git_sha,creation_time\n\
+         1,12,fd,amd64,success,888,Yerus,34acef168fc5ec454d3825fc864964951b130b49,1970-01-01T00:01:01+00:00\n\
+         2,12,\"fd,ripgrep\",arm64,running,,,34acef168fc5ec454d3825fc864964951b130b49,1970-01-01T01:01:01+00:00\n\
+         3,13,\"say\"\"hi\"\"\",riscv64,failed,0,riscv-builder,ffe2f8c8,1970-01-02T00:00:00+00:00\n"
+    );
+
+    // the end date is inclusive
+    assert_eq!(
+        parse_export_range(Some("1970-01-01"), Some("1970-01-01")).unwrap(),
+        (Some(time(0)), Some(time(86400)))
+    );
+    assert_eq!(parse_export_range(None, None).unwrap(), (None, None));
+    assert!(parse_export_range(Some("1970-01-02"), Some("1970-01-01")).is_err());
+    assert!(parse_export_range(Some("yesterday"), None).is_err());
+}
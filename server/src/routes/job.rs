@@ -1,3 +1,4 @@
+use crate::audit::API_ACTOR;
 use crate::models::{Job, Pipeline, User, Worker};
 use crate::routes::{AnyhowError, AppState};
 use anyhow::Context;
@@ -228,6 +229,6 @@ pub async fn job_restart(
     State(AppState { pool, .. }): State<AppState>,
     Json(payload): Json<JobRestartRequest>,
 ) -> Result<Json<JobRestartResponse>, AnyhowError> {
-    let new_job = crate::api::job_restart(pool, payload.job_id).await?;
+    let new_job = crate::api::job_restart(pool, payload.job_id, API_ACTOR).await?;
     return Ok(Json(JobRestartResponse { job_id: new_job.id }));
 }
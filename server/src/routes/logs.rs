@@ -0,0 +1,221 @@
+use crate::routes::{AnyhowError, AppState};
+use crate::ARGS;
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use std::io::SeekFrom;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+/// Bytes of a log selected by the `Range` header
+#[derive(Debug, PartialEq, Eq)]
+pub enum LogRange {
+    Full,
+    /// First and last byte, inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parse a single `bytes=start-end`, `bytes=start-` or `bytes=-suffix` range,
+/// other ranges are ignored and the whole log is sent
+pub fn parse_range(range: Option<&str>, len: u64) -> LogRange {
+    let Some((start, end)) = range
+        .and_then(|range| range.trim().strip_prefix("bytes="))
+        .filter(|range| !range.contains(','))
+        .and_then(|range| range.split_once('-'))
+    else {
+        return LogRange::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        // the tail of the log
+        return match end.parse::<u64>() {
+            Ok(0) => LogRange::Unsatisfiable,
+            Ok(_) if len == 0 => LogRange::Unsatisfiable,
+            Ok(suffix) => LogRange::Partial(len.saturating_sub(suffix), len - 1),
+            Err(_) => LogRange::Full,
+        };
+    }
+
+    let Ok(start) = start.parse::<u64>() else {
+        return LogRange::Full;
+    };
+    let end = if end.is_empty() {
+        None
+    } else {
+        match end.parse::<u64>() {
+            Ok(end) if end >= start => Some(end),
+            _ => return LogRange::Full,
+        }
+    };
+    if start >= len {
+        return LogRange::Unsatisfiable;
+    }
+    LogRange::Partial(start, end.map_or(len - 1, |end| end.min(len - 1)))
+}
+
+/// Respond with the log file, or the part of it selected by the `Range` header,
+/// streamed from disk so that tailing large logs does not read them whole
+pub async fn log_response(
+    path: Option<&std::path::Path>,
+    range: Option<&str>,
+) -> anyhow::Result<Response> {
+    let not_found = || Ok((StatusCode::NOT_FOUND, "Log not found").into_response());
+    let Some(path) = path else {
+        return not_found();
+    };
+    let mut file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return not_found(),
+        Err(err) => return Err(anyhow::Error::from(err).context("Failed to read log")),
+    };
+    let len = file.metadata().await.context("Failed to read log")?.len();
+    let content_type = (header::CONTENT_TYPE, "text/plain; charset=utf-8");
+    let accept_ranges = (header::ACCEPT_RANGES, "bytes");
+
+    Ok(match parse_range(range, len) {
+        LogRange::Full => (
+            StatusCode::OK,
+            [content_type, accept_ranges],
+            [(header::CONTENT_LENGTH, len.to_string())],
+            Body::from_stream(ReaderStream::new(file)),
+        )
+            .into_response(),
+        LogRange::Partial(start, end) => {
+            file.seek(SeekFrom::Start(start))
+                .await
+                .context("Failed to read log")?;
+            (
+                StatusCode::PARTIAL_CONTENT,
+                [content_type, accept_ranges],
+                [
+                    (header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}")),
+                    (header::CONTENT_LENGTH, (end - start + 1).to_string()),
+                ],
+                Body::from_stream(ReaderStream::new(file.take(end - start + 1))),
+            )
+                .into_response()
+        }
+        LogRange::Unsatisfiable => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(header::CONTENT_RANGE, format!("bytes */{len}"))],
+        )
+            .into_response(),
+    })
+}
+
+/// File name of a log uploaded by a worker, `None` if it is not a plain name
+pub fn log_file_name(log_url: &str) -> Option<&str> {
+    log_url
+        .rsplit_once('/')
+        .map(|(_, name)| name)
+        .filter(|name| !name.is_empty() && *name != "." && *name != "..")
+}
+
+/// Log of the latest job of an arch in a pipeline
+pub async fn log_get(
+    Path((pipeline_id, arch)): Path<(i32, String)>,
+    State(AppState { pool, .. }): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, AnyhowError> {
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok());
+    let Some(logs_dir) = &ARGS.logs_dir else {
+        return Ok(log_response(None, range).await?);
+    };
+
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+    let log_url = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::pipeline_id.eq(pipeline_id))
+        .filter(crate::schema::jobs::dsl::arch.eq(&arch))
+        .filter(crate::schema::jobs::dsl::log_url.is_not_null())
+        .order(crate::schema::jobs::dsl::id.desc())
+        .select(crate::schema::jobs::dsl::log_url)
+        .first::<Option<String>>(&mut conn)
+        .optional()?
+        .flatten();
+
+    let path = log_url
+        .as_deref()
+        .and_then(log_file_name)
+        .map(|name| logs_dir.join(name));
+    Ok(log_response(path.as_deref(), range).await?)
+}
+
+#[tokio::test]
+async fn test_log_response() {
+    let body = |res: Response| async move {
+        axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .to_vec()
+    };
+    let log = b"Building fd\nBuild succeeded\n".to_vec();
+    let file = std::env::temp_dir().join(format!("buildit-log-{}.txt", std::process::id()));
+    std::fs::write(&file, &log).unwrap();
+    let path = Some(file.as_path());
+
+    // full fetch
+    let res = log_response(path, None).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers()[header::CONTENT_TYPE],
+        "text/plain; charset=utf-8"
+    );
+    assert_eq!(body(res).await, log);
+
+    // the tail of the log
+    let res = log_response(path, Some("bytes=-10")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes 18-27/28");
+    assert_eq!(res.headers()[header::CONTENT_LENGTH], "10");
+    assert_eq!(body(res).await, b"succeeded\n");
+
+    // a range in the middle
+    let res = log_response(path, Some("bytes=9-13")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body(res).await, b"fd\nBu");
+
+    let res = log_response(path, Some("bytes=28-")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes */28");
+    std::fs::remove_file(&file).unwrap();
+
+    // missing log
+    let res = log_response(path, Some("bytes=-10")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let res = log_response(None, Some("bytes=-10")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    assert_eq!(parse_range(Some("bytes=0-4"), 28), LogRange::Partial(0, 4));
+    assert_eq!(
+        parse_range(Some("bytes=12-"), 28),
+        LogRange::Partial(12, 27)
+    );
+    assert_eq!(
+        parse_range(Some("bytes=20-99"), 28),
+        LogRange::Partial(20, 27)
+    );
+    assert_eq!(parse_range(Some("bytes=-99"), 28), LogRange::Partial(0, 27));
+    assert_eq!(parse_range(Some("bytes=-0"), 28), LogRange::Unsatisfiable);
+    assert_eq!(parse_range(Some("bytes=-10"), 0), LogRange::Unsatisfiable);
+    assert_eq!(parse_range(Some("bytes=0-1,4-5"), 28), LogRange::Full);
+    assert_eq!(parse_range(Some("bytes=5-1"), 28), LogRange::Full);
+    assert_eq!(parse_range(Some("lines=1-2"), 28), LogRange::Full);
+
+    assert_eq!(
+        log_file_name("https://buildit.aosc.io/logs/fd-9.0.0-amd64-Yerus-2024-01-01-00:00:00.txt"),
+        Some("fd-9.0.0-amd64-Yerus-2024-01-01-00:00:00.txt")
+    );
+    assert_eq!(log_file_name("https://buildit.aosc.io/logs/"), None);
+    assert_eq!(log_file_name("https://buildit.aosc.io/logs/.."), None);
+}
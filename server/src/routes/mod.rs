@@ -1,14 +1,18 @@
-use crate::{DbPool, RemoteAddr, HEARTBEAT_TIMEOUT};
+use crate::{
+    metrics::{render_metrics, ARCH_SUCCESS},
+    notifier::Notifiers,
+    DbPool, RemoteAddr, HEARTBEAT_TIMEOUT,
+};
 use anyhow::Context;
 use axum::{
     extract::{Json, State},
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
 };
 use chrono::Utc;
 use diesel::dsl::{count, sum};
 use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
-use futures::channel::mpsc::UnboundedSender;
+use futures::channel::{mpsc::UnboundedSender, oneshot};
 use serde::Serialize;
 use std::{
     collections::{BTreeMap, HashMap, VecDeque},
@@ -18,14 +22,20 @@ use std::{
 use teloxide::prelude::*;
 use tracing::info;
 
+pub mod export;
 pub mod job;
+pub mod logs;
 pub mod pipeline;
+pub mod stats;
 pub mod webhook;
 pub mod websocket;
 pub mod worker;
 
+pub use export::*;
 pub use job::*;
+pub use logs::*;
 pub use pipeline::*;
+pub use stats::*;
 pub use webhook::*;
 pub use websocket::*;
 pub use worker::*;
@@ -34,6 +44,14 @@ pub async fn ping() -> &'static str {
     "PONG"
 }
 
+/// Gauges for Prometheus, computed from results received since startup
+pub async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render_metrics(&ARCH_SUCCESS.ratios(Utc::now())),
+    )
+}
+
 pub struct Viewer {
     remote_addr: RemoteAddr,
     sender: UnboundedSender<axum::extract::ws::Message>,
@@ -43,6 +61,10 @@ pub struct Viewer {
 pub struct WSState {
     last_logs: VecDeque<axum::extract::ws::Message>,
     viewers: Vec<Arc<Viewer>>,
+    /// Requests to the worker, set while it is connected with the worker secret
+    control: Option<UnboundedSender<axum::extract::ws::Message>>,
+    /// Live log requests waiting for a reply, by request id
+    live_log_requests: HashMap<u64, (i32, oneshot::Sender<String>)>,
 }
 
 // map from hostname to ws state
@@ -52,6 +74,7 @@ pub type WSStateMap = Arc<Mutex<HashMap<String, WSState>>>;
 pub struct AppState {
     pub pool: DbPool,
     pub bot: Option<Bot>,
+    pub notifiers: Notifiers,
     pub ws_state_map: WSStateMap,
 }
 
@@ -1,66 +1,226 @@
 use crate::models::User;
-use crate::routes::{AnyhowError, AppState};
+use crate::routes::{AnyhowError, AppState, KeyedLocks};
 use crate::{
     api::{self, JobSource, PipelineStatus},
     models::{Job, Pipeline},
+    oidc::{
+        check_workflow_repo, github_actions_actor, github_actions_jwks, verify_github_actions_token,
+    },
+    repo::{repo_by_full_name, repo_of},
+    DbPool, ARGS,
 };
-use anyhow::Context;
+use anyhow::{bail, Context};
 use axum::extract::{Json, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
 use diesel::{
     BelongingToDsl, Connection, ExpressionMethods, GroupedBy, QueryDsl, RunQueryDsl,
     SelectableHelper,
 };
 use serde::{Deserialize, Serialize};
-use tracing::error;
+use std::future::Future;
+use tracing::{error, info};
 
 #[derive(Deserialize)]
 pub struct PipelineNewRequest {
     git_branch: String,
     packages: String,
     archs: String,
+    /// Name of a secondary repo, the primary one if not given
+    repo: Option<String>,
 }
 
 #[derive(Serialize)]
 pub struct PipelineNewResponse {
     id: i32,
+    /// Packages listed more than once, built once
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    duplicates: Vec<String>,
+}
+
+/// Valid `Idempotency-Key` header if given
+pub fn idempotency_key(headers: &HeaderMap) -> anyhow::Result<Option<&str>> {
+    let Some(key) = headers.get("Idempotency-Key") else {
+        return Ok(None);
+    };
+    match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= 255 => Ok(Some(key)),
+        _ => bail!("Invalid Idempotency-Key: must be 1 to 255 visible ASCII characters"),
+    }
+}
+
+// requests with the same idempotency key are handled one at a time,
+// so that concurrent retries do not both create pipelines
+static IDEMPOTENCY_LOCKS: KeyedLocks<String> = KeyedLocks::new();
+
+/// Answer with the pipeline created by an earlier request with the key, or
+/// create one with `create`, which records the key along with the pipeline
+async fn idempotent_pipeline_new<F, Fut>(
+    pool: &DbPool,
+    key: Option<&str>,
+    ttl_secs: i64,
+    create: F,
+) -> anyhow::Result<PipelineNewResponse>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = anyhow::Result<PipelineNewResponse>>,
+{
+    let Some(key) = key else {
+        return create().await;
+    };
+    let _lock = IDEMPOTENCY_LOCKS.lock(key.to_string()).await;
+    {
+        let mut conn = pool
+            .get()
+            .context("Failed to get db connection from pool")?;
+        if let Some(id) = api::idempotency_key_get(&mut conn, key, chrono::Utc::now(), ttl_secs)? {
+            info!("Idempotency-Key {key} was used by pipeline #{id}, skipping");
+            return Ok(PipelineNewResponse {
+                id,
+                duplicates: vec![],
+            });
+        }
+    }
+    create().await
 }
 
 pub async fn pipeline_new(
     State(AppState { pool, .. }): State<AppState>,
+    headers: HeaderMap,
     Json(payload): Json<PipelineNewRequest>,
 ) -> Result<Json<PipelineNewResponse>, AnyhowError> {
-    let pipeline = api::pipeline_new(
+    let key = idempotency_key(&headers)?;
+    let res = idempotent_pipeline_new(&pool, key, ARGS.idempotency_ttl, || async {
+        let repo = repo_of(payload.repo.as_deref())?;
+        let (pipeline, notes) = api::pipeline_new(
+            pool.clone(),
+            &repo,
+            &payload.git_branch,
+            None,
+            None,
+            &payload.packages,
+            &payload.archs,
+            JobSource::Manual,
+            None,
+            false,
+            0,
+            false,
+            None,
+            key,
+        )
+        .await?;
+        Ok(PipelineNewResponse {
+            id: pipeline.id,
+            duplicates: notes.duplicates,
+        })
+    })
+    .await?;
+    Ok(Json(res))
+}
+
+/// Create a pipeline from a GitHub Actions workflow, authenticated by the
+/// OIDC token of the workflow in the `Authorization: Bearer` header
+pub async fn pipeline_new_github_actions(
+    State(AppState { pool, .. }): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<PipelineNewRequest>,
+) -> Result<Response, AnyhowError> {
+    let forbidden = |reason: String| Ok((StatusCode::FORBIDDEN, reason).into_response());
+    let Some(allowed_repos) = ARGS.oidc_repos.as_deref() else {
+        return forbidden("Builds from GitHub Actions are not enabled".to_string());
+    };
+    let Some(token) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return forbidden("Missing bearer token".to_string());
+    };
+
+    let kid = jsonwebtoken::decode_header(token)
+        .ok()
+        .and_then(|header| header.kid);
+    let jwks = github_actions_jwks(kid.as_deref()).await?;
+    let claims = match verify_github_actions_token(token, &jwks, &ARGS.oidc_audience, allowed_repos)
+    {
+        Ok(claims) => claims,
+        Err(err) => {
+            info!("Rejected GitHub Actions token: {err:?}");
+            return forbidden(format!("{err:#}"));
+        }
+    };
+    info!(
+        "Build of {} requested by {} from {} ({})",
+        payload.packages, claims.actor, claims.repository, claims.git_ref
+    );
+
+    // default to the repo of the workflow
+    let repo = match payload.repo.as_deref() {
+        Some(name) => repo_of(Some(name))?,
+        None => match repo_by_full_name(&claims.repository) {
+            Some(repo) => repo,
+            None => {
+                return forbidden(format!(
+                    "Repository {} is not configured",
+                    claims.repository
+                ))
+            }
+        },
+    };
+    if let Err(err) = check_workflow_repo(&repo, &claims) {
+        info!("Rejected GitHub Actions build: {err:?}");
+        return forbidden(format!("{err:#}"));
+    }
+    let (pipeline, notes) = api::pipeline_new(
         pool,
+        &repo,
         &payload.git_branch,
         None,
         None,
         &payload.packages,
         &payload.archs,
         JobSource::Manual,
+        Some(&github_actions_actor(&claims)),
         false,
+        0,
+        false,
+        None,
+        None,
     )
     .await?;
-    Ok(Json(PipelineNewResponse { id: pipeline.id }))
+    Ok(Json(PipelineNewResponse {
+        id: pipeline.id,
+        duplicates: notes.duplicates,
+    })
+    .into_response())
 }
 
 #[derive(Deserialize)]
 pub struct PipelineNewPRRequest {
     pr: u64,
     archs: Option<String>,
+    force: Option<bool>,
+    repo: Option<String>,
 }
 
 pub async fn pipeline_new_pr(
     State(AppState { pool, .. }): State<AppState>,
     Json(payload): Json<PipelineNewPRRequest>,
 ) -> Result<Json<PipelineNewResponse>, AnyhowError> {
-    let pipeline = api::pipeline_new_pr(
+    let (pipeline, notes) = api::pipeline_new_pr(
         pool,
+        &repo_of(payload.repo.as_deref())?,
         payload.pr,
         payload.archs.as_deref(),
         JobSource::Manual,
+        None,
+        payload.force == Some(true),
     )
     .await?;
-    Ok(Json(PipelineNewResponse { id: pipeline.id }))
+    Ok(Json(PipelineNewResponse {
+        id: pipeline.id,
+        duplicates: notes.duplicates,
+    }))
 }
 
 #[derive(Deserialize)]
@@ -240,7 +400,7 @@ pub async fn pipeline_list(
                 let mut has_unfinished = false;
                 for job in &jobs {
                     match job.status.as_str() {
-                        "error" => has_error = true,
+                        "error" | "expired" | "lost" => has_error = true,
                         "success" => {
                             // success
                         }
@@ -248,7 +408,7 @@ pub async fn pipeline_list(
                             // failed
                             has_failed = true;
                         }
-                        "created" => {
+                        "created" | "held" => {
                             has_unfinished = true;
                         }
                         "running" => {
@@ -310,3 +470,80 @@ pub async fn pipeline_status(
 ) -> Result<Json<Vec<PipelineStatus>>, AnyhowError> {
     Ok(Json(api::pipeline_status(pool).await?))
 }
+
+#[test]
+fn test_idempotency_key() {
+    let mut headers = HeaderMap::new();
+    assert_eq!(idempotency_key(&headers).unwrap(), None);
+
+    headers.insert("Idempotency-Key", "ci-1234-build".parse().unwrap());
+    assert_eq!(idempotency_key(&headers).unwrap(), Some("ci-1234-build"));
+
+    headers.insert("Idempotency-Key", "".parse().unwrap());
+    assert!(idempotency_key(&headers).is_err());
+
+    headers.insert("Idempotency-Key", "x".repeat(256).parse().unwrap());
+    assert!(idempotency_key(&headers).is_err());
+}
+
+#[tokio::test]
+async fn test_idempotent_pipeline_new() {
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    let Some(pool) = crate::test_db() else {
+        return;
+    };
+    let next_id = AtomicI32::new(12);
+    let request = |key: Option<&'static str>| {
+        let pool = pool.clone();
+        let next_id = &next_id;
+        async move {
+            idempotent_pipeline_new(&pool, key, 3600, || async {
+                // as slow as updating the tree, so that retries arrive meanwhile
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                let id = next_id.fetch_add(1, Ordering::SeqCst);
+                let mut conn = pool.get()?;
+                conn.transaction::<(), anyhow::Error, _>(|conn| {
+                    diesel::insert_into(crate::schema::pipelines::table)
+                        .values(&Pipeline {
+                            id,
+                            ..Pipeline::fixture()
+                        })
+                        .execute(conn)?;
+                    if let Some(key) = key {
+                        api::idempotency_key_set(conn, key, id, chrono::Utc::now(), 3600)?;
+                    }
+                    Ok(())
+                })?;
+                Ok(PipelineNewResponse {
+                    id,
+                    duplicates: vec![],
+                })
+            })
+            .await
+            .unwrap()
+            .id
+        }
+    };
+
+    // a retry while the first request is still creating the pipeline gets the same one
+    assert_eq!(
+        tokio::join!(request(Some("ci-1")), request(Some("ci-1"))),
+        (12, 12)
+    );
+    assert_eq!(request(Some("ci-1")).await, 12);
+
+    // other keys and requests without keys create their own
+    assert_eq!(request(Some("ci-2")).await, 13);
+    assert_eq!(request(None).await, 14);
+    assert_eq!(request(None).await, 15);
+
+    let mut conn = pool.get().unwrap();
+    assert_eq!(
+        crate::schema::pipelines::dsl::pipelines
+            .count()
+            .get_result::<i64>(&mut conn)
+            .unwrap(),
+        4
+    );
+}
@@ -0,0 +1,321 @@
+use crate::routes::{AnyhowError, AppState};
+use anyhow::Context;
+use axum::extract::{Json, Query, State};
+use chrono::{DateTime, Utc};
+use diesel::{
+    dsl::count,
+    sql_types::{BigInt, Double, Nullable, Text, Timestamptz},
+    Connection, ExpressionMethods, PgConnection, QueryDsl, QueryableByName, RunQueryDsl,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Deserialize)]
+pub struct StatsRequest {
+    /// Time window in days, defaults to 7
+    days: Option<i64>,
+    /// Number of failing packages to return, defaults to 10
+    top: Option<i64>,
+}
+
+#[derive(QueryableByName)]
+struct ArchStatsRow {
+    #[diesel(sql_type = Text)]
+    arch: String,
+    #[diesel(sql_type = BigInt)]
+    total_job_count: i64,
+    #[diesel(sql_type = BigInt)]
+    success_job_count: i64,
+    #[diesel(sql_type = Nullable<Double>)]
+    median_elapsed_secs: Option<f64>,
+    #[diesel(sql_type = Nullable<Double>)]
+    p95_elapsed_secs: Option<f64>,
+}
+
+#[derive(QueryableByName)]
+struct DailyStatsRow {
+    #[diesel(sql_type = Timestamptz)]
+    day: DateTime<Utc>,
+    #[diesel(sql_type = BigInt)]
+    total_job_count: i64,
+    #[diesel(sql_type = BigInt)]
+    success_job_count: i64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct StatsResponseByArch {
+    total_job_count: i64,
+    success_job_count: i64,
+    failed_job_count: i64,
+    success_rate: f64,
+    median_elapsed_secs: Option<f64>,
+    p95_elapsed_secs: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct StatsResponseByDay {
+    day: DateTime<Utc>,
+    total_job_count: i64,
+    success_job_count: i64,
+}
+
+#[derive(Serialize)]
+pub struct StatsResponseFailingPackage {
+    package: String,
+    failed_job_count: i64,
+}
+
+#[derive(Serialize)]
+pub struct StatsResponse {
+    since: DateTime<Utc>,
+
+    total_job_count: i64,
+    success_job_count: i64,
+    failed_job_count: i64,
+    success_rate: f64,
+
+    by_arch: BTreeMap<String, StatsResponseByArch>,
+    by_day: Vec<StatsResponseByDay>,
+    top_failing_packages: Vec<StatsResponseFailingPackage>,
+}
+
+fn success_rate(success: i64, total: i64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        success as f64 / total as f64
+    }
+}
+
+fn collect_by_arch(rows: Vec<ArchStatsRow>) -> BTreeMap<String, StatsResponseByArch> {
+    rows.into_iter()
+        .map(|row| {
+            (
+                row.arch,
+                StatsResponseByArch {
+                    total_job_count: row.total_job_count,
+                    success_job_count: row.success_job_count,
+                    failed_job_count: row.total_job_count - row.success_job_count,
+                    success_rate: success_rate(row.success_job_count, row.total_job_count),
+                    median_elapsed_secs: row.median_elapsed_secs,
+                    p95_elapsed_secs: row.p95_elapsed_secs,
+                },
+            )
+        })
+        .collect()
+}
+
+pub async fn stats_overview(
+    Query(query): Query<StatsRequest>,
+    State(AppState { pool, .. }): State<AppState>,
+) -> Result<Json<StatsResponse>, AnyhowError> {
+    let days = query.days.unwrap_or(7).clamp(1, 365);
+    let top = query.top.unwrap_or(10).clamp(1, 100);
+    let since = Utc::now() - chrono::Duration::try_days(days).unwrap();
+
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+    Ok(Json(stats_since(&mut conn, since, top)?))
+}
+
+/// Statistics of jobs finished since `since`, with the `top` most failing packages
+fn stats_since(
+    conn: &mut PgConnection,
+    since: DateTime<Utc>,
+    top: i64,
+) -> anyhow::Result<StatsResponse> {
+    conn.transaction::<StatsResponse, anyhow::Error, _>(|conn| {
+        // aggregate in the database, uses jobs_finish_time_idx
+        let by_arch = collect_by_arch(
+            diesel::sql_query(
+                "SELECT arch, \
+                COUNT(*) AS total_job_count, \
+                COUNT(*) FILTER (WHERE status = 'success') AS success_job_count, \
+                percentile_cont(0.5) WITHIN GROUP (ORDER BY elapsed_secs) AS median_elapsed_secs, \
+                percentile_cont(0.95) WITHIN GROUP (ORDER BY elapsed_secs) AS p95_elapsed_secs \
+                FROM jobs \
+                WHERE finish_time >= $1 AND status IN ('success', 'failed') \
+                GROUP BY arch",
+            )
+            .bind::<Timestamptz, _>(since)
+            .load::<ArchStatsRow>(conn)?,
+        );
+
+        let by_day = diesel::sql_query(
+            "SELECT date_trunc('day', finish_time) AS day, \
+            COUNT(*) AS total_job_count, \
+            COUNT(*) FILTER (WHERE status = 'success') AS success_job_count \
+            FROM jobs \
+            WHERE finish_time >= $1 AND status IN ('success', 'failed') \
+            GROUP BY day \
+            ORDER BY day",
+        )
+        .bind::<Timestamptz, _>(since)
+        .load::<DailyStatsRow>(conn)?
+        .into_iter()
+        .map(|row| StatsResponseByDay {
+            day: row.day,
+            total_job_count: row.total_job_count,
+            success_job_count: row.success_job_count,
+        })
+        .collect();
+
+        let top_failing_packages = crate::schema::jobs::dsl::jobs
+            .filter(crate::schema::jobs::dsl::finish_time.ge(since))
+            .filter(crate::schema::jobs::dsl::status.eq("failed"))
+            .filter(crate::schema::jobs::dsl::failed_package.is_not_null())
+            .group_by(crate::schema::jobs::dsl::failed_package)
+            .select((
+                crate::schema::jobs::dsl::failed_package,
+                count(crate::schema::jobs::dsl::id),
+            ))
+            .order(count(crate::schema::jobs::dsl::id).desc())
+            .limit(top)
+            .load::<(Option<String>, i64)>(conn)?
+            .into_iter()
+            .map(|(package, failed_job_count)| StatsResponseFailingPackage {
+                package: package.unwrap_or_default(),
+                failed_job_count,
+            })
+            .collect();
+
+        let total_job_count = by_arch.values().map(|s| s.total_job_count).sum();
+        let success_job_count = by_arch.values().map(|s| s.success_job_count).sum();
+
+        Ok(StatsResponse {
+            since,
+            total_job_count,
+            success_job_count,
+            failed_job_count: total_job_count - success_job_count,
+            success_rate: success_rate(success_job_count, total_job_count),
+            by_arch,
+            by_day,
+            top_failing_packages,
+        })
+    })
+}
+
+#[test]
+fn test_collect_by_arch() {
+    let by_arch = collect_by_arch(vec![
+        ArchStatsRow {
+            arch: "amd64".to_string(),
+            total_job_count: 8,
+            success_job_count: 6,
+            median_elapsed_secs: Some(120.0),
+            p95_elapsed_secs: Some(3600.0),
+        },
+        ArchStatsRow {
+            arch: "riscv64".to_string(),
+            total_job_count: 0,
+            success_job_count: 0,
+            median_elapsed_secs: None,
+            p95_elapsed_secs: None,
+        },
+    ]);
+
+    assert_eq!(
+        by_arch["amd64"],
+        StatsResponseByArch {
+            total_job_count: 8,
+            success_job_count: 6,
+            failed_job_count: 2,
+            success_rate: 0.75,
+            median_elapsed_secs: Some(120.0),
+            p95_elapsed_secs: Some(3600.0),
+        }
+    );
+    assert_eq!(by_arch["riscv64"].success_rate, 0.0);
+}
+
+#[test]
+fn test_stats_since() {
+    use crate::models::{Job, Pipeline};
+
+    let Some(pool) = crate::test_db() else {
+        return;
+    };
+    let mut conn = pool.get().unwrap();
+    // days start at midnight UTC
+    diesel::sql_query("SET TIME ZONE 'UTC'")
+        .execute(&mut conn)
+        .unwrap();
+    diesel::insert_into(crate::schema::pipelines::table)
+        .values(&Pipeline::fixture())
+        .execute(&mut conn)
+        .unwrap();
+    let since = DateTime::from_timestamp(1704067200, 0).unwrap(); // 2024-01-01
+    let day = |days: i64| since + chrono::Duration::try_hours(days * 24 + 12).unwrap();
+    let job = |id: i32, arch: &str, status: &str, elapsed: i64, finished: DateTime<Utc>| Job {
+        id,
+        arch: arch.to_string(),
+        status: status.to_string(),
+        elapsed_secs: Some(elapsed),
+        finish_time: Some(finished),
+        failed_package: (status == "failed")
+            .then(|| if id == 7 { "ripgrep" } else { "fd" }.to_string()),
+        ..Job::fixture()
+    };
+    diesel::insert_into(crate::schema::jobs::table)
+        .values(&[
+            job(1, "amd64", "success", 10, day(0)),
+            job(2, "amd64", "success", 20, day(0)),
+            job(3, "amd64", "success", 30, day(0)),
+            job(4, "amd64", "success", 40, day(0)),
+            job(5, "amd64", "failed", 100, day(1)),
+            job(6, "arm64", "success", 50, day(1)),
+            job(7, "arm64", "failed", 60, day(1)),
+            job(8, "arm64", "failed", 70, day(1)),
+            // neither success nor failure
+            job(9, "amd64", "error", 9999, day(1)),
+            job(10, "amd64", "cancelled", 9999, day(1)),
+            // before the window
+            job(11, "amd64", "failed", 9999, day(-1)),
+        ])
+        .execute(&mut conn)
+        .unwrap();
+
+    let stats = stats_since(&mut conn, since, 1).unwrap();
+    assert_eq!(stats.total_job_count, 8);
+    assert_eq!(stats.success_job_count, 5);
+    assert_eq!(stats.failed_job_count, 3);
+    assert_eq!(stats.success_rate, 0.625);
+
+    let close = |a: Option<f64>, b: f64| (a.unwrap() - b).abs() < 1e-9;
+    let amd64 = &stats.by_arch["amd64"];
+    assert_eq!(
+        (
+            amd64.total_job_count,
+            amd64.success_job_count,
+            amd64.failed_job_count
+        ),
+        (5, 4, 1)
+    );
+    assert_eq!(amd64.success_rate, 0.8);
+    assert!(close(amd64.median_elapsed_secs, 30.0));
+    // interpolated between the 4th and 5th of 10, 20, 30, 40, 100
+    assert!(close(amd64.p95_elapsed_secs, 88.0));
+    let arm64 = &stats.by_arch["arm64"];
+    assert_eq!((arm64.total_job_count, arm64.success_job_count), (3, 1));
+    assert!(close(arm64.median_elapsed_secs, 60.0));
+    assert!(close(arm64.p95_elapsed_secs, 69.0));
+    assert_eq!(stats.by_arch.len(), 2);
+
+    let by_day = stats
+        .by_day
+        .iter()
+        .map(|day| (day.day, day.total_job_count, day.success_job_count))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        by_day,
+        vec![
+            (since, 4, 4),
+            (since + chrono::Duration::try_days(1).unwrap(), 4, 1)
+        ]
+    );
+
+    assert_eq!(stats.top_failing_packages.len(), 1);
+    assert_eq!(stats.top_failing_packages[0].package, "fd");
+    assert_eq!(stats.top_failing_packages[0].failed_job_count, 2);
+}
@@ -1,14 +1,22 @@
-use anyhow::{anyhow, bail};
+use anyhow::anyhow;
 use axum::{extract::State, Json};
 use hyper::HeaderMap;
-use reqwest::StatusCode;
 use serde::Deserialize;
 use serde_json::Value;
+use std::{future::Future, time::Duration};
+use teloxide::Bot;
 use tracing::{info, warn};
 
-use crate::{api, formatter::to_html_new_pipeline_summary, DbPool, ARGS};
+use crate::{
+    api,
+    formatter::{format_pr_status, new_pipeline_summary, to_html_pipeline_notes},
+    github::{get_crab_github_bot, is_org_user},
+    models::Job,
+    repo::{repo_by_full_name, RepoConfig, PRIMARY_REPO_FULL_NAME},
+    DbPool, ARGS,
+};
 
-use super::{AnyhowError, AppState};
+use super::{AnyhowError, AppState, WSStateMap};
 
 #[derive(Debug, Deserialize)]
 pub struct WebhookComment {
@@ -28,6 +36,60 @@ struct User {
     login: String,
 }
 
+/// Pull requests with this label are rebuilt when new commits are pushed
+pub const AUTO_REBUILD_LABEL: &str = "buildit-auto";
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookPullRequest {
+    action: String,
+    number: u64,
+    pull_request: PullRequest,
+    sender: User,
+    repository: Option<Repository>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Repository {
+    full_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PullRequest {
+    #[serde(default)]
+    labels: Vec<Label>,
+    head: Option<Head>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Head {
+    sha: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Label {
+    name: String,
+}
+
+impl WebhookPullRequest {
+    /// Whether new commits were pushed to a pull request that opted in to rebuilds
+    pub fn wants_rebuild(&self) -> bool {
+        self.action == "synchronize"
+            && self
+                .pull_request
+                .labels
+                .iter()
+                .any(|label| label.name == AUTO_REBUILD_LABEL)
+    }
+
+    /// Commit the pull request points to after the event
+    pub fn head_sha(&self) -> Option<&str> {
+        self.pull_request
+            .head
+            .as_ref()
+            .map(|head| head.sha.as_str())
+    }
+}
+
 pub async fn webhook_handler(
     State(state): State<AppState>,
     headers: HeaderMap,
@@ -39,13 +101,32 @@ pub async fn webhook_handler(
         Some("issue_comment") => {
             let webhook_comment: WebhookComment = serde_json::from_value(json)?;
             let pool = state.pool;
+            let bot = state.bot;
+            let ws_state_map = state.ws_state_map;
 
             if webhook_comment.action == "created" {
                 tokio::spawn(async move {
-                    let res = handle_webhook_comment(&webhook_comment.comment, pool).await;
-                    if let Err(err) = res {
-                        warn!("Failed to handle webhook comment: {}", err);
-                    }
+                    let comment = &webhook_comment.comment;
+                    with_retry("handle webhook comment", || {
+                        handle_webhook_comment(comment, pool.clone(), bot.clone(), &ws_state_map)
+                    })
+                    .await;
+                });
+            }
+        }
+        Some("pull_request") => {
+            let webhook_pr: WebhookPullRequest = serde_json::from_value(json)?;
+            let pool = state.pool;
+            let bot = state.bot;
+            let ws_state_map = state.ws_state_map;
+
+            if webhook_pr.wants_rebuild() {
+                tokio::spawn(async move {
+                    let webhook_pr = &webhook_pr;
+                    with_retry(&format!("rebuild PR #{}", webhook_pr.number), || {
+                        handle_synchronize(webhook_pr, pool.clone(), bot.clone(), &ws_state_map)
+                    })
+                    .await;
                 });
             }
         }
@@ -57,98 +138,440 @@ pub async fn webhook_handler(
     Ok(())
 }
 
-async fn handle_webhook_comment(comment: &Comment, pool: DbPool) -> anyhow::Result<()> {
+/// Times a webhook event is handled again after transient failures
+const WEBHOOK_RETRIES: u32 = 3;
+
+/// What to do with a webhook event after an attempt to handle it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    /// Handled, nothing more to do
+    Done,
+    /// Failed for a reason that may go away, handle it again after the delay
+    Retry(Duration),
+    /// Failed for good or too many times, log and drop the event
+    Drop,
+}
+
+/// Decide what to do after the `attempt`-th (from 0) attempt to handle a webhook event
+pub fn disposition(res: &anyhow::Result<()>, attempt: u32) -> Disposition {
+    match res {
+        Ok(()) => Disposition::Done,
+        Err(err) if is_transient(err) && attempt < WEBHOOK_RETRIES => {
+            Disposition::Retry(Duration::from_secs(10 << attempt))
+        }
+        Err(_) => Disposition::Drop,
+    }
+}
+
+/// Rate limited or a server error
+pub fn is_transient_status(status: u16) -> bool {
+    status == 429 || (500..600).contains(&status)
+}
+
+/// Network errors, server errors of GitHub and running out of database connections
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            err.is_timeout()
+                || err.is_connect()
+                || err
+                    .status()
+                    .is_some_and(|status| is_transient_status(status.as_u16()))
+        } else if let Some(err) = cause.downcast_ref::<octocrab::Error>() {
+            match err {
+                octocrab::Error::GitHub { source, .. } => {
+                    is_transient_status(source.status_code.as_u16())
+                }
+                octocrab::Error::Hyper { .. } | octocrab::Error::Service { .. } => true,
+                _ => false,
+            }
+        } else {
+            cause.is::<diesel::r2d2::PoolError>()
+        }
+    })
+}
+
+/// Handle a webhook event, retrying transient failures with backoff
+///
+/// Handlers must only fail before making changes, so that retrying does not repeat them.
+async fn with_retry<F, Fut>(what: &str, mut f: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let mut attempt = 0;
+    loop {
+        let res = f().await;
+        match (disposition(&res, attempt), res) {
+            (Disposition::Retry(delay), Err(err)) => {
+                warn!(
+                    "Failed to {what}, retrying in {}s: {err:#}",
+                    delay.as_secs()
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            (Disposition::Drop, Err(err)) => {
+                warn!("Failed to {what}: {err:#}");
+                return;
+            }
+            _ => return,
+        }
+    }
+}
+
+/// `owner/repo` of an issue from its API URL
+pub fn repo_of_issue_url(issue_url: &str) -> Option<&str> {
+    let path = issue_url.split_once("/repos/")?.1;
+    let (full_name, _) = path.rsplit_once("/issues/")?;
+    (full_name.split('/').count() == 2).then_some(full_name)
+}
+
+async fn handle_webhook_comment(
+    comment: &Comment,
+    pool: DbPool,
+    bot: Option<Bot>,
+    ws_state_map: &WSStateMap,
+) -> anyhow::Result<()> {
     let is_org_user = is_org_user(&comment.user.login).await?;
 
     if !is_org_user {
         return Ok(());
     }
 
-    let body = comment.body.split_whitespace().collect::<Vec<_>>();
-
     let num = comment
         .issue_url
         .split('/')
         .last()
         .and_then(|x| x.parse::<u64>().ok())
         .ok_or_else(|| anyhow!("Failed to get pr number"))?;
+    let full_name = repo_of_issue_url(&comment.issue_url)
+        .ok_or_else(|| anyhow!("Failed to get repo of {}", comment.issue_url))?;
+    let Some(repo) = repo_by_full_name(full_name) else {
+        warn!("Ignoring comment on {full_name}, which is not a configured repo");
+        return Ok(());
+    };
 
-    let mut is_request = false;
-
-    for (i, c) in body.iter().enumerate() {
-        if is_request {
-            match c.to_owned() {
-                "build" => {
-                    let mut archs = None;
-                    if let Some(v) = body.get(i + 1) {
-                        archs = Some(v.to_owned());
-                    }
+    match parse_bot_request(&comment.body) {
+        Some(BotRequest::Build { archs, force }) => {
+            pipeline_new_pr_impl(pool, &repo, num, archs, &comment.user.login, force).await?;
+        }
+        Some(BotRequest::Cancel) => {
+            let crab = get_crab_github_bot().await?;
+            let msg = match api::pipeline_cancel_pr(
+                pool,
+                bot,
+                ws_state_map,
+                &repo.full_name(),
+                num,
+                &comment.user.login,
+            )
+            .await
+            {
+                Ok(Some((pipeline, jobs))) => format_cancelled(pipeline.id, &jobs),
+                Ok(None) => format!("No queued or running pipeline of PR #{num} to cancel"),
+                Err(err) => format!("Failed to cancel pipeline: {err}"),
+            };
 
-                    pipeline_new_pr_impl(pool, num, archs).await?;
-                }
-                x => {
-                    warn!("Unsupport request: {x}")
-                }
+            // the pipeline is cancelled already, do not retry
+            if let Err(err) = crab
+                .issues(&repo.owner, &repo.repo)
+                .create_comment(num, msg)
+                .await
+            {
+                warn!("Failed to comment on PR #{num}: {err}");
             }
-            break;
         }
-        if *c == "@aosc-buildit-bot" {
-            is_request = true;
+        Some(BotRequest::Status) => {
+            let msg = match api::pr_latest_build(pool, &repo.full_name(), num).await? {
+                Some((pipeline, jobs)) => format_pr_status(num, &pipeline, &jobs),
+                None => format!("PR #{num} has not been built yet"),
+            };
+
+            let crab = get_crab_github_bot().await?;
+            crab.issues(&repo.owner, &repo.repo)
+                .create_comment(num, msg)
+                .await?;
+        }
+        Some(BotRequest::Unknown(x)) => {
+            warn!("Unsupport request: {x}")
         }
+        None => {}
     }
 
     Ok(())
 }
 
+/// A request to the bot in a GitHub comment
+#[derive(Debug, PartialEq, Eq)]
+pub enum BotRequest<'a> {
+    Build { archs: Option<&'a str>, force: bool },
+    Cancel,
+    Status,
+    Unknown(&'a str),
+}
+
+/// Parse the request following `@aosc-buildit-bot` in the comment
+pub fn parse_bot_request(body: &str) -> Option<BotRequest<'_>> {
+    let body = body.split_whitespace().collect::<Vec<_>>();
+    let i = body.iter().position(|c| *c == "@aosc-buildit-bot")?;
+
+    Some(match *body.get(i + 1)? {
+        "build" => {
+            let args = body[i + 2..]
+                .iter()
+                .filter(|x| **x != "--force")
+                .collect::<Vec<_>>();
+            let force = args.len() < body.len() - i - 2;
+            let archs = args.first().map(|x| **x);
+            BotRequest::Build { archs, force }
+        }
+        "cancel" => BotRequest::Cancel,
+        "status" => BotRequest::Status,
+        x => BotRequest::Unknown(x),
+    })
+}
+
+pub fn format_cancelled(pipeline_id: i32, jobs: &[Job]) -> String {
+    let jobs = jobs
+        .iter()
+        .map(|job| {
+            let state = if job.status == "running" {
+                "running"
+            } else {
+                "queued"
+            };
+            format!("{} ({state})", job.arch)
+        })
+        .collect::<Vec<_>>();
+    format!("Cancelled pipeline #{pipeline_id}: {}", jobs.join(", "))
+}
+
+/// Rebuild a pull request for the archs of its previous pipeline
+async fn handle_synchronize(
+    webhook_pr: &WebhookPullRequest,
+    pool: DbPool,
+    bot: Option<Bot>,
+    ws_state_map: &WSStateMap,
+) -> anyhow::Result<()> {
+    let full_name = webhook_pr
+        .repository
+        .as_ref()
+        .map(|repo| repo.full_name.as_str())
+        .unwrap_or(PRIMARY_REPO_FULL_NAME);
+    let Some(repo) = repo_by_full_name(full_name) else {
+        warn!("Ignoring PR of {full_name}, which is not a configured repo");
+        return Ok(());
+    };
+    let Some(pipeline) =
+        api::pipeline_latest_of_pr(pool.clone(), &repo.full_name(), webhook_pr.number).await?
+    else {
+        info!(
+            "PR #{} has not been built before, skipping rebuild",
+            webhook_pr.number
+        );
+        return Ok(());
+    };
+
+    // stop building the commits replaced by the push
+    if let Some(head_sha) = webhook_pr.head_sha() {
+        for (pipeline, jobs) in api::pipeline_supersede_pr(
+            pool.clone(),
+            bot,
+            ws_state_map,
+            &repo.full_name(),
+            webhook_pr.number,
+            head_sha,
+            &webhook_pr.sender.login,
+        )
+        .await?
+        {
+            info!(
+                "{}, superseded by {head_sha}",
+                format_cancelled(pipeline.id, &jobs)
+            );
+        }
+    }
+
+    info!(
+        "Rebuilding PR #{} on {} after new commits",
+        webhook_pr.number, pipeline.archs
+    );
+    pipeline_new_pr_impl(
+        pool,
+        &repo,
+        webhook_pr.number,
+        Some(&pipeline.archs),
+        &webhook_pr.sender.login,
+        false,
+    )
+    .await
+}
+
 async fn pipeline_new_pr_impl(
     pool: DbPool,
+    repo: &RepoConfig,
     num: u64,
     archs: Option<&str>,
+    requested_by: &str,
+    force: bool,
 ) -> Result<(), anyhow::Error> {
-    let res = api::pipeline_new_pr(pool, num, archs, api::JobSource::Github(num)).await;
-
-    let crab = octocrab::Octocrab::builder()
-        .user_access_token(ARGS.github_access_token.clone())
-        .build()?;
+    let crab = get_crab_github_bot().await?;
+    let res = api::pipeline_new_pr(
+        pool.clone(),
+        repo,
+        num,
+        archs,
+        api::JobSource::Github(num),
+        Some(requested_by),
+        force,
+    )
+    .await;
 
     let msg = match res {
-        Ok(res) => to_html_new_pipeline_summary(
-            res.id,
-            &res.git_branch,
-            &res.git_sha,
-            res.github_pr.map(|n| n as u64),
-            &res.archs.split(',').collect::<Vec<_>>(),
-            &res.packages.split(',').collect::<Vec<_>>(),
-        ),
-        Err(e) => {
-            format!("Failed to create pipeline: {e}")
+        Ok((res, notes)) => {
+            new_pipeline_summary(
+                &res,
+                api::unchanged_since(pool, &res)
+                    .await
+                    .unwrap_or_else(|err| {
+                        warn!("Failed to compare build plans: {err}");
+                        None
+                    }),
+                ARGS.new_pipeline_template.as_ref(),
+            ) + &to_html_pipeline_notes(&notes)
         }
+        Err(e) => match e.downcast_ref::<api::PrNotFound>() {
+            Some(not_found) => not_found.to_string(),
+            None => format!("Failed to create pipeline: {e}"),
+        },
     };
 
-    crab.issues("aosc-dev", "aosc-os-abbs")
+    // the pipeline is created already, do not retry
+    if let Err(err) = crab
+        .issues(&repo.owner, &repo.repo)
         .create_comment(num, msg)
-        .await?;
+        .await
+    {
+        warn!("Failed to comment on PR #{num}: {err}");
+    }
 
     Ok(())
 }
 
-async fn is_org_user(user: &str) -> anyhow::Result<bool> {
-    let client = reqwest::Client::builder().user_agent("buildit").build()?;
+#[test]
+fn test_webhook_pull_request() {
+    let event = |action: &str, labels: &[&str]| -> WebhookPullRequest {
+        serde_json::from_value(serde_json::json!({
+            "action": action,
+            "number": 4992,
+            "pull_request": {
+                "number": 4992,
+                "labels": labels
+                    .iter()
+                    .map(|name| serde_json::json!({ "id": 1, "name": name }))
+                    .collect::<Vec<_>>(),
+                "head": { "sha": "ffe2f8c8b5a4d4d8c6e1a3f1a79e3c8c4d4b1a2e" },
+            },
+            "sender": { "login": "cyan" },
+        }))
+        .unwrap()
+    };
+
+    // labeled pr gets new commits
+    let pushed = event("synchronize", &["upgrade", AUTO_REBUILD_LABEL]);
+    assert!(pushed.wants_rebuild());
+    assert_eq!(
+        pushed.head_sha(),
+        Some("ffe2f8c8b5a4d4d8c6e1a3f1a79e3c8c4d4b1a2e")
+    );
+
+    // not opted in
+    assert!(!event("synchronize", &["upgrade"]).wants_rebuild());
+    assert!(!event("synchronize", &[]).wants_rebuild());
+
+    // no new commits
+    assert!(!event("labeled", &[AUTO_REBUILD_LABEL]).wants_rebuild());
+    assert!(!event("opened", &[AUTO_REBUILD_LABEL]).wants_rebuild());
+}
+
+#[test]
+fn test_repo_of_issue_url() {
+    assert_eq!(
+        repo_of_issue_url("https://api.github.com/repos/AOSC-Dev/aoscx-abbs/issues/12"),
+        Some("AOSC-Dev/aoscx-abbs")
+    );
+    assert_eq!(
+        repo_of_issue_url("https://api.github.com/repos/AOSC-Dev/aosc-os-abbs/issues/4992"),
+        Some("AOSC-Dev/aosc-os-abbs")
+    );
+    assert_eq!(
+        repo_of_issue_url("https://api.github.com/issues/4992"),
+        None
+    );
+}
+
+#[test]
+fn test_parse_bot_request() {
+    assert_eq!(
+        parse_bot_request("@aosc-buildit-bot build"),
+        Some(BotRequest::Build {
+            archs: None,
+            force: false
+        })
+    );
+    assert_eq!(
+        parse_bot_request("LGTM\n@aosc-buildit-bot build --force amd64,arm64"),
+        Some(BotRequest::Build {
+            archs: Some("amd64,arm64"),
+            force: true
+        })
+    );
+    assert_eq!(
+        parse_bot_request("@aosc-buildit-bot cancel"),
+        Some(BotRequest::Cancel)
+    );
+    assert_eq!(
+        parse_bot_request("@aosc-buildit-bot status"),
+        Some(BotRequest::Status)
+    );
+    assert_eq!(
+        parse_bot_request("@aosc-buildit-bot merge"),
+        Some(BotRequest::Unknown("merge"))
+    );
+    assert_eq!(parse_bot_request("@aosc-buildit-bot"), None);
+    assert_eq!(parse_bot_request("cancel"), None);
+}
+
+#[tokio::test]
+async fn test_disposition() {
+    assert_eq!(disposition(&Ok(()), 0), Disposition::Done);
 
-    let resp = client
-        .get(format!(
-            "https://api.github.com/orgs/aosc-dev/public_members/{}",
-            user
-        ))
+    // nothing listens on port 1
+    let err = reqwest::Client::new()
+        .get("http://127.0.0.1:1")
         .send()
         .await
-        .and_then(|x| x.error_for_status());
+        .unwrap_err();
+    let res = Err(anyhow::Error::new(err).context("Network is not reachable"));
+    assert_eq!(
+        disposition(&res, 0),
+        Disposition::Retry(Duration::from_secs(10))
+    );
+    assert_eq!(
+        disposition(&res, 2),
+        Disposition::Retry(Duration::from_secs(40))
+    );
+    assert_eq!(disposition(&res, WEBHOOK_RETRIES), Disposition::Drop);
 
-    match resp {
-        Ok(_) => Ok(true),
-        Err(e) => match e.status() {
-            Some(StatusCode::NOT_FOUND) => Ok(false),
-            _ => bail!("Network is not reachable: {e}"),
-        },
-    }
+    // permanent failures are not retried
+    assert_eq!(
+        disposition(&Err(anyhow!("Failed to get pr number")), 0),
+        Disposition::Drop
+    );
+
+    assert!(is_transient_status(429));
+    assert!(is_transient_status(502));
+    assert!(!is_transient_status(404));
+    assert!(!is_transient_status(422));
 }
@@ -1,49 +1,79 @@
 use super::{AppState, WSStateMap};
-use crate::{routes::Viewer, RemoteAddr};
+use crate::{routes::Viewer, RemoteAddr, ARGS};
+use anyhow::{anyhow, bail};
 use axum::{
-    extract::{ws::WebSocket, ConnectInfo, Path, State, WebSocketUpgrade},
+    extract::{
+        ws::{Message, WebSocket},
+        ConnectInfo, Path, State, WebSocketUpgrade,
+    },
+    http::HeaderMap,
     response::IntoResponse,
 };
-use futures::{channel::mpsc::unbounded, future, SinkExt, StreamExt, TryStreamExt};
-use std::sync::Arc;
-use tracing::info;
+use common::{WorkerControl, WorkerControlReply, WORKER_SECRET_HEADER};
+use futures::{
+    channel::{mpsc::unbounded, oneshot},
+    future, SinkExt, StreamExt, TryStreamExt,
+};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::{info, warn};
+
+/// How long to wait for a worker to send the log of its running job
+pub const LIVE_LOG_TIMEOUT: Duration = Duration::from_secs(10);
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(0);
 
 pub async fn ws_worker_handler(
     Path(hostname): Path<String>,
     ws: WebSocketUpgrade,
     ConnectInfo(addr): ConnectInfo<RemoteAddr>,
+    headers: HeaderMap,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_worker_socket(socket, addr, hostname, state.ws_state_map))
+    // workers without the secret can still stream logs
+    let authorized = headers
+        .get(WORKER_SECRET_HEADER)
+        .is_some_and(|secret| secret.as_bytes() == ARGS.worker_secret.expose().as_bytes());
+    ws.on_upgrade(move |socket| {
+        handle_worker_socket(socket, addr, hostname, authorized, state.ws_state_map)
+    })
 }
 
 async fn handle_worker_socket(
     socket: WebSocket,
     who: RemoteAddr,
     hostname: String,
+    authorized: bool,
     state_map: WSStateMap,
 ) {
     info!("{:?} connected as worker with hostname {}", who, hostname);
 
-    let (_outgoing, incoming) = socket.split();
+    let (outgoing, incoming) = socket.split();
+
+    // forward requests to the worker
+    let control = if authorized {
+        let (tx, rx) = unbounded();
+        state_map
+            .lock()
+            .unwrap()
+            .entry(hostname.clone())
+            .or_default()
+            .control = Some(tx.clone());
+        tokio::spawn(rx.map(Ok).forward(outgoing));
+        Some(tx)
+    } else {
+        None
+    };
 
     // forward websocket to tx
     if let Err(err) = incoming
         .try_for_each(|msg| {
-            // We want to broadcast the message to viewers subscribing to the hostname
-            let mut map = state_map.lock().unwrap();
-            if let Some(state) = map.get_mut(&hostname) {
-                for recp in &state.viewers {
-                    recp.sender.unbounded_send(msg.clone()).ok();
-                }
-
-                // save last 1000 entries
-                state.last_logs.push_back(msg.clone());
-                if state.last_logs.len() > 1000 {
-                    state.last_logs.pop_front();
-                }
-            }
-
+            handle_worker_message(&state_map, &hostname, authorized, msg);
             future::ok(())
         })
         .await
@@ -58,6 +88,128 @@ async fn handle_worker_socket(
         "{:?} disconnected as worker with hostname {}",
         who, hostname
     );
+
+    if let Some(control) = control {
+        control.close_channel();
+        let mut map = state_map.lock().unwrap();
+        if let Some(state) = map.get_mut(&hostname) {
+            // unless the worker has reconnected meanwhile
+            if state
+                .control
+                .as_ref()
+                .is_some_and(|tx| tx.same_receiver(&control))
+            {
+                state.control = None;
+                state.live_log_requests.clear();
+            }
+        }
+    }
+}
+
+/// Relay log lines to viewers, and replies to requests to their senders
+///
+/// Replies are only taken from connections with the worker secret, which are
+/// the ones the requests were sent to.
+fn handle_worker_message(state_map: &WSStateMap, hostname: &str, authorized: bool, msg: Message) {
+    let mut map = state_map.lock().unwrap();
+    let Some(state) = map.get_mut(hostname) else {
+        return;
+    };
+
+    if let Message::Binary(data) = &msg {
+        if !authorized {
+            warn!("Got reply from {hostname} without the worker secret, ignoring");
+            return;
+        }
+        match serde_json::from_slice::<WorkerControlReply>(data) {
+            Ok(WorkerControlReply::LiveLog {
+                request_id,
+                job_id,
+                log,
+            }) => match state.live_log_requests.get(&request_id) {
+                Some((expected, _)) if *expected == job_id => {
+                    let (_, tx) = state.live_log_requests.remove(&request_id).unwrap();
+                    tx.send(log).ok();
+                }
+                _ => warn!("Got unexpected live log of job {job_id} from {hostname}"),
+            },
+            Err(err) => warn!("Got invalid reply from {hostname}: {err}"),
+        }
+        return;
+    }
+
+    // We want to broadcast the message to viewers subscribing to the hostname
+    for recp in &state.viewers {
+        recp.sender.unbounded_send(msg.clone()).ok();
+    }
+
+    // save last 1000 entries
+    state.last_logs.push_back(msg);
+    if state.last_logs.len() > 1000 {
+        state.last_logs.pop_front();
+    }
+}
+
+/// Ask the worker for the latest log lines of the job it is running
+pub async fn request_live_log(
+    state_map: &WSStateMap,
+    hostname: &str,
+    job_id: i32,
+    timeout: Duration,
+) -> anyhow::Result<String> {
+    let request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::SeqCst);
+    let (tx, rx) = oneshot::channel();
+    {
+        let mut map = state_map.lock().unwrap();
+        let state = map
+            .get_mut(hostname)
+            .filter(|state| state.control.is_some())
+            .ok_or_else(|| anyhow!("Worker {hostname} is not connected"))?;
+        let req = serde_json::to_string(&WorkerControl::RequestLiveLog { request_id, job_id })?;
+        state
+            .control
+            .as_ref()
+            .unwrap()
+            .unbounded_send(Message::Text(req))
+            .map_err(|_| anyhow!("Worker {hostname} is not connected"))?;
+        state.live_log_requests.insert(request_id, (job_id, tx));
+    }
+
+    let res = tokio::time::timeout(timeout, rx).await;
+    if let Some(state) = state_map.lock().unwrap().get_mut(hostname) {
+        state.live_log_requests.remove(&request_id);
+    }
+    match res {
+        Ok(Ok(log)) => Ok(log),
+        Ok(Err(_)) => bail!("Worker {hostname} disconnected"),
+        Err(_) => bail!(
+            "Worker {hostname} did not answer in {} seconds",
+            timeout.as_secs()
+        ),
+    }
+}
+
+/// Ask every connected worker to send a heartbeat now, returns how many were asked
+pub fn ping_workers(state_map: &WSStateMap) -> usize {
+    let req = serde_json::to_string(&WorkerControl::PingNow).unwrap();
+    state_map
+        .lock()
+        .unwrap()
+        .values()
+        .filter_map(|state| state.control.as_ref())
+        .filter(|control| control.unbounded_send(Message::Text(req.clone())).is_ok())
+        .count()
+}
+
+/// Ask the worker to stop building a cancelled job, returns whether it was asked
+pub fn cancel_on_worker(state_map: &WSStateMap, hostname: &str, job_id: i32) -> bool {
+    let req = serde_json::to_string(&WorkerControl::CancelJob { job_id }).unwrap();
+    state_map
+        .lock()
+        .unwrap()
+        .get(hostname)
+        .and_then(|state| state.control.as_ref())
+        .is_some_and(|control| control.unbounded_send(Message::Text(req)).is_ok())
 }
 
 pub async fn ws_viewer_handler(
@@ -116,3 +268,138 @@ async fn handle_viewer_socket(
     let state = map.entry(hostname.clone()).or_default();
     state.viewers.retain(|v| !Arc::ptr_eq(v, &viewer));
 }
+
+#[tokio::test]
+async fn test_live_log() {
+    let state_map = WSStateMap::default();
+    assert!(request_live_log(&state_map, "Yerus", 34, LIVE_LOG_TIMEOUT)
+        .await
+        .is_err());
+
+    // simulated worker answering requests
+    let (tx, mut rx) = unbounded();
+    state_map
+        .lock()
+        .unwrap()
+        .entry("Yerus".to_string())
+        .or_default()
+        .control = Some(tx);
+    let worker_map = state_map.clone();
+    let worker = tokio::spawn(async move {
+        let Some(Message::Text(req)) = rx.next().await else {
+            panic!("expected a request");
+        };
+        let Ok(WorkerControl::RequestLiveLog { request_id, job_id }) = serde_json::from_str(&req)
+        else {
+            panic!("expected a live log request");
+        };
+        assert_eq!(job_id, 34);
+
+        // replies for other jobs are ignored
+        let reply = |job_id: i32, log: &str| {
+            Message::Binary(
+                serde_json::to_vec(&WorkerControlReply::LiveLog {
+                    request_id,
+                    job_id,
+                    log: log.to_string(),
+                })
+                .unwrap(),
+            )
+        };
+        // and so are replies from connections without the secret
+        handle_worker_message(&worker_map, "Yerus", false, reply(34, "spoofed"));
+        handle_worker_message(&worker_map, "Yerus", true, reply(35, "wrong job"));
+        rx
+    });
+    let log = request_live_log(&state_map, "Yerus", 34, Duration::from_millis(500)).await;
+    assert!(log.unwrap_err().to_string().contains("did not answer"));
+    let mut rx = worker.await.unwrap();
+
+    let worker_map = state_map.clone();
+    tokio::spawn(async move {
+        let Some(Message::Text(req)) = rx.next().await else {
+            panic!("expected a request");
+        };
+        let Ok(WorkerControl::RequestLiveLog { request_id, job_id }) = serde_json::from_str(&req)
+        else {
+            panic!("expected a live log request");
+        };
+        let reply = WorkerControlReply::LiveLog {
+            request_id,
+            job_id,
+            log: "Building fd\nBuilding ripgrep".to_string(),
+        };
+        handle_worker_message(
+            &worker_map,
+            "Yerus",
+            true,
+            Message::Binary(serde_json::to_vec(&reply).unwrap()),
+        );
+    });
+    assert_eq!(
+        request_live_log(&state_map, "Yerus", 34, LIVE_LOG_TIMEOUT)
+            .await
+            .unwrap(),
+        "Building fd\nBuilding ripgrep"
+    );
+
+    // replies are not shown to viewers, log lines are
+    handle_worker_message(
+        &state_map,
+        "Yerus",
+        false,
+        Message::Text("Build finished".to_string()),
+    );
+    let map = state_map.lock().unwrap();
+    let state = map.get("Yerus").unwrap();
+    assert_eq!(state.last_logs.len(), 1);
+    assert!(state.live_log_requests.is_empty());
+}
+
+#[test]
+fn test_ping_workers() {
+    let state_map = WSStateMap::default();
+    assert_eq!(ping_workers(&state_map), 0);
+
+    let (tx, mut rx) = unbounded();
+    let (gone, _) = unbounded();
+    gone.close_channel();
+    {
+        let mut map = state_map.lock().unwrap();
+        map.entry("Yerus".to_string()).or_default().control = Some(tx);
+        map.entry("riscv-builder".to_string()).or_default().control = Some(gone);
+        // viewers of a worker that is not connected
+        map.entry("Taiyuan".to_string()).or_default();
+    }
+    assert_eq!(ping_workers(&state_map), 1);
+    let Ok(Message::Text(req)) = rx.try_recv() else {
+        panic!("expected a ping");
+    };
+    assert_eq!(
+        serde_json::from_str::<WorkerControl>(&req).unwrap(),
+        WorkerControl::PingNow
+    );
+}
+
+#[test]
+fn test_cancel_on_worker() {
+    let state_map = WSStateMap::default();
+    assert!(!cancel_on_worker(&state_map, "Yerus", 34));
+
+    let (tx, mut rx) = unbounded();
+    state_map
+        .lock()
+        .unwrap()
+        .entry("Yerus".to_string())
+        .or_default()
+        .control = Some(tx);
+    assert!(cancel_on_worker(&state_map, "Yerus", 34));
+    assert!(!cancel_on_worker(&state_map, "Taiyuan", 34));
+    let Ok(Message::Text(req)) = rx.try_recv() else {
+        panic!("expected a cancel request");
+    };
+    assert_eq!(
+        serde_json::from_str::<WorkerControl>(&req).unwrap(),
+        WorkerControl::CancelJob { job_id: 34 }
+    );
+}
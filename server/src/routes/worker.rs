@@ -1,34 +1,53 @@
 use crate::routes::{AnyhowError, AppState};
 use crate::HEARTBEAT_TIMEOUT;
 use crate::{
-    api::{self},
-    formatter::{to_html_build_result, to_markdown_build_result, FAILED, SUCCESS},
-    github::get_crab_github_installation,
-    models::{Job, NewWorker, Pipeline, Worker},
-    ARGS,
+    api::{self, notify_mode_get, NotifyMode},
+    build_and_pr::pipeline_job_finished,
+    canary::canary_job_finished,
+    dashboard::refresh_summary,
+    dispatch::worker_job_archs,
+    formatter::{failure_kind_of, JobSummary, FAILED, SUCCESS},
+    github::{get_crab_github_bot, get_crab_github_installation},
+    metrics::ARCH_SUCCESS,
+    models::{Job, NewJob, NewWorker, Pipeline, Worker},
+    notifier::{notify_all, JobResultSummary, Notifier},
+    repo::owner_repo,
+    DbPool, DispatchMode, ARGS,
 };
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use axum::extract::{Json, Query, State};
+use buildit_utils::{github::GitRefKind, LOONGARCH64, NOARCH};
 use buildit_utils::{AMD64, ARM64, LOONGSON3, PPC64EL, RISCV64};
-use buildit_utils::{LOONGARCH64, NOARCH};
 
 use chrono::{DateTime, Utc};
 use common::{
-    JobOk, JobResult, WorkerHeartbeatRequest, WorkerJobUpdateRequest, WorkerPollRequest,
-    WorkerPollResponse,
+    JobOk, JobResult, WorkerHeartbeatRequest, WorkerJobProgressRequest, WorkerJobUpdateRequest,
+    WorkerPollRequest, WorkerPollResponse, WorkerRegisterRequest, JOB_RESULT_SCHEMA_VERSION,
+    MIN_JOB_RESULT_SCHEMA_VERSION,
 };
 
 use diesel::{BoolExpressionMethods, JoinOnDsl, NullableExpressionMethods};
-use diesel::{Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use diesel::{
+    Connection, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, QueryResult,
+    RunQueryDsl,
+};
 use octocrab::models::CheckRunId;
 use octocrab::params::checks::CheckRunConclusion;
 use octocrab::params::checks::CheckRunOutput;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
 
-use teloxide::types::ChatId;
-use teloxide::{prelude::*, types::ParseMode};
+use teloxide::prelude::*;
+use teloxide::types::{ChatId, MessageId};
 use tracing::{error, info, warn};
 
 #[derive(Deserialize)]
@@ -127,153 +146,339 @@ pub async fn worker_list(
     ))
 }
 
-pub async fn worker_heartbeat(
-    State(AppState { pool, .. }): State<AppState>,
-    Json(payload): Json<WorkerHeartbeatRequest>,
-) -> Result<(), AnyhowError> {
-    if payload.worker_secret != ARGS.worker_secret {
-        return Err(anyhow!("Invalid worker secret").into());
+fn labels_column(labels: &[String]) -> Option<String> {
+    Some(labels.join(",")).filter(|s| !s.is_empty())
+}
+
+/// Row of a worker as recorded by its registration
+pub fn worker_from_register(payload: &WorkerRegisterRequest, now: DateTime<Utc>) -> NewWorker {
+    NewWorker {
+        hostname: payload.hostname.clone(),
+        arch: payload.arch.clone(),
+        git_commit: payload.git_commit.clone(),
+        memory_bytes: payload.memory_bytes,
+        logical_cores: payload.logical_cores,
+        last_heartbeat_time: now,
+        disk_free_space_bytes: payload.disk_free_space_bytes,
+        performance: payload.performance,
+        // checked by the next heartbeat
+        internet_connectivity: false,
+        version: payload.version.clone(),
+        labels: labels_column(&payload.labels),
     }
+}
 
-    // insert or update worker
-    let mut conn = pool
-        .get()
-        .context("Failed to get db connection from pool")?;
+/// Apply a heartbeat to the known row of the worker
+///
+/// A heartbeat from an unknown worker registers it with whatever it carries.
+pub fn worker_from_heartbeat(
+    known: Option<NewWorker>,
+    payload: &WorkerHeartbeatRequest,
+    now: DateTime<Utc>,
+) -> NewWorker {
+    let mut worker = known.unwrap_or_else(|| NewWorker {
+        hostname: payload.hostname.clone(),
+        arch: payload.arch.clone(),
+        git_commit: String::new(),
+        memory_bytes: 0,
+        logical_cores: 0,
+        last_heartbeat_time: now,
+        disk_free_space_bytes: 0,
+        performance: None,
+        internet_connectivity: false,
+        version: None,
+        labels: None,
+    });
+    worker.last_heartbeat_time = now;
+    worker.disk_free_space_bytes = payload.disk_free_space_bytes;
+    worker.internet_connectivity = payload.internet_connectivity.unwrap_or(false);
+
+    // sent by workers without registration
+    if let Some(git_commit) = &payload.git_commit {
+        worker.git_commit = git_commit.clone();
+    }
+    if let Some(memory_bytes) = payload.memory_bytes {
+        worker.memory_bytes = memory_bytes;
+    }
+    if let Some(logical_cores) = payload.logical_cores {
+        worker.logical_cores = logical_cores;
+    }
+    if payload.performance.is_some() {
+        worker.performance = payload.performance;
+    }
+    if payload.version.is_some() {
+        worker.version = payload.version.clone();
+    }
+    if let Some(labels) = &payload.labels {
+        worker.labels = labels_column(labels);
+    }
+    worker
+}
 
-    conn.transaction::<(), diesel::result::Error, _>(|conn| {
-        use crate::schema::workers::dsl::*;
-        match workers
-            .filter(hostname.eq(&payload.hostname))
-            .filter(arch.eq(&payload.arch))
+/// Insert or update the row of a worker, `f` gets the current row if any
+///
+/// A worker is identified by hostname and arch (`unique_hostname_arch`), so a
+/// restarted worker takes over its row, while workers of other archs on the
+/// same host keep their own.
+fn upsert_worker(
+    conn: &mut diesel::PgConnection,
+    hostname: &str,
+    arch: &str,
+    f: impl FnOnce(Option<NewWorker>) -> NewWorker,
+) -> diesel::QueryResult<()> {
+    conn.transaction(|conn| {
+        match crate::schema::workers::dsl::workers
+            .filter(crate::schema::workers::dsl::hostname.eq(hostname))
+            .filter(crate::schema::workers::dsl::arch.eq(arch))
             .first::<Worker>(conn)
             .optional()?
         {
             Some(worker) => {
                 // existing worker, update it
-                diesel::update(workers.find(worker.id))
-                    .set((
-                        git_commit.eq(payload.git_commit),
-                        memory_bytes.eq(payload.memory_bytes),
-                        logical_cores.eq(payload.logical_cores),
-                        disk_free_space_bytes.eq(payload.disk_free_space_bytes),
-                        last_heartbeat_time.eq(chrono::Utc::now()),
-                        performance.eq(payload.performance),
-                        internet_connectivity.eq(payload.internet_connectivity.unwrap_or(false)),
-                    ))
+                let id = worker.id;
+                let new_worker = f(Some(NewWorker {
+                    hostname: worker.hostname,
+                    arch: worker.arch,
+                    git_commit: worker.git_commit,
+                    memory_bytes: worker.memory_bytes,
+                    logical_cores: worker.logical_cores,
+                    last_heartbeat_time: worker.last_heartbeat_time,
+                    disk_free_space_bytes: worker.disk_free_space_bytes,
+                    performance: worker.performance,
+                    internet_connectivity: worker.internet_connectivity,
+                    version: worker.version,
+                    labels: worker.labels,
+                }));
+                diesel::update(crate::schema::workers::dsl::workers.find(id))
+                    .set(&new_worker)
                     .execute(conn)?;
             }
             None => {
-                let new_worker = NewWorker {
-                    hostname: payload.hostname.clone(),
-                    arch: payload.arch.clone(),
-                    git_commit: payload.git_commit.clone(),
-                    memory_bytes: payload.memory_bytes,
-                    logical_cores: payload.logical_cores,
-                    disk_free_space_bytes: payload.disk_free_space_bytes,
-                    last_heartbeat_time: chrono::Utc::now(),
-                    performance: payload.performance,
-                    internet_connectivity: payload.internet_connectivity.unwrap_or(false),
-                };
                 diesel::insert_into(crate::schema::workers::table)
-                    .values(&new_worker)
+                    .values(&f(None))
                     .execute(conn)?;
             }
         }
         Ok(())
+    })
+}
+
+pub async fn worker_register(
+    State(AppState { pool, .. }): State<AppState>,
+    Json(payload): Json<WorkerRegisterRequest>,
+) -> Result<(), AnyhowError> {
+    if payload.worker_secret != ARGS.worker_secret.expose() {
+        return Err(anyhow!("Invalid worker secret").into());
+    }
+
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    upsert_worker(&mut conn, &payload.hostname, &payload.arch, |_| {
+        worker_from_register(&payload, Utc::now())
     })?;
     Ok(())
 }
 
-pub async fn worker_poll(
+pub async fn worker_heartbeat(
     State(AppState { pool, .. }): State<AppState>,
-    Json(payload): Json<WorkerPollRequest>,
-) -> Result<Json<Option<WorkerPollResponse>>, AnyhowError> {
-    if payload.worker_secret != ARGS.worker_secret {
+    Json(payload): Json<WorkerHeartbeatRequest>,
+) -> Result<(), AnyhowError> {
+    if payload.worker_secret != ARGS.worker_secret.expose() {
         return Err(anyhow!("Invalid worker secret").into());
     }
 
-    // find a job that can be assigned to the worker
+    // insert or update worker
     let mut conn = pool
         .get()
         .context("Failed to get db connection from pool")?;
 
-    match conn.transaction::<Option<(Pipeline, Job)>, diesel::result::Error, _>(|conn| {
-        use crate::schema::jobs::dsl::*;
+    upsert_worker(&mut conn, &payload.hostname, &payload.arch, |known| {
+        worker_from_heartbeat(known, &payload, Utc::now())
+    })?;
+    Ok(())
+}
+
+/// Who a job counts against for the fairness cap: the PR it builds,
+/// otherwise the Telegram chat that requested it
+pub fn submitter_of(pipeline: &Pipeline) -> Option<String> {
+    match (pipeline.github_pr, pipeline.telegram_user) {
+        (Some(pr), _) => Some(format!("{}#{}", pipeline.repo, pr)),
+        (None, Some(chat_id)) => Some(format!("telegram:{chat_id}")),
+        (None, None) => None,
+    }
+}
+
+/// First queued job whose submitter runs fewer than `cap` jobs
+///
+/// `candidates` are in queue order, `running` has the pipeline of each running job.
+pub fn pick_fair_job(
+    candidates: Vec<(Job, Pipeline)>,
+    running: &[Pipeline],
+    cap: usize,
+) -> Option<(Job, Pipeline)> {
+    let mut running_count: BTreeMap<String, usize> = BTreeMap::new();
+    for submitter in running.iter().filter_map(submitter_of) {
+        *running_count.entry(submitter).or_default() += 1;
+    }
 
-        // find worker id
-        let worker = crate::schema::workers::dsl::workers
-            .filter(crate::schema::workers::dsl::hostname.eq(&payload.hostname))
-            .filter(crate::schema::workers::dsl::arch.eq(&payload.arch))
-            .first::<Worker>(conn)?;
+    candidates.into_iter().find(|(_, pipeline)| {
+        submitter_of(pipeline)
+            .map(|submitter| running_count.get(&submitter).copied().unwrap_or(0) < cap)
+            .unwrap_or(true)
+    })
+}
 
-        // remove if any job is already allocated to the worker
-        diesel::update(jobs.filter(assigned_worker_id.eq(worker.id)))
-            .set((status.eq("created"), assigned_worker_id.eq(None::<i32>)))
-            .execute(conn)?;
+/// Queued jobs loaded at a time while looking for one under the fairness cap
+const FAIR_PICK_PAGE_SIZE: i64 = 32;
+
+/// Hand the next job the worker can take to it, requeueing the job it
+/// was running before. Workers older than `min_worker_version` get nothing,
+/// as their results would be rejected
+pub fn assign_job(
+    conn: &mut PgConnection,
+    payload: &WorkerPollRequest,
+    dispatch_mode: DispatchMode,
+    max_running_per_submitter: usize,
+    min_worker_version: Option<&str>,
+) -> QueryResult<Option<(Pipeline, Job)>> {
+    use crate::schema::jobs::dsl::*;
+
+    // find worker id
+    let worker = crate::schema::workers::dsl::workers
+        .filter(crate::schema::workers::dsl::hostname.eq(&payload.hostname))
+        .filter(crate::schema::workers::dsl::arch.eq(&payload.arch))
+        .first::<Worker>(conn)?;
+
+    // requeue the job the worker was running before, unless it was cancelled meanwhile
+    diesel::update(
+        jobs.filter(assigned_worker_id.eq(worker.id))
+            .filter(status.eq("running")),
+    )
+    .set((status.eq("created"), assigned_worker_id.eq(None::<i32>)))
+    .execute(conn)?;
+
+    if api::is_worker_outdated(worker.version.as_deref(), min_worker_version) {
+        warn!(
+            "Not assigning jobs to outdated worker {} ({}), version {} is older than the minimum {}",
+            worker.hostname,
+            worker.arch,
+            worker.version.as_deref().unwrap_or("unknown"),
+            min_worker_version.unwrap_or_default()
+        );
+        return Ok(None);
+    }
 
-        // prioritize jobs on stable branch
-        let mut sql = jobs
+    // prioritize jobs by requested priority, then jobs on stable branch
+    let job_archs = worker_job_archs(dispatch_mode, &payload.arch, &payload.arch_patterns);
+    let queued = || {
+        let sql = jobs
             .inner_join(crate::schema::pipelines::dsl::pipelines)
-            .order_by(
+            .order_by(priority.desc())
+            .then_order_by(
                 crate::schema::pipelines::dsl::git_branch
                     .eq("stable")
                     .desc(),
             )
+            .then_order_by(id.asc())
             .filter(status.eq("created"))
+            .filter(arch.eq_any(&job_archs))
             .into_boxed();
-        if payload.arch == "amd64" {
-            // route noarch to amd64
-            sql = sql.filter(arch.eq(&payload.arch).or(arch.eq("noarch")));
-        } else {
-            sql = sql.filter(arch.eq(&payload.arch));
-        }
 
         // handle filters
-        sql = sql
-            .filter(
-                require_min_core
-                    .is_null()
-                    .or(require_min_core.le(payload.logical_cores)),
-            )
-            .filter(
-                require_min_total_mem
-                    .is_null()
-                    .or(require_min_total_mem.le(payload.memory_bytes)),
-            )
-            .filter(
-                require_min_total_mem_per_core
-                    .is_null()
-                    .or(require_min_total_mem_per_core
-                        .le((payload.memory_bytes as f32) / (payload.logical_cores as f32))),
-            )
-            .filter(
-                require_min_disk
-                    .is_null()
-                    .or(require_min_disk.le(payload.disk_free_space_bytes)),
-            );
-
-        let res = sql.first::<(Job, Pipeline)>(conn).optional()?;
-        match res {
-            Some((job, pipeline)) => {
-                // allocate to the worker
-                diesel::update(&job)
-                    .set((
-                        status.eq("running"),
-                        assigned_worker_id.eq(worker.id),
-                        assign_time.eq(chrono::Utc::now()),
-                    ))
-                    .execute(conn)?;
+        sql.filter(
+            require_min_core
+                .is_null()
+                .or(require_min_core.le(payload.logical_cores)),
+        )
+        .filter(
+            require_min_total_mem
+                .is_null()
+                .or(require_min_total_mem.le(payload.memory_bytes)),
+        )
+        .filter(
+            require_min_total_mem_per_core
+                .is_null()
+                .or(require_min_total_mem_per_core
+                    .le((payload.memory_bytes as f32) / (payload.logical_cores as f32))),
+        )
+        .filter(
+            require_min_disk
+                .is_null()
+                .or(require_min_disk.le(payload.disk_free_space_bytes)),
+        )
+        .filter(
+            require_label
+                .is_null()
+                .or(require_label.eq_any(&payload.labels)),
+        )
+    };
+
+    // skip jobs of submitters already running their share
+    let running = jobs
+        .inner_join(crate::schema::pipelines::dsl::pipelines)
+        .filter(status.eq("running"))
+        .select(crate::schema::pipelines::all_columns)
+        .load::<Pipeline>(conn)?;
+    let mut res = None;
+    for page in 0.. {
+        let candidates = queued()
+            .offset(page * FAIR_PICK_PAGE_SIZE)
+            .limit(FAIR_PICK_PAGE_SIZE)
+            .load::<(Job, Pipeline)>(conn)?;
+        let last_page = (candidates.len() as i64) < FAIR_PICK_PAGE_SIZE;
+        res = pick_fair_job(candidates, &running, max_running_per_submitter);
+        if res.is_some() || last_page {
+            break;
+        }
+    }
+    match res {
+        Some((job, pipeline)) => {
+            // allocate to the worker
+            diesel::update(&job)
+                .set((
+                    status.eq("running"),
+                    assigned_worker_id.eq(worker.id),
+                    assign_time.eq(chrono::Utc::now()),
+                ))
+                .execute(conn)?;
 
-                Ok(Some((pipeline, job)))
-            }
-            None => Ok(None),
+            Ok(Some((pipeline, job)))
         }
+        None => Ok(None),
+    }
+}
+
+pub async fn worker_poll(
+    State(AppState { pool, bot, .. }): State<AppState>,
+    Json(payload): Json<WorkerPollRequest>,
+) -> Result<Json<Option<WorkerPollResponse>>, AnyhowError> {
+    if payload.worker_secret != ARGS.worker_secret.expose() {
+        return Err(anyhow!("Invalid worker secret").into());
+    }
+
+    // find a job that can be assigned to the worker
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    match conn.transaction(|conn| {
+        assign_job(
+            conn,
+            &payload,
+            ARGS.dispatch_mode,
+            ARGS.max_running_per_submitter,
+            ARGS.min_worker_version.as_deref(),
+        )
     })? {
         Some((pipeline, job)) => {
             // update github check run status to in-progress
             if let Some(github_check_run_id) = job.github_check_run_id {
+                let repo = pipeline.repo.clone();
                 tokio::spawn(async move {
                     if let Ok(Some(crab)) = get_crab_github_installation().await {
+                        let (owner, repo) = owner_repo(&repo);
                         let output = CheckRunOutput {
                             title: format!("Running on {}", payload.hostname),
                             summary: String::new(),
@@ -282,7 +487,7 @@ pub async fn worker_poll(
                             images: vec![],
                         };
                         if let Err(err) = crab
-                            .checks("AOSC-Dev", "aosc-os-abbs")
+                            .checks(owner, repo)
                             .update_check_run(CheckRunId(github_check_run_id as u64))
                             .status(octocrab::params::checks::CheckRunStatus::InProgress)
                             .output(output)
@@ -296,26 +501,228 @@ pub async fn worker_poll(
                 });
             }
 
+            tokio::spawn(refresh_summary(pool, bot, pipeline.id));
+
             // job allocated
             Ok(Json(Some(WorkerPollResponse {
                 job_id: job.id,
                 git_branch: pipeline.git_branch,
                 git_sha: pipeline.git_sha,
                 packages: job.packages,
+                source_mirror: ARGS.source_mirror.clone(),
+                git_url: Some(format!("https://github.com/{}.git", pipeline.repo)),
+                arch: job.arch,
+                skip_pushpkg: pipeline.git_ref_kind != GitRefKind::Branch.as_str(),
             })))
         }
         None => Ok(Json(None)),
     }
 }
 
-pub async fn worker_job_update(
+/// Minimum interval between edits of a progress message, to respect telegram rate limits
+const PROGRESS_EDIT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Live progress message of a running job
+#[derive(Default)]
+pub struct LiveProgress {
+    /// When the job was assigned, requeued jobs start over with a new message
+    pub assign_time: Option<DateTime<Utc>>,
+    pub message_id: Option<MessageId>,
+    pub current_package: String,
+    pub index: u32,
+    pub total: u32,
+    last_edit: Option<Instant>,
+}
+
+impl LiveProgress {
+    /// Apply a progress event, return whether the message should be updated now
+    pub fn update(&mut self, current_package: &str, index: u32, total: u32, now: Instant) -> bool {
+        // ignore out-of-order events
+        if index < self.index {
+            return false;
+        }
+        self.current_package = current_package.to_string();
+        self.index = index;
+        self.total = total;
+
+        let due = index == total
+            || self
+                .last_edit
+                .map(|last_edit| now.duration_since(last_edit) >= PROGRESS_EDIT_INTERVAL)
+                .unwrap_or(true);
+        if due {
+            self.last_edit = Some(now);
+        }
+        due
+    }
+
+    fn to_text(&self, job: &Job, hostname: &str) -> String {
+        format!(
+            "Job #{} on {} ({}): building {}/{}: {}",
+            job.id, hostname, job.arch, self.index, self.total, self.current_package
+        )
+    }
+}
+
+/// Progress messages of running jobs, by job id
+static JOB_PROGRESS: Lazy<tokio::sync::Mutex<BTreeMap<i32, LiveProgress>>> =
+    Lazy::new(|| tokio::sync::Mutex::new(BTreeMap::new()));
+
+/// Forget the progress of jobs no longer running: finished, cancelled,
+/// expired, lost or requeued
+fn prune_job_progress(
+    conn: &mut PgConnection,
+    progress: &mut BTreeMap<i32, LiveProgress>,
+) -> QueryResult<()> {
+    if progress.is_empty() {
+        return Ok(());
+    }
+    let running = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::id.eq_any(progress.keys().copied().collect::<Vec<_>>()))
+        .filter(crate::schema::jobs::dsl::status.eq("running"))
+        .select(crate::schema::jobs::dsl::id)
+        .load::<i32>(conn)?;
+    progress.retain(|job_id, _| running.contains(job_id));
+    Ok(())
+}
+
+/// Forget the progress messages of jobs that stopped running
+pub async fn forget_stopped_job_progress(conn: &mut PgConnection) {
+    if let Err(err) = prune_job_progress(conn, &mut *JOB_PROGRESS.lock().await) {
+        warn!("Failed to forget progress of stopped jobs: {err}");
+    }
+}
+
+pub async fn worker_job_progress(
     State(AppState { pool, bot, .. }): State<AppState>,
+    Json(payload): Json<WorkerJobProgressRequest>,
+) -> Result<(), AnyhowError> {
+    if payload.worker_secret != ARGS.worker_secret.expose() {
+        return Err(anyhow!("Invalid worker secret").into());
+    }
+
+    let mut conn = pool
+        .get()
+        .context("Failed to get db connection from pool")?;
+
+    let job = crate::schema::jobs::dsl::jobs
+        .find(payload.job_id)
+        .first::<Job>(&mut conn)?;
+
+    let worker = crate::schema::workers::dsl::workers
+        .filter(crate::schema::workers::dsl::hostname.eq(&payload.hostname))
+        .filter(crate::schema::workers::dsl::arch.eq(&payload.arch))
+        .first::<Worker>(&mut conn)?;
+
+    if job.status != "running" || job.assigned_worker_id != Some(worker.id) {
+        return Err(anyhow!("Worker not assigned to the job").into());
+    }
+
+    let pipeline = crate::schema::pipelines::dsl::pipelines
+        .find(job.pipeline_id)
+        .first::<Pipeline>(&mut conn)?;
+
+    // only chats receiving all results get progress
+    let (Some(bot), Some(chat_id)) = (bot, pipeline.telegram_user) else {
+        return Ok(());
+    };
+    if pipeline.source != "telegram"
+        || !matches!(notify_mode_get(&mut conn, chat_id), Ok(NotifyMode::All))
+    {
+        return Ok(());
+    }
+
+    let (message_id, text) = {
+        let mut progress = JOB_PROGRESS.lock().await;
+        let progress = progress.entry(job.id).or_default();
+        if progress.assign_time != job.assign_time {
+            *progress = LiveProgress {
+                assign_time: job.assign_time,
+                ..Default::default()
+            };
+        }
+        if !progress.update(
+            &payload.current_package,
+            payload.index,
+            payload.total,
+            Instant::now(),
+        ) {
+            return Ok(());
+        }
+        (
+            progress.message_id,
+            progress.to_text(&job, &worker.hostname),
+        )
+    };
+
+    let message_id = match message_id {
+        Some(message_id) => {
+            match bot
+                .edit_message_text(ChatId(chat_id), message_id, text)
+                .await
+            {
+                Ok(_) => Some(message_id),
+                Err(err) => {
+                    // send a new message next time
+                    warn!("Failed to edit progress message of job {}: {}", job.id, err);
+                    None
+                }
+            }
+        }
+        None => match bot.send_message(ChatId(chat_id), text).await {
+            Ok(msg) => Some(msg.id),
+            Err(err) => {
+                warn!("Failed to send progress message of job {}: {}", job.id, err);
+                None
+            }
+        },
+    };
+    if let Some(progress) = JOB_PROGRESS.lock().await.get_mut(&job.id) {
+        progress.message_id = message_id;
+    }
+
+    Ok(())
+}
+
+/// Reject job results too old to be understood, warn about newer ones
+/// since their additional fields are simply ignored
+fn check_schema_version(req: &WorkerJobUpdateRequest) -> anyhow::Result<()> {
+    if req.schema_version < MIN_JOB_RESULT_SCHEMA_VERSION {
+        warn!(
+            "Rejecting result of job {} from {}: schema version {} is older than the minimum supported {}",
+            req.job_id, req.hostname, req.schema_version, MIN_JOB_RESULT_SCHEMA_VERSION
+        );
+        bail!(
+            "Unsupported job result schema version {}, please upgrade the worker",
+            req.schema_version
+        );
+    }
+
+    if req.schema_version > JOB_RESULT_SCHEMA_VERSION {
+        warn!(
+            "Result of job {} from {} has schema version {}, newer than supported {}, unknown fields are ignored",
+            req.job_id, req.hostname, req.schema_version, JOB_RESULT_SCHEMA_VERSION
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn worker_job_update(
+    State(AppState {
+        pool,
+        bot,
+        notifiers,
+        ..
+    }): State<AppState>,
     Json(payload): Json<WorkerJobUpdateRequest>,
 ) -> Result<(), AnyhowError> {
-    if payload.worker_secret != ARGS.worker_secret {
+    if payload.worker_secret != ARGS.worker_secret.expose() {
         return Err(anyhow!("Invalid worker secret").into());
     }
 
+    check_schema_version(&payload)?;
+
     let mut conn = pool
         .get()
         .context("Failed to get db connection from pool")?;
@@ -333,32 +740,141 @@ pub async fn worker_job_update(
         return Err(anyhow!("Worker not assigned to the job").into());
     }
 
+    if api::is_worker_outdated(
+        worker.version.as_deref(),
+        ARGS.min_worker_version.as_deref(),
+    ) {
+        warn!(
+            "Rejecting result of job {} from outdated worker {} ({}), version {} is older than the minimum {}",
+            job.id,
+            worker.hostname,
+            worker.arch,
+            worker.version.as_deref().unwrap_or("unknown"),
+            ARGS.min_worker_version.as_deref().unwrap_or_default()
+        );
+        return Err(anyhow!("Worker is outdated, please upgrade").into());
+    }
+
     let pipeline = crate::schema::pipelines::dsl::pipelines
         .find(job.pipeline_id)
         .first::<Pipeline>(&mut conn)?;
 
-    let mut retry = None;
-    loop {
-        if retry.map(|x| x < 5).unwrap_or(true) {
-            match handle_success_message(&job, &pipeline, &payload, &bot, retry).await {
-                HandleSuccessResult::Ok | HandleSuccessResult::DoNotRetry => {
-                    break;
+    JOB_PROGRESS.lock().await.remove(&job.id);
+
+    let flaky_retry = match &payload.result {
+        JobResult::Ok(job_ok) if ARGS.flaky_retry == Some(true) => {
+            let patterns = match &ARGS.flaky_patterns {
+                Some(patterns) => patterns.split('|').collect::<Vec<_>>(),
+                None => DEFAULT_FLAKY_PATTERNS.to_vec(),
+            };
+            should_retry_flaky(job_ok, job.retry_count, &patterns)
+        }
+        _ => false,
+    };
+
+    // record the result before reporting it, unless the job was cancelled
+    // while the result was on its way
+    let recorded = record_job_result(&mut conn, &job, worker.id, &payload.result, flaky_retry)?;
+    if !recorded {
+        info!(
+            "Job {} was cancelled before its result from {} arrived, not reporting it",
+            job.id, worker.hostname
+        );
+        return Ok(());
+    }
+
+    // telegram notification preference, github is always updated
+    let success = matches!(&payload.result, JobResult::Ok(job_ok) if job_ok.build_success && job_ok.pushpkg_success);
+
+    // retried jobs count once, by the result of the retry
+    if matches!(&payload.result, JobResult::Ok(_)) && !flaky_retry {
+        let ratio = ARCH_SUCCESS.record(&job.arch, Utc::now(), success);
+        info!(
+            "Success ratio of {} in the last hour is now {:.2}",
+            job.arch, ratio
+        );
+    }
+    let notify_telegram = match pipeline.telegram_user {
+        Some(chat_id) if pipeline.source == "telegram" => {
+            match notify_mode_get(&mut conn, chat_id) {
+                // the live summary shows successful jobs already
+                Ok(_)
+                    if success
+                        && ARGS.live_summary == Some(true)
+                        && pipeline.telegram_message_id.is_some() =>
+                {
+                    false
                 }
-                HandleSuccessResult::Retry(x) => {
-                    info!("Retrying handlE_success_message");
-                    retry = Some(x);
-                    continue;
+                Ok(mode) => mode.should_notify(success),
+                Err(err) => {
+                    warn!("Failed to get notify mode of chat {}: {}", chat_id, err);
+                    true
                 }
             }
-        } else {
-            break;
+        }
+        _ => false,
+    };
+    let destination = pipeline
+        .telegram_user
+        .filter(|_| notify_telegram)
+        .map(|chat_id| chat_id.to_string());
+
+    if flaky_retry {
+        // report the result of the retried job instead
+        info!("Job {} failed with transient error, retrying", job.id);
+    } else {
+        let mut retry = None;
+        loop {
+            if retry.map(|x| x < 5).unwrap_or(true) {
+                match handle_success_message(
+                    &job,
+                    &pipeline,
+                    &payload,
+                    &notifiers,
+                    destination.as_deref(),
+                    retry,
+                )
+                .await
+                {
+                    HandleSuccessResult::Ok | HandleSuccessResult::DoNotRetry => {
+                        break;
+                    }
+                    HandleSuccessResult::Retry(x) => {
+                        info!("Retrying handlE_success_message");
+                        retry = Some(x);
+                        continue;
+                    }
+                }
+            } else {
+                break;
+            }
         }
     }
 
+    if !flaky_retry {
+        tokio::spawn(job_finished(pool, bot, pipeline.id));
+    }
+    Ok(())
+}
+
+/// Record the result of a job still running on the worker, with a retry of
+/// it if `flaky_retry`, false if the job was cancelled meanwhile
+fn record_job_result(
+    conn: &mut PgConnection,
+    job: &Job,
+    worker_id: i32,
+    result: &JobResult,
+    flaky_retry: bool,
+) -> anyhow::Result<bool> {
     use crate::schema::jobs::dsl::*;
-    match payload.result {
+    let still_running = id
+        .eq(job.id)
+        .and(status.eq("running"))
+        .and(assigned_worker_id.eq(worker_id));
+    conn.transaction::<bool, anyhow::Error, _>(|conn| match result {
         JobResult::Ok(res) => {
-            diesel::update(jobs.filter(id.eq(payload.job_id)))
+            let kind = failure_kind_of(res);
+            let updated = diesel::update(jobs.filter(still_running))
                 .set((
                     status.eq(if res.build_success && res.pushpkg_success {
                         "success"
@@ -368,31 +884,170 @@ pub async fn worker_job_update(
                     build_success.eq(res.build_success),
                     pushpkg_success.eq(res.pushpkg_success),
                     successful_packages.eq(res.successful_packages.join(",")),
-                    failed_package.eq(res.failed_package),
+                    failed_package.eq(res.failed_package.clone()),
                     skipped_packages.eq(res.skipped_packages.join(",")),
-                    log_url.eq(res.log_url),
+                    log_url.eq(res.log_url.clone()),
                     finish_time.eq(chrono::Utc::now()),
                     elapsed_secs.eq(res.elapsed_secs),
                     assigned_worker_id.eq(None::<i32>),
-                    built_by_worker_id.eq(Some(worker.id)),
+                    built_by_worker_id.eq(Some(worker_id)),
+                    environment.eq(res
+                        .environment
+                        .as_ref()
+                        .map(serde_json::to_string)
+                        .transpose()?),
+                    failure_kind.eq(kind.map(|kind| kind.as_str())),
+                    peak_memory_bytes.eq(res.peak_memory_bytes),
+                    disk_bytes.eq(res.disk_bytes),
+                    package_timings.eq(res
+                        .package_timings
+                        .as_ref()
+                        .map(serde_json::to_string)
+                        .transpose()?),
                 ))
-                .execute(&mut conn)?;
+                .execute(conn)?;
+
+            if updated > 0 && flaky_retry {
+                // keep the check run, it is updated by the retried job
+                let new_job = NewJob {
+                    pipeline_id: job.pipeline_id,
+                    packages: job.packages.clone(),
+                    arch: job.arch.clone(),
+                    creation_time: chrono::Utc::now(),
+                    status: "created".to_string(),
+                    github_check_run_id: job.github_check_run_id,
+                    require_min_core: job.require_min_core,
+                    require_min_total_mem: job.require_min_total_mem,
+                    require_min_total_mem_per_core: job.require_min_total_mem_per_core,
+                    require_min_disk: job.require_min_disk,
+                    retry_count: job.retry_count + 1,
+                    priority: job.priority,
+                    require_label: job.require_label.clone(),
+                };
+                diesel::insert_into(crate::schema::jobs::table)
+                    .values(&new_job)
+                    .execute(conn)?;
+            }
+            Ok(updated > 0)
         }
         JobResult::Error(err) => {
-            diesel::update(jobs.filter(id.eq(payload.job_id)))
+            let updated = diesel::update(jobs.filter(still_running))
                 .set((
                     status.eq("error"),
                     error_message.eq(err),
-                    built_by_worker_id.eq(Some(worker.id)),
+                    built_by_worker_id.eq(Some(worker_id)),
                 ))
-                .execute(&mut conn)?;
+                .execute(conn)?;
+            Ok(updated > 0)
         }
+    })
+}
+
+/// Settle the pipeline after one of its jobs finished, by a worker result or
+/// otherwise, e.g. cancelled or expired
+pub async fn job_finished(pool: DbPool, bot: Option<Bot>, pipeline_id: i32) {
+    match pool.get() {
+        Ok(mut conn) => forget_stopped_job_progress(&mut conn).await,
+        Err(err) => warn!("Failed to get db connection from pool: {err}"),
     }
-    Ok(())
+    // held jobs are settled before checking whether the pipeline has finished
+    canary_job_finished(pool.clone(), bot.clone(), pipeline_id).await;
+    refresh_summary(pool.clone(), bot.clone(), pipeline_id).await;
+    pipeline_job_finished(pool, bot, pipeline_id).await;
+}
+
+// log lines that indicate a network blip rather than a real build failure
+const DEFAULT_FLAKY_PATTERNS: &[&str] = &[
+    "Could not resolve host",
+    "Temporary failure in name resolution",
+    "Connection timed out",
+    "Connection reset by peer",
+    "Failed to connect to",
+];
+
+/// Whether a failed job should be retried once before reporting the failure
+pub fn should_retry_flaky(job_ok: &JobOk, retry_count: i32, patterns: &[&str]) -> bool {
+    if (job_ok.build_success && job_ok.pushpkg_success) || retry_count > 0 {
+        return false;
+    }
+
+    match &job_ok.log_tail {
+        Some(log_tail) => {
+            let log_tail = log_tail.to_string_lossy();
+            patterns
+                .iter()
+                .any(|pattern| !pattern.is_empty() && log_tail.contains(pattern))
+        }
+        None => false,
+    }
+}
+
+/// Mutexes created on demand for each key, so that updates to the same
+/// resource are serialized while unrelated ones run concurrently
+pub struct KeyedLocks<K> {
+    locks: std::sync::Mutex<BTreeMap<K, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl<K: Ord> KeyedLocks<K> {
+    pub const fn new() -> Self {
+        Self {
+            locks: std::sync::Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub async fn lock(&self, key: K) -> tokio::sync::OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap();
+            // drop mutexes nobody holds or waits for
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(key).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+}
+
+impl<K: Ord> Default for KeyedLocks<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// results of jobs of the same pr edit the same comments and pr body
+static GITHUB_PR_LOCKS: KeyedLocks<i64> = KeyedLocks::new();
+
+// throttle GitHub API calls to avoid hitting the secondary rate limits
+// when many jobs finish at once
+static GITHUB_API_SEMAPHORE: Lazy<Semaphore> =
+    Lazy::new(|| Semaphore::new(ARGS.github_api_concurrency.max(1)));
+
+/// Run a GitHub API call while holding a permit of `semaphore`
+async fn github_api_call<F: Future>(semaphore: &Semaphore, f: F) -> F::Output {
+    let _permit = semaphore.acquire().await;
+    f.await
 }
 
-static GITHUB_PR_CHECKLIST_LOCK: Lazy<tokio::sync::Mutex<()>> =
-    Lazy::new(|| tokio::sync::Mutex::new(()));
+/// Output of the completed check run of a job
+pub fn check_run_output(job_ok: &JobOk, summary: String) -> CheckRunOutput {
+    CheckRunOutput {
+        title: format!(
+            "Built {} packages in {}s",
+            job_ok.successful_packages.len(),
+            job_ok.elapsed_secs,
+        ),
+        summary,
+        text: None,
+        annotations: vec![],
+        images: vec![],
+    }
+}
+
+/// Link of the completed check run, the build log if it was pushed
+pub fn check_run_details_url(job_id: i32, job_ok: &JobOk) -> String {
+    job_ok
+        .log_url
+        .clone()
+        .unwrap_or_else(|| format!("https://buildit.aosc.io/jobs/{}", job_id))
+}
 
 pub enum HandleSuccessResult {
     Ok,
@@ -400,14 +1055,18 @@ pub enum HandleSuccessResult {
     DoNotRetry,
 }
 
-#[tracing::instrument(skip(bot))]
+/// Report a job result to notifiers and GitHub, `destination` is where
+/// notifiers should deliver it if anywhere
+#[tracing::instrument(skip(notifiers))]
 pub async fn handle_success_message(
     job: &Job,
     pipeline: &Pipeline,
     req: &WorkerJobUpdateRequest,
-    bot: &Option<Bot>,
+    notifiers: &[Box<dyn Notifier>],
+    destination: Option<&str>,
     retry: Option<u8>,
 ) -> HandleSuccessResult {
+    let (owner, repo) = owner_repo(&pipeline.repo);
     match &req.result {
         JobResult::Ok(job_ok) => {
             info!("Processing job result {:?} ...", job_ok);
@@ -419,55 +1078,41 @@ pub async fn handle_success_message(
             } = &job_ok;
 
             let success = *build_success && *pushpkg_success;
+            let summary = JobSummary {
+                pipeline,
+                job,
+                job_ok,
+                worker_hostname: &req.hostname,
+                worker_arch: &req.arch,
+                success,
+                template: ARGS.job_summary_template.as_ref(),
+            };
 
-            if pipeline.source == "telegram" {
-                if let Some(bot) = bot {
-                    info!("Sending result to telegram");
-                    let s = to_html_build_result(
-                        pipeline,
-                        job,
-                        job_ok,
-                        &req.hostname,
-                        &req.arch,
-                        success,
-                    );
-
-                    if let Err(e) = bot
-                        .send_message(ChatId(pipeline.telegram_user.unwrap()), &s)
-                        .parse_mode(ParseMode::Html)
-                        .disable_web_page_preview(true)
-                        .await
-                    {
-                        error!("Failed to send build result to telegram: {}", e);
-                        return update_retry(retry);
-                    }
-                } else {
-                    error!("Telegram bot not configured");
-                    return HandleSuccessResult::DoNotRetry;
-                }
+            // notifiers are not retried, only deliver on the first attempt
+            if retry.is_none() {
+                notify_all(notifiers, destination, &JobResultSummary::from_ok(&summary)).await;
             }
 
             // if associated with github pr, update comments
-            let new_content =
-                to_markdown_build_result(pipeline, job, job_ok, &req.hostname, &req.arch, success);
+            let new_content = summary.to_markdown_v2();
             if let Some(pr_num) = pipeline.github_pr {
+                // the operations are not atomic, so we use lock to avoid racing
+                let _lock = GITHUB_PR_LOCKS.lock(pr_num).await;
+
                 info!("Updating GitHub PR comments");
-                let crab = match octocrab::Octocrab::builder()
-                    .user_access_token(ARGS.github_access_token.clone())
-                    .build()
-                {
+                let crab = match get_crab_github_bot().await {
                     Ok(crab) => crab,
                     Err(e) => {
                         error!("Failed to build octocrab instance: {e}");
-                        return HandleSuccessResult::DoNotRetry;
+                        return update_retry(retry);
                     }
                 };
 
-                let comments = crab
-                    .issues("AOSC-Dev", "aosc-os-abbs")
-                    .list_comments(pr_num as u64)
-                    .send()
-                    .await;
+                let comments = github_api_call(
+                    &GITHUB_API_SEMAPHORE,
+                    crab.issues(owner, repo).list_comments(pr_num as u64).send(),
+                )
+                .await;
 
                 let comments = match comments {
                     Ok(c) => c,
@@ -478,7 +1123,8 @@ pub async fn handle_success_message(
                 };
 
                 for c in comments {
-                    if c.user.login == "aosc-buildit-bot" {
+                    // comments are posted by the github app if configured
+                    if c.user.login == "aosc-buildit-bot" || c.user.r#type == "Bot" {
                         let body = c.body.unwrap_or_else(String::new);
                         if !body
                             .split_ascii_whitespace()
@@ -492,10 +1138,11 @@ pub async fn handle_success_message(
                         for line in body.split('\n') {
                             let arch = line.strip_prefix("Architecture:").map(|x| x.trim());
                             if arch.map(|x| x == job.arch).unwrap_or(false) {
-                                if let Err(e) = crab
-                                    .issues("AOSC-Dev", "aosc-os-abbs")
-                                    .delete_comment(c.id)
-                                    .await
+                                if let Err(e) = github_api_call(
+                                    &GITHUB_API_SEMAPHORE,
+                                    crab.issues(owner, repo).delete_comment(c.id),
+                                )
+                                .await
                                 {
                                     error!("Failed to delete comment from pr: {e}");
                                     return update_retry(retry);
@@ -505,26 +1152,30 @@ pub async fn handle_success_message(
                     }
                 }
 
-                // Disable comment posting, since we have check run reporting
-                /*
-                if let Err(e) = crab
-                    .issues("AOSC-Dev", "aosc-os-abbs")
-                    .create_comment(pr_num, new_content.clone())
-                    .await
+                // comment when configured, or when there is no check run to report to
+                if ARGS
+                    .github_report
+                    .comment(job.github_check_run_id.is_some())
                 {
-                    error!("{e}");
-                    return update_retry(retry);
+                    if let Err(e) = github_api_call(
+                        &GITHUB_API_SEMAPHORE,
+                        crab.issues(owner, repo)
+                            .create_comment(pr_num as u64, new_content.clone()),
+                    )
+                    .await
+                    {
+                        error!("Failed to create comment on pr: {e}");
+                        return update_retry(retry);
+                    }
                 }
-                */
 
                 // update checklist
-                // the operation is not atomic, so we use lock to avoid racing
                 info!("Updating GitHub PR checklist");
-                let _lock = GITHUB_PR_CHECKLIST_LOCK.lock().await;
-                let pr = match crab
-                    .pulls("AOSC-Dev", "aosc-os-abbs")
-                    .get(pr_num as u64)
-                    .await
+                let pr = match github_api_call(
+                    &GITHUB_API_SEMAPHORE,
+                    crab.pulls(owner, repo).get(pr_num as u64),
+                )
+                .await
                 {
                     Ok(pr) => pr,
                     Err(e) => {
@@ -559,12 +1210,14 @@ pub async fn handle_success_message(
                     body.replace(&format!("- [x] {pr_arch}"), &format!("- [ ] {pr_arch}"))
                 };
 
-                if let Err(e) = crab
-                    .pulls("AOSC-Dev", "aosc-os-abbs")
-                    .update(pr_num as u64)
-                    .body(body)
-                    .send()
-                    .await
+                if let Err(e) = github_api_call(
+                    &GITHUB_API_SEMAPHORE,
+                    crab.pulls(owner, repo)
+                        .update(pr_num as u64)
+                        .body(body)
+                        .send(),
+                )
+                .await
                 {
                     error!("Failed to update pr body: {e}");
                     return update_retry(retry);
@@ -577,30 +1230,20 @@ pub async fn handle_success_message(
                 // authenticate with github app
                 match get_crab_github_installation().await {
                     Ok(Some(crab)) => {
-                        let handler = crab.checks("AOSC-Dev", "aosc-os-abbs");
-                        let output = CheckRunOutput {
-                            title: format!(
-                                "Built {} packages in {}s",
-                                job_ok.successful_packages.len(),
-                                job_ok.elapsed_secs,
-                            ),
-                            summary: new_content,
-                            text: None,
-                            annotations: vec![],
-                            images: vec![],
-                        };
+                        let handler = crab.checks(owner, repo);
                         let builder = handler
                             .update_check_run(CheckRunId(github_check_run_id as u64))
                             .status(octocrab::params::checks::CheckRunStatus::Completed)
-                            .output(output)
+                            .output(check_run_output(job_ok, new_content))
                             .conclusion(if success {
                                 CheckRunConclusion::Success
                             } else {
                                 CheckRunConclusion::Failure
                             })
-                            .details_url(format!("https://buildit.aosc.io/jobs/{}", job.id));
+                            .details_url(check_run_details_url(job.id, job_ok));
 
-                        if let Err(e) = builder.send().await {
+                        if let Err(e) = github_api_call(&GITHUB_API_SEMAPHORE, builder.send()).await
+                        {
                             error!("Failed to update github check run: {e}");
                             return update_retry(retry);
                         }
@@ -616,47 +1259,35 @@ pub async fn handle_success_message(
             }
         }
         JobResult::Error(error) => {
-            if pipeline.source == "telegram" {
-                if let Some(bot) = bot {
-                    if let Err(e) = bot
-                        .send_message(
-                            ChatId(pipeline.telegram_user.unwrap()),
-                            format!(
-                                "{}({}) build packages: {:?} Got Error: {}",
-                                req.hostname, job.arch, pipeline.packages, error
-                            ),
-                        )
-                        .await
-                    {
-                        error!("Failed to send message to telegram: {e}");
-                        return update_retry(retry);
-                    }
-                } else {
-                    error!("Telegram bot not configured");
-                    return HandleSuccessResult::DoNotRetry;
-                }
-            } else if pipeline.source == "github" {
-                let crab = match octocrab::Octocrab::builder()
-                    .user_access_token(ARGS.github_access_token.clone())
-                    .build()
-                {
+            if retry.is_none() {
+                notify_all(
+                    notifiers,
+                    destination,
+                    &JobResultSummary::from_error(job, pipeline, &req.hostname, error),
+                )
+                .await;
+            }
+
+            if pipeline.source == "github" {
+                let crab = match get_crab_github_bot().await {
                     Ok(crab) => crab,
                     Err(e) => {
                         error!("Failed to create octocrab instance: {e}");
-                        return HandleSuccessResult::DoNotRetry;
+                        return update_retry(retry);
                     }
                 };
 
-                if let Err(e) = crab
-                    .issues("AOSC-Dev", "aosc-os-abbs")
-                    .create_comment(
+                if let Err(e) = github_api_call(
+                    &GITHUB_API_SEMAPHORE,
+                    crab.issues(owner, repo).create_comment(
                         pipeline.github_pr.unwrap() as u64,
                         format!(
                             "{}({}) build packages: {:?} Got Error: {}",
                             req.hostname, job.arch, pipeline.packages, error
                         ),
-                    )
-                    .await
+                    ),
+                )
+                .await
                 {
                     error!("Failed to create comment on github: {e}");
                     return update_retry(retry);
@@ -745,3 +1376,698 @@ pub async fn worker_info(
         })?,
     ))
 }
+
+#[test]
+fn test_should_retry_flaky() {
+    let mut job_ok = JobOk {
+        build_success: false,
+        successful_packages: vec![],
+        failed_package: Some("fd".to_string()),
+        skipped_packages: vec![],
+        log_url: None,
+        elapsed_secs: 10,
+        pushpkg_success: false,
+        log_tail: Some("curl: (6) Could not resolve host: github.com".into()),
+        environment: None,
+        peak_memory_bytes: None,
+        disk_bytes: None,
+        package_timings: None,
+    };
+
+    // transient failure is retried once
+    assert!(should_retry_flaky(&job_ok, 0, DEFAULT_FLAKY_PATTERNS));
+    assert!(!should_retry_flaky(&job_ok, 1, DEFAULT_FLAKY_PATTERNS));
+
+    // real failure is reported immediately
+    job_ok.log_tail = Some("error: expected `;`, found `}`".into());
+    assert!(!should_retry_flaky(&job_ok, 0, DEFAULT_FLAKY_PATTERNS));
+    job_ok.log_tail = None;
+    assert!(!should_retry_flaky(&job_ok, 0, DEFAULT_FLAKY_PATTERNS));
+}
+
+#[tokio::test]
+async fn test_github_api_call() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    let semaphore = Semaphore::new(1);
+    let in_flight = AtomicUsize::new(0);
+    let max_in_flight = AtomicUsize::new(0);
+    let update_comment = || async {
+        let n = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        max_in_flight.fetch_max(n, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        in_flight.fetch_sub(1, Ordering::SeqCst);
+    };
+
+    tokio::join!(
+        github_api_call(&semaphore, update_comment()),
+        github_api_call(&semaphore, update_comment()),
+    );
+    assert_eq!(max_in_flight.load(Ordering::SeqCst), 1);
+
+    // without the semaphore the two updates overlap
+    tokio::join!(update_comment(), update_comment());
+    assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_check_schema_version() {
+    // v1 result sent by workers without versioning
+    let mut req: WorkerJobUpdateRequest = serde_json::from_value(serde_json::json!({
+        "hostname": "Yerus",
+        "arch": "amd64",
+        "job_id": 1,
+        "worker_secret": "secret",
+        "result": {
+            "Ok": {
+                "build_success": true,
+                "successful_packages": ["fd"],
+                "failed_package": null,
+                "skipped_packages": [],
+                "log_url": null,
+                "elapsed_secs": 888,
+                "pushpkg_success": true,
+            }
+        },
+    }))
+    .unwrap();
+    assert_eq!(req.schema_version, 1);
+    assert!(check_schema_version(&req).is_ok());
+    let JobResult::Ok(job_ok) = &req.result else {
+        panic!("expected job ok");
+    };
+    assert_eq!(job_ok.log_tail, None);
+    assert_eq!(job_ok.environment, None);
+
+    req.schema_version = JOB_RESULT_SCHEMA_VERSION + 1;
+    assert!(check_schema_version(&req).is_ok());
+
+    req.schema_version = 0;
+    assert!(check_schema_version(&req)
+        .unwrap_err()
+        .to_string()
+        .contains("Unsupported job result schema version 0"));
+}
+
+#[test]
+fn test_source_mirror() {
+    let mut resp = WorkerPollResponse {
+        job_id: 1,
+        git_branch: "fd-9.0.0".to_string(),
+        git_sha: "34acef168fc5ec454d3825fc864964951b130b49".to_string(),
+        packages: "fd".to_string(),
+        source_mirror: None,
+        git_url: None,
+        arch: "amd64".to_string(),
+        skip_pushpkg: false,
+    };
+
+    // omitted when unset, for older workers
+    let json = serde_json::to_value(&resp).unwrap();
+    assert!(json.get("source_mirror").is_none());
+    let resp2: WorkerPollResponse = serde_json::from_value(json).unwrap();
+    assert_eq!(resp2.source_mirror, None);
+    assert_eq!(
+        resp2.source_url("https://github.com/AOSC-Dev/aosc-os-abbs.git"),
+        "https://github.com/AOSC-Dev/aosc-os-abbs.git"
+    );
+
+    resp.source_mirror = Some("https://proxy.example.org/".to_string());
+    let resp2: WorkerPollResponse =
+        serde_json::from_value(serde_json::to_value(&resp).unwrap()).unwrap();
+    assert_eq!(
+        resp2.source_mirror.as_deref(),
+        Some("https://proxy.example.org/")
+    );
+    assert_eq!(
+        resp2.source_url("https://github.com/AOSC-Dev/aosc-os-abbs.git"),
+        "https://proxy.example.org/https://github.com/AOSC-Dev/aosc-os-abbs.git"
+    );
+}
+
+#[test]
+fn test_fairness_cap() {
+    let pipeline = |id: i32, github_pr: Option<i64>, telegram_user: Option<i64>| Pipeline {
+        id,
+        github_pr,
+        telegram_user,
+        ..Pipeline::fixture()
+    };
+    let job = |id: i32, pipeline_id: i32| Job {
+        id,
+        pipeline_id,
+        ..Job::fixture()
+    };
+    // PR #4992 queued jobs 1-3 ahead of job 4 of another chat
+    let queue = |ids: &[i32]| {
+        ids.iter()
+            .map(|&id| {
+                if id == 4 {
+                    (job(id, 2), pipeline(2, None, Some(1234)))
+                } else {
+                    (job(id, 1), pipeline(1, Some(4992), Some(5678)))
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    let pr = || pipeline(1, Some(4992), Some(5678));
+    let chat = || pipeline(2, None, Some(1234));
+    let picked = |ids: &[i32], running: &[Pipeline]| {
+        pick_fair_job(queue(ids), running, 2).map(|(job, _)| job.id)
+    };
+
+    assert_eq!(picked(&[1, 2, 3, 4], &[]), Some(1));
+    assert_eq!(picked(&[2, 3, 4], &[pr()]), Some(2));
+    // the third job of the PR waits, other submitters go ahead
+    assert_eq!(picked(&[3, 4], &[pr(), pr()]), Some(4));
+    assert_eq!(picked(&[3], &[pr(), pr(), chat()]), None);
+    // until one of its jobs finishes
+    assert_eq!(picked(&[3], &[pr(), chat()]), Some(3));
+
+    // pipelines from the API are not capped
+    let api = || pipeline(3, None, None);
+    assert_eq!(submitter_of(&api()), None);
+    assert_eq!(
+        pick_fair_job(vec![(job(5, 3), api())], &[api(), api()], 2).map(|(job, _)| job.id),
+        Some(5)
+    );
+    assert_eq!(
+        submitter_of(&pr()).as_deref(),
+        Some("AOSC-Dev/aosc-os-abbs#4992")
+    );
+    assert_eq!(submitter_of(&chat()).as_deref(), Some("telegram:1234"));
+}
+
+#[test]
+fn test_live_progress() {
+    let start = Instant::now();
+    let mut progress = LiveProgress::default();
+
+    // first event is shown immediately
+    assert!(progress.update("zlib", 1, 10, start));
+    assert_eq!((progress.index, progress.total), (1, 10));
+    assert_eq!(progress.current_package, "zlib");
+
+    // throttled, but still tracked
+    assert!(!progress.update("binutils", 2, 10, start + Duration::from_secs(3)));
+    assert_eq!(progress.index, 2);
+    assert_eq!(progress.current_package, "binutils");
+    assert!(progress.update("gcc", 3, 10, start + Duration::from_secs(10)));
+    assert_eq!(progress.index, 3);
+
+    // out-of-order event is ignored
+    assert!(!progress.update("binutils", 2, 10, start + Duration::from_secs(30)));
+    assert_eq!(progress.index, 3);
+    assert_eq!(progress.current_package, "gcc");
+
+    // last package is always shown
+    assert!(progress.update("glibc", 10, 10, start + Duration::from_secs(31)));
+    assert_eq!(progress.index, 10);
+}
+
+#[tokio::test]
+async fn test_keyed_locks() {
+    let locks = KeyedLocks::new();
+    let timeout = Duration::from_millis(50);
+
+    let guard = locks.lock(1).await;
+    // a different key is not blocked
+    assert!(tokio::time::timeout(timeout, locks.lock(2)).await.is_ok());
+    // the same key waits until released
+    assert!(tokio::time::timeout(timeout, locks.lock(1)).await.is_err());
+    drop(guard);
+    assert!(tokio::time::timeout(timeout, locks.lock(1)).await.is_ok());
+
+    // released mutexes are cleaned up
+    let _guard = locks.lock(3).await;
+    assert_eq!(
+        locks.locks.lock().unwrap().keys().collect::<Vec<_>>(),
+        vec![&3]
+    );
+}
+
+#[test]
+fn test_check_run_payloads() {
+    use crate::GithubReport;
+
+    assert_eq!(api::check_run_name("riscv64"), "buildit riscv64");
+
+    let job_ok = JobOk {
+        build_success: true,
+        successful_packages: vec!["fd".to_string(), "fd2".to_string()],
+        failed_package: None,
+        skipped_packages: vec![],
+        log_url: Some("https://pastebin.aosc.io/paste/c0rWzj4EsSC~CVXs2qXtFw".to_string()),
+        elapsed_secs: 888,
+        pushpkg_success: true,
+        log_tail: None,
+        environment: None,
+        peak_memory_bytes: None,
+        disk_bytes: None,
+        package_timings: None,
+    };
+    let output = serde_json::to_value(check_run_output(&job_ok, "summary".to_string())).unwrap();
+    assert_eq!(output["title"], "Built 2 packages in 888s");
+    assert_eq!(output["summary"], "summary");
+    assert_eq!(
+        check_run_details_url(1, &job_ok),
+        "https://pastebin.aosc.io/paste/c0rWzj4EsSC~CVXs2qXtFw"
+    );
+
+    let job_ok = JobOk {
+        log_url: None,
+        ..job_ok
+    };
+    assert_eq!(
+        check_run_details_url(1, &job_ok),
+        "https://buildit.aosc.io/jobs/1"
+    );
+
+    // comments are the fallback without check runs
+    assert!(!GithubReport::CheckRun.comment(true));
+    assert!(GithubReport::CheckRun.comment(false));
+    assert!(GithubReport::Comment.comment(false));
+    assert!(GithubReport::Both.comment(true));
+    assert!(!GithubReport::Comment.check_run());
+}
+
+#[test]
+fn test_worker_register() {
+    let now = DateTime::from_timestamp(61, 0).unwrap();
+    let later = DateTime::from_timestamp(121, 0).unwrap();
+    let register = WorkerRegisterRequest {
+        hostname: "Yerus".to_string(),
+        arch: "amd64".to_string(),
+        worker_secret: "secret".to_string(),
+        git_commit: "v0.1.0".to_string(),
+        memory_bytes: 64 << 30,
+        logical_cores: 32,
+        disk_free_space_bytes: 100 << 30,
+        performance: Some(10),
+        version: Some("0.1.0".to_string()),
+        labels: vec!["bigmem".to_string(), "nvme".to_string()],
+    };
+    let heartbeat = WorkerHeartbeatRequest {
+        hostname: "Yerus".to_string(),
+        arch: "amd64".to_string(),
+        worker_secret: "secret".to_string(),
+        disk_free_space_bytes: 90 << 30,
+        internet_connectivity: Some(true),
+        git_commit: None,
+        memory_bytes: None,
+        logical_cores: None,
+        performance: None,
+        version: None,
+        labels: None,
+    };
+
+    // register then heartbeat: capabilities are kept
+    let registered = worker_from_register(&register, now);
+    assert_eq!(registered.logical_cores, 32);
+    assert_eq!(registered.labels.as_deref(), Some("bigmem,nvme"));
+    let worker = worker_from_heartbeat(Some(registered.clone()), &heartbeat, later);
+    assert_eq!(worker.last_heartbeat_time, later);
+    assert_eq!(worker.disk_free_space_bytes, 90 << 30);
+    assert!(worker.internet_connectivity);
+    assert_eq!(
+        worker,
+        NewWorker {
+            last_heartbeat_time: later,
+            disk_free_space_bytes: 90 << 30,
+            internet_connectivity: true,
+            ..registered.clone()
+        }
+    );
+
+    // registering again is idempotent
+    assert_eq!(worker_from_register(&register, now), registered);
+
+    // heartbeat first: registered implicitly, filled in by the registration
+    let worker = worker_from_heartbeat(None, &heartbeat, now);
+    assert_eq!(worker.hostname, "Yerus");
+    assert_eq!(worker.arch, "amd64");
+    assert_eq!(worker.logical_cores, 0);
+    assert_eq!(worker.labels, None);
+    assert_eq!(worker_from_register(&register, now), registered);
+
+    // workers without registration send capabilities with each heartbeat
+    let legacy = WorkerHeartbeatRequest {
+        git_commit: Some("v0.0.9".to_string()),
+        memory_bytes: Some(32 << 30),
+        logical_cores: Some(16),
+        labels: Some(vec![]),
+        ..heartbeat.clone()
+    };
+    let worker = worker_from_heartbeat(None, &legacy, now);
+    assert_eq!(worker.git_commit, "v0.0.9");
+    assert_eq!(worker.memory_bytes, 32 << 30);
+    assert_eq!(worker.logical_cores, 16);
+    let worker = worker_from_heartbeat(Some(registered), &legacy, later);
+    assert_eq!(worker.logical_cores, 16);
+    assert_eq!(worker.labels, None);
+}
+
+#[tokio::test]
+async fn test_poll_after_cancel() {
+    use crate::routes::WSStateMap;
+    use common::WorkerControl;
+    use futures::channel::mpsc::unbounded;
+
+    let Some(pool) = crate::test_db() else {
+        return;
+    };
+    let mut conn = pool.get().unwrap();
+    diesel::insert_into(crate::schema::workers::table)
+        .values(&Worker::fixture())
+        .execute(&mut conn)
+        .unwrap();
+    diesel::insert_into(crate::schema::pipelines::table)
+        .values(&[
+            Pipeline {
+                source: "github".to_string(),
+                github_pr: Some(4321),
+                ..Pipeline::fixture()
+            },
+            Pipeline {
+                id: 13,
+                ..Pipeline::fixture()
+            },
+        ])
+        .execute(&mut conn)
+        .unwrap();
+    diesel::insert_into(crate::schema::jobs::table)
+        .values(&[
+            Job {
+                status: "running".to_string(),
+                assigned_worker_id: Some(1),
+                assign_time: Some(Utc::now()),
+                ..Job::fixture()
+            },
+            Job {
+                id: 2,
+                pipeline_id: 13,
+                ..Job::fixture()
+            },
+        ])
+        .execute(&mut conn)
+        .unwrap();
+
+    let ws_state_map = WSStateMap::default();
+    let (tx, mut rx) = unbounded();
+    ws_state_map
+        .lock()
+        .unwrap()
+        .entry("Yerus".to_string())
+        .or_default()
+        .control = Some(tx);
+
+    let (_, cancelled) = api::pipeline_cancel_pr(
+        pool.clone(),
+        None,
+        &ws_state_map,
+        "AOSC-Dev/aosc-os-abbs",
+        4321,
+        "octocat",
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(cancelled.len(), 1);
+    // the worker is told to stop
+    let Ok(axum::extract::ws::Message::Text(req)) = rx.try_recv() else {
+        panic!("expected a cancel request");
+    };
+    assert_eq!(
+        serde_json::from_str::<WorkerControl>(&req).unwrap(),
+        WorkerControl::CancelJob { job_id: 1 }
+    );
+
+    // the next poll of the worker does not bring the cancelled job back
+    let payload = WorkerPollRequest {
+        hostname: "Yerus".to_string(),
+        arch: "amd64".to_string(),
+        worker_secret: String::new(),
+        memory_bytes: 1 << 34,
+        logical_cores: 16,
+        disk_free_space_bytes: 1 << 40,
+        labels: vec![],
+        arch_patterns: vec![],
+    };
+    let (_, job) = assign_job(&mut conn, &payload, DispatchMode::PerArch, 1, None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(job.id, 2);
+    let cancelled = crate::schema::jobs::dsl::jobs
+        .find(1)
+        .first::<Job>(&mut conn)
+        .unwrap();
+    assert_eq!(cancelled.status, "cancelled");
+    assert_eq!(cancelled.assigned_worker_id, None);
+
+    // polling again requeues the running job only
+    let (_, job) = assign_job(&mut conn, &payload, DispatchMode::PerArch, 1, None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(job.id, 2);
+    let cancelled = crate::schema::jobs::dsl::jobs
+        .find(1)
+        .first::<Job>(&mut conn)
+        .unwrap();
+    assert_eq!(cancelled.status, "cancelled");
+}
+
+#[test]
+fn test_poll_outdated_worker() {
+    let Some(pool) = crate::test_db() else {
+        return;
+    };
+    let mut conn = pool.get().unwrap();
+    diesel::insert_into(crate::schema::workers::table)
+        .values(&Worker {
+            version: Some("0.1.9".to_string()),
+            ..Worker::fixture()
+        })
+        .execute(&mut conn)
+        .unwrap();
+    diesel::insert_into(crate::schema::pipelines::table)
+        .values(&Pipeline::fixture())
+        .execute(&mut conn)
+        .unwrap();
+    diesel::insert_into(crate::schema::jobs::table)
+        .values(&Job::fixture())
+        .execute(&mut conn)
+        .unwrap();
+
+    let payload = WorkerPollRequest {
+        hostname: "Yerus".to_string(),
+        arch: "amd64".to_string(),
+        worker_secret: String::new(),
+        memory_bytes: 1 << 34,
+        logical_cores: 16,
+        disk_free_space_bytes: 1 << 40,
+        labels: vec![],
+        arch_patterns: vec![],
+    };
+    assert!(
+        assign_job(&mut conn, &payload, DispatchMode::PerArch, 1, Some("0.2.0"))
+            .unwrap()
+            .is_none()
+    );
+    let job = crate::schema::jobs::dsl::jobs
+        .find(1)
+        .first::<Job>(&mut conn)
+        .unwrap();
+    assert_eq!(job.status, "created");
+
+    // up to date
+    let (_, job) = assign_job(&mut conn, &payload, DispatchMode::PerArch, 1, Some("0.1.0"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(job.id, 1);
+}
+
+#[test]
+fn test_record_job_result() {
+    let Some(pool) = crate::test_db() else {
+        return;
+    };
+    let mut conn = pool.get().unwrap();
+    diesel::insert_into(crate::schema::workers::table)
+        .values(&Worker::fixture())
+        .execute(&mut conn)
+        .unwrap();
+    diesel::insert_into(crate::schema::pipelines::table)
+        .values(&Pipeline::fixture())
+        .execute(&mut conn)
+        .unwrap();
+    let job = |id: i32, status: &str| Job {
+        id,
+        status: status.to_string(),
+        assigned_worker_id: Some(1),
+        ..Job::fixture()
+    };
+    diesel::insert_into(crate::schema::jobs::table)
+        .values(&[job(1, "running"), job(2, "running"), job(3, "cancelled")])
+        .execute(&mut conn)
+        .unwrap();
+    let failed = JobResult::Ok(JobOk {
+        build_success: false,
+        successful_packages: vec![],
+        failed_package: Some("fd".to_string()),
+        skipped_packages: vec![],
+        log_url: None,
+        elapsed_secs: 60,
+        pushpkg_success: false,
+        log_tail: None,
+        environment: None,
+        peak_memory_bytes: None,
+        disk_bytes: None,
+        package_timings: None,
+    });
+    let find_job = |conn: &mut PgConnection, id: i32| {
+        crate::schema::jobs::dsl::jobs
+            .find(id)
+            .first::<Job>(conn)
+            .unwrap()
+    };
+
+    // results of running jobs are recorded, with the retry if flaky
+    assert!(record_job_result(&mut conn, &job(1, "running"), 1, &failed, true).unwrap());
+    let recorded = find_job(&mut conn, 1);
+    assert_eq!(recorded.status, "failed");
+    assert_eq!(recorded.assigned_worker_id, None);
+    assert_eq!(recorded.built_by_worker_id, Some(1));
+    let retries = crate::schema::jobs::dsl::jobs
+        .filter(crate::schema::jobs::dsl::retry_count.eq(1))
+        .load::<Job>(&mut conn)
+        .unwrap();
+    assert_eq!(retries.len(), 1);
+    assert_eq!(retries[0].status, "created");
+
+    // a second report of the same job is dropped
+    assert!(!record_job_result(&mut conn, &job(1, "running"), 1, &failed, true).unwrap());
+
+    // so are results of jobs of other workers, or cancelled meanwhile
+    let error = JobResult::Error("ciel exploded".to_string());
+    assert!(!record_job_result(&mut conn, &job(2, "running"), 2, &error, false).unwrap());
+    assert_eq!(find_job(&mut conn, 2).status, "running");
+    assert!(!record_job_result(&mut conn, &job(3, "running"), 1, &failed, true).unwrap());
+    let cancelled = find_job(&mut conn, 3);
+    assert_eq!(cancelled.status, "cancelled");
+    assert_eq!(cancelled.failed_package, None);
+    assert!(record_job_result(&mut conn, &job(2, "running"), 1, &error, false).unwrap());
+    assert_eq!(find_job(&mut conn, 2).status, "error");
+    assert_eq!(
+        crate::schema::jobs::dsl::jobs
+            .count()
+            .get_result::<i64>(&mut conn)
+            .unwrap(),
+        4
+    );
+}
+
+#[test]
+fn test_assign_job_past_capped_submitter() {
+    let Some(pool) = crate::test_db() else {
+        return;
+    };
+    let mut conn = pool.get().unwrap();
+    diesel::insert_into(crate::schema::workers::table)
+        .values(&Worker::fixture())
+        .execute(&mut conn)
+        .unwrap();
+    diesel::insert_into(crate::schema::pipelines::table)
+        .values(&[
+            Pipeline {
+                source: "github".to_string(),
+                github_pr: Some(4992),
+                ..Pipeline::fixture()
+            },
+            Pipeline {
+                id: 13,
+                telegram_user: Some(1234),
+                ..Pipeline::fixture()
+            },
+        ])
+        .execute(&mut conn)
+        .unwrap();
+    let job = |id: i32, pipeline_id: i32, status: &str| Job {
+        id,
+        pipeline_id,
+        status: status.to_string(),
+        ..Job::fixture()
+    };
+    // the PR runs two jobs and has pages of jobs queued ahead of the chat
+    let mut queue = vec![job(1, 12, "running"), job(2, 12, "running")];
+    queue.extend((3..100).map(|id| job(id, 12, "created")));
+    queue.push(job(100, 13, "created"));
+    diesel::insert_into(crate::schema::jobs::table)
+        .values(&queue)
+        .execute(&mut conn)
+        .unwrap();
+
+    let payload = WorkerPollRequest {
+        hostname: "Yerus".to_string(),
+        arch: "amd64".to_string(),
+        worker_secret: String::new(),
+        memory_bytes: 1 << 34,
+        logical_cores: 16,
+        disk_free_space_bytes: 1 << 40,
+        labels: vec![],
+        arch_patterns: vec![],
+    };
+    let (_, assigned) = assign_job(&mut conn, &payload, DispatchMode::PerArch, 2, None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(assigned.id, 100);
+
+    // as if running elsewhere, polls requeue the job of the polling worker
+    diesel::update(crate::schema::jobs::dsl::jobs.find(100))
+        .set(crate::schema::jobs::dsl::assigned_worker_id.eq(None::<i32>))
+        .execute(&mut conn)
+        .unwrap();
+    // nothing left for the PR under the cap
+    assert!(
+        assign_job(&mut conn, &payload, DispatchMode::PerArch, 2, None)
+            .unwrap()
+            .is_none()
+    );
+    let (_, assigned) = assign_job(&mut conn, &payload, DispatchMode::PerArch, 3, None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(assigned.id, 3);
+}
+
+#[test]
+fn test_prune_job_progress() {
+    let Some(pool) = crate::test_db() else {
+        return;
+    };
+    let mut conn = pool.get().unwrap();
+    diesel::insert_into(crate::schema::pipelines::table)
+        .values(&Pipeline::fixture())
+        .execute(&mut conn)
+        .unwrap();
+    let job = |id: i32, status: &str| Job {
+        id,
+        status: status.to_string(),
+        ..Job::fixture()
+    };
+    diesel::insert_into(crate::schema::jobs::table)
+        .values(&[
+            job(1, "running"),
+            job(2, "cancelled"),
+            job(3, "expired"),
+            // requeued
+            job(4, "created"),
+        ])
+        .execute(&mut conn)
+        .unwrap();
+
+    let mut progress = (1..=5)
+        .map(|id| (id, LiveProgress::default()))
+        .collect::<BTreeMap<_, _>>();
+    prune_job_progress(&mut conn, &mut progress).unwrap();
+    assert_eq!(progress.keys().copied().collect::<Vec<_>>(), vec![1]);
+}
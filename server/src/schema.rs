@@ -1,5 +1,30 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    audit_log (id) {
+        id -> Int4,
+        time -> Timestamptz,
+        actor -> Text,
+        action -> Text,
+        details -> Text,
+    }
+}
+
+diesel::table! {
+    chat_settings (telegram_chat_id) {
+        telegram_chat_id -> Int8,
+        notify_mode -> Text,
+    }
+}
+
+diesel::table! {
+    idempotency_keys (key) {
+        key -> Text,
+        pipeline_id -> Int4,
+        creation_time -> Timestamptz,
+    }
+}
+
 diesel::table! {
     jobs (id) {
         id -> Int4,
@@ -25,6 +50,36 @@ diesel::table! {
         require_min_total_mem_per_core -> Nullable<Float4>,
         require_min_disk -> Nullable<Int8>,
         assign_time -> Nullable<Timestamptz>,
+        retry_count -> Int4,
+        environment -> Nullable<Text>,
+        priority -> Int4,
+        failure_kind -> Nullable<Text>,
+        require_label -> Nullable<Text>,
+        peak_memory_bytes -> Nullable<Int8>,
+        disk_bytes -> Nullable<Int8>,
+        package_timings -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    opened_prs (id) {
+        id -> Int4,
+        pr_number -> Int8,
+        title -> Text,
+        git_ref -> Text,
+        telegram_chat_id -> Int8,
+        creation_time -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    pending_prs (pipeline_id) {
+        pipeline_id -> Int4,
+        title -> Text,
+        git_ref -> Text,
+        packages -> Text,
+        telegram_chat_id -> Int8,
+        creation_time -> Timestamptz,
     }
 }
 
@@ -40,6 +95,12 @@ diesel::table! {
         github_pr -> Nullable<Int8>,
         telegram_user -> Nullable<Int8>,
         creator_user_id -> Nullable<Int4>,
+        requested_by -> Nullable<Text>,
+        build_plan_hash -> Nullable<Text>,
+        repo -> Text,
+        parent_pipeline_id -> Nullable<Int4>,
+        telegram_message_id -> Nullable<Int4>,
+        git_ref_kind -> Text,
     }
 }
 
@@ -68,10 +129,24 @@ diesel::table! {
         performance -> Nullable<Int8>,
         visible -> Bool,
         internet_connectivity -> Bool,
+        version -> Nullable<Text>,
+        labels -> Nullable<Text>,
     }
 }
 
+diesel::joinable!(idempotency_keys -> pipelines (pipeline_id));
 diesel::joinable!(jobs -> pipelines (pipeline_id));
+diesel::joinable!(pending_prs -> pipelines (pipeline_id));
 diesel::joinable!(pipelines -> users (creator_user_id));
 
-diesel::allow_tables_to_appear_in_same_query!(jobs, pipelines, users, workers,);
+diesel::allow_tables_to_appear_in_same_query!(
+    audit_log,
+    chat_settings,
+    idempotency_keys,
+    jobs,
+    opened_prs,
+    pending_prs,
+    pipelines,
+    users,
+    workers,
+);
@@ -0,0 +1,155 @@
+use anyhow::bail;
+use std::collections::BTreeMap;
+
+/// Variables of the job summary template
+pub const JOB_SUMMARY_VARS: &[&str] = &[
+    "status",
+    "result",
+    "job_id",
+    "job_url",
+    "pipeline_id",
+    "pipeline_url",
+    "worker",
+    "worker_arch",
+    "arch",
+    "packages",
+    "successful_packages",
+    "failed_package",
+    "skipped_packages",
+    "enqueue_time",
+    "elapsed",
+    "git_branch",
+    "git_sha",
+    "git_commit",
+    "github_pr",
+    "requested_by",
+    "failure_reason",
+    "resource_usage",
+    "log_url",
+];
+
+/// Variables of the new pipeline template
+pub const NEW_PIPELINE_VARS: &[&str] = &[
+    "pipeline_id",
+    "pipeline_url",
+    "git_branch",
+    "git_sha",
+    "git_commit",
+    "github_pr",
+    "archs",
+    "packages",
+    "unchanged_since",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Var(String),
+}
+
+/// Telegram HTML message with `{variable}` placeholders, `{{` and `}}` are
+/// literal braces
+///
+/// Variables are checked when the template is parsed, so rendering never fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template(Vec<Part>);
+
+impl Template {
+    pub fn parse(template: &str, vars: &[&str]) -> anyhow::Result<Self> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(ch) => name.push(ch),
+                            None => bail!("Unclosed {{ in template"),
+                        }
+                    }
+                    let name = name.trim();
+                    if !vars.contains(&name) {
+                        bail!(
+                            "Unknown variable {{{name}}} in template, valid variables are: {}",
+                            vars.join(", ")
+                        );
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Var(name.to_string()));
+                }
+                '}' => bail!("Unmatched }} in template, use }}}} for a literal brace"),
+                ch => text.push(ch),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Self(parts))
+    }
+
+    /// Fill in the variables, values are HTML-escaped and missing ones are left empty
+    pub fn render(&self, values: &BTreeMap<&str, String>) -> String {
+        self.0
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Var(name) => values
+                    .get(name.as_str())
+                    .map(|value| teloxide::utils::html::escape(value))
+                    .unwrap_or_default(),
+            })
+            .collect()
+    }
+}
+
+pub fn parse_job_summary_template(s: &str) -> anyhow::Result<Template> {
+    Template::parse(s, JOB_SUMMARY_VARS)
+}
+
+pub fn parse_new_pipeline_template(s: &str) -> anyhow::Result<Template> {
+    Template::parse(s, NEW_PIPELINE_VARS)
+}
+
+#[test]
+fn test_template() {
+    let template = parse_job_summary_template(
+        "{status} <b>{packages}</b> on { arch } took {elapsed} {{{job_id}}}",
+    )
+    .unwrap();
+    let values = BTreeMap::from([
+        ("status", "✅️".to_string()),
+        ("packages", "fd, <fd2>".to_string()),
+        ("arch", "amd64".to_string()),
+        ("elapsed", "888s".to_string()),
+        ("job_id", "1".to_string()),
+    ]);
+    assert_eq!(
+        template.render(&values),
+        "✅️ <b>fd, &lt;fd2&gt;</b> on amd64 took 888s {1}"
+    );
+    // unset variables render empty
+    assert_eq!(template.render(&BTreeMap::new()), " <b></b> on  took  {}");
+
+    // rejected when loading
+    assert!(parse_job_summary_template("{archs}").is_err());
+    assert!(parse_new_pipeline_template("{archs}").is_ok());
+    assert!(parse_job_summary_template("{arch").is_err());
+    assert!(parse_job_summary_template("arch}").is_err());
+    assert_eq!(
+        parse_job_summary_template("no variables").unwrap(),
+        Template(vec![Part::Text("no variables".to_string())])
+    );
+}
@@ -0,0 +1,116 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::fmt::Display;
+
+/// Likely reason of a failed build, guessed from the log tail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    DiskFull,
+    NetworkError,
+    PatchFailed,
+    MissingDependency,
+    TestFailure,
+    CompileError,
+    Unknown,
+}
+
+impl FailureKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureKind::DiskFull => "disk full",
+            FailureKind::NetworkError => "network error",
+            FailureKind::PatchFailed => "patch apply failed",
+            FailureKind::MissingDependency => "missing dependency",
+            FailureKind::TestFailure => "test failure",
+            FailureKind::CompileError => "compile error",
+            FailureKind::Unknown => "unknown",
+        }
+    }
+}
+
+impl Display for FailureKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// checked in order, the first match wins:
+// causes go before symptoms, e.g. a full disk also breaks compilation
+const FAILURE_RULES: &[(FailureKind, &str)] = &[
+    (FailureKind::DiskFull, r"No space left on device"),
+    (
+        FailureKind::NetworkError,
+        r"Could not resolve host|Temporary failure in name resolution|Connection timed out",
+    ),
+    (
+        FailureKind::PatchFailed,
+        r"(?m)^Hunk #\d+ FAILED|^patch: \*\*\*\*|error: patch failed|does not apply",
+    ),
+    (
+        FailureKind::MissingDependency,
+        r"Unable to locate package|unmet dependencies|No package '[^']+' found|was not found in the pkg-config search path|Could not find a package configuration file|fatal error: [^:]+: No such file or directory|ModuleNotFoundError",
+    ),
+    (
+        FailureKind::TestFailure,
+        r"(?m)^FAIL:|\d+ tests? failed|test result: FAILED|\*\*\* \[[^\]]*(check|test)[^\]]*\] Error",
+    ),
+    (
+        FailureKind::CompileError,
+        r"error\[E\d+\]|(?m)^\S+:\d+:\d+: error:|ninja: build stopped|make(\[\d+\])?: \*\*\* .*Error \d+",
+    ),
+];
+
+static RULES: Lazy<Vec<(FailureKind, Regex)>> = Lazy::new(|| {
+    FAILURE_RULES
+        .iter()
+        .map(|(kind, pattern)| (*kind, Regex::new(pattern).unwrap()))
+        .collect()
+});
+
+pub fn classify_failure(log_tail: &str) -> FailureKind {
+    RULES
+        .iter()
+        .find(|(_, regex)| regex.is_match(log_tail))
+        .map(|(kind, _)| *kind)
+        .unwrap_or(FailureKind::Unknown)
+}
+
+#[test]
+fn test_classify_failure() {
+    assert_eq!(
+        classify_failure("cc1: fatal error: write: No space left on device\nmake: *** [Makefile:12: all] Error 1"),
+        FailureKind::DiskFull
+    );
+    assert_eq!(
+        classify_failure(
+            "patching file src/main.c\nHunk #2 FAILED at 120.\n1 out of 2 hunks FAILED"
+        ),
+        FailureKind::PatchFailed
+    );
+    assert_eq!(
+        classify_failure("src/foo.c:1:10: fatal error: zstd.h: No such file or directory\ncompilation terminated."),
+        FailureKind::MissingDependency
+    );
+    assert_eq!(
+        classify_failure("checking for GLIB... no\nNo package 'glib-2.0' found"),
+        FailureKind::MissingDependency
+    );
+    assert_eq!(
+        classify_failure(
+            "FAIL: test-suite.log\n# FAIL:  3\nmake[2]: *** [Makefile:1234: check-TESTS] Error 1"
+        ),
+        FailureKind::TestFailure
+    );
+    assert_eq!(
+        classify_failure("src/main.c:42:5: error: 'foo' undeclared (first use in this function)\nmake[1]: *** [Makefile:80: main.o] Error 1"),
+        FailureKind::CompileError
+    );
+    assert_eq!(
+        classify_failure("error[E0425]: cannot find value `x` in this scope"),
+        FailureKind::CompileError
+    );
+    assert_eq!(
+        classify_failure("[ERROR]: Build failed"),
+        FailureKind::Unknown
+    );
+}
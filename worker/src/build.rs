@@ -1,18 +1,31 @@
 use crate::{get_memory_bytes, Args};
+use anyhow::anyhow;
 use chrono::Local;
-use common::{JobOk, WorkerJobUpdateRequest, WorkerPollRequest, WorkerPollResponse};
-use flume::Sender;
+use common::{
+    JobOk, LogBytes, WorkerJobProgressRequest, WorkerJobUpdateRequest, WorkerPollRequest,
+    WorkerPollResponse,
+};
+use flume::{Receiver, Sender};
 use futures_util::future::try_join3;
 use log::{error, info, warn};
 use std::{
-    path::Path,
+    borrow::Cow,
+    collections::BTreeMap,
+    path::{Path, PathBuf},
     process::{Output, Stdio},
+    sync::{
+        atomic::{AtomicI32, AtomicI64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
+use sysinfo::System;
 use tokio::{
     fs,
     io::{AsyncBufReadExt, AsyncRead, BufReader},
     process::Command,
+    sync::Notify,
+    task::JoinHandle,
     time::sleep,
 };
 use tokio_tungstenite::tungstenite::Message;
@@ -40,14 +53,16 @@ async fn get_output_logged(
         .current_dir(cwd)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
+        // stop building when the job is cancelled
+        .kill_on_drop(true)
         .spawn()?;
 
     // learn from tokio wait_with_output
     async fn read_and_send<A: AsyncRead + Unpin>(
         io: &mut Option<A>,
         tx: Sender<Message>,
-    ) -> tokio::io::Result<String> {
-        let mut res = String::new();
+    ) -> tokio::io::Result<Vec<u8>> {
+        let mut res = vec![];
         if let Some(io) = io.as_mut() {
             // do not use next_line: stream may contain invalid utf-8!
             let mut reader = BufReader::new(io);
@@ -57,6 +72,9 @@ async fn get_output_logged(
                     // EOF
                     Ok(0) => break,
                     Ok(_size) => {
+                        // the log keeps the original bytes
+                        res.extend_from_slice(&buffer);
+
                         // drop trailing \n or \r\n
                         if buffer.ends_with(&[b'\n']) {
                             buffer.pop();
@@ -65,11 +83,9 @@ async fn get_output_logged(
                             }
                         }
 
-                        // convert \r to \n
+                        // convert \r to \n, viewers only get text
                         for line in String::from_utf8_lossy(&buffer).split("\r") {
                             tx.send_async(Message::Text(line.to_string())).await.ok();
-                            res += &line;
-                            res += "\n";
                         }
                     }
                     Err(err) => {
@@ -103,15 +119,18 @@ async fn get_output_logged(
         )
         .as_bytes(),
     );
-    logs.extend("STDOUT:\n".as_bytes());
-    logs.extend(stdout.as_bytes());
-    logs.extend("STDERR:\n".as_bytes());
-    logs.extend(stderr.as_bytes());
+    for (name, output) in [("STDOUT", &stdout), ("STDERR", &stderr)] {
+        logs.extend(format!("{name}:\n").as_bytes());
+        logs.extend(output);
+        if !output.is_empty() && !output.ends_with(b"\n") {
+            logs.push(b'\n');
+        }
+    }
 
     Ok(Output {
         status,
-        stdout: stdout.into_bytes(),
-        stderr: stderr.into_bytes(),
+        stdout,
+        stderr,
     })
 }
 
@@ -150,20 +169,241 @@ async fn run_logged_with_retry(
     Ok(false)
 }
 
+/// Parse acbs progress line like `Building gcc (3/10)...`
+fn parse_progress(line: &str) -> Option<(String, u32, u32)> {
+    let (_, rest) = line.split_once("Building ")?;
+    let (package, rest) = rest.split_once(" (")?;
+    let (progress, _) = rest.split_once(')')?;
+    let (index, total) = progress.split_once('/')?;
+    Some((
+        package.to_string(),
+        index.trim().parse().ok()?,
+        total.trim().parse().ok()?,
+    ))
+}
+
+/// Time spent on each package, measured between acbs progress lines
+#[derive(Default)]
+struct PackageTimer {
+    current: Option<(String, Instant)>,
+    timings: Vec<(String, i64)>,
+}
+
+impl PackageTimer {
+    fn start(&mut self, package: &str, now: Instant) {
+        self.stop(now);
+        self.current = Some((package.to_string(), now));
+    }
+
+    fn stop(&mut self, now: Instant) {
+        if let Some((package, begin)) = self.current.take() {
+            self.timings
+                .push((package, now.duration_since(begin).as_secs() as i64));
+        }
+    }
+
+    /// Return (package, seconds) in build order
+    fn finish(mut self, now: Instant) -> Vec<(String, i64)> {
+        self.stop(now);
+        self.timings
+    }
+}
+
+/// Forward build output to `tx`, and report build progress to server
+///
+/// Returns the time spent on each package
+async fn forward_progress(
+    rx: Receiver<Message>,
+    tx: Sender<Message>,
+    args: Args,
+    job_id: i32,
+) -> Vec<(String, i64)> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .unwrap();
+    let mut timer = PackageTimer::default();
+
+    while let Ok(msg) = rx.recv_async().await {
+        if let Message::Text(line) = &msg {
+            if let Some((current_package, index, total)) = parse_progress(line) {
+                timer.start(&current_package, Instant::now());
+                let req = WorkerJobProgressRequest {
+                    hostname: gethostname::gethostname().to_string_lossy().to_string(),
+                    arch: args.arch.clone(),
+                    worker_secret: args.worker_secret.clone(),
+                    job_id,
+                    current_package,
+                    index,
+                    total,
+                };
+                if let Err(err) = client
+                    .post(format!("{}/api/worker/job_progress", args.server))
+                    .json(&req)
+                    .send()
+                    .await
+                    .and_then(|resp| resp.error_for_status())
+                {
+                    warn!("Failed to report job progress: {err}");
+                }
+            }
+        }
+        tx.send_async(msg).await.ok();
+    }
+
+    // output ends when ciel exits
+    timer.finish(Instant::now())
+}
+
+/// Collect last `n` lines of the log, bytes are kept as they are
+fn get_log_tail(logs: &[u8], n: usize) -> LogBytes {
+    let logs = logs.strip_suffix(b"\n").unwrap_or(logs);
+    let lines = logs.split(|byte| *byte == b'\n').collect::<Vec<_>>();
+    LogBytes(lines[lines.len().saturating_sub(n)..].join(&b'\n'))
+}
+
+/// Track memory and disk usage growth of the system while a build runs
+struct ResourceSampler {
+    peak_memory_bytes: Arc<AtomicI64>,
+    disk_bytes: Arc<AtomicI64>,
+    handle: JoinHandle<()>,
+}
+
+impl ResourceSampler {
+    /// Start sampling, disk usage is measured on the filesystem of `path`
+    fn start(path: PathBuf) -> Self {
+        let peak_memory_bytes = Arc::new(AtomicI64::new(0));
+        let disk_bytes = Arc::new(AtomicI64::new(0));
+
+        let peak_memory = peak_memory_bytes.clone();
+        let disk = disk_bytes.clone();
+        let handle = tokio::spawn(async move {
+            let mut system = System::new();
+            system.refresh_memory();
+            let base_memory = system.used_memory() as i64;
+            let base_free = fs2::free_space(&path).ok();
+            loop {
+                system.refresh_memory();
+                peak_memory.fetch_max(system.used_memory() as i64 - base_memory, Ordering::Relaxed);
+                if let (Some(base_free), Ok(free)) = (base_free, fs2::free_space(&path)) {
+                    disk.fetch_max(base_free as i64 - free as i64, Ordering::Relaxed);
+                }
+                sleep(Duration::from_secs(5)).await;
+            }
+        });
+
+        Self {
+            peak_memory_bytes,
+            disk_bytes,
+            handle,
+        }
+    }
+
+    /// Return (peak memory, disk) usage in bytes so far
+    fn usage(&self) -> (i64, i64) {
+        (
+            self.peak_memory_bytes.load(Ordering::Relaxed),
+            self.disk_bytes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+impl Drop for ResourceSampler {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Room reserved for the elision marker in `truncate_log`, the smallest
+/// limit it can keep logs under
+pub(crate) const ELISION_MARKER_RESERVE: usize = 64;
+
+/// Keep the head and tail of the log if it exceeds `max_bytes`
+///
+/// The configure step is usually at the head while the error is at the tail.
+/// Cuts never split a UTF-8 sequence.
+pub fn truncate_log(logs: &[u8], max_bytes: usize) -> Cow<'_, [u8]> {
+    if logs.len() <= max_bytes {
+        return Cow::Borrowed(logs);
+    }
+
+    let keep = max_bytes.saturating_sub(ELISION_MARKER_RESERVE) / 2;
+    let is_continuation = |i: usize| i < logs.len() && logs[i] & 0xC0 == 0x80;
+
+    let mut head_end = keep;
+    while head_end > 0 && is_continuation(head_end) {
+        head_end -= 1;
+    }
+    let mut tail_start = logs.len() - keep;
+    while is_continuation(tail_start) {
+        tail_start += 1;
+    }
+
+    let mut res = logs[..head_end].to_vec();
+    res.extend_from_slice(
+        format!("\n... {} bytes elided ...\n", tail_start - head_end).as_bytes(),
+    );
+    res.extend_from_slice(&logs[tail_start..]);
+    Cow::Owned(res)
+}
+
+/// Collect toolchain versions of the build environment, best effort
+async fn get_environment(args: &Args, ciel_path: &Path) -> BTreeMap<String, String> {
+    let mut res = BTreeMap::new();
+
+    if let Ok(output) = Command::new("uname").arg("-r").output().await {
+        if output.status.success() {
+            res.insert(
+                "kernel".to_string(),
+                String::from_utf8_lossy(&output.stdout).trim().to_string(),
+            );
+        }
+    }
+
+    // missing packages are reported to stderr, keep the others
+    if let Ok(output) = Command::new("ciel")
+        .args([
+            "shell",
+            "-i",
+            &args.ciel_instance,
+            "dpkg-query -W -f '${Package} ${Version}\\n' gcc glibc autobuild3 autobuild4 acbs",
+        ])
+        .current_dir(ciel_path)
+        .output()
+        .await
+    {
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            if let Some((package, version)) = line.trim().split_once(' ') {
+                res.insert(package.to_string(), version.to_string());
+            }
+        }
+    }
+
+    res
+}
+
 async fn build(
     job: &WorkerPollResponse,
-    tree_path: &Path,
     args: &Args,
     tx: Sender<Message>,
 ) -> anyhow::Result<WorkerJobUpdateRequest> {
     let begin = Instant::now();
+    // jobs of other archs come from a shared queue
+    let arch = args.build_arch(&job.arch);
+    let ciel_path = args
+        .ciel_path_of(arch)
+        .ok_or_else(|| anyhow!("No ciel workspace for {arch}, see --ciel-workspaces"))?
+        .to_path_buf();
+    let tree_path = ciel_path.join("TREE");
+    let sampler = ResourceSampler::start(ciel_path.clone());
     let mut successful_packages = vec![];
     let mut failed_package = None;
     let mut skipped_packages = vec![];
     let mut build_success = false;
+    let mut package_timings = None;
     let mut logs = vec![];
 
-    let mut output_path = args.ciel_path.clone();
+    let mut output_path = ciel_path.clone();
     output_path.push(format!("OUTPUT-{}", job.git_branch));
 
     // clear output directory
@@ -176,10 +416,14 @@ async fn build(
         "git",
         &[
             "fetch",
-            "https://github.com/AOSC-Dev/aosc-os-abbs.git",
+            &job.source_url(
+                job.git_url
+                    .as_deref()
+                    .unwrap_or("https://github.com/AOSC-Dev/aosc-os-abbs.git"),
+            ),
             &job.git_branch,
         ],
-        tree_path,
+        &tree_path,
         &mut logs,
         tx.clone(),
     )
@@ -193,7 +437,7 @@ async fn build(
         get_output_logged(
             "git",
             &["checkout", "-b", &job.git_branch],
-            tree_path,
+            &tree_path,
             &mut logs,
             tx.clone(),
         )
@@ -202,7 +446,7 @@ async fn build(
         get_output_logged(
             "git",
             &["checkout", &job.git_branch],
-            tree_path,
+            &tree_path,
             &mut logs,
             tx.clone(),
         )
@@ -213,7 +457,7 @@ async fn build(
         let output = get_output_logged(
             "git",
             &["reset", &job.git_sha, "--hard"],
-            tree_path,
+            &tree_path,
             &mut logs,
             tx.clone(),
         )
@@ -224,7 +468,7 @@ async fn build(
             get_output_logged(
                 "ciel",
                 &["update-os"],
-                &args.ciel_path,
+                &ciel_path,
                 &mut logs,
                 tx.clone(),
             )
@@ -233,9 +477,17 @@ async fn build(
             // build packages
             let mut ciel_args = vec!["build", "-i", &args.ciel_instance];
             ciel_args.extend(job.packages.split(','));
+            let (progress_tx, progress_rx) = flume::unbounded();
+            let progress = tokio::spawn(forward_progress(
+                progress_rx,
+                tx.clone(),
+                args.clone(),
+                job.job_id,
+            ));
             let output =
-                get_output_logged("ciel", &ciel_args, &args.ciel_path, &mut logs, tx.clone())
+                get_output_logged("ciel", &ciel_args, &ciel_path, &mut logs, progress_tx)
                     .await?;
+            package_timings = progress.await.ok().filter(|timings| !timings.is_empty());
 
             build_success = output.status.success();
 
@@ -286,7 +538,17 @@ async fn build(
                 }
             }
 
-            if build_success {
+            if build_success && job.skip_pushpkg {
+                // tags and commits have no topic to push to
+                let msg = format!(
+                    "{}: Not pushing packages, {} is not a branch\n",
+                    Local::now(),
+                    job.git_branch
+                );
+                logs.extend(msg.as_bytes());
+                info!("{}", msg.trim());
+                pushpkg_success = true;
+            } else if build_success {
                 if let Some(upload_ssh_key) = &args.upload_ssh_key {
                     let mut pushpkg_args = vec![
                         "--host",
@@ -320,13 +582,24 @@ async fn build(
         "{}-{}-{}-{}-{}.txt",
         job.job_id,
         job.git_branch,
-        args.arch,
+        arch,
         gethostname::gethostname().to_string_lossy(),
         Local::now().format("%Y-%m-%d-%H:%M:%S")
     );
 
+    // attach log tail on failure, so that server can diagnose it
+    let log_tail = if build_success && pushpkg_success {
+        None
+    } else {
+        Some(get_log_tail(&logs, 100))
+    };
+
+    let environment = get_environment(args, &ciel_path).await;
+    let (peak_memory_bytes, disk_bytes) = sampler.usage();
+    drop(sampler);
+
     let path = format!("/tmp/{file_name}");
-    fs::write(&path, logs).await?;
+    fs::write(&path, truncate_log(&logs, args.max_log_bytes)).await?;
 
     let mut log_url = None;
     if let Some(upload_ssh_key) = &args.upload_ssh_key {
@@ -375,16 +648,41 @@ async fn build(
             log_url,
             elapsed_secs: begin.elapsed().as_secs() as i64,
             pushpkg_success,
+            log_tail,
+            environment: Some(environment),
+            peak_memory_bytes: Some(peak_memory_bytes),
+            disk_bytes: Some(disk_bytes),
+            package_timings,
         }),
+        schema_version: common::JOB_RESULT_SCHEMA_VERSION,
     };
 
     Ok(result)
 }
 
-async fn build_worker_inner(args: &Args, tx: Sender<Message>) -> anyhow::Result<()> {
-    let mut tree_path = args.ciel_path.clone();
-    tree_path.push("TREE");
+/// Last job the server asked to stop
+static CANCELLED_JOB: AtomicI32 = AtomicI32::new(0);
+
+static CANCEL_JOB: Notify = Notify::const_new();
+
+/// Stop building the job if it is running, the server cancelled it
+pub fn cancel_job(job_id: i32) {
+    CANCELLED_JOB.store(job_id, Ordering::SeqCst);
+    CANCEL_JOB.notify_waiters();
+}
+
+/// Resolves once the job is cancelled
+async fn cancelled(job_id: i32) {
+    loop {
+        let notified = CANCEL_JOB.notified();
+        if CANCELLED_JOB.load(Ordering::SeqCst) == job_id {
+            return;
+        }
+        notified.await;
+    }
+}
 
+async fn build_worker_inner(args: &Args, tx: Sender<Message>) -> anyhow::Result<()> {
     info!("Receiving new messages");
 
     let client = reqwest::Client::builder()
@@ -400,6 +698,8 @@ async fn build_worker_inner(args: &Args, tx: Sender<Message>) -> anyhow::Result<
         memory_bytes: get_memory_bytes(),
         disk_free_space_bytes: fs2::free_space(std::env::current_dir()?)? as i64,
         logical_cores: num_cpus::get() as i32,
+        labels: args.labels.clone(),
+        arch_patterns: args.arch_patterns.clone(),
     };
 
     loop {
@@ -413,7 +713,16 @@ async fn build_worker_inner(args: &Args, tx: Sender<Message>) -> anyhow::Result<
         {
             info!("Processing job {:?}", job);
 
-            match build(&job, &tree_path, args, tx.clone()).await {
+            let res = tokio::select! {
+                res = build(&job, args, tx.clone()) => Some(res),
+                _ = cancelled(job.job_id) => None,
+            };
+            let Some(res) = res else {
+                // the server rejects results of cancelled jobs
+                warn!("Job {} was cancelled, stopped building it", job.job_id);
+                continue;
+            };
+            match res {
                 Ok(result) => {
                     // post result
                     info!("Finished to run job {:?} with result {:?}", job, result);
@@ -433,6 +742,7 @@ async fn build_worker_inner(args: &Args, tx: Sender<Message>) -> anyhow::Result<
                             worker_secret: args.worker_secret.clone(),
                             job_id: job.job_id,
                             result: common::JobResult::Error(err.to_string()),
+                            schema_version: common::JOB_RESULT_SCHEMA_VERSION,
                         })
                         .send()
                         .await?;
@@ -452,3 +762,48 @@ pub async fn build_worker(args: Args, tx: Sender<Message>) -> ! {
         tokio::time::sleep(Duration::from_secs(5)).await;
     }
 }
+
+#[test]
+fn test_truncate_log() {
+    // under the limit
+    let logs = b"configure\nmake\nerror\n";
+    assert_eq!(truncate_log(logs, 1024), Cow::Borrowed(&logs[..]));
+    assert_eq!(truncate_log(logs, logs.len()).len(), logs.len());
+
+    // over the limit
+    let mut logs = b"configure: ok\n".to_vec();
+    logs.extend_from_slice(&[b'x'; 10000]);
+    logs.extend_from_slice(b"\nerror: failed\n");
+    let truncated = truncate_log(&logs, 1024);
+    assert!(truncated.len() <= 1024);
+    let truncated = String::from_utf8(truncated.into_owned()).unwrap();
+    assert!(truncated.starts_with("configure: ok\n"));
+    assert!(truncated.ends_with("\nerror: failed\n"));
+    // (1024 - 64) / 2 bytes are kept on each side
+    assert!(truncated.contains(&format!("\n... {} bytes elided ...\n", logs.len() - 960)));
+
+    // multibyte characters are not split
+    let logs = "构建失败".repeat(100);
+    for max_bytes in 100..110 {
+        let truncated = truncate_log(logs.as_bytes(), max_bytes);
+        let truncated = String::from_utf8(truncated.into_owned()).unwrap();
+        assert!(truncated.starts_with('构'));
+        assert!(truncated.ends_with('败'));
+    }
+
+    // the smallest limit leaves room for the marker only
+    let truncated = truncate_log(logs.as_bytes(), ELISION_MARKER_RESERVE);
+    assert!(truncated.len() <= ELISION_MARKER_RESERVE);
+    assert_eq!(
+        truncated,
+        Cow::Owned::<[u8]>(format!("\n... {} bytes elided ...\n", logs.len()).into_bytes())
+    );
+}
+
+#[test]
+fn test_get_log_tail() {
+    let logs = b"configure\nmake\nCC \xff\xfe.o\r\nerror\n";
+    assert_eq!(get_log_tail(logs, 2), LogBytes(b"CC \xff\xfe.o\r\nerror".to_vec()));
+    assert_eq!(get_log_tail(logs, 10).0, logs[..logs.len() - 1]);
+    assert_eq!(get_log_tail(b"", 10), LogBytes(vec![]));
+}
@@ -1,5 +1,5 @@
 use crate::{get_memory_bytes, Args};
-use common::WorkerHeartbeatRequest;
+use common::{WorkerHeartbeatRequest, WorkerRegisterRequest};
 use log::{info, warn};
 use std::{
     sync::atomic::{AtomicBool, Ordering},
@@ -8,6 +8,13 @@ use std::{
 
 static INTERNET_CONNECTIVITY: AtomicBool = AtomicBool::new(false);
 
+static HEARTBEAT_NOW: tokio::sync::Notify = tokio::sync::Notify::const_new();
+
+/// Send the next heartbeat right away, e.g. when the server asks for it
+pub fn heartbeat_now() {
+    HEARTBEAT_NOW.notify_one();
+}
+
 pub async fn internet_connectivity_worker() -> ! {
     info!("Starting internet connectivity worker");
     let client = reqwest::Client::builder()
@@ -31,24 +38,51 @@ pub async fn heartbeat_worker_inner(args: &Args) -> anyhow::Result<()> {
         .timeout(Duration::from_secs(30))
         .build()
         .unwrap();
+    let hostname = gethostname::gethostname().to_string_lossy().to_string();
+
+    // registering again after errors is harmless
+    info!("Registering worker {}", hostname);
+    client
+        .post(format!("{}/api/worker/register", args.server))
+        .json(&WorkerRegisterRequest {
+            hostname: hostname.clone(),
+            arch: args.arch.clone(),
+            worker_secret: args.worker_secret.clone(),
+            git_commit: env!("VERGEN_GIT_DESCRIBE").to_string(),
+            memory_bytes: get_memory_bytes(),
+            logical_cores: num_cpus::get() as i32,
+            disk_free_space_bytes: fs2::free_space(std::env::current_dir()?)? as i64,
+            performance: args.worker_performance,
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            labels: args.labels.clone(),
+        })
+        .send()
+        .await?
+        .error_for_status()?;
+
     loop {
         // info!("Sending heartbeat");
         client
             .post(format!("{}/api/worker/heartbeat", args.server))
             .json(&WorkerHeartbeatRequest {
-                hostname: gethostname::gethostname().to_string_lossy().to_string(),
+                hostname: hostname.clone(),
                 arch: args.arch.clone(),
                 worker_secret: args.worker_secret.clone(),
-                git_commit: env!("VERGEN_GIT_DESCRIBE").to_string(),
-                memory_bytes: get_memory_bytes(),
                 disk_free_space_bytes: fs2::free_space(std::env::current_dir()?)? as i64,
-                logical_cores: num_cpus::get() as i32,
-                performance: args.worker_performance,
                 internet_connectivity: Some(INTERNET_CONNECTIVITY.load(Ordering::SeqCst)),
+                git_commit: None,
+                memory_bytes: None,
+                logical_cores: None,
+                performance: None,
+                version: None,
+                labels: None,
             })
             .send()
             .await?;
-        tokio::time::sleep(Duration::from_secs(60)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(60)) => {}
+            _ = HEARTBEAT_NOW.notified() => {}
+        }
     }
 }
 
@@ -1,5 +1,5 @@
 use clap::Parser;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use sysinfo::System;
 
 pub mod build;
@@ -58,6 +58,79 @@ pub struct Args {
     /// Performance number of the worker (smaller is better)
     #[arg(short = 'p', long, env = "BUILDIT_WORKER_PERFORMANCE")]
     pub worker_performance: Option<i64>,
+
+    /// Capabilities of the worker, separated by commas, e.g. bigmem,gpu
+    #[arg(long, env = "BUILDIT_WORKER_LABELS", value_delimiter = ',')]
+    pub labels: Vec<String>,
+
+    /// Arch patterns to take jobs of when the server uses a shared queue,
+    /// separated by commas, e.g. loong*,mips64r6el. Jobs of archs other than
+    /// `arch` are built in `ciel_workspaces`
+    #[arg(long, env = "BUILDIT_ARCH_PATTERNS", value_delimiter = ',')]
+    pub arch_patterns: Vec<String>,
+
+    /// Logs larger than this are truncated to their head and tail before upload
+    #[arg(
+        long,
+        default_value_t = 64 * 1024 * 1024,
+        env = "BUILDIT_MAX_LOG_BYTES",
+        value_parser = parse_max_log_bytes
+    )]
+    pub max_log_bytes: usize,
+
+    /// Ciel workspaces of the other archs matched by arch patterns, separated
+    /// by commas, e.g. loongson3=/buildroots/loongson3
+    #[arg(
+        long,
+        env = "BUILDIT_CIEL_WORKSPACES",
+        value_delimiter = ',',
+        value_parser = parse_ciel_workspace
+    )]
+    pub ciel_workspaces: Vec<(String, PathBuf)>,
+}
+
+fn parse_ciel_workspace(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((arch, path)) if !arch.is_empty() && !path.is_empty() => {
+            Ok((arch.to_string(), PathBuf::from(path)))
+        }
+        _ => Err(format!("expected ARCH=PATH, got {s}")),
+    }
+}
+
+fn parse_max_log_bytes(s: &str) -> Result<usize, String> {
+    let max_bytes = s.parse::<usize>().map_err(|err| err.to_string())?;
+    // smaller limits would be exceeded by the elision marker alone
+    if max_bytes < build::ELISION_MARKER_RESERVE {
+        return Err(format!(
+            "must be at least {} bytes, got {max_bytes}",
+            build::ELISION_MARKER_RESERVE
+        ));
+    }
+    Ok(max_bytes)
+}
+
+impl Args {
+    /// Arch to build a job of `job_arch` as: noarch jobs, and those of
+    /// servers predating shared queues, are built as the worker arch
+    pub fn build_arch<'a>(&'a self, job_arch: &'a str) -> &'a str {
+        if job_arch.is_empty() || job_arch == "noarch" {
+            &self.arch
+        } else {
+            job_arch
+        }
+    }
+
+    /// Ciel workspace to build `arch` in, `None` if the worker cannot build it
+    pub fn ciel_path_of(&self, arch: &str) -> Option<&Path> {
+        if arch == self.arch {
+            return Some(&self.ciel_path);
+        }
+        self.ciel_workspaces
+            .iter()
+            .find(|(workspace_arch, _)| workspace_arch == arch)
+            .map(|(_, path)| path.as_path())
+    }
 }
 
 pub fn get_memory_bytes() -> i64 {
@@ -68,3 +141,74 @@ pub fn get_memory_bytes() -> i64 {
         system.total_memory() as i64
     }
 }
+
+#[test]
+fn test_ciel_path_of() {
+    let args = Args::parse_from([
+        "worker",
+        "--server",
+        "https://buildit.aosc.io",
+        "--worker-secret",
+        "secret",
+        "--arch",
+        "loongarch64",
+        "--ciel-path",
+        "/buildroots/loongarch64",
+        "--ciel-workspaces",
+        "loongson3=/buildroots/loongson3",
+    ]);
+    assert_eq!(args.build_arch("loongson3"), "loongson3");
+    assert_eq!(args.build_arch("noarch"), "loongarch64");
+    // from servers predating shared queues
+    assert_eq!(args.build_arch(""), "loongarch64");
+
+    assert_eq!(
+        args.ciel_path_of("loongarch64"),
+        Some(Path::new("/buildroots/loongarch64"))
+    );
+    assert_eq!(
+        args.ciel_path_of("loongson3"),
+        Some(Path::new("/buildroots/loongson3"))
+    );
+    assert_eq!(args.ciel_path_of("amd64"), None);
+
+    assert!(Args::try_parse_from([
+        "worker",
+        "--server",
+        "https://buildit.aosc.io",
+        "--worker-secret",
+        "secret",
+        "--arch",
+        "loongarch64",
+        "--ciel-path",
+        "/buildroots/loongarch64",
+        "--ciel-workspaces",
+        "loongson3",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_max_log_bytes() {
+    let parse = |max_log_bytes: &str| {
+        Args::try_parse_from([
+            "worker",
+            "--server",
+            "https://buildit.aosc.io",
+            "--worker-secret",
+            "secret",
+            "--arch",
+            "amd64",
+            "--ciel-path",
+            "/buildroots/amd64",
+            "--max-log-bytes",
+            max_log_bytes,
+        ])
+        .map(|args| args.max_log_bytes)
+    };
+    assert_eq!(parse("1048576").unwrap(), 1048576);
+    assert_eq!(parse("64").unwrap(), 64);
+    assert!(parse("63").is_err());
+    assert!(parse("0").is_err());
+    assert!(parse("1M").is_err());
+}
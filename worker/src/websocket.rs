@@ -1,10 +1,17 @@
-use crate::Args;
+use crate::{build::cancel_job, heartbeat::heartbeat_now, Args};
+use common::{WorkerControl, WorkerControlReply, WORKER_SECRET_HEADER};
 use flume::Receiver;
-use futures_util::StreamExt;
+use futures_util::{SinkExt, StreamExt};
 use log::{info, warn};
 use reqwest::Url;
-use std::time::Duration;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use std::{collections::VecDeque, time::Duration};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+};
+
+/// Log lines kept to answer live log requests
+const LIVE_LOG_LINES: usize = 200;
 
 pub async fn websocket_worker(args: Args, rx: Receiver<Message>) -> anyhow::Result<()> {
     // wss://hostname/api/ws/worker/:hostname
@@ -15,14 +22,70 @@ pub async fn websocket_worker(args: Args, rx: Receiver<Message>) -> anyhow::Resu
         .join("worker/")?
         .join(&hostname)?;
 
+    let mut tail = VecDeque::new();
     loop {
         info!("Starting websocket connect to {:?}", ws);
-        match connect_async(ws.as_str()).await {
+        let mut req = ws.as_str().into_client_request()?;
+        req.headers_mut()
+            .insert(WORKER_SECRET_HEADER, HeaderValue::from_str(&args.worker_secret)?);
+        match connect_async(req).await {
             Ok((ws_stream, _)) => {
-                let (write, _) = ws_stream.split();
-                let rx = rx.clone().into_stream();
-                if let Err(e) = rx.map(Ok).forward(write).await {
-                    warn!("Failed to forward message to websocket: {e}");
+                let (mut write, mut read) = ws_stream.split();
+                let mut rx = rx.clone().into_stream();
+                loop {
+                    tokio::select! {
+                        msg = rx.next() => {
+                            let Some(msg) = msg else {
+                                break;
+                            };
+                            if let Message::Text(line) = &msg {
+                                tail.push_back(line.clone());
+                                if tail.len() > LIVE_LOG_LINES {
+                                    tail.pop_front();
+                                }
+                            }
+                            if let Err(e) = write.send(msg).await {
+                                warn!("Failed to forward message to websocket: {e}");
+                                break;
+                            }
+                        }
+                        msg = read.next() => {
+                            let text = match msg {
+                                Some(Ok(Message::Text(text))) => text,
+                                Some(Ok(_)) => continue,
+                                Some(Err(e)) => {
+                                    warn!("Failed to read from websocket: {e}");
+                                    break;
+                                }
+                                None => break,
+                            };
+                            let reply = match serde_json::from_str::<WorkerControl>(&text) {
+                                Ok(WorkerControl::RequestLiveLog { request_id, job_id }) => {
+                                    WorkerControlReply::LiveLog {
+                                        request_id,
+                                        job_id,
+                                        log: Vec::from(tail.clone()).join("\n"),
+                                    }
+                                }
+                                Ok(WorkerControl::PingNow) => {
+                                    heartbeat_now();
+                                    continue;
+                                }
+                                Ok(WorkerControl::CancelJob { job_id }) => {
+                                    cancel_job(job_id);
+                                    continue;
+                                }
+                                Err(e) => {
+                                    warn!("Got unknown request from server: {e}");
+                                    continue;
+                                }
+                            };
+                            if let Err(e) = write.send(Message::Binary(serde_json::to_vec(&reply)?)).await {
+                                warn!("Failed to send live log to websocket: {e}");
+                                break;
+                            }
+                        }
+                    }
                 }
             }
             Err(err) => {